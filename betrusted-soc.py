@@ -543,6 +543,25 @@ class BtEvents(Module, AutoCSR, AutoDoc):
         self.comb += self.ev.com_int.trigger.eq(com_int)
         self.comb += self.ev.rtc_int.trigger.eq(rtc_int)
 
+# XadcAlarm ----------------------------------------------------------------------------------------
+
+class XadcAlarm(Module, AutoCSR, AutoDoc):
+    def __init__(self, alarm, ot):
+        self.intro = ModuleDoc("""XadcAlarm - interrupt on XADC alarms
+
+        Raises an event whenever any of the XADC's alarm outputs or its over-temperature output
+        changes, in either direction. Which alarms are active is read from the XADC's flag register.
+        """)
+        self.submodules.ev = EventManager()
+        self.ev.change = EventSourcePulse()   # one cycle per change
+        self.ev.finalize()
+
+        alarms = Signal(len(alarm) + 1)
+        alarms_d = Signal(len(alarm) + 1)
+        self.specials += MultiReg(Cat(alarm, ot), alarms)
+        self.sync += alarms_d.eq(alarms)
+        self.comb += self.ev.change.trigger.eq(alarms != alarms_d)
+
 # BtPower ------------------------------------------------------------------------------------------
 
 class BtPower(Module, AutoCSR, AutoDoc):
@@ -742,6 +761,7 @@ class RomTest(Module, AutoDoc, AutoCSR):
 # System constants ---------------------------------------------------------------------------------
 
 boot_offset    = 0x500000 # enough space to hold 2x FPGA bitstreams before the firmware start
+loader_offset  = 0x280000 # fw/loader, which starts firmware slot A or B
 bios_size      = 0x8000
 SPI_FLASH_SIZE = 128 * 1024 * 1024
 
@@ -772,8 +792,8 @@ class BetrustedSoC(SoCCore):
         ## a user will burn a random AES key into their FPGA and encrypt their bitstream to their
         ## unique AES key, creating a root of trust that offers a defense against trivial patch attacks.
 
-        if xous == False:  # raw firmware boots from SPINOR directly; xous boots from default Litex internal ROM
-            reset_address = self.mem_map["spiflash"]+boot_offset
+        if xous == False:  # raw firmware boots through fw/loader on SPINOR; xous boots from default Litex internal ROM
+            reset_address = self.mem_map["spiflash"]+loader_offset
             bios_size = 0
         else:
             reset_address = self.mem_map["rom"]
@@ -1043,6 +1063,14 @@ class BetrustedSoC(SoCCore):
             self.platform.add_platform_command('set_false_path -rise_from [get_clocks usb_12] -rise_to [get_clocks sys_clk] -through [get_cells -filter {{NAME =~ "storage_5*"}}]')
             self.platform.add_platform_command('set_false_path -rise_from [get_clocks usb_12] -rise_to [get_clocks sys_clk] -through [get_cells -filter {{NAME =~ "storage_6*"}}]')
             self.platform.add_platform_command('set_false_path -rise_from [get_clocks usb_12] -rise_to [get_clocks sys_clk] -through [get_cells -filter {{NAME =~ "storage_7*"}}]')
+
+        # XADC alarm events ------------------------------------------------------------------------
+        # added after all the other interrupts, usb included, so that their numbers don't move;
+        # that makes it 8 on EVT and 9 on DVT (XADC_ALARM_IRQ in hal_supervisor.rs)
+        self.submodules.xadcalarm = XadcAlarm(self.info.xadc.alarm, self.info.xadc.ot)
+        self.add_csr("xadcalarm")
+        self.add_interrupt("xadcalarm")
+
         # Lock down both ICAPE2 blocks -------------------------------------------------------------
         # this attempts to make it harder to partially reconfigure a bitstream that attempts to use
         # the ICAP block. An ICAP block can read out everything inside the FPGA, including key ROM,
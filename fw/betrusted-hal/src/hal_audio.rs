@@ -2,7 +2,7 @@ use bitflags::*;
 use volatile::Volatile;
 use crate::hal_i2c::i2c_master;
 use crate::hal_time::delay_ms;
-use crate::hal_time::get_time_ms;
+use crate::hal_time::Deadline;
 use crate::hal_xadc::*;
 
 pub const LM49352_I2C_ADR: u8 = 0b001_1010;
@@ -594,8 +594,8 @@ impl BtAudio {
         let mut sample: u32;
 
         xadc.audio_only();
-        let start: u32 = get_time_ms(&self.p);
-        while get_time_ms(&self.p) - start < 10000 {
+        let deadline: Deadline = Deadline::from_ms(&self.p, 10000);
+        while !deadline.expired(&self.p) {
             if self.p.AUDIO.tx_stat.read().free().bit() {
                 sample = xadc.audio() as u32;
                 unsafe { (*volatile_audio).write(sample | (sample << 16)); }
@@ -0,0 +1,390 @@
+use alloc::vec::Vec;
+
+/// number of words in a gas gauge response from an EC on the legacy protocol
+pub const GG_WORDS: usize = 4;
+/// number of words in a gas gauge response over the framed protocol
+pub const GG_WORDS_EXTENDED: usize = 7;
+
+/// One reading of the battery gas gauge, as relayed by the EC.
+///
+/// Response word layout: avg current, standby current, voltage, and a fourth word that isn't
+/// decoded. Over the framed protocol, the fourth word is the remaining capacity and three more
+/// follow: full-charge capacity, state of charge, temperature.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GasGauge {
+    /// average current, negative when discharging
+    pub avg_current_ma: i16,
+    /// standby current
+    pub sby_current_ma: i16,
+    /// battery voltage
+    pub voltage_mv: u16,
+    /// only reported over the framed protocol
+    pub charge: Option<ChargeState>,
+}
+
+/// the part of a gas gauge reading only an EC on the framed protocol reports
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChargeState {
+    /// capacity left in the battery
+    pub remaining_mah: u16,
+    /// capacity of the battery when fully charged
+    pub full_mah: u16,
+    /// state of charge, 0-100
+    pub soc_percent: u8,
+    /// temperature in units of 0.1K, as the gauge reports it
+    pub temperature_dk: u16,
+}
+
+impl GasGauge {
+    /// None unless `words` is GG_WORDS or GG_WORDS_EXTENDED long
+    pub fn from_words(words: &[u16]) -> Option<Self> {
+        let charge: Option<ChargeState> = match words.len() {
+            GG_WORDS => None,
+            GG_WORDS_EXTENDED => Some(ChargeState {
+                remaining_mah: words[3],
+                full_mah: words[4],
+                soc_percent: words[5].min(100) as u8,
+                temperature_dk: words[6],
+            }),
+            _ => return None,
+        };
+        Some(GasGauge {
+            avg_current_ma: words[0] as i16,
+            sby_current_ma: words[1] as i16,
+            voltage_mv: words[2],
+            charge,
+        })
+    }
+
+    /// GG_WORDS_EXTENDED words if there is a charge state, GG_WORDS otherwise
+    pub fn to_words(&self) -> Vec<u16> {
+        let mut words: Vec<u16> = Vec::with_capacity(GG_WORDS_EXTENDED);
+        words.extend_from_slice(&[self.avg_current_ma as u16, self.sby_current_ma as u16, self.voltage_mv]);
+        match self.charge {
+            Some(c) => words.extend_from_slice(&[c.remaining_mah, c.full_mah, c.soc_percent as u16, c.temperature_dk]),
+            None => words.push(0),
+        }
+        words
+    }
+
+    /// the gauge's state of charge, or one estimated from the voltage on the legacy protocol
+    pub fn soc_percent(&self) -> Option<u8> {
+        match self.charge {
+            Some(c) => Some(c.soc_percent),
+            None => self.estimated_soc_percent(),
+        }
+    }
+
+    /// true if soc_percent() is a guess from the voltage rather than the gauge's figure
+    pub fn soc_estimated(&self) -> bool {
+        self.charge.is_none()
+    }
+
+    /// state of charge interpolated from SOC_CURVE; None for a reading with no voltage
+    pub fn estimated_soc_percent(&self) -> Option<u8> {
+        let mv: u16 = self.voltage_mv;
+        if mv == 0 {
+            return None;
+        }
+        let (mut lo_mv, mut lo_soc): (u16, u8) = SOC_CURVE[0];
+        if mv <= lo_mv {
+            return Some(0);
+        }
+        for &(hi_mv, hi_soc) in SOC_CURVE[1..].iter() {
+            if mv <= hi_mv {
+                let span: u32 = (hi_soc - lo_soc) as u32 * (mv - lo_mv) as u32 / (hi_mv - lo_mv) as u32;
+                return Some(lo_soc + span as u8);
+            }
+            lo_mv = hi_mv;
+            lo_soc = hi_soc;
+        }
+        Some(100)
+    }
+
+    pub fn charging(&self) -> bool {
+        self.avg_current_ma > 0
+    }
+}
+
+impl ChargeState {
+    /// temperature in units of 0.1C
+    pub fn temperature_dc(&self) -> i16 {
+        (self.temperature_dk as i32 - 2732).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BatteryEvent {
+    /// state of charge fell to or below the low threshold
+    Low,
+    /// state of charge climbed back to or above the recovery threshold
+    Recovered,
+}
+
+/// Resting cell voltage against state of charge for a single Li-ion cell, used to estimate
+/// the charge when the EC doesn't report one. Voltage sags under load and climbs on the charger,
+/// so the estimate is rough; it's good for a low-battery warning, not for a percentage readout.
+const SOC_CURVE: [(u16, u8); 10] = [
+    (3300, 0), (3500, 5), (3600, 10), (3700, 30), (3750, 45),
+    (3800, 55), (3900, 70), (4000, 82), (4100, 92), (4200, 100),
+];
+
+/// number of samples kept for averaging
+pub const BATT_HISTORY: usize = 32;
+/// default state of charge at which BatteryEvent::Low fires
+pub const BATT_LOW_PERCENT: u8 = 10;
+/// default state of charge at which BatteryEvent::Recovered fires
+pub const BATT_RECOVER_PERCENT: u8 = 15;
+
+#[derive(Copy, Clone, Debug, Default)]
+struct BatterySample {
+    time_ms: u32,
+    gauge: GasGauge,
+}
+
+/// Keeps a history of gas gauge readings and derives battery metrics from it, so
+/// consumers don't each have to interpret raw readings.
+pub struct BtBattery {
+    samples: [BatterySample; BATT_HISTORY],
+    /// index of the next sample to overwrite
+    head: usize,
+    count: usize,
+    low_percent: u8,
+    recover_percent: u8,
+    low: bool,
+}
+
+impl BtBattery {
+    pub fn new() -> Self {
+        BtBattery {
+            samples: [BatterySample::default(); BATT_HISTORY],
+            head: 0,
+            count: 0,
+            low_percent: BATT_LOW_PERCENT,
+            recover_percent: BATT_RECOVER_PERCENT,
+            low: false,
+        }
+    }
+
+    /// recover_percent is forced above low_percent so the event can't chatter
+    pub fn set_thresholds(&mut self, low_percent: u8, recover_percent: u8) {
+        self.low_percent = low_percent;
+        self.recover_percent = recover_percent.max(low_percent.saturating_add(1));
+    }
+
+    /// Records a new reading taken at `time_ms`, and returns an event if the low battery
+    /// state changed. On the legacy protocol the state of charge is estimated from the voltage;
+    /// readings with neither never change it.
+    pub fn update(&mut self, time_ms: u32, gauge: GasGauge) -> Option<BatteryEvent> {
+        self.samples[self.head] = BatterySample { time_ms, gauge };
+        self.head = (self.head + 1) % BATT_HISTORY;
+        if self.count < BATT_HISTORY {
+            self.count += 1;
+        }
+
+        let soc_percent: u8 = gauge.soc_percent()?;
+        if !self.low && soc_percent <= self.low_percent {
+            self.low = true;
+            Some(BatteryEvent::Low)
+        } else if self.low && soc_percent >= self.recover_percent {
+            self.low = false;
+            Some(BatteryEvent::Recovered)
+        } else {
+            None
+        }
+    }
+
+    pub fn latest(&self) -> Option<GasGauge> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.samples[(self.head + BATT_HISTORY - 1) % BATT_HISTORY].gauge)
+        }
+    }
+
+    /// time covered by the sample history, in ms
+    pub fn history_ms(&self) -> u32 {
+        if self.count < 2 {
+            return 0;
+        }
+        let newest = self.samples[(self.head + BATT_HISTORY - 1) % BATT_HISTORY].time_ms;
+        let oldest = self.samples[(self.head + BATT_HISTORY - self.count) % BATT_HISTORY].time_ms;
+        newest.wrapping_sub(oldest)
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.low
+    }
+
+    /// average current over the sample history, negative when discharging
+    pub fn avg_current_ma(&self) -> Option<i16> {
+        if self.count == 0 {
+            return None;
+        }
+        let mut sum: i32 = 0;
+        for i in 0..self.count {
+            sum += self.samples[i].gauge.avg_current_ma as i32;
+        }
+        Some((sum / self.count as i32) as i16)
+    }
+
+    /// minutes until the battery is empty at the average discharge rate; None if not discharging
+    /// or if the EC doesn't report the remaining capacity, as on the legacy protocol
+    pub fn time_to_empty_min(&self) -> Option<u32> {
+        let current = self.avg_current_ma()?;
+        if current >= 0 {
+            return None;
+        }
+        let remaining = self.latest()?.charge?.remaining_mah as u32;
+        Some(remaining * 60 / (-(current as i32)) as u32)
+    }
+
+    /// minutes until the battery is full at the average charge rate; None if not charging or
+    /// if the EC doesn't report the capacity
+    pub fn time_to_full_min(&self) -> Option<u32> {
+        let current = self.avg_current_ma()?;
+        if current <= 0 {
+            return None;
+        }
+        let charge = self.latest()?.charge?;
+        let missing = charge.full_mah.saturating_sub(charge.remaining_mah) as u32;
+        Some(missing * 60 / current as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gauge(current: i16, remaining: u16, soc: u8) -> GasGauge {
+        GasGauge { avg_current_ma: current, sby_current_ma: -1, voltage_mv: 3800,
+            charge: Some(ChargeState { remaining_mah: remaining, full_mah: 1000, soc_percent: soc, temperature_dk: 2982 }) }
+    }
+
+    #[test]
+    fn words_roundtrip() {
+        let g = gauge(-250, 640, 64);
+        assert_eq!(g.to_words().len(), GG_WORDS_EXTENDED);
+        assert_eq!(GasGauge::from_words(&g.to_words()), Some(g));
+        assert_eq!(g.charge.unwrap().temperature_dc(), 250);
+        assert!(!g.charging());
+
+        let legacy = GasGauge { charge: None, ..g };
+        assert_eq!(legacy.to_words().len(), GG_WORDS);
+        assert_eq!(GasGauge::from_words(&legacy.to_words()), Some(legacy));
+        assert_eq!(GasGauge::from_words(&[0; 5]), None);
+    }
+
+    #[test]
+    fn soc_is_clamped() {
+        let mut words = gauge(0, 0, 0).to_words();
+        words[5] = 0xFFFF;
+        assert_eq!(GasGauge::from_words(&words).unwrap().soc_percent(), Some(100));
+    }
+
+    #[test]
+    fn temperature_saturates() {
+        let hot = ChargeState { temperature_dk: 0xFFFF, ..Default::default() };
+        assert_eq!(hot.temperature_dc(), i16::MAX);
+        let cold = ChargeState { temperature_dk: 0, ..Default::default() };
+        assert_eq!(cold.temperature_dc(), -2732);
+    }
+
+    #[test]
+    fn legacy_readings_have_no_charge_state() {
+        let mut b = BtBattery::new();
+        let legacy = GasGauge { charge: None, ..gauge(-100, 0, 0) };
+        assert_eq!(b.update(0, legacy), None);
+        assert!(!b.is_low());
+        assert_eq!(b.avg_current_ma(), Some(-100));
+        assert_eq!(b.time_to_empty_min(), None);
+        assert!(legacy.soc_estimated());
+        assert!(!gauge(-100, 0, 0).soc_estimated());
+    }
+
+    #[test]
+    fn legacy_charge_from_voltage() {
+        let at = |mv: u16| GasGauge { voltage_mv: mv, charge: None, ..Default::default() };
+        assert_eq!(at(0).soc_percent(), None);
+        assert_eq!(at(3000).soc_percent(), Some(0));
+        assert_eq!(at(3550).soc_percent(), Some(7));
+        assert_eq!(at(3800).soc_percent(), Some(55));
+        assert_eq!(at(4350).soc_percent(), Some(100));
+        // the gauge's own figure wins when there is one
+        assert_eq!(gauge(0, 0, 40).soc_percent(), Some(40));
+
+        // so a legacy EC still raises low battery
+        let mut b = BtBattery::new();
+        assert_eq!(b.update(0, at(3700)), None);
+        assert_eq!(b.update(1, at(3580)), Some(BatteryEvent::Low));
+        assert_eq!(b.update(2, at(3680)), Some(BatteryEvent::Recovered));
+    }
+
+    #[test]
+    fn empty_monitor() {
+        let b = BtBattery::new();
+        assert_eq!(b.latest(), None);
+        assert_eq!(b.avg_current_ma(), None);
+        assert_eq!(b.time_to_empty_min(), None);
+        assert_eq!(b.history_ms(), 0);
+    }
+
+    #[test]
+    fn time_to_empty_and_full() {
+        let mut b = BtBattery::new();
+        b.update(0, gauge(-100, 500, 50));
+        b.update(1000, gauge(-300, 500, 50));
+        assert_eq!(b.avg_current_ma(), Some(-200));
+        assert_eq!(b.time_to_empty_min(), Some(150));
+        assert_eq!(b.time_to_full_min(), None);
+
+        let mut b = BtBattery::new();
+        b.update(0, gauge(500, 750, 75));
+        assert_eq!(b.time_to_full_min(), Some(30));
+        assert_eq!(b.time_to_empty_min(), None);
+    }
+
+    #[test]
+    fn history_wraps() {
+        let mut b = BtBattery::new();
+        for i in 0..(BATT_HISTORY as u32 * 2) {
+            b.update(i * 100, gauge(if i < BATT_HISTORY as u32 { 1000 } else { -10 }, 500, 50));
+        }
+        // only the newer half is remembered
+        assert_eq!(b.avg_current_ma(), Some(-10));
+        assert_eq!(b.history_ms(), (BATT_HISTORY as u32 - 1) * 100);
+        assert_eq!(b.latest().unwrap().avg_current_ma, -10);
+    }
+
+    #[test]
+    fn history_across_timer_wrap() {
+        let mut b = BtBattery::new();
+        b.update(0xFFFF_FF00, gauge(-10, 500, 50));
+        b.update(0x0000_0100, gauge(-10, 500, 50));
+        assert_eq!(b.history_ms(), 0x200);
+    }
+
+    #[test]
+    fn low_battery_hysteresis() {
+        let mut b = BtBattery::new();
+        assert_eq!(b.update(0, gauge(-100, 200, 20)), None);
+        assert_eq!(b.update(1, gauge(-100, 100, 10)), Some(BatteryEvent::Low));
+        assert!(b.is_low());
+        // no repeats while low, and no recovery inside the hysteresis band
+        assert_eq!(b.update(2, gauge(-100, 90, 9)), None);
+        assert_eq!(b.update(3, gauge(100, 110, 11)), None);
+        assert_eq!(b.update(4, gauge(100, 140, 14)), None);
+        assert_eq!(b.update(5, gauge(100, 150, 15)), Some(BatteryEvent::Recovered));
+        assert_eq!(b.update(6, gauge(-100, 120, 12)), None);
+        assert_eq!(b.update(7, gauge(-100, 100, 10)), Some(BatteryEvent::Low));
+    }
+
+    #[test]
+    fn thresholds_keep_a_band() {
+        let mut b = BtBattery::new();
+        b.set_thresholds(20, 5);
+        assert_eq!(b.update(0, gauge(-100, 200, 20)), Some(BatteryEvent::Low));
+        assert_eq!(b.update(1, gauge(-100, 200, 20)), None);
+        assert_eq!(b.update(2, gauge(100, 210, 21)), Some(BatteryEvent::Recovered));
+    }
+}
@@ -0,0 +1,254 @@
+//! Where the A/B boot state lives, for the loader and the firmware alike.
+//!
+//! boot-control decides what to boot; this keeps its state in flash:
+//!
+//!   * the boot control block, as a log of records in the first two sectors of "bootctl"
+//!   * the rollback counter, as a thermometer code at the start of the OTP area, where no
+//!     amount of erasing or reflashing can take it back down
+//!   * the firmware slots, each a signed image header in the first sector, followed by the
+//!     payload at SLOT_CODE_OFFSET, which is where the slot's firmware is linked to run from
+
+use crate::hal_spinor::{flash_partition, FlashError, NorPhy, Partition, SpiNor, NOR_SECTOR, SPINOR_MMAP_BASE};
+use crate::hal_time::TickSource;
+use alloc::vec;
+use alloc::vec::Vec;
+use boot_control::{counter_advance, counter_value, log_latest, log_next, BootBlock, LogWrite, Slot, BOOT_LOG_LEN, BOOT_LOG_SECTOR};
+use fw_image::{ImageHash, ImageHeader, IMAGE_HEADER_LEN};
+
+/// offset of a slot's payload: the header gets the first sector to itself
+pub const SLOT_CODE_OFFSET: u32 = 0x1000;
+/// the rollback counter's share of the OTP area, which lets it count to 2048
+pub const ROLLBACK_OTP_OFFSET: u32 = 0;
+pub const ROLLBACK_OTP_LEN: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct BootLayout {
+    log: Partition,
+    slots: [Partition; 2],
+}
+
+impl BootLayout {
+    fn board() -> Self {
+        BootLayout {
+            log: flash_partition("bootctl").unwrap(),
+            slots: [flash_partition("firmware_a").unwrap(), flash_partition("firmware_b").unwrap()],
+        }
+    }
+}
+
+pub fn slot_partition(slot: Slot) -> Partition {
+    BootLayout::board().slots[slot.index()]
+}
+
+/// the CPU address the slot's firmware starts at
+pub fn slot_entry(slot: Slot) -> usize {
+    SPINOR_MMAP_BASE + (slot_partition(slot).offset + SLOT_CODE_OFFSET) as usize
+}
+
+/// Boot state on a flash chip
+pub struct BootStore<'a, T: NorPhy + TickSource> {
+    flash: &'a mut SpiNor<T>,
+    layout: BootLayout,
+}
+
+impl<'a, T: NorPhy + TickSource> BootStore<'a, T> {
+    pub fn new(flash: &'a mut SpiNor<T>) -> Self {
+        BootStore { flash, layout: BootLayout::board() }
+    }
+
+    fn log(&mut self) -> Result<Vec<u8>, FlashError> {
+        let mut log: Vec<u8> = vec![0; BOOT_LOG_LEN];
+        self.flash.read(self.layout.log.offset, &mut log)?;
+        Ok(log)
+    }
+
+    /// the newest boot control block, or None if there isn't an intact one
+    pub fn load(&mut self) -> Option<BootBlock> {
+        log_latest(&self.log().ok()?).map(|r| r.block)
+    }
+
+    pub fn store(&mut self, block: &BootBlock) -> Result<(), FlashError> {
+        let log: Vec<u8> = self.log()?;
+        let w: LogWrite = log_next(&log, block);
+        let base: u32 = self.layout.log.offset;
+        if let Some(sector) = w.erase {
+            self.flash.erase(base + sector as u32, BOOT_LOG_SECTOR as u32)?;
+        }
+        self.flash.program(base + w.offset as u32, &w.record)
+    }
+
+    /// the rollback counter, which the floor can never be below
+    pub fn counter(&mut self) -> Result<u32, FlashError> {
+        let mut otp: [u8; ROLLBACK_OTP_LEN] = [0; ROLLBACK_OTP_LEN];
+        self.flash.otp_read(ROLLBACK_OTP_OFFSET, &mut otp)?;
+        Ok(counter_value(&otp))
+    }
+
+    /// Raise the rollback counter to `to`; it's left alone if it's already there. This can't
+    /// be undone. FlashError::Bounds if the counter can't go that high.
+    pub fn advance_counter(&mut self, to: u32) -> Result<(), FlashError> {
+        let mut otp: [u8; ROLLBACK_OTP_LEN] = [0; ROLLBACK_OTP_LEN];
+        self.flash.otp_read(ROLLBACK_OTP_OFFSET, &mut otp)?;
+        let old: [u8; ROLLBACK_OTP_LEN] = otp;
+        if !counter_advance(&mut otp, to) {
+            return Err(FlashError::Bounds);
+        }
+        if otp != old {
+            self.flash.otp_program(ROLLBACK_OTP_OFFSET, &otp)?;
+        }
+        Ok(())
+    }
+
+    /// the header of the image in `slot`, unchecked; None if there's no image there
+    pub fn slot_header(&mut self, slot: Slot) -> Option<ImageHeader> {
+        let mut bytes: [u8; IMAGE_HEADER_LEN] = [0; IMAGE_HEADER_LEN];
+        self.flash.read(self.layout.slots[slot.index()].offset, &mut bytes).ok()?;
+        ImageHeader::from_bytes(&bytes).ok()
+    }
+
+    /// The version of the image in `slot` if it's fit to boot: signed by `pubkey`, linked for
+    /// this slot, and with a payload that matches the signed hash.
+    pub fn slot_check<H: ImageHash>(&mut self, slot: Slot, pubkey: &[u8; 32], hash: &mut H) -> Option<u32> {
+        let header: ImageHeader = self.slot_header(slot)?;
+        let part: Partition = self.layout.slots[slot.index()];
+        if header.verify_signature(pubkey).is_err()
+            || header.slot_b() != (slot == Slot::B)
+            || header.length > part.len - SLOT_CODE_OFFSET {
+            return None;
+        }
+        let mut chunk: [u8; NOR_SECTOR as usize] = [0; NOR_SECTOR as usize];
+        let mut addr: u32 = part.offset + SLOT_CODE_OFFSET;
+        let end: u32 = addr + header.length;
+        hash.start();
+        while addr < end {
+            let n: usize = ((end - addr) as usize).min(chunk.len());
+            self.flash.read(addr, &mut chunk[..n]).ok()?;
+            hash.update(&chunk[..n]);
+            addr += n as u32;
+        }
+        if hash.finish() == header.sha256 { Some(header.version) } else { None }
+    }
+
+    /// Write a complete image, header then payload, into `slot`.
+    pub fn write_slot(&mut self, slot: Slot, image: &[u8]) -> Result<(), FlashError> {
+        let part: Partition = self.layout.slots[slot.index()];
+        if image.len() < IMAGE_HEADER_LEN || (image.len() - IMAGE_HEADER_LEN) as u32 > part.len - SLOT_CODE_OFFSET {
+            return Err(FlashError::Bounds);
+        }
+        let (header, payload) = image.split_at(IMAGE_HEADER_LEN);
+        let len: u32 = SLOT_CODE_OFFSET + (payload.len() as u32).div_ceil(NOR_SECTOR) * NOR_SECTOR;
+        self.flash.erase(part.offset, len)?;
+        self.flash.program(part.offset + SLOT_CODE_OFFSET, payload)?;
+        // the header last, so a slot only looks complete once it is
+        self.flash.program(part.offset, header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal_spinor::MockNor;
+    use boot_control::boot_decide;
+    use fw_image::host::{public_key, sign_image, sign_image_flags, SoftSha256};
+    use fw_image::IMAGE_FLAG_SLOT_B;
+
+    const SECRET: [u8; 32] = [3; 32];
+    const SLOT_LEN: u32 = 0x4_0000;
+
+    fn flash() -> SpiNor<MockNor> {
+        SpiNor::new(MockNor::new(4 * SLOT_LEN), 4 * SLOT_LEN)
+    }
+
+    fn store(flash: &mut SpiNor<MockNor>) -> BootStore<'_, MockNor> {
+        BootStore {
+            flash,
+            layout: BootLayout {
+                log: Partition { name: "bootctl", offset: 0, len: SLOT_LEN },
+                slots: [
+                    Partition { name: "firmware_a", offset: SLOT_LEN, len: SLOT_LEN },
+                    Partition { name: "firmware_b", offset: 2 * SLOT_LEN, len: SLOT_LEN },
+                ],
+            },
+        }
+    }
+
+    #[test]
+    fn block_survives_many_stores() {
+        let mut f = flash();
+        let mut s = store(&mut f);
+        assert_eq!(s.load(), None);
+        for v in 0..300 {
+            let block = BootBlock { min_version: v, ..BootBlock::default() };
+            s.store(&block).unwrap();
+            assert_eq!(s.load(), Some(block));
+        }
+    }
+
+    #[test]
+    fn counter_survives_erasing_everything() {
+        let mut f = flash();
+        let mut s = store(&mut f);
+        s.advance_counter(7).unwrap();
+        s.advance_counter(3).unwrap();
+        assert_eq!(s.counter(), Ok(7));
+        assert_eq!(s.advance_counter(ROLLBACK_OTP_LEN as u32 * 8 + 1), Err(FlashError::Bounds));
+        f.erase(0, 4 * SLOT_LEN).unwrap();
+        assert_eq!(store(&mut f).counter(), Ok(7));
+    }
+
+    #[test]
+    fn slots_only_boot_their_own_images() {
+        let payload: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let key: [u8; 32] = public_key(&SECRET);
+        let mut hash = SoftSha256::new();
+        let mut f = flash();
+        let mut s = store(&mut f);
+
+        s.write_slot(Slot::A, &sign_image(&SECRET, 4, &payload)).unwrap();
+        s.write_slot(Slot::B, &sign_image(&SECRET, 5, &payload)).unwrap();
+        assert_eq!(s.slot_check(Slot::A, &key, &mut hash), Some(4));
+        // linked for A, so no good in B
+        assert_eq!(s.slot_check(Slot::B, &key, &mut hash), None);
+        assert_eq!(s.slot_header(Slot::B).map(|h| h.version), Some(5));
+
+        s.write_slot(Slot::B, &sign_image_flags(&SECRET, IMAGE_FLAG_SLOT_B, 5, &payload)).unwrap();
+        assert_eq!(s.slot_check(Slot::B, &key, &mut hash), Some(5));
+        assert_eq!(s.slot_check(Slot::B, &public_key(&[4; 32]), &mut hash), None);
+
+        // a flipped bit in the payload
+        let at: u32 = 2 * SLOT_LEN + SLOT_CODE_OFFSET + 5000;
+        let mut byte: [u8; 1] = [0];
+        f.read(at, &mut byte).unwrap();
+        f.phy_mut().mem[at as usize] = byte[0] ^ 1;
+        assert_eq!(store(&mut f).slot_check(Slot::B, &key, &mut hash), None);
+    }
+
+    #[test]
+    fn loader_round_trip() {
+        // what the loader does at each boot, against what's in flash
+        let payload: Vec<u8> = vec![0x13; 3000];
+        let key: [u8; 32] = public_key(&SECRET);
+        let mut hash = SoftSha256::new();
+        let mut f = flash();
+        let mut s = store(&mut f);
+        s.write_slot(Slot::A, &sign_image(&SECRET, 1, &payload)).unwrap();
+        s.write_slot(Slot::B, &sign_image_flags(&SECRET, IMAGE_FLAG_SLOT_B, 2, &payload)).unwrap();
+        let mut block: BootBlock = BootBlock { min_version: 1, ..BootBlock::default() };
+        block.stage_update(Slot::B, 2).unwrap();
+        block.confirm().unwrap();
+        s.store(&block).unwrap();
+
+        let versions = [s.slot_check(Slot::A, &key, &mut hash), s.slot_check(Slot::B, &key, &mut hash)];
+        let d = boot_decide(s.load(), s.counter().unwrap(), versions);
+        assert_eq!(d.slot, Some(Slot::B));
+        s.advance_counter(d.counter.unwrap()).unwrap();
+        s.store(&d.block).unwrap();
+        assert_eq!(s.counter(), Ok(2));
+
+        // rolling the log back doesn't let slot A's older image boot again
+        f.erase(0, BOOT_LOG_SECTOR as u32 * 2).unwrap();
+        let mut s = store(&mut f);
+        let d = boot_decide(s.load(), s.counter().unwrap(), [Some(1), Some(2)]);
+        assert_eq!(d.slot, Some(Slot::B));
+    }
+}
@@ -1,4 +1,4 @@
-#[allow(dead_code)]
+use crate::hal_time::Deadline;
 
 /// com_txrx is a polled-implementation of an atomic TX/RX swap operation
 /// The code is a little awkward for several reasons:
@@ -20,6 +20,7 @@
 /// "done" on "go" doesn't work because the time it takes to auto-clear the signal
 /// is long enough that the CPU may actually see the stale done value after hitting
 /// go if the CPU is running on the fast side...
+#[allow(dead_code)]
 pub fn com_txrx(p: &betrusted_pac::Peripherals, tx: u16) -> u16 {
     // clear the done bit
     p.COM.control.write(|w| w.clrdone().bit(true));
@@ -38,4 +39,60 @@ pub fn com_txrx(p: &betrusted_pac::Peripherals, tx: u16) -> u16 {
     // grab the RX value and return it
     let rx: u16 = p.COM.rx.read().bits() as u16;
     rx
-}
\ No newline at end of file
+}
+
+/// Same as com_txrx, but gives up and returns None if "done" doesn't change state within
+/// `timeout_ms`, e.g. because the EC is held in reset or the SPI block is wedged.
+pub fn com_txrx_timeout(p: &betrusted_pac::Peripherals, tx: u16, timeout_ms: u32) -> Option<u16> {
+    let deadline: Deadline = Deadline::from_ms(p, timeout_ms);
+
+    p.COM.control.write(|w| w.clrdone().bit(true));
+    while p.COM.status.read().done().bit_is_set() {
+        if deadline.expired(p) {
+            return None;
+        }
+    }
+
+    unsafe{ p.COM.tx.write(|w| w.bits(tx as u32)); }
+    p.COM.control.write(|w| w.go().bit(true));
+
+    while !p.COM.status.read().done().bit_is_set() {
+        if deadline.expired(p) {
+            return None;
+        }
+    }
+
+    Some(p.COM.rx.read().bits() as u16)
+}
+
+/// A word-level link to the EC. Each call shifts one 16-bit word out and returns the word
+/// that was shifted in at the same time, or None if the exchange timed out.
+///
+/// BtCom is the hardware implementation; protocol code is written against this trait so it
+/// can also run against a simulated EC on the host.
+pub trait ComPhy {
+    fn txrx(&mut self, tx: u16) -> Option<u16>;
+}
+
+/// how long a single word exchange may take before the EC is considered unresponsive
+const COM_TIMEOUT_MS: u32 = 2;
+
+pub struct BtCom {
+    p: betrusted_pac::Peripherals,
+}
+
+impl BtCom {
+    pub fn new() -> Self {
+        unsafe {
+            BtCom {
+                p: betrusted_pac::Peripherals::steal(),
+            }
+        }
+    }
+}
+
+impl ComPhy for BtCom {
+    fn txrx(&mut self, tx: u16) -> Option<u16> {
+        com_txrx_timeout(&self.p, tx, COM_TIMEOUT_MS)
+    }
+}
@@ -0,0 +1,403 @@
+//! Display compositor: trusted and untrusted regions of the screen
+//!
+//! The compositor owns the display. The top TRUSTED_ROWS lines belong to the system, and can
+//! only be drawn through trusted(), which the OS keeps to itself; its own drawing goes through
+//! system(), which stops short of them. Everyone else gets a Surface:
+//! a DrawTarget with its own origin, clipped to a rectangle that can't reach the trusted rows
+//! or another surface. Every surface is framed by a border that only the compositor draws, so
+//! anything a client renders, including a lookalike status bar, is visibly marked as coming
+//! from inside a surface.
+
+use crate::hal_lcd::{BtDisplay, LcdPanel, FB_LINES};
+use alloc::vec::Vec;
+use embedded_graphics::drawable::Pixel;
+use embedded_graphics::geometry::{Point, Size};
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::DrawTarget;
+
+/// lines at the top of the screen reserved for trusted system status
+pub const TRUSTED_ROWS: i32 = 32;
+/// width of the frame around a surface: a solid line, a blank line, then a hatched line
+pub const SURFACE_BORDER: i32 = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompositorError {
+    /// the surface would cover part of the trusted rows
+    Reserved,
+    /// the surface runs off the screen
+    Bounds,
+    /// the surface would cover part of another surface
+    Overlap,
+    /// no room inside the border
+    TooSmall,
+    /// no surface with that id
+    NoSurface,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SurfaceId(u32);
+
+#[derive(Copy, Clone, Debug)]
+struct SurfaceSlot {
+    id: SurfaceId,
+    /// outer bounds, border included
+    top_left: Point,
+    size: Size,
+}
+
+impl SurfaceSlot {
+    fn bottom_right(&self) -> Point {
+        Point::new(self.top_left.x + self.size.width as i32, self.top_left.y + self.size.height as i32)
+    }
+
+    fn overlaps(&self, other: &SurfaceSlot) -> bool {
+        let (a0, a1) = (self.top_left, self.bottom_right());
+        let (b0, b1) = (other.top_left, other.bottom_right());
+        a0.x < b1.x && b0.x < a1.x && a0.y < b1.y && b0.y < a1.y
+    }
+
+    fn interior_origin(&self) -> Point {
+        Point::new(self.top_left.x + SURFACE_BORDER, self.top_left.y + SURFACE_BORDER)
+    }
+
+    fn interior_size(&self) -> Size {
+        Size::new(self.size.width - 2 * SURFACE_BORDER as u32, self.size.height - 2 * SURFACE_BORDER as u32)
+    }
+}
+
+pub struct Compositor<D: DrawTarget<BinaryColor>> {
+    display: D,
+    surfaces: Vec<SurfaceSlot>,
+    next_id: u32,
+}
+
+impl<D: DrawTarget<BinaryColor>> Compositor<D> {
+    pub fn new(display: D) -> Self {
+        Compositor { display, surfaces: Vec::new(), next_id: 0 }
+    }
+
+    /// the display as drawn so far, for screenshots
+    pub fn display(&self) -> &D {
+        &self.display
+    }
+
+    pub fn into_inner(self) -> D {
+        self.display
+    }
+
+    /// drawing surface covering the trusted rows, in screen coordinates
+    pub fn trusted(&mut self) -> TrustedSurface<'_, D> {
+        TrustedSurface::new(&mut self.display)
+    }
+
+    /// drawing surface for the OS, in screen coordinates, covering everything but the trusted rows
+    pub fn system(&mut self) -> SystemSurface<'_, D> {
+        SystemSurface { display: &mut self.display }
+    }
+
+    /// No client surface is open, so everything below the trusted rows is the system's own.
+    pub fn system_only(&self) -> bool {
+        self.surfaces.is_empty()
+    }
+
+    /// Give a client the rectangle at `top_left` of `size`, border included. The rectangle is
+    /// blanked and framed.
+    pub fn open(&mut self, top_left: Point, size: Size) -> Result<SurfaceId, CompositorError> {
+        let screen: Size = self.display.size();
+        if size.width <= 2 * SURFACE_BORDER as u32 || size.height <= 2 * SURFACE_BORDER as u32 {
+            return Err(CompositorError::TooSmall);
+        }
+        if top_left.x < 0 || top_left.y < 0
+            || top_left.x as u32 + size.width > screen.width || top_left.y as u32 + size.height > screen.height {
+            return Err(CompositorError::Bounds);
+        }
+        if top_left.y < TRUSTED_ROWS {
+            return Err(CompositorError::Reserved);
+        }
+        let slot: SurfaceSlot = SurfaceSlot { id: SurfaceId(self.next_id), top_left, size };
+        if self.surfaces.iter().any(|s| s.overlaps(&slot)) {
+            return Err(CompositorError::Overlap);
+        }
+        self.next_id += 1;
+        self.surfaces.push(slot);
+        self.fill(&slot, BinaryColor::Off);
+        self.draw_border(&slot);
+        Ok(slot.id)
+    }
+
+    /// take a surface away from its client and blank it
+    pub fn close(&mut self, id: SurfaceId) -> Result<(), CompositorError> {
+        let index: usize = self.surfaces.iter().position(|s| s.id == id).ok_or(CompositorError::NoSurface)?;
+        let slot: SurfaceSlot = self.surfaces.remove(index);
+        self.fill(&slot, BinaryColor::Off);
+        Ok(())
+    }
+
+    pub fn surface(&mut self, id: SurfaceId) -> Option<Surface<'_, D>> {
+        let slot: SurfaceSlot = *self.surfaces.iter().find(|s| s.id == id)?;
+        Some(Surface { display: &mut self.display, origin: slot.interior_origin(), size: slot.interior_size() })
+    }
+
+    /// Put the borders back, for after the OS has cleared the display.
+    pub fn draw_borders(&mut self) {
+        for i in 0..self.surfaces.len() {
+            let slot: SurfaceSlot = self.surfaces[i];
+            self.draw_border(&slot);
+        }
+    }
+
+    fn fill(&mut self, slot: &SurfaceSlot, color: BinaryColor) {
+        let br: Point = slot.bottom_right();
+        for y in slot.top_left.y..br.y {
+            for x in slot.top_left.x..br.x {
+                self.display.draw_pixel(Pixel(Point::new(x, y), color));
+            }
+        }
+    }
+
+    fn draw_border(&mut self, slot: &SurfaceSlot) {
+        let br: Point = slot.bottom_right();
+        for y in slot.top_left.y..br.y {
+            for x in slot.top_left.x..br.x {
+                // distance in from the nearest edge
+                let ring: i32 = (x - slot.top_left.x).min(y - slot.top_left.y).min(br.x - 1 - x).min(br.y - 1 - y);
+                let color: BinaryColor = match ring {
+                    0 => BinaryColor::On,
+                    1 => BinaryColor::Off,
+                    2 if (x + y) % 2 == 0 => BinaryColor::On,
+                    2 => BinaryColor::Off,
+                    _ => continue,
+                };
+                self.display.draw_pixel(Pixel(Point::new(x, y), color));
+            }
+        }
+    }
+}
+
+impl<P: LcdPanel> Compositor<BtDisplay<P>> {
+    pub fn init(&mut self, clk_mhz: u32) {
+        self.display.init(clk_mhz);
+    }
+
+    pub fn flush(&mut self) -> Result<(), ()> {
+        self.display.flush()
+    }
+
+    pub fn blocking_flush(&mut self) {
+        self.display.blocking_flush();
+    }
+
+    /// blank the whole screen, trusted rows too; the status bar has to be redrawn after
+    pub fn clear(&mut self) {
+        self.display.clear();
+    }
+
+    /// blank everything below the trusted rows
+    pub fn clear_system(&mut self) {
+        self.display.clear_lines(TRUSTED_ROWS as usize..FB_LINES);
+    }
+}
+
+/// A client's view of its part of the screen. Coordinates start at the top left of the inside
+/// of the border, and anything outside is dropped.
+pub struct Surface<'a, D: DrawTarget<BinaryColor>> {
+    display: &'a mut D,
+    origin: Point,
+    size: Size,
+}
+
+impl<'a, D: DrawTarget<BinaryColor>> DrawTarget<BinaryColor> for Surface<'a, D> {
+    fn size(&self) -> Size {
+        self.size
+    }
+
+    fn draw_pixel(&mut self, pixel: Pixel<BinaryColor>) {
+        let Pixel(coord, color) = pixel;
+        if coord.x < 0 || coord.y < 0 || coord.x >= self.size.width as i32 || coord.y >= self.size.height as i32 {
+            return;
+        }
+        self.display.draw_pixel(Pixel(Point::new(coord.x + self.origin.x, coord.y + self.origin.y), color));
+    }
+}
+
+/// The screen below the trusted rows, for the OS. Coordinates are the screen's, and anything
+/// aimed at the trusted rows is dropped.
+pub struct SystemSurface<'a, D: DrawTarget<BinaryColor>> {
+    display: &'a mut D,
+}
+
+impl<'a, D: DrawTarget<BinaryColor>> DrawTarget<BinaryColor> for SystemSurface<'a, D> {
+    fn size(&self) -> Size {
+        self.display.size()
+    }
+
+    fn draw_pixel(&mut self, pixel: Pixel<BinaryColor>) {
+        let Pixel(coord, _) = pixel;
+        let size: Size = self.display.size();
+        if coord.x < 0 || coord.y < TRUSTED_ROWS || coord.x >= size.width as i32 || coord.y >= size.height as i32 {
+            return;
+        }
+        self.display.draw_pixel(pixel);
+    }
+}
+
+/// The trusted rows, for the system's own status display.
+pub struct TrustedSurface<'a, D: DrawTarget<BinaryColor>> {
+    display: &'a mut D,
+}
+
+impl<'a, D: DrawTarget<BinaryColor>> TrustedSurface<'a, D> {
+    /// Only the compositor hands these out, through trusted().
+    pub(crate) fn new(display: &'a mut D) -> Self {
+        TrustedSurface { display }
+    }
+}
+
+impl<'a, D: DrawTarget<BinaryColor>> DrawTarget<BinaryColor> for TrustedSurface<'a, D> {
+    fn size(&self) -> Size {
+        Size::new(self.display.size().width, TRUSTED_ROWS as u32)
+    }
+
+    fn draw_pixel(&mut self, pixel: Pixel<BinaryColor>) {
+        let Pixel(coord, _) = pixel;
+        if coord.x < 0 || coord.y < 0 || coord.x >= self.display.size().width as i32 || coord.y >= TRUSTED_ROWS {
+            return;
+        }
+        self.display.draw_pixel(pixel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const W: u32 = 64;
+    const H: u32 = 96;
+
+    /// plain in-memory framebuffer, true for a dark pixel
+    struct MemFb {
+        px: Vec<bool>,
+    }
+
+    impl MemFb {
+        fn new() -> Self {
+            MemFb { px: vec![false; (W * H) as usize] }
+        }
+
+        fn get(&self, x: i32, y: i32) -> bool {
+            self.px[(y as u32 * W + x as u32) as usize]
+        }
+    }
+
+    impl DrawTarget<BinaryColor> for MemFb {
+        fn size(&self) -> Size {
+            Size::new(W, H)
+        }
+
+        fn draw_pixel(&mut self, pixel: Pixel<BinaryColor>) {
+            let Pixel(p, c) = pixel;
+            assert!(p.x >= 0 && p.y >= 0 && p.x < W as i32 && p.y < H as i32, "off screen at {:?}", p);
+            self.px[(p.y as u32 * W + p.x as u32) as usize] = c == BinaryColor::On;
+        }
+    }
+
+    /// scribble over a generous area around a target, in its own coordinates
+    fn scribble<T: DrawTarget<BinaryColor>>(t: &mut T) {
+        for y in -20..H as i32 + 20 {
+            for x in -20..W as i32 + 20 {
+                t.draw_pixel(Pixel(Point::new(x, y), BinaryColor::On));
+            }
+        }
+    }
+
+    #[test]
+    fn surfaces_keep_out_of_reserved_space() {
+        let mut c = Compositor::new(MemFb::new());
+        assert_eq!(c.open(Point::new(0, TRUSTED_ROWS - 1), Size::new(20, 20)), Err(CompositorError::Reserved));
+        assert_eq!(c.open(Point::new(50, 40), Size::new(20, 20)), Err(CompositorError::Bounds));
+        assert_eq!(c.open(Point::new(-1, 40), Size::new(20, 20)), Err(CompositorError::Bounds));
+        assert_eq!(c.open(Point::new(0, 40), Size::new(6, 20)), Err(CompositorError::TooSmall));
+        let a = c.open(Point::new(0, TRUSTED_ROWS), Size::new(20, 20)).unwrap();
+        assert_eq!(c.open(Point::new(19, 51), Size::new(20, 20)), Err(CompositorError::Overlap));
+        // touching is fine
+        let b = c.open(Point::new(20, TRUSTED_ROWS), Size::new(20, 20)).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn drawing_is_clipped_to_the_interior() {
+        let mut c = Compositor::new(MemFb::new());
+        let id = c.open(Point::new(10, 40), Size::new(30, 20)).unwrap();
+        let mut s = c.surface(id).unwrap();
+        assert_eq!(s.size(), Size::new(30 - 2 * SURFACE_BORDER as u32, 20 - 2 * SURFACE_BORDER as u32));
+        scribble(&mut s);
+
+        let fb = c.into_inner();
+        for y in 0..H as i32 {
+            for x in 0..W as i32 {
+                let inside = x >= 10 + SURFACE_BORDER && x < 40 - SURFACE_BORDER && y >= 40 + SURFACE_BORDER && y < 60 - SURFACE_BORDER;
+                let on_frame = x >= 10 && x < 40 && y >= 40 && y < 60 && !inside;
+                if inside {
+                    assert!(fb.get(x, y));
+                } else if !on_frame {
+                    assert!(!fb.get(x, y), "leak at {},{}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn border_survives_its_client() {
+        let mut c = Compositor::new(MemFb::new());
+        let id = c.open(Point::new(4, 40), Size::new(24, 24)).unwrap();
+        let before = c.display().px.clone();
+        scribble(&mut c.surface(id).unwrap());
+        let fb = c.display();
+        // solid outside, blank gap, hatched inner line, all untouched
+        assert!((4..28).all(|x| fb.get(x, 40) && fb.get(x, 63)));
+        assert!((41..63).all(|y| !fb.get(5, y)));
+        assert!(fb.get(6, 42) && !fb.get(7, 42) && fb.get(8, 42));
+        for y in 40..64 {
+            for x in 4..28 {
+                if x < 4 + SURFACE_BORDER || x >= 28 - SURFACE_BORDER || y < 40 + SURFACE_BORDER || y >= 64 - SURFACE_BORDER {
+                    assert_eq!(fb.get(x, y), before[(y as u32 * W + x as u32) as usize]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trusted_rows_are_separate() {
+        let mut c = Compositor::new(MemFb::new());
+        scribble(&mut c.trusted());
+        let fb = c.display();
+        assert!((0..W as i32).all(|x| fb.get(x, 0) && fb.get(x, TRUSTED_ROWS - 1)));
+        assert!((0..W as i32).all(|x| !fb.get(x, TRUSTED_ROWS)));
+    }
+
+    #[test]
+    fn system_surface_keeps_out_of_the_trusted_rows() {
+        let mut c = Compositor::new(MemFb::new());
+        assert_eq!(c.system().size(), Size::new(W, H));
+        scribble(&mut c.system());
+        let fb = c.display();
+        assert!((0..W as i32).all(|x| (0..TRUSTED_ROWS).all(|y| !fb.get(x, y))));
+        assert!((0..W as i32).all(|x| fb.get(x, TRUSTED_ROWS) && fb.get(x, H as i32 - 1)));
+    }
+
+    #[test]
+    fn close_blanks_and_frees() {
+        let mut c = Compositor::new(MemFb::new());
+        assert!(c.system_only());
+        let id = c.open(Point::new(0, 40), Size::new(20, 20)).unwrap();
+        assert!(!c.system_only());
+        scribble(&mut c.surface(id).unwrap());
+        c.close(id).unwrap();
+        assert!(c.system_only());
+        assert!(c.surface(id).is_none());
+        assert_eq!(c.close(id), Err(CompositorError::NoSurface));
+        assert!(c.display().px.iter().all(|&p| !p));
+        assert!(c.open(Point::new(0, 40), Size::new(20, 20)).is_ok());
+    }
+}
@@ -0,0 +1,455 @@
+//! Key/value settings store, kept as a log in two alternating flash sectors.
+//!
+//! Each sector starts with a header carrying a generation number, and the one with the higher
+//! valid generation holds the live log. Settings are appended as CRC-protected records, the last
+//! record for a key winning. When the log fills up, the live settings are copied into the other
+//! sector and its header is written last, so a compaction cut short by power loss leaves the old
+//! log in charge. A record torn by power loss fails its CRC and is dropped at the next mount,
+//! along with anything after it.
+
+use crate::hal_spinor::{FlashError, NorPhy, Region, NOR_SECTOR};
+use crate::hal_time::TickSource;
+use alloc::vec::Vec;
+use host_link::crc32;
+
+/// Flash with sector erase; addresses are relative to the start of the area given to the store.
+pub trait SectorFlash {
+    fn sector_size(&self) -> u32;
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError>;
+    /// program bytes that are known to be erased
+    fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError>;
+    fn erase_sector(&mut self, addr: u32) -> Result<(), FlashError>;
+}
+
+impl<'a, T: NorPhy + TickSource> SectorFlash for Region<'a, T> {
+    fn sector_size(&self) -> u32 {
+        NOR_SECTOR
+    }
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        Region::read(self, addr, buf)
+    }
+
+    fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        Region::program(self, addr, data)
+    }
+
+    fn erase_sector(&mut self, addr: u32) -> Result<(), FlashError> {
+        self.erase(addr, NOR_SECTOR)
+    }
+}
+
+pub const CONFIG_MAX_KEY: usize = 32;
+pub const CONFIG_MAX_VALUE: usize = 256;
+
+const CONFIG_MAGIC: u32 = 0x564B_5442; // "BTKV"
+/// magic, generation, CRC of the two
+const HEADER_LEN: u32 = 12;
+/// kind, key length, value length
+const RECORD_HEADER_LEN: usize = 4;
+const RECORD_MAX: usize = RECORD_HEADER_LEN + CONFIG_MAX_KEY + CONFIG_MAX_VALUE + 4;
+
+const RECORD_DELETE: u8 = 0x00;
+const RECORD_SET: u8 = 0x01;
+/// an erased byte where the next record's kind would be
+const RECORD_NONE: u8 = 0xFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    Flash(FlashError),
+    /// the live settings don't fit in a sector
+    Full,
+    /// empty or oversized key, or oversized value
+    Size,
+}
+
+/// live settings, in no particular order
+pub type ConfigEntries = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn record_encode(key: &[u8], value: Option<&[u8]>, out: &mut [u8; RECORD_MAX]) -> usize {
+    let v: &[u8] = value.unwrap_or(&[]);
+    out[0] = if value.is_some() { RECORD_SET } else { RECORD_DELETE };
+    out[1] = key.len() as u8;
+    out[2..4].copy_from_slice(&(v.len() as u16).to_le_bytes());
+    let mut n: usize = RECORD_HEADER_LEN;
+    out[n..n + key.len()].copy_from_slice(key);
+    n += key.len();
+    out[n..n + v.len()].copy_from_slice(v);
+    n += v.len();
+    let crc: u32 = crc32(&out[..n]);
+    out[n..n + 4].copy_from_slice(&crc.to_le_bytes());
+    n + 4
+}
+
+fn header_encode(generation: u32) -> [u8; HEADER_LEN as usize] {
+    let mut h: [u8; HEADER_LEN as usize] = [0; HEADER_LEN as usize];
+    h[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+    h[4..8].copy_from_slice(&generation.to_le_bytes());
+    let crc: u32 = crc32(&h[..8]);
+    h[8..12].copy_from_slice(&crc.to_le_bytes());
+    h
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// generation of the log in the sector at `base`, if it has a valid header
+fn header_read<F: SectorFlash>(flash: &mut F, base: u32) -> Result<Option<u32>, FlashError> {
+    let mut h: [u8; HEADER_LEN as usize] = [0; HEADER_LEN as usize];
+    flash.read(base, &mut h)?;
+    if u32_at(&h, 0) == CONFIG_MAGIC && u32_at(&h, 8) == crc32(&h[..8]) {
+        Ok(Some(u32_at(&h, 4)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The settings store. Holds no flash itself; pass the same flash area to every call.
+pub struct ConfigStore {
+    sector_size: u32,
+    /// sector holding the live log, 0 or 1
+    active: u32,
+    generation: u32,
+    /// offset within the active sector of the next record
+    tail: u32,
+    /// something unreadable sits at the tail, so nothing more can be appended until compaction
+    damaged: bool,
+}
+
+impl ConfigStore {
+    /// Find the live log in the first two sectors of `flash`, formatting them if neither
+    /// has one.
+    pub fn mount<F: SectorFlash>(flash: &mut F) -> Result<Self, ConfigError> {
+        let sector_size: u32 = flash.sector_size();
+        let gens: [Option<u32>; 2] = [
+            header_read(flash, 0).map_err(ConfigError::Flash)?,
+            header_read(flash, sector_size).map_err(ConfigError::Flash)?,
+        ];
+        let (active, generation) = match gens {
+            [Some(a), Some(b)] if b > a => (1, b),
+            [Some(a), _] => (0, a),
+            [None, Some(b)] => (1, b),
+            [None, None] => {
+                crate::info!("config: formatting");
+                flash.erase_sector(0).map_err(ConfigError::Flash)?;
+                flash.program(0, &header_encode(1)).map_err(ConfigError::Flash)?;
+                (0, 1)
+            },
+        };
+        let mut store: ConfigStore = ConfigStore { sector_size, active, generation, tail: HEADER_LEN, damaged: false };
+        store.scan(flash, |_, _| ())?;
+        if store.damaged {
+            crate::warn!("config: damaged record at 0x{:x}", store.tail);
+        }
+        Ok(store)
+    }
+
+    fn base(&self) -> u32 {
+        self.active * self.sector_size
+    }
+
+    /// Walk the live log, calling `f` with each record's key and value (None for a delete).
+    /// Leaves the tail at the first record that isn't valid.
+    fn scan<F: SectorFlash, G: FnMut(&[u8], Option<&[u8]>)>(&mut self, flash: &mut F, mut f: G) -> Result<(), ConfigError> {
+        let base: u32 = self.base();
+        let mut rec: [u8; RECORD_MAX] = [0; RECORD_MAX];
+        let mut at: u32 = HEADER_LEN;
+        self.damaged = false;
+        loop {
+            if at + RECORD_HEADER_LEN as u32 > self.sector_size {
+                break;
+            }
+            flash.read(base + at, &mut rec[..RECORD_HEADER_LEN]).map_err(ConfigError::Flash)?;
+            if rec[0] == RECORD_NONE {
+                break;
+            }
+            let key_len: usize = rec[1] as usize;
+            let value_len: usize = u16::from_le_bytes([rec[2], rec[3]]) as usize;
+            let len: usize = RECORD_HEADER_LEN + key_len + value_len + 4;
+            let plausible: bool = (rec[0] == RECORD_SET || rec[0] == RECORD_DELETE)
+                && (1..=CONFIG_MAX_KEY).contains(&key_len) && value_len <= CONFIG_MAX_VALUE
+                && at + len as u32 <= self.sector_size;
+            if !plausible {
+                self.damaged = true;
+                break;
+            }
+            flash.read(base + at, &mut rec[..len]).map_err(ConfigError::Flash)?;
+            if u32_at(&rec, len - 4) != crc32(&rec[..len - 4]) {
+                self.damaged = true;
+                break;
+            }
+            let key: &[u8] = &rec[RECORD_HEADER_LEN..RECORD_HEADER_LEN + key_len];
+            let value: &[u8] = &rec[RECORD_HEADER_LEN + key_len..len - 4];
+            f(key, if rec[0] == RECORD_SET { Some(value) } else { None });
+            at += len as u32;
+        }
+        self.tail = at;
+
+        // a torn write may have programmed bytes beyond what made it into the kind byte
+        if !self.damaged {
+            let mut chunk: [u8; 64] = [0; 64];
+            let mut check: u32 = at;
+            while check < self.sector_size && !self.damaged {
+                let n: usize = ((self.sector_size - check) as usize).min(chunk.len());
+                flash.read(base + check, &mut chunk[..n]).map_err(ConfigError::Flash)?;
+                self.damaged = chunk[..n].iter().any(|&b| b != 0xFF);
+                check += n as u32;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn entries<F: SectorFlash>(&mut self, flash: &mut F) -> Result<ConfigEntries, ConfigError> {
+        let mut entries: Vec<(Vec<u8>, Option<Vec<u8>>)> = Vec::new();
+        self.scan(flash, |key, value| {
+            let value: Option<Vec<u8>> = value.map(|v| v.to_vec());
+            match entries.iter_mut().find(|(k, _)| k.as_slice() == key) {
+                Some(entry) => entry.1 = value,
+                None => entries.push((key.to_vec(), value)),
+            }
+        })?;
+        Ok(entries.into_iter().filter_map(|(k, v)| v.map(|v| (k, v))).collect())
+    }
+
+    pub fn get<F: SectorFlash>(&mut self, flash: &mut F, key: &[u8]) -> Result<Option<Vec<u8>>, ConfigError> {
+        let mut found: Option<Vec<u8>> = None;
+        self.scan(flash, |k, v| {
+            if k == key {
+                found = v.map(|v| v.to_vec());
+            }
+        })?;
+        Ok(found)
+    }
+
+    pub fn set<F: SectorFlash>(&mut self, flash: &mut F, key: &[u8], value: &[u8]) -> Result<(), ConfigError> {
+        if value.len() > CONFIG_MAX_VALUE {
+            return Err(ConfigError::Size);
+        }
+        self.append(flash, key, Some(value))
+    }
+
+    pub fn remove<F: SectorFlash>(&mut self, flash: &mut F, key: &[u8]) -> Result<(), ConfigError> {
+        if self.get(flash, key)?.is_none() {
+            return Ok(());
+        }
+        self.append(flash, key, None)
+    }
+
+    /// bytes of the active sector in use, header included
+    pub fn used(&self) -> u32 {
+        self.tail
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn append<F: SectorFlash>(&mut self, flash: &mut F, key: &[u8], value: Option<&[u8]>) -> Result<(), ConfigError> {
+        if key.is_empty() || key.len() > CONFIG_MAX_KEY {
+            return Err(ConfigError::Size);
+        }
+        let mut rec: [u8; RECORD_MAX] = [0; RECORD_MAX];
+        let len: usize = record_encode(key, value, &mut rec);
+        if self.damaged || self.tail + len as u32 > self.sector_size {
+            return self.compact(flash, Some((key, value)));
+        }
+        let at: u32 = self.base() + self.tail;
+        // whatever happens, the tail can't be trusted until it's been read back
+        self.damaged = true;
+        flash.program(at, &rec[..len]).map_err(ConfigError::Flash)?;
+        self.damaged = false;
+        self.tail += len as u32;
+        Ok(())
+    }
+
+    /// Copy the live settings, with `pending` applied, into the other sector and switch to it.
+    pub fn compact<F: SectorFlash>(&mut self, flash: &mut F, pending: Option<(&[u8], Option<&[u8]>)>) -> Result<(), ConfigError> {
+        let mut entries: ConfigEntries = self.entries(flash)?;
+        if let Some((key, value)) = pending {
+            entries.retain(|(k, _)| k.as_slice() != key);
+            if let Some(v) = value {
+                entries.push((key.to_vec(), v.to_vec()));
+            }
+        }
+        let mut rec: [u8; RECORD_MAX] = [0; RECORD_MAX];
+        let needed: u32 = entries.iter()
+            .map(|(k, v)| (RECORD_HEADER_LEN + k.len() + v.len() + 4) as u32)
+            .sum::<u32>() + HEADER_LEN;
+        if needed > self.sector_size {
+            return Err(ConfigError::Full);
+        }
+
+        let target: u32 = 1 - self.active;
+        let base: u32 = target * self.sector_size;
+        crate::debug!("config: compacting {} entries into sector {}", entries.len(), target);
+        flash.erase_sector(base).map_err(ConfigError::Flash)?;
+        let mut at: u32 = HEADER_LEN;
+        for (k, v) in entries.iter() {
+            let len: usize = record_encode(k, Some(v), &mut rec);
+            flash.program(base + at, &rec[..len]).map_err(ConfigError::Flash)?;
+            at += len as u32;
+        }
+        // this is the moment the new log takes over
+        flash.program(base, &header_encode(self.generation + 1)).map_err(ConfigError::Flash)?;
+
+        self.active = target;
+        self.generation += 1;
+        self.tail = at;
+        self.damaged = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal_spinor::{MockNor, Partition, SpiNor};
+    use alloc::collections::BTreeMap;
+    use alloc::format;
+    use alloc::vec;
+
+    const AREA: Partition = Partition { name: "config", offset: 0, len: 2 * NOR_SECTOR };
+
+    type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+    fn flash() -> SpiNor<MockNor> {
+        SpiNor::new(MockNor::new(AREA.len), AREA.len)
+    }
+
+    enum Op {
+        Set(&'static [u8], Vec<u8>),
+        Remove(&'static [u8]),
+    }
+
+    /// enough churn on a few keys to fill a sector and compact
+    fn workload() -> Vec<Op> {
+        let keys: [&'static [u8]; 4] = [b"tz", b"kbd.layout", b"backlight", b"owner"];
+        let mut ops: Vec<Op> = Vec::new();
+        for i in 0..120usize {
+            let key = keys[i * 7 % keys.len()];
+            if i % 37 == 36 {
+                ops.push(Op::Remove(key));
+            } else {
+                let len = 8 + i * 13 % 40;
+                ops.push(Op::Set(key, (0..len).map(|j| (i + j) as u8).collect()));
+            }
+        }
+        ops
+    }
+
+    fn apply(store: &mut ConfigStore, flash: &mut SpiNor<MockNor>, op: &Op) -> Result<(), ConfigError> {
+        let mut region = flash.region(AREA);
+        match op {
+            Op::Set(k, v) => store.set(&mut region, k, v),
+            Op::Remove(k) => store.remove(&mut region, k),
+        }
+    }
+
+    fn model_apply(model: &mut Model, op: &Op) {
+        match op {
+            Op::Set(k, v) => { model.insert(k.to_vec(), v.clone()); },
+            Op::Remove(k) => { model.remove(*k); },
+        }
+    }
+
+    fn contents(store: &mut ConfigStore, flash: &mut SpiNor<MockNor>) -> Model {
+        store.entries(&mut flash.region(AREA)).unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let mut f = flash();
+        let mut store = ConfigStore::mount(&mut f.region(AREA)).unwrap();
+        let mut r = f.region(AREA);
+        assert_eq!(store.get(&mut r, b"tz").unwrap(), None);
+        store.set(&mut r, b"tz", b"UTC+8").unwrap();
+        store.set(&mut r, b"bl", &[3]).unwrap();
+        store.set(&mut r, b"tz", b"UTC-5").unwrap();
+        assert_eq!(store.get(&mut r, b"tz").unwrap(), Some(b"UTC-5".to_vec()));
+        store.remove(&mut r, b"bl").unwrap();
+        assert_eq!(store.get(&mut r, b"bl").unwrap(), None);
+        assert_eq!(store.set(&mut r, b"", b"x"), Err(ConfigError::Size));
+        assert_eq!(store.set(&mut r, b"big", &[0; CONFIG_MAX_VALUE + 1]), Err(ConfigError::Size));
+
+        // and it all survives a remount
+        let mut store = ConfigStore::mount(&mut r).unwrap();
+        assert_eq!(store.entries(&mut r).unwrap(), vec![(b"tz".to_vec(), b"UTC-5".to_vec())]);
+    }
+
+    #[test]
+    fn compaction_alternates_sectors() {
+        let mut f = flash();
+        let mut store = ConfigStore::mount(&mut f.region(AREA)).unwrap();
+        let mut model = Model::new();
+        for op in workload().iter() {
+            apply(&mut store, &mut f, op).unwrap();
+            model_apply(&mut model, op);
+        }
+        assert!(store.generation() >= 2);
+        assert_eq!(contents(&mut store, &mut f), model);
+        let mut store = ConfigStore::mount(&mut f.region(AREA)).unwrap();
+        assert_eq!(contents(&mut store, &mut f), model);
+        assert_eq!(f.phy().erase_counts[0] + f.phy().erase_counts[1], store.generation());
+    }
+
+    #[test]
+    fn full_store_is_refused() {
+        let mut f = flash();
+        let mut store = ConfigStore::mount(&mut f.region(AREA)).unwrap();
+        let mut r = f.region(AREA);
+        let mut i: u32 = 0;
+        let err = loop {
+            let key = format!("key{}", i);
+            if let Err(e) = store.set(&mut r, key.as_bytes(), &[0x55; 200]) {
+                break e;
+            }
+            i += 1;
+        };
+        assert_eq!(err, ConfigError::Full);
+        // what was there is intact
+        let mut store = ConfigStore::mount(&mut r).unwrap();
+        assert_eq!(store.entries(&mut r).unwrap().len(), i as usize);
+    }
+
+    #[test]
+    fn survives_power_loss_at_every_byte() {
+        let ops = workload();
+
+        // how much writing the whole workload takes
+        let mut f = flash();
+        f.phy_mut().power_budget = Some(u32::max_value());
+        let mut store = ConfigStore::mount(&mut f.region(AREA)).unwrap();
+        for op in ops.iter() {
+            apply(&mut store, &mut f, op).unwrap();
+        }
+        let total: u32 = u32::max_value() - f.phy().power_budget.unwrap();
+
+        for budget in 0..total {
+            let mut f = flash();
+            f.phy_mut().power_budget = Some(budget);
+            let mut before = Model::new();
+            let mut after = Model::new();
+            if let Ok(mut store) = ConfigStore::mount(&mut f.region(AREA)) {
+                for op in ops.iter() {
+                    model_apply(&mut after, op);
+                    if apply(&mut store, &mut f, op).is_err() {
+                        break;
+                    }
+                    model_apply(&mut before, op);
+                }
+            }
+
+            // power comes back
+            f.phy_mut().power_budget = None;
+            let mut store = ConfigStore::mount(&mut f.region(AREA)).unwrap();
+            let got = contents(&mut store, &mut f);
+            assert!(got == before || got == after, "budget {}: {:?}", budget, got);
+
+            // and the store carries on working
+            let mut r = f.region(AREA);
+            store.set(&mut r, b"tz", b"after").unwrap();
+            assert_eq!(store.get(&mut r, b"tz").unwrap(), Some(b"after".to_vec()));
+        }
+    }
+}
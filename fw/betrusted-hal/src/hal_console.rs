@@ -0,0 +1,513 @@
+//! Scrolling text console
+//!
+//! A grid of character cells in one of the fixed-width fonts, drawn onto any DrawTarget: the
+//! whole display, or a compositor surface. Text is written at the end, wrapping at word
+//! boundaries where it can, and rows that scroll off the top are kept in a scrollback buffer
+//! that can be paged through with the arrow keys.
+//!
+//! Besides printable characters, write_str() understands
+//!
+//!   \n        new line
+//!   \r        back to the start of the row, to overwrite it
+//!   \x08      back one cell
+//!   ESC[7m    inverse on; ESC[27m, ESC[0m and ESC[m turn it off, and ESC[1;7m and the like
+//!             apply each parameter in turn
+//!   ESC[2K    clear the row; ESC[K clears from the cursor to the end of the row
+//!
+//! Any other escape sequence is swallowed: a control sequence runs up to its final byte, in
+//! '@'..='~', whatever parameters it carries.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use embedded_graphics::prelude::*;
+use embedded_graphics::fonts::{Font8x16, Font12x16};
+use embedded_graphics::pixelcolor::BinaryColor;
+
+/// rows kept beyond those on screen
+pub const CONSOLE_SCROLLBACK: usize = 200;
+
+const ESC: char = '\x1b';
+/// control sequence parameters kept; any more are read but ignored
+const CSI_PARAMS: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleFont {
+    Font8x16,
+    Font12x16,
+}
+
+impl ConsoleFont {
+    /// the size of one character cell
+    pub fn cell(self) -> Size {
+        match self {
+            ConsoleFont::Font8x16 => Size::new(8, 16),
+            ConsoleFont::Font12x16 => Size::new(12, 16),
+        }
+    }
+
+    fn draw<D: DrawTarget<BinaryColor>>(self, text: &str, at: Point, inverse: bool, d: &mut D) {
+        let (fg, bg) = if inverse { (BinaryColor::Off, BinaryColor::On) } else { (BinaryColor::On, BinaryColor::Off) };
+        match self {
+            ConsoleFont::Font8x16 => Font8x16::render_str(text)
+                .stroke_color(Some(fg))
+                .fill_color(Some(bg))
+                .translate(at)
+                .draw(d),
+            ConsoleFont::Font12x16 => Font12x16::render_str(text)
+                .stroke_color(Some(fg))
+                .fill_color(Some(bg))
+                .translate(at)
+                .draw(d),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Glyph {
+    ch: char,
+    inverse: bool,
+}
+
+const BLANK: Glyph = Glyph { ch: ' ', inverse: false };
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// seen ESC
+    Esc,
+    /// in a control sequence, with the parameters so far and the index of the one being read
+    Csi([u32; CSI_PARAMS], usize),
+}
+
+pub struct Console {
+    font: ConsoleFont,
+    cols: usize,
+    rows: usize,
+    /// oldest first; the last is the one being written
+    lines: VecDeque<Vec<Glyph>>,
+    /// cursor column in the last row
+    col: usize,
+    inverse: bool,
+    escape: Escape,
+    /// rows the view is scrolled back from the bottom
+    scroll: usize,
+    cursor_visible: bool,
+}
+
+impl Console {
+    /// a console filling `size` pixels, in as many whole cells as fit
+    pub fn new(font: ConsoleFont, size: Size) -> Self {
+        let cell: Size = font.cell();
+        let mut lines: VecDeque<Vec<Glyph>> = VecDeque::new();
+        lines.push_back(Vec::new());
+        Console {
+            font,
+            cols: ((size.width / cell.width) as usize).max(1),
+            rows: ((size.height / cell.height) as usize).max(1),
+            lines,
+            col: 0,
+            inverse: false,
+            escape: Escape::None,
+            scroll: 0,
+            cursor_visible: true,
+        }
+    }
+
+    pub fn font(&self) -> ConsoleFont {
+        self.font
+    }
+
+    /// width and height in cells
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// the space the console takes up on screen
+    pub fn size(&self) -> Size {
+        let cell: Size = self.font.cell();
+        Size::new(self.cols as u32 * cell.width, self.rows as u32 * cell.height)
+    }
+
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.cursor_visible = visible;
+    }
+
+    /// the cursor's column and its row on screen, if it's in view
+    pub fn cursor(&self) -> Option<(usize, usize)> {
+        if self.scroll != 0 {
+            return None;
+        }
+        let row: usize = self.rows.min(self.lines.len()) - 1;
+        Some((self.col.min(self.cols - 1), row))
+    }
+
+    /// write `text` and end the line
+    pub fn add_line(&mut self, text: &str) {
+        self.write(text);
+        self.write("\n");
+    }
+
+    pub fn write(&mut self, text: &str) {
+        for c in text.chars() {
+            self.put(c);
+        }
+    }
+
+    /// throw away everything, scrollback included
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.lines.push_back(Vec::new());
+        self.col = 0;
+        self.scroll = 0;
+    }
+
+    fn put(&mut self, c: char) {
+        match (self.escape, c) {
+            (Escape::None, ESC) => self.escape = Escape::Esc,
+            (Escape::None, '\n') => self.new_line(),
+            (Escape::None, '\r') => self.col = 0,
+            (Escape::None, '\x08') => self.col = self.col.saturating_sub(1),
+            (Escape::None, c) if c.is_control() => (),
+            (Escape::None, c) => self.put_glyph(Glyph { ch: c, inverse: self.inverse }),
+            (Escape::Esc, '[') => self.escape = Escape::Csi([0; CSI_PARAMS], 0),
+            (Escape::Esc, ' '..='/') => (), // intermediate bytes, the final one is still to come
+            (Escape::Esc, _) => self.escape = Escape::None,
+            (Escape::Csi(mut params, i), '0'..='9') => {
+                if i < CSI_PARAMS {
+                    params[i] = params[i].saturating_mul(10).saturating_add(c as u32 - '0' as u32);
+                }
+                self.escape = Escape::Csi(params, i);
+            },
+            (Escape::Csi(params, i), ';') => self.escape = Escape::Csi(params, i.saturating_add(1)),
+            (Escape::Csi(..), ' '..='?') => (), // other parameter and intermediate bytes
+            (Escape::Csi(params, i), '@'..='~') => {
+                self.escape = Escape::None;
+                self.control(&params[..(i + 1).min(CSI_PARAMS)], c);
+            },
+            (Escape::Csi(..), _) => self.escape = Escape::None, // not a control sequence after all
+        }
+    }
+
+    /// act on a control sequence with final byte `c`; missing parameters are 0
+    fn control(&mut self, params: &[u32], c: char) {
+        if c == 'm' {
+            for &n in params {
+                match n {
+                    7 => self.inverse = true,
+                    0 | 27 => self.inverse = false,
+                    _ => (),
+                }
+            }
+            return;
+        }
+        match (c, params[0]) {
+            ('K', 0) => {
+                let col: usize = self.col;
+                self.current().truncate(col);
+            },
+            ('K', 2) => self.current().clear(),
+            _ => (),
+        }
+    }
+
+    fn current(&mut self) -> &mut Vec<Glyph> {
+        self.lines.back_mut().unwrap()
+    }
+
+    fn put_glyph(&mut self, g: Glyph) {
+        if self.col >= self.cols {
+            if g.ch == ' ' {
+                self.new_line(); // a space at the break would only indent the next row
+                return;
+            }
+            self.wrap();
+        }
+        let col: usize = self.col;
+        let line: &mut Vec<Glyph> = self.current();
+        while line.len() < col {
+            line.push(BLANK);
+        }
+        if col < line.len() {
+            line[col] = g;
+        } else {
+            line.push(g);
+        }
+        self.col += 1;
+    }
+
+    /// Start a new row because the current one is full. A word that doesn't fit moves down
+    /// whole, unless it fills the row by itself.
+    fn wrap(&mut self) {
+        let line: &mut Vec<Glyph> = self.current();
+        let mut tail: Vec<Glyph> = Vec::new();
+        if let Some(space) = line.iter().rposition(|g| g.ch == ' ') {
+            if space > 0 {
+                tail = line.split_off(space + 1);
+                line.truncate(space);
+            }
+        }
+        self.new_line();
+        self.col = tail.len();
+        *self.current() = tail;
+    }
+
+    fn new_line(&mut self) {
+        self.lines.push_back(Vec::new());
+        self.col = 0;
+        if self.lines.len() > self.rows + CONSOLE_SCROLLBACK {
+            self.lines.pop_front();
+            if self.scroll != 0 {
+                // the rows in view moved up one; follow them until the oldest is gone
+                self.scroll = (self.scroll + 1).min(self.max_scroll());
+            }
+        } else if self.scroll != 0 {
+            self.scroll += 1; // keep the view on what's being read
+        }
+    }
+
+    /// how far back the view can go
+    fn max_scroll(&self) -> usize {
+        self.lines.len().saturating_sub(self.rows)
+    }
+
+    pub fn page_up(&mut self) {
+        self.scroll = (self.scroll + self.rows.saturating_sub(1).max(1)).min(self.max_scroll());
+    }
+
+    pub fn page_down(&mut self) {
+        self.scroll = self.scroll.saturating_sub(self.rows.saturating_sub(1).max(1));
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll = 0;
+    }
+
+    /// rows the view is scrolled back from the newest output
+    pub fn scrolled_back(&self) -> usize {
+        self.scroll
+    }
+
+    /// Handle a key meant for the console: up and down arrows page through the scrollback.
+    /// Returns false for keys it has no use for.
+    pub fn key(&mut self, c: char) -> bool {
+        match c {
+            '↑' => self.page_up(),
+            '↓' => self.page_down(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// the rows on screen, top first
+    fn visible(&self) -> impl Iterator<Item = &Vec<Glyph>> {
+        let end: usize = self.lines.len() - self.scroll;
+        let start: usize = end.saturating_sub(self.rows);
+        self.lines.range(start..end)
+    }
+
+    /// the text of a row on screen, without attributes or trailing blanks
+    pub fn row_text(&self, row: usize) -> String {
+        match self.visible().nth(row) {
+            Some(line) => line.iter().map(|g| g.ch).collect::<String>().trim_end().into(),
+            None => String::new(),
+        }
+    }
+
+    /// whether the cell at `col` of screen row `row` is in inverse video
+    pub fn inverse_at(&self, col: usize, row: usize) -> bool {
+        self.visible().nth(row).and_then(|line| line.get(col)).is_some_and(|g| g.inverse)
+    }
+
+    /// Screen row `row` as runs of cells that look the same, each with its starting column
+    /// and whether it's inverse, padded with blanks to the full width. The cursor shows as an
+    /// inverted cell.
+    fn runs(&self, row: usize) -> Vec<(usize, bool, String)> {
+        let line: Option<&Vec<Glyph>> = self.visible().nth(row);
+        let cursor: Option<(usize, usize)> = if self.cursor_visible { self.cursor() } else { None };
+        let glyph = |col: usize| -> Glyph {
+            let g: Glyph = line.and_then(|l| l.get(col)).copied().unwrap_or(BLANK);
+            Glyph { ch: g.ch, inverse: g.inverse != (cursor == Some((col, row))) }
+        };
+        let mut runs: Vec<(usize, bool, String)> = Vec::new();
+        for col in 0..self.cols {
+            let g: Glyph = glyph(col);
+            match runs.last_mut() {
+                Some((_, inverse, text)) if *inverse == g.inverse => text.push(g.ch),
+                _ => runs.push((col, g.inverse, String::from(g.ch.encode_utf8(&mut [0; 4])))),
+            }
+        }
+        runs
+    }
+
+    /// Draw the console with its top left corner at `top_left`. Every cell is painted, so
+    /// nothing needs clearing first.
+    pub fn draw<D: DrawTarget<BinaryColor>>(&self, d: &mut D, top_left: Point) {
+        let cell: Size = self.font.cell();
+        for row in 0..self.rows {
+            let y: i32 = top_left.y + (row as u32 * cell.height) as i32;
+            for (col, inverse, text) in self.runs(row) {
+                let x: i32 = top_left.x + (col as u32 * cell.width) as i32;
+                self.font.draw(&text, Point::new(x, y), inverse, d);
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use alloc::vec;
+
+    /// 10 columns by 3 rows of 8x16 cells
+    fn console() -> Console {
+        Console::new(ConsoleFont::Font8x16, Size::new(84, 50))
+    }
+
+    fn screen(c: &Console) -> Vec<String> {
+        (0..c.rows()).map(|r| c.row_text(r)).collect()
+    }
+
+    #[test]
+    fn fits_whole_cells() {
+        let c = console();
+        assert_eq!((c.cols(), c.rows()), (10, 3));
+        assert_eq!(c.size(), Size::new(80, 48));
+        assert_eq!(Console::new(ConsoleFont::Font12x16, Size::new(316, 96)).cols(), 26);
+    }
+
+    #[test]
+    fn wraps_at_words() {
+        let mut c = console();
+        c.add_line("the quick brown fox");
+        assert_eq!(screen(&c), vec!["the quick", "brown fox", ""]);
+
+        // a word longer than a row is broken
+        let mut c = console();
+        c.write("abcdefghijklmn");
+        assert_eq!(screen(&c), vec!["abcdefghij", "klmn", ""]);
+        assert_eq!(c.cursor(), Some((4, 1)));
+
+        // a space at the break doesn't start the next row
+        let mut c = console();
+        c.write("0123456789 next");
+        assert_eq!(screen(&c), vec!["0123456789", "next", ""]);
+    }
+
+    #[test]
+    fn scrollback_pages() {
+        let mut c = console();
+        for i in 0..10 {
+            writeln!(c, "line {}", i).unwrap();
+        }
+        assert_eq!(screen(&c), vec!["line 8", "line 9", ""]);
+        assert_eq!(c.cursor(), Some((0, 2)));
+
+        assert!(c.key('↑'));
+        assert_eq!(screen(&c), vec!["line 6", "line 7", "line 8"]);
+        assert_eq!(c.cursor(), None);
+        // output while scrolled back doesn't move the view
+        c.add_line("line 10");
+        assert_eq!(screen(&c), vec!["line 6", "line 7", "line 8"]);
+
+        for _ in 0..10 {
+            c.page_up();
+        }
+        assert_eq!(screen(&c), vec!["line 0", "line 1", "line 2"]);
+        assert!(c.key('↓'));
+        assert_eq!(screen(&c), vec!["line 2", "line 3", "line 4"]);
+        assert!(!c.key('x'));
+        c.scroll_to_bottom();
+        assert_eq!(screen(&c), vec!["line 9", "line 10", ""]);
+
+        // the oldest rows go once the scrollback is full
+        for _ in 0..CONSOLE_SCROLLBACK {
+            c.add_line("more");
+        }
+        for _ in 0..CONSOLE_SCROLLBACK {
+            c.page_up();
+        }
+        assert_eq!(c.scrolled_back(), CONSOLE_SCROLLBACK);
+        assert_eq!(screen(&c), vec!["line 9", "line 10", "more"]);
+    }
+
+    #[test]
+    fn full_scrollback_keeps_the_view() {
+        let mut c = console();
+        for i in 0..CONSOLE_SCROLLBACK + 3 {
+            writeln!(c, "line {}", i).unwrap();
+        }
+        c.page_up();
+        let view = screen(&c);
+        for _ in 0..5 {
+            c.add_line("more");
+            assert_eq!(screen(&c), view);
+        }
+
+        // at the very top, the view stays on the oldest rows left
+        for _ in 0..CONSOLE_SCROLLBACK {
+            c.page_up();
+        }
+        c.add_line("more");
+        assert_eq!(c.scrolled_back(), CONSOLE_SCROLLBACK);
+        assert_eq!(screen(&c), vec!["line 7", "line 8", "line 9"]);
+    }
+
+    #[test]
+    fn attributes_and_clearing() {
+        let mut c = console();
+        c.write("a\x1b[7mbc\x1b[0md");
+        assert_eq!(c.row_text(0), "abcd");
+        assert!(!c.inverse_at(0, 0) && c.inverse_at(1, 0) && c.inverse_at(2, 0) && !c.inverse_at(3, 0));
+
+        c.write("\x1b[2K\rxy");
+        assert_eq!(c.row_text(0), "xy");
+        c.write("z\r\x1b[Kq");
+        assert_eq!(c.row_text(0), "q");
+        c.write("\x08w\x1b(B");
+        assert_eq!(c.row_text(0), "w");
+        assert_eq!(c.cursor(), Some((1, 0)));
+    }
+
+    #[test]
+    fn control_sequences_run_to_their_final_byte() {
+        let mut c = console();
+        c.write("a\x1b[1;7mb\x1b[0;1mc\x1b[?25ld\x1b[38;5;196me\x1b[7;27;7mf\x1b[m");
+        assert_eq!(c.row_text(0), "abcdef");
+        assert!(c.inverse_at(1, 0) && !c.inverse_at(2, 0) && !c.inverse_at(3, 0) && c.inverse_at(5, 0));
+
+        c.write("\x1b[A\x1b[2;5K");
+        assert_eq!(c.row_text(0), "");
+    }
+
+    #[test]
+    fn cursor_is_an_inverse_cell() {
+        let mut c = console();
+        c.write("a\x1b[7mb\x1b[m");
+        assert_eq!(c.runs(0), vec![
+            (0, false, String::from("a")),
+            (1, true, String::from("b ")),
+            (3, false, String::from("       ")),
+        ]);
+        assert_eq!(c.runs(1), vec![(0, false, String::from("          "))]);
+
+        c.set_cursor_visible(false);
+        assert_eq!(c.runs(0)[1], (1, true, String::from("b")));
+        c.page_up();
+        c.set_cursor_visible(true);
+        assert_eq!(c.cursor(), Some((2, 0))); // nothing to scroll back to
+    }
+}
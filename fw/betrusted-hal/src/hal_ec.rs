@@ -0,0 +1,545 @@
+use crate::hal_com::ComPhy;
+use crate::hal_battery::{GasGauge, GG_WORDS, GG_WORDS_EXTENDED};
+use alloc::vec::Vec;
+use bitflags::*;
+
+/// word sent by the SoC when it is only clocking in data from the EC
+pub const COM_DUMMY: u16 = 0xF0F0;
+/// word sent by the EC while it has nothing (yet) to say
+pub const COM_IDLE: u16 = 0xDDDD;
+/// high byte of a response header; the low byte is the payload length
+pub const COM_RESP_SYNC: u16 = 0xA500;
+/// resynchronizes the link; not framed
+pub const COM_LINK_RESET: u16 = 0xFFFF;
+/// asks the EC whether it speaks the framed protocol; not an opcode of the legacy protocol
+pub const COM_HELLO: u16 = 0x7F00;
+/// introduces a request frame; not an opcode of the legacy protocol
+pub const COM_FRAME_START: u16 = 0x7F01;
+/// framed protocol version this code speaks, as reported in the EC's answer to COM_HELLO
+pub const EC_FRAMED_VERSION: u16 = 1;
+
+/// longest payload either side may send, in words
+pub const EC_MAX_PAYLOAD: usize = 16;
+/// number of words to clock out waiting for a response header before giving up
+const EC_RESPONSE_POLLS: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EcCommand {
+    /// payload: PowerFlags. response: empty
+    PowerSet = 0x90,
+    /// payload: empty. response: GG_WORDS words of gas gauge data, or GG_WORDS_EXTENDED over
+    /// the framed protocol
+    GasGauge = 0x70,
+    /// payload: brightness, 0-31. response: empty
+    Backlight = 0x68,
+    /// payload: 1 for boost mode, 0 for charge mode. response: empty
+    Charger = 0x5A,
+}
+
+impl EcCommand {
+    pub fn from_u8(code: u8) -> Option<EcCommand> {
+        match code {
+            0x90 => Some(EcCommand::PowerSet),
+            0x70 => Some(EcCommand::GasGauge),
+            0x68 => Some(EcCommand::Backlight),
+            0x5A => Some(EcCommand::Charger),
+            _ => None,
+        }
+    }
+}
+
+/// how requests reach the EC
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EcProtocol {
+    /// one word per command, opcode in the high byte and argument in the low byte, as every
+    /// EC understands; nothing is checked
+    Legacy,
+    /// CRC-checked frames, once the EC has said it understands them
+    Framed,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EcError {
+    /// the COM block never reported "done" for a word
+    Timeout,
+    /// the EC never started a response
+    NoResponse,
+    /// the response failed its CRC check
+    Crc,
+    /// the response was longer than the caller (or the protocol) allows
+    Length,
+}
+
+bitflags! {
+    pub struct PowerFlags: u16 {
+        /// keep the EC itself powered
+        const EC_STAY_ON     = 0b0000_0001;
+        /// keep the SoC powered
+        const SOC_ON         = 0b0000_0010;
+        /// fast discharge of the FPGA power domain
+        const DISCHARGE_FPGA = 0b0000_0100;
+    }
+}
+
+/// CRC-16/CCITT (poly 0x1021, init 0xFFFF) over words, most significant byte first
+pub fn ec_crc16(words: &[u16]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for word in words {
+        for byte in [(word >> 8) as u8, *word as u8].iter() {
+            crc ^= (*byte as u16) << 8;
+            for _ in 0..8 {
+                if crc & 0x8000 != 0 {
+                    crc = (crc << 1) ^ 0x1021;
+                } else {
+                    crc <<= 1;
+                }
+            }
+        }
+    }
+    crc
+}
+
+/// Builds a complete request frame, COM_FRAME_START included; payload is truncated to
+/// EC_MAX_PAYLOAD words.
+pub fn ec_frame(cmd: EcCommand, payload: &[u16]) -> Vec<u16> {
+    let payload = &payload[..payload.len().min(EC_MAX_PAYLOAD)];
+    let mut frame: Vec<u16> = Vec::with_capacity(payload.len() + 3);
+    frame.push(COM_FRAME_START);
+    frame.push(((cmd as u16) << 8) | payload.len() as u16);
+    frame.extend_from_slice(payload);
+    let crc = ec_crc16(&frame[1..]);
+    frame.push(crc);
+    frame
+}
+
+/// The single word the legacy protocol uses for `cmd`; only the low byte of `arg` fits.
+pub fn ec_legacy_word(cmd: EcCommand, arg: u16) -> u16 {
+    match cmd {
+        EcCommand::Charger => if arg != 0 { 0x5AFE } else { 0x5A00 },
+        _ => ((cmd as u16) << 8) | (arg & 0xFF),
+    }
+}
+
+/// Protocol spoken between the SoC and the EC over the COM link.
+///
+/// Every EC understands the legacy protocol: one word per command, the opcode in the high
+/// byte and its argument in the low byte. A gas gauge read is followed by GG_WORDS
+/// COM_DUMMY words to clock the reading out, then a COM_LINK_RESET.
+///
+/// Framed requests are only sent once `negotiate()` has found an EC that understands them;
+/// a legacy EC would take each word of a frame for a command of its own. They are sent as:
+///   * COM_FRAME_START, which the legacy protocol doesn't use
+///   * header word: command code in the high byte, payload length (in words) in the low byte
+///   * payload words
+///   * CRC-16/CCITT over the header and payload words
+///
+/// After the request, the SoC clocks out COM_DUMMY words. The EC answers with COM_IDLE until
+/// it has a response ready, then sends COM_RESP_SYNC | length, the payload words, and a CRC
+/// computed the same way over its own header and payload. A bare COM_LINK_RESET word (never
+/// framed) makes the EC discard any partially received frame and pending response.
+pub struct BtEc<T: ComPhy> {
+    phy: T,
+    protocol: EcProtocol,
+}
+
+impl<T: ComPhy> BtEc<T> {
+    /// starts out on the legacy protocol
+    pub fn new(phy: T) -> Self {
+        BtEc { phy, protocol: EcProtocol::Legacy }
+    }
+
+    /// for a second handle on an EC that has already been negotiated with
+    pub fn with_protocol(phy: T, protocol: EcProtocol) -> Self {
+        BtEc { phy, protocol }
+    }
+
+    pub fn protocol(&self) -> EcProtocol {
+        self.protocol
+    }
+
+    fn txrx(&mut self, tx: u16) -> Result<u16, EcError> {
+        self.phy.txrx(tx).ok_or(EcError::Timeout)
+    }
+
+    pub fn link_reset(&mut self) -> Result<(), EcError> {
+        self.txrx(COM_LINK_RESET).map(|_| ())
+    }
+
+    /// Sends COM_HELLO and switches to the framed protocol if the EC answers it with a
+    /// version we speak. A legacy EC ignores the word, and the legacy protocol stays in use.
+    pub fn negotiate(&mut self) -> Result<EcProtocol, EcError> {
+        self.txrx(COM_HELLO)?;
+        let mut version: [u16; 1] = [0];
+        self.protocol = match self.response(&mut version) {
+            Ok(1) if version[0] >= EC_FRAMED_VERSION => EcProtocol::Framed,
+            Err(EcError::Timeout) => return Err(EcError::Timeout),
+            _ => {
+                self.link_reset()?;
+                EcProtocol::Legacy
+            },
+        };
+        Ok(self.protocol)
+    }
+
+    /// Sends `cmd` with `payload`, and places the EC's response payload into `response`.
+    /// Returns the number of response words received.
+    ///
+    /// On the legacy protocol the payload is a single byte, and `response` is filled with
+    /// the words the EC shifts out after the command, unchecked.
+    pub fn transaction(&mut self, cmd: EcCommand, payload: &[u16], response: &mut [u16]) -> Result<usize, EcError> {
+        if self.protocol == EcProtocol::Legacy {
+            if payload.len() > 1 || payload.iter().any(|arg| *arg > 0xFF) {
+                return Err(EcError::Length);
+            }
+            self.txrx(ec_legacy_word(cmd, payload.first().copied().unwrap_or(0)))?;
+            for word in response.iter_mut() {
+                *word = self.txrx(COM_DUMMY)?;
+            }
+            if !response.is_empty() {
+                // the legacy EC keeps shifting out its reply until the link is reset
+                self.link_reset()?;
+            }
+            return Ok(response.len());
+        }
+
+        for word in ec_frame(cmd, payload) {
+            self.txrx(word)?;
+        }
+        self.response(response)
+    }
+
+    /// waits for a framed response, and checks it
+    fn response(&mut self, response: &mut [u16]) -> Result<usize, EcError> {
+        let mut header: Option<u16> = None;
+        for _ in 0..EC_RESPONSE_POLLS {
+            let word = self.txrx(COM_DUMMY)?;
+            if word & 0xFF00 == COM_RESP_SYNC {
+                header = Some(word);
+                break;
+            }
+        }
+        let header = header.ok_or(EcError::NoResponse)?;
+        let len = (header & 0xFF) as usize;
+        if len > EC_MAX_PAYLOAD || len > response.len() {
+            // drop whatever the EC still has queued for us
+            self.link_reset()?;
+            return Err(EcError::Length);
+        }
+
+        // a fixed buffer, so negotiate() can run before the heap is up
+        let mut received: [u16; EC_MAX_PAYLOAD + 1] = [0; EC_MAX_PAYLOAD + 1];
+        received[0] = header;
+        for word in received[1..len + 1].iter_mut() {
+            *word = self.txrx(COM_DUMMY)?;
+        }
+        let crc = self.txrx(COM_DUMMY)?;
+        if crc != ec_crc16(&received[..len + 1]) {
+            return Err(EcError::Crc);
+        }
+
+        response[..len].copy_from_slice(&received[1..len + 1]);
+        Ok(len)
+    }
+
+    /// the legacy protocol only carries the low eight flags, which are all there are
+    pub fn power_set(&mut self, flags: PowerFlags) -> Result<(), EcError> {
+        self.transaction(EcCommand::PowerSet, &[flags.bits()], &mut []).map(|_| ())
+    }
+
+    pub fn read_gas_gauge(&mut self) -> Result<GasGauge, EcError> {
+        let mut words: [u16; GG_WORDS_EXTENDED] = [0; GG_WORDS_EXTENDED];
+        let expected: usize = match self.protocol {
+            EcProtocol::Legacy => GG_WORDS,
+            EcProtocol::Framed => GG_WORDS_EXTENDED,
+        };
+        let len: usize = self.transaction(EcCommand::GasGauge, &[], &mut words[..expected])?;
+        GasGauge::from_words(&words[..len]).ok_or(EcError::Length)
+    }
+
+    /// brightness is clamped to 0-31
+    pub fn backlight(&mut self, brightness: u8) -> Result<(), EcError> {
+        self.transaction(EcCommand::Backlight, &[brightness.min(31) as u16], &mut []).map(|_| ())
+    }
+
+    /// true puts the charger into boost mode, false back into charge mode
+    pub fn set_boost(&mut self, boost: bool) -> Result<(), EcError> {
+        self.transaction(EcCommand::Charger, &[boost as u16], &mut []).map(|_| ())
+    }
+}
+
+/// A simulated EC speaking the legacy and, unless `framed` is cleared, the framed protocol,
+/// so that protocol users can be tested on the host.
+///
+/// Like the real COM link, each exchange returns the word the EC had loaded before it saw `tx`.
+pub struct FakeEc {
+    /// answers COM_HELLO and accepts frames; a legacy EC doesn't
+    pub framed: bool,
+    /// last PowerFlags received
+    pub power: PowerFlags,
+    /// last backlight brightness received
+    pub backlight: u8,
+    /// last charger mode received
+    pub boost: bool,
+    /// what a GasGauge command returns
+    pub gas_gauge: GasGauge,
+    /// number of COM_IDLE words sent before each response
+    pub latency: usize,
+    /// when set, every exchange times out
+    pub stalled: bool,
+    /// when set, the next response has its CRC corrupted
+    pub corrupt_next: bool,
+    /// number of request frames dropped because of a bad CRC or unknown command
+    pub rejected: usize,
+    /// words of the request frame being received, once COM_FRAME_START has been seen
+    rx: Option<Vec<u16>>,
+    tx: Vec<u16>,
+}
+
+impl FakeEc {
+    pub fn new() -> Self {
+        FakeEc {
+            framed: true,
+            power: PowerFlags::empty(),
+            backlight: 0,
+            boost: false,
+            gas_gauge: GasGauge::default(),
+            latency: 0,
+            stalled: false,
+            corrupt_next: false,
+            rejected: 0,
+            rx: None,
+            tx: Vec::new(),
+        }
+    }
+
+    fn respond(&mut self, payload: &[u16]) {
+        for _ in 0..self.latency {
+            self.tx.push(COM_IDLE);
+        }
+        let mut frame: Vec<u16> = Vec::new();
+        frame.push(COM_RESP_SYNC | payload.len() as u16);
+        frame.extend_from_slice(payload);
+        let mut crc = ec_crc16(&frame);
+        if self.corrupt_next {
+            crc ^= 1;
+            self.corrupt_next = false;
+        }
+        frame.push(crc);
+        self.tx.extend_from_slice(&frame);
+    }
+
+    /// a single-word command, as the legacy EC decodes them; anything else is ignored
+    fn legacy(&mut self, word: u16) {
+        match EcCommand::from_u8((word >> 8) as u8) {
+            Some(EcCommand::PowerSet) => self.power = PowerFlags::from_bits_truncate(word & 0xFF),
+            Some(EcCommand::Backlight) => self.backlight = word as u8,
+            Some(EcCommand::Charger) => self.boost = word == 0x5AFE,
+            Some(EcCommand::GasGauge) => {
+                let words = self.gas_gauge.to_words();
+                self.tx.extend_from_slice(&words[..GG_WORDS]);
+            },
+            None => (),
+        }
+    }
+
+    fn receive(&mut self, word: u16) {
+        if word == COM_LINK_RESET {
+            self.rx = None;
+            self.tx.clear();
+            return;
+        }
+        let rx: &mut Vec<u16> = match self.rx.as_mut() {
+            Some(rx) => rx,
+            None => {
+                match word {
+                    COM_HELLO if self.framed => self.respond(&[EC_FRAMED_VERSION]),
+                    COM_FRAME_START if self.framed => self.rx = Some(Vec::new()),
+                    COM_DUMMY => (),
+                    _ => self.legacy(word),
+                }
+                return;
+            },
+        };
+        rx.push(word);
+
+        let len = (rx[0] & 0xFF) as usize;
+        if rx.len() < len + 2 {
+            return;
+        }
+        let frame: Vec<u16> = self.rx.take().unwrap_or_default();
+        let cmd = EcCommand::from_u8((frame[0] >> 8) as u8);
+        if frame[len + 1] != ec_crc16(&frame[..len + 1]) || cmd.is_none() {
+            self.rejected += 1;
+            return;
+        }
+        let payload = &frame[1..len + 1];
+        match cmd.unwrap() {
+            EcCommand::PowerSet => {
+                self.power = PowerFlags::from_bits_truncate(payload.first().copied().unwrap_or(0));
+                self.respond(&[]);
+            }
+            EcCommand::GasGauge => {
+                let words = self.gas_gauge.to_words();
+                self.respond(&words);
+            }
+            EcCommand::Backlight => {
+                self.backlight = payload.first().copied().unwrap_or(0) as u8;
+                self.respond(&[]);
+            }
+            EcCommand::Charger => {
+                self.boost = payload.first().copied().unwrap_or(0) != 0;
+                self.respond(&[]);
+            }
+        }
+    }
+}
+
+impl ComPhy for FakeEc {
+    fn txrx(&mut self, tx: u16) -> Option<u16> {
+        if self.stalled {
+            return None;
+        }
+        let rx = if self.tx.is_empty() { COM_IDLE } else { self.tx.remove(0) };
+        self.receive(tx);
+        Some(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal_battery::ChargeState;
+
+    #[test]
+    fn crc_matches_ccitt() {
+        // CRC-16/CCITT-FALSE of "12345678"
+        assert_eq!(ec_crc16(&[0x3132, 0x3334, 0x3536, 0x3738]), 0xA12B);
+        assert_eq!(ec_crc16(&[]), 0xFFFF);
+    }
+
+    /// a handle that has negotiated the framed protocol with `fake`
+    fn framed(fake: FakeEc) -> BtEc<FakeEc> {
+        let mut ec = BtEc::new(fake);
+        assert_eq!(ec.negotiate(), Ok(EcProtocol::Framed));
+        ec
+    }
+
+    #[test]
+    fn frame_layout() {
+        let frame = ec_frame(EcCommand::PowerSet, &[0x0003]);
+        assert_eq!(frame.len(), 4);
+        assert_eq!(frame[0], COM_FRAME_START);
+        assert_eq!(frame[1], 0x9001);
+        assert_eq!(frame[2], 0x0003);
+        assert_eq!(frame[3], ec_crc16(&[0x9001, 0x0003]));
+    }
+
+    #[test]
+    fn legacy_words() {
+        // as the SoC sent them before there was any framing
+        assert_eq!(ec_legacy_word(EcCommand::PowerSet, 0x0003), 0x9003);
+        assert_eq!(ec_legacy_word(EcCommand::Backlight, 31), 0x681F);
+        assert_eq!(ec_legacy_word(EcCommand::Charger, 1), 0x5AFE);
+        assert_eq!(ec_legacy_word(EcCommand::Charger, 0), 0x5A00);
+        assert_eq!(ec_legacy_word(EcCommand::GasGauge, 0), 0x7000);
+    }
+
+    #[test]
+    fn legacy_ec_stays_on_legacy_words() {
+        let mut fake = FakeEc::new();
+        fake.framed = false;
+        fake.power = PowerFlags::EC_STAY_ON | PowerFlags::SOC_ON;
+        fake.gas_gauge = GasGauge { avg_current_ma: -150, sby_current_ma: -2, voltage_mv: 3900,
+            charge: Some(ChargeState { soc_percent: 72, ..Default::default() }) };
+        let mut ec = BtEc::new(fake);
+        assert_eq!(ec.negotiate(), Ok(EcProtocol::Legacy));
+        // the hello went unanswered without touching anything
+        assert_eq!(ec.phy.power, PowerFlags::EC_STAY_ON | PowerFlags::SOC_ON);
+
+        ec.backlight(200).unwrap();
+        ec.set_boost(true).unwrap();
+        assert_eq!(ec.phy.backlight, 31);
+        assert!(ec.phy.boost);
+        // only the words a legacy EC sends
+        let gg = ec.read_gas_gauge().unwrap();
+        assert_eq!(gg.voltage_mv, 3900);
+        assert_eq!(gg.charge, None);
+        ec.power_set(PowerFlags::EC_STAY_ON | PowerFlags::DISCHARGE_FPGA).unwrap();
+        assert_eq!(ec.phy.power, PowerFlags::EC_STAY_ON | PowerFlags::DISCHARGE_FPGA);
+        assert_eq!(ec.phy.rejected, 0);
+    }
+
+    #[test]
+    fn typed_commands_reach_the_ec() {
+        let mut ec = framed(FakeEc::new());
+        ec.power_set(PowerFlags::EC_STAY_ON | PowerFlags::SOC_ON).unwrap();
+        ec.backlight(200).unwrap();
+        ec.set_boost(true).unwrap();
+        assert_eq!(ec.phy.power, PowerFlags::EC_STAY_ON | PowerFlags::SOC_ON);
+        assert_eq!(ec.phy.backlight, 31);
+        assert!(ec.phy.boost);
+        assert_eq!(ec.phy.rejected, 0);
+    }
+
+    #[test]
+    fn gas_gauge_roundtrip_with_latency() {
+        let mut fake = FakeEc::new();
+        fake.latency = 10;
+        fake.gas_gauge = GasGauge { avg_current_ma: -150, sby_current_ma: -2, voltage_mv: 3900,
+            charge: Some(ChargeState { remaining_mah: 800, full_mah: 1100, soc_percent: 72, temperature_dk: 2982 }) };
+        let mut ec = framed(fake);
+        let gg = ec.read_gas_gauge().unwrap();
+        assert_eq!(gg.avg_current_ma, -150);
+        assert_eq!(gg.sby_current_ma, -2);
+        assert_eq!(gg.voltage_mv, 3900);
+        assert_eq!(gg.soc_percent(), Some(72));
+        assert_eq!(gg.charge.unwrap().temperature_dk, 2982);
+    }
+
+    #[test]
+    fn corrupted_response_is_rejected() {
+        let mut ec = framed(FakeEc::new());
+        ec.phy.corrupt_next = true;
+        assert_eq!(ec.read_gas_gauge(), Err(EcError::Crc));
+        // the link recovers on the next transaction
+        assert!(ec.read_gas_gauge().is_ok());
+    }
+
+    #[test]
+    fn slow_ec_gives_no_response() {
+        let mut ec = framed(FakeEc::new());
+        ec.phy.latency = EC_RESPONSE_POLLS + 1;
+        assert_eq!(ec.power_set(PowerFlags::EC_STAY_ON), Err(EcError::NoResponse));
+        ec.link_reset().unwrap();
+        ec.phy.latency = 0;
+        assert!(ec.power_set(PowerFlags::EC_STAY_ON).is_ok());
+    }
+
+    #[test]
+    fn stalled_link_times_out() {
+        let mut fake = FakeEc::new();
+        fake.stalled = true;
+        let mut ec = BtEc::new(fake);
+        assert_eq!(ec.read_gas_gauge(), Err(EcError::Timeout));
+    }
+
+    #[test]
+    fn oversized_response_is_rejected() {
+        let mut ec = framed(FakeEc::new());
+        let mut short: [u16; 2] = [0; 2];
+        assert_eq!(ec.transaction(EcCommand::GasGauge, &[], &mut short), Err(EcError::Length));
+        assert!(ec.read_gas_gauge().is_ok());
+    }
+
+    #[test]
+    fn link_reset_discards_partial_frame() {
+        let mut ec = framed(FakeEc::new());
+        // half a frame, as if the SoC was reset mid-transaction
+        ec.txrx(COM_FRAME_START).unwrap();
+        ec.txrx(0x9001).unwrap();
+        ec.link_reset().unwrap();
+        ec.power_set(PowerFlags::SOC_ON).unwrap();
+        assert_eq!(ec.phy.power, PowerFlags::SOC_ON);
+        assert_eq!(ec.phy.rejected, 0);
+    }
+}
@@ -1,6 +1,16 @@
-use crate::hal_time::get_time_ms;
+use crate::hal_time::{Deadline, Duration, Instant, TimerId};
+use alloc::vec::Vec;
+use spin::Mutex;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// CPU clock handed to the last i2c_init(), so bus recovery can restore the prescaler
+static I2C_CLOCK_MHZ: AtomicU32 = AtomicU32::new(0);
+
+/// TIP timeout for the recovery clocks and scan probes; each takes well under a millisecond at 100kHz
+const I2C_PROBE_TIMEOUT_MS: u32 = 2;
 
 pub fn i2c_init(p: &betrusted_pac::Peripherals, clock_mhz: u32) {
+    I2C_CLOCK_MHZ.store(clock_mhz, Ordering::Relaxed);
     let clkcode: u32 = (clock_mhz * 1_000_000) / (5 * 100_000) - 1;
 
     // set the prescale assuming 100MHz cpu operation: 100MHz / ( 5 * 100kHz ) - 1 = 199
@@ -14,14 +24,14 @@ pub fn i2c_init(p: &betrusted_pac::Peripherals, clock_mhz: u32) {
 // threads and interurpts, this should be refactored to be asynchronous
 /// Wait until a transaction in progress ends. [FIXME] would be good to yield here once threading is enabled.
 fn i2c_tip_wait(p: &betrusted_pac::Peripherals, timeout_ms: u32) -> u32 {
-    let starttime: u32 = get_time_ms(p);
+    let deadline: Deadline = Deadline::from_ms(p, timeout_ms);
 
     // wait for TIP to go high
     loop {
         if p.I2C.status.read().tip().bit() == true {
             break;
         }
-        if get_time_ms(p) > starttime + timeout_ms {
+        if deadline.expired(p) {
             unsafe{p.I2C.command.write( |w| {w.bits(0)}); }
             return 1;
         }
@@ -32,7 +42,7 @@ fn i2c_tip_wait(p: &betrusted_pac::Peripherals, timeout_ms: u32) -> u32 {
         if p.I2C.status.read().tip().bit() == false {
             break;
         }
-        if get_time_ms(p) > starttime + timeout_ms {
+        if deadline.expired(p) {
             unsafe{p.I2C.command.write( |w| {w.bits(0)}); }
             return 1;
         }
@@ -46,6 +56,10 @@ fn i2c_tip_wait(p: &betrusted_pac::Peripherals, timeout_ms: u32) -> u32 {
 pub fn i2c_master(p: &betrusted_pac::Peripherals, addr: u8, txbuf: Option<&[u8]>, rxbuf: Option<&mut [u8]>, timeout_ms: u32) -> u32 {
     let mut ret: u32 = 0;
 
+    if !i2c_engine_drain(p, timeout_ms) {
+        return 1;
+    }
+
     // write half
     if txbuf.is_some() {
         let txbuf_checked : &[u8] = txbuf.unwrap();
@@ -101,4 +115,547 @@ pub fn i2c_master(p: &betrusted_pac::Peripherals, addr: u8, txbuf: Option<&[u8]>
     }
 
     ret
-}
\ No newline at end of file
+}
+
+/// Wait for any queued transactions to finish before the polled routines take over the bus.
+/// Returns false if the queue didn't drain within `timeout_ms`.
+fn i2c_engine_drain(p: &betrusted_pac::Peripherals, timeout_ms: u32) -> bool {
+    let deadline: Deadline = Deadline::from_ms(p, timeout_ms);
+    while with_engine(p, |engine| engine.pending.len() > 0) {
+        if deadline.expired(p) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Free a bus that a slave is holding, e.g. the RTC or codec keeping SDA low after being reset
+/// in the middle of a read.
+///
+/// The core can't toggle SCL on its own, so the clocks come from a read command with the ACK
+/// bit set to NACK: that is exactly nine SCL pulses with SDA released by the master, enough
+/// for a stuck slave to finish shifting out its byte and see the NACK. A STOP follows, then the
+/// core is disabled and brought back up with the prescaler from the last i2c_init().
+///
+/// Returns true if the core no longer sees the bus as busy.
+fn i2c_bus_clear(p: &betrusted_pac::Peripherals) -> bool {
+    p.I2C.command.write( |w| {w.rd().bit(true).ack().bit(true)});
+    i2c_tip_wait(p, I2C_PROBE_TIMEOUT_MS);
+    p.I2C.command.write( |w| {w.sto().bit(true)});
+
+    // give the STOP time to go out before the core is reset
+    let deadline: Deadline = Deadline::from_ms(p, I2C_PROBE_TIMEOUT_MS);
+    while p.I2C.status.read().busy().bit() && !deadline.expired(p) {}
+
+    p.I2C.control.write( |w| {w.en().bit(false)});
+    i2c_init(p, I2C_CLOCK_MHZ.load(Ordering::Relaxed));
+
+    !p.I2C.status.read().busy().bit()
+}
+
+/// Recover a wedged bus. Any transaction in flight on the interrupt-driven engine is failed
+/// with `I2cError::BusReset`; transactions still waiting in the queue are started once the bus
+/// has been cleared.
+///
+/// Returns true if the bus is idle afterwards.
+pub fn i2c_recover(p: &betrusted_pac::Peripherals) -> bool {
+    let aborted = with_engine(p, |engine| {
+        match engine.phase {
+            I2cPhase::Idle => None,
+            _ => {
+                engine.phase = I2cPhase::Idle;
+                let entry: I2cEntry = engine.pending.remove(0);
+                engine.retire(entry, Err(I2cError::BusReset))
+            }
+        }
+    });
+    if let Some((entry, result)) = aborted {
+        run_callback(entry, result);
+    }
+
+    let idle: bool = i2c_bus_clear(p);
+    with_engine(p, |engine| engine.start(p));
+    if idle {
+        crate::info!("bus recovered");
+    } else {
+        crate::error!("bus still busy after recovery");
+    }
+    idle
+}
+
+/// Probe every non-reserved 7-bit address (0x08-0x77, the same range i2cdetect uses) with an
+/// address-only write, and return the addresses that ACK. This uses the polled path, so it's
+/// meant for bring-up and the REPL rather than anything time-critical.
+pub fn i2c_scan(p: &betrusted_pac::Peripherals) -> Vec<u8> {
+    let mut found: Vec<u8> = Vec::new();
+
+    if !i2c_engine_drain(p, I2C_TXN_TIMEOUT_MS) {
+        return found;
+    }
+
+    for addr in 0x08..0x78_u8 {
+        unsafe{ p.I2C.txr.write( |w| {w.bits( (addr << 1 | 0) as u32 )}); }
+        // START, address, then STOP right after the ACK bit
+        p.I2C.command.write( |w| {w.sta().bit(true).wr().bit(true).sto().bit(true)});
+        if i2c_tip_wait(p, I2C_PROBE_TIMEOUT_MS) != 0 {
+            // something is holding the bus; clear it and carry on with the next address
+            i2c_bus_clear(p);
+            continue;
+        }
+        if !p.I2C.status.read().rx_ack().bit() {
+            found.push(addr);
+        }
+    }
+
+    found
+}
+
+/// I2C interrupt number, as assigned by the SoC interrupt map (uart = 0, timer0 = 1, i2c = 2)
+pub const I2C_IRQ: usize = 2;
+
+/// time allowed for a queued transaction to complete before it's aborted
+pub const I2C_TXN_TIMEOUT_MS: u32 = 10;
+
+/// event bit for "byte transfer done" (bit 0 is the core's raw interrupt line, which we don't use)
+const I2C_EV_TXRX_DONE: u32 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum I2cError {
+    /// the addressed device did not ACK its address
+    AddressNack,
+    /// the addressed device did not ACK a data byte
+    DataNack,
+    /// the transaction did not complete within I2C_TXN_TIMEOUT_MS
+    Timeout,
+    /// the I2C interrupt could not be claimed
+    IrqUnavailable,
+    /// the transaction was in flight when the bus was reset by `i2c_recover()`
+    BusReset,
+    /// the transaction has nothing to write and nothing to read
+    Empty,
+}
+
+/// the result of a completed transaction: the bytes read back on success
+pub type I2cResult = Result<Vec<u8>, I2cError>;
+
+/// completion callback, given the bytes read back, which are only borrowed for the call
+pub type I2cCallback = fn(I2cTicket, Result<&[u8], I2cError>);
+
+/// handle returned by `submit()`, used to `poll()` for the result
+pub type I2cTicket = u32;
+
+pub enum I2cPoll {
+    /// still queued or in flight
+    Pending,
+    /// finished; the ticket is retired once this is returned
+    Done(I2cResult),
+    /// the ticket was never issued, was already retired, or is reported through a callback
+    Unknown,
+}
+
+/// A single I2C transaction. If both `tx` and a read length are given, the read half is
+/// issued with a repeated start after the write half, without an intervening STOP.
+pub struct I2cTransaction {
+    addr: u8,
+    tx: Vec<u8>,
+    rx_len: usize,
+    callback: Option<I2cCallback>,
+}
+
+impl I2cTransaction {
+    pub fn write(addr: u8, data: &[u8]) -> Self {
+        I2cTransaction { addr: addr, tx: data.to_vec(), rx_len: 0, callback: None }
+    }
+
+    pub fn read(addr: u8, len: usize) -> Self {
+        I2cTransaction { addr: addr, tx: Vec::new(), rx_len: len, callback: None }
+    }
+
+    pub fn write_read(addr: u8, data: &[u8], len: usize) -> Self {
+        I2cTransaction { addr: addr, tx: data.to_vec(), rx_len: len, callback: None }
+    }
+
+    /// true if there is no byte to move; the engine can't put such a transaction on the bus
+    pub fn is_empty(&self) -> bool {
+        self.tx.is_empty() && self.rx_len == 0
+    }
+
+    /// report completion by calling `f` from interrupt context instead of through `poll()`
+    pub fn with_callback(mut self, f: I2cCallback) -> Self {
+        self.callback = Some(f);
+        self
+    }
+}
+
+/// which phase of the transaction the last "transfer done" event corresponds to
+#[derive(Copy, Clone)]
+enum I2cPhase {
+    Idle,
+    TxAddr,
+    TxData(usize),
+    RxAddr,
+    RxData(usize),
+}
+
+struct I2cEntry {
+    ticket: I2cTicket,
+    txn: I2cTransaction,
+    rx: Vec<u8>,
+    started: Instant,
+}
+
+/// The interrupt handler neither allocates nor frees: the heap's lock isn't safe to take in
+/// interrupt context. Every entry's rx buffer is allocated up front, `done` and `spent` are
+/// given room for every pending entry by submit(), and entries that are finished with wait in
+/// `spent` to be dropped outside the handler.
+struct I2cEngine {
+    /// queued transactions; the entry at index 0 is the one on the bus
+    pending: Vec<I2cEntry>,
+    /// finished transactions waiting to be collected by poll()
+    done: Vec<(I2cTicket, I2cResult)>,
+    /// retired entries, freed the next time the engine is used outside the handler
+    spent: Vec<I2cEntry>,
+    phase: I2cPhase,
+    next_ticket: I2cTicket,
+}
+
+static I2C_ENGINE: Mutex<I2cEngine> = Mutex::new(I2cEngine {
+    pending: Vec::new(),
+    done: Vec::new(),
+    spent: Vec::new(),
+    phase: I2cPhase::Idle,
+    next_ticket: 1,
+});
+
+impl I2cEngine {
+    /// kick off the transaction at the head of the queue, if the bus is idle
+    fn start(&mut self, p: &betrusted_pac::Peripherals) {
+        if let I2cPhase::Idle = self.phase {
+            if let Some(entry) = self.pending.get_mut(0) {
+                entry.started = Instant::now(p);
+                if entry.txn.tx.len() > 0 {
+                    unsafe{ p.I2C.txr.write( |w| {w.bits( (entry.txn.addr << 1 | 0) as u32 )}); }
+                    self.phase = I2cPhase::TxAddr;
+                } else {
+                    unsafe{ p.I2C.txr.write( |w| {w.bits( (entry.txn.addr << 1 | 1) as u32 )}); }
+                    self.phase = I2cPhase::RxAddr;
+                }
+                p.I2C.command.write( |w| {w.sta().bit(true).wr().bit(true)});
+            }
+        }
+    }
+
+    /// issue the read command for rx byte `index`; the last byte is NACK'd and followed by a STOP
+    fn read_byte(&mut self, p: &betrusted_pac::Peripherals, index: usize, len: usize) {
+        if index == len - 1 {
+            p.I2C.command.write( |w| {w.rd().bit(true).ack().bit(true).sto().bit(true)});
+        } else {
+            p.I2C.command.write( |w| {w.rd().bit(true)});
+        }
+        self.phase = I2cPhase::RxData(index);
+    }
+
+    /// advance the current transaction after a "transfer done" event. Returns the finished
+    /// entry, holding whatever was read, and its outcome once it has been retired from the bus.
+    fn advance(&mut self, p: &betrusted_pac::Peripherals) -> Option<(I2cEntry, Result<(), I2cError>)> {
+        let nack: bool = p.I2C.status.read().rx_ack().bit();
+        let phase = self.phase;
+        let (tx_len, rx_len) = match self.pending.get(0) {
+            Some(entry) => (entry.txn.tx.len(), entry.txn.rx_len),
+            None => {
+                self.phase = I2cPhase::Idle;
+                return None;
+            }
+        };
+
+        let outcome: Option<Result<(), I2cError>> = match phase {
+            I2cPhase::Idle => None,
+            I2cPhase::TxAddr | I2cPhase::TxData(_) => {
+                let sent: usize = match phase { I2cPhase::TxData(i) => i + 1, _ => 0 };
+                if nack {
+                    p.I2C.command.write( |w| {w.sto().bit(true)});
+                    match phase {
+                        I2cPhase::TxAddr => Some(Err(I2cError::AddressNack)),
+                        _ => Some(Err(I2cError::DataNack)),
+                    }
+                } else if sent < tx_len {
+                    let byte: u8 = self.pending[0].txn.tx[sent];
+                    unsafe{ p.I2C.txr.write( |w| {w.bits( byte as u32 )}); }
+                    if sent == tx_len - 1 && rx_len == 0 {
+                        p.I2C.command.write( |w| {w.wr().bit(true).sto().bit(true)});
+                    } else {
+                        p.I2C.command.write( |w| {w.wr().bit(true)});
+                    }
+                    self.phase = I2cPhase::TxData(sent);
+                    None
+                } else if rx_len > 0 {
+                    // repeated start into the read half
+                    let addr: u8 = self.pending[0].txn.addr;
+                    unsafe{ p.I2C.txr.write( |w| {w.bits( (addr << 1 | 1) as u32 )}); }
+                    p.I2C.command.write( |w| {w.sta().bit(true).wr().bit(true)});
+                    self.phase = I2cPhase::RxAddr;
+                    None
+                } else {
+                    // the STOP went out with the last byte
+                    Some(Ok(()))
+                }
+            },
+            I2cPhase::RxAddr => {
+                if nack {
+                    p.I2C.command.write( |w| {w.sto().bit(true)});
+                    Some(Err(I2cError::AddressNack))
+                } else {
+                    self.read_byte(p, 0, rx_len);
+                    None
+                }
+            },
+            I2cPhase::RxData(index) => {
+                let byte: u8 = p.I2C.rxr.read().bits() as u8;
+                self.pending[0].rx.push(byte); // within the capacity reserved by submit()
+                if index + 1 < rx_len {
+                    self.read_byte(p, index + 1, rx_len);
+                    None
+                } else {
+                    Some(Ok(()))
+                }
+            },
+        };
+
+        if let Some(result) = outcome {
+            self.phase = I2cPhase::Idle;
+            Some((self.pending.remove(0), result))
+        } else {
+            None
+        }
+    }
+
+    /// File a result. Entries with a callback are handed back to the caller to run it outside
+    /// the engine lock, then go to spent(); everything else waits in the done queue for poll().
+    fn retire(&mut self, mut entry: I2cEntry, result: Result<(), I2cError>) -> Option<(I2cEntry, Result<(), I2cError>)> {
+        if entry.txn.callback.is_some() {
+            return Some((entry, result));
+        }
+        let rx: Vec<u8> = core::mem::take(&mut entry.rx);
+        self.done.push((entry.ticket, result.map(|()| rx)));
+        self.spent.push(entry);
+        None
+    }
+
+    /// put a retired entry aside to be freed outside the interrupt handler
+    fn spent(&mut self, entry: I2cEntry) {
+        self.spent.push(entry);
+    }
+}
+
+/// run a retired entry's callback, then put the entry aside; the engine lock must not be held
+fn run_callback(entry: I2cEntry, result: Result<(), I2cError>) {
+    if let Some(f) = entry.txn.callback {
+        f(entry.ticket, result.map(|()| &entry.rx[..]));
+    }
+    I2C_ENGINE.lock().spent(entry);
+}
+
+/// run `f` on the engine with the I2C interrupt masked, so the handler can't contend for the lock
+fn with_engine<R, F: FnOnce(&mut I2cEngine) -> R>(p: &betrusted_pac::Peripherals, f: F) -> R {
+    let enabled: u32 = p.I2C.ev_enable.read().bits();
+    unsafe{ p.I2C.ev_enable.write( |w| {w.bits(0)}); }
+    let ret: R = f(&mut *I2C_ENGINE.lock());
+    unsafe{ p.I2C.ev_enable.write( |w| {w.bits(enabled)}); }
+    ret
+}
+
+/// I2C interrupt handler, registered by `BtI2c::init()`
+pub fn i2c_handle_irq(_irq: usize) {
+    let p: betrusted_pac::Peripherals = unsafe{ betrusted_pac::Peripherals::steal() };
+    let pending: u32 = p.I2C.ev_pending.read().bits();
+    unsafe{ p.I2C.ev_pending.write( |w| {w.bits(pending)}); }
+
+    if pending & I2C_EV_TXRX_DONE != 0 {
+        let finished = {
+            let mut engine = I2C_ENGINE.lock();
+            match engine.advance(&p) {
+                Some((entry, result)) => {
+                    let cb = engine.retire(entry, result);
+                    engine.start(&p);
+                    cb
+                },
+                None => None,
+            }
+        };
+        if let Some((entry, result)) = finished {
+            run_callback(entry, result);
+        }
+    }
+}
+
+/// Periodic timer callback that aborts a transaction that has overrun I2C_TXN_TIMEOUT_MS, so
+/// that one reported only through a callback still times out on a stuck bus. Schedule it with
+/// BtTimers::every() at I2C_TXN_TIMEOUT_MS.
+pub fn i2c_timeout_timer(_id: TimerId) {
+    BtI2c::new().expire();
+}
+
+/// Interrupt-driven I2C transaction engine
+///
+/// Transactions are queued with `BtI2c::submit()` and executed one byte-phase at a time from
+/// the I2C interrupt handler, so the caller never spins on TIP. Each phase of the transaction
+/// (address, tx byte, rx byte) ends with a "transfer done" event from the core; the handler
+/// inspects the ACK status, loads the next phase, and retires the transaction once the STOP
+/// has been issued.
+///
+/// Completion is reported in one of two ways:
+///   * the caller holds on to the ticket returned by `submit()` and calls `poll()` until the
+///     result is available, or
+///   * the transaction carries a callback, which is invoked from interrupt context with the
+///     result. Callbacks should be short, as they run inside the interrupt handler, and must
+///     not allocate, as the heap's lock isn't safe to take there.
+///
+/// A transaction that overruns I2C_TXN_TIMEOUT_MS is aborted by the next poll(), or by
+/// i2c_timeout_timer() if the caller isn't polling.
+///
+/// The polled `i2c_master()` call remains available for code that runs before interrupts
+/// are enabled, but the two must not be mixed on the bus at the same time.
+///
+/// `init()` must be called once, after which transactions can be submitted from any context
+/// that isn't the I2C interrupt handler itself.
+pub struct BtI2c {
+    p: betrusted_pac::Peripherals,
+}
+
+impl BtI2c {
+    pub fn new() -> Self {
+        unsafe {
+            BtI2c {
+                p: betrusted_pac::Peripherals::steal(),
+            }
+        }
+    }
+
+    /// set up the prescaler, claim the I2C interrupt and enable the transfer-done event
+    pub fn init(&mut self, clock_mhz: u32) -> Result<(), I2cError> {
+        i2c_init(&self.p, clock_mhz);
+        if xous_nommu::syscalls::sys_interrupt_claim(I2C_IRQ, i2c_handle_irq).is_err() {
+            return Err(I2cError::IrqUnavailable);
+        }
+        unsafe{ self.p.I2C.ev_pending.write( |w| {w.bits(self.p.I2C.ev_pending.read().bits())}); }
+        unsafe{ self.p.I2C.ev_enable.write( |w| {w.bits(I2C_EV_TXRX_DONE)}); }
+        Ok(())
+    }
+
+    /// queue a transaction; it starts immediately if the bus is idle
+    pub fn submit(&mut self, txn: I2cTransaction) -> Result<I2cTicket, I2cError> {
+        if txn.is_empty() {
+            return Err(I2cError::Empty);
+        }
+        let p = &self.p;
+        Ok(with_engine(p, |engine| {
+            engine.spent.clear();
+            let ticket: I2cTicket = engine.next_ticket;
+            engine.next_ticket = engine.next_ticket.wrapping_add(1);
+            let rx_len: usize = txn.rx_len;
+            engine.pending.push(I2cEntry {
+                ticket: ticket,
+                txn: txn,
+                rx: Vec::with_capacity(rx_len),
+                started: Instant::from_ticks(0),
+            });
+            // room for everything pending to be retired without the handler allocating
+            let pending: usize = engine.pending.len();
+            engine.done.reserve(pending);
+            engine.spent.reserve(pending);
+            engine.start(p);
+            ticket
+        }))
+    }
+
+    /// Abort the in-flight transaction if it has exceeded its timeout, and clear the bus in
+    /// case a device is holding it.
+    pub fn expire(&mut self) {
+        let p = &self.p;
+        let expired = with_engine(p, |engine| {
+            engine.spent.clear();
+            match engine.phase {
+                I2cPhase::Idle => None,
+                _ => {
+                    if engine.pending[0].started.elapsed(p) > Duration::from_ms(I2C_TXN_TIMEOUT_MS) {
+                        engine.phase = I2cPhase::Idle;
+                        let entry: I2cEntry = engine.pending.remove(0);
+                        Some(engine.retire(entry, Err(I2cError::Timeout)))
+                    } else {
+                        None
+                    }
+                }
+            }
+        });
+        if let Some(cb) = expired {
+            // a transaction that never finishes usually means a slave is holding the bus
+            crate::warn!("transaction timed out, clearing bus");
+            i2c_bus_clear(p);
+            with_engine(p, |engine| engine.start(p));
+            if let Some((entry, result)) = cb {
+                run_callback(entry, result);
+            }
+        }
+    }
+
+    /// check on a submitted transaction, aborting it if it has timed out
+    pub fn poll(&mut self, ticket: I2cTicket) -> I2cPoll {
+        self.expire();
+        with_engine(&self.p, |engine| {
+            if let Some(index) = engine.done.iter().position(|(t, _)| *t == ticket) {
+                let (_, result) = engine.done.remove(index);
+                I2cPoll::Done(result)
+            } else if engine.pending.iter().any(|entry| entry.ticket == ticket) {
+                I2cPoll::Pending
+            } else {
+                I2cPoll::Unknown
+            }
+        })
+    }
+
+    /// true if any transaction is queued or in flight
+    pub fn busy(&mut self) -> bool {
+        with_engine(&self.p, |engine| engine.pending.len() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn empty_transactions() {
+        assert!(I2cTransaction::write(0x68, &[]).is_empty());
+        assert!(I2cTransaction::read(0x68, 0).is_empty());
+        assert!(I2cTransaction::write_read(0x68, &[], 0).is_empty());
+        assert!(!I2cTransaction::write(0x68, &[0x03]).is_empty());
+        assert!(!I2cTransaction::read(0x68, 1).is_empty());
+        // a write_read with nothing to write is just a read
+        assert!(!I2cTransaction::write_read(0x68, &[], 7).is_empty());
+        assert!(!I2cTransaction::write_read(0x68, &[0x03], 0).is_empty());
+    }
+    #[test]
+    fn retiring_stays_within_reserved_room() {
+        let mut engine = I2cEngine {
+            pending: Vec::new(),
+            done: Vec::with_capacity(1),
+            spent: Vec::with_capacity(1),
+            phase: I2cPhase::Idle,
+            next_ticket: 1,
+        };
+        let mut rx: Vec<u8> = Vec::with_capacity(2);
+        rx.extend_from_slice(&[0x12, 0x34]);
+        let entry = I2cEntry { ticket: 5, txn: I2cTransaction::read(0x68, 2), rx: rx, started: Instant::from_ticks(0) };
+        assert!(engine.retire(entry, Ok(())).is_none());
+        assert_eq!((engine.done.capacity(), engine.spent.capacity()), (1, 1));
+        assert_eq!(engine.done[0], (5, Ok(vec![0x12, 0x34])));
+
+        // callbacks are handed back rather than queued
+        fn ignore(_: I2cTicket, _: Result<&[u8], I2cError>) {}
+        let txn: I2cTransaction = I2cTransaction::read(0x68, 1).with_callback(ignore);
+        let entry = I2cEntry { ticket: 6, txn: txn, rx: Vec::new(), started: Instant::from_ticks(0) };
+        assert!(engine.retire(entry, Err(I2cError::Timeout)).is_some());
+        assert_eq!(engine.done.len(), 1);
+    }
+}
@@ -1,5 +1,7 @@
 use alloc::vec::Vec;
-use crate::hal_time::get_time_ms;
+use alloc::string::String;
+use alloc::vec;
+use crate::hal_time::{Duration, Instant};
 
 /// note: the code is structured to use at most 16 rows or 16 cols
 const KBD_ROWS: usize = 9;
@@ -62,6 +64,7 @@ fn kbd_getcodes(p: &betrusted_pac::Peripherals) -> Option<Vec<(usize,usize)>> {
 }
 
 /// holds the four basic possible values of a key location
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct ScanCode {
     /// base key value
     pub key: Option<char>,    
@@ -73,18 +76,159 @@ pub struct ScanCode {
     pub alt: Option<char>,    
 }
 
+impl ScanCode {
+    pub const NONE: ScanCode = ScanCode { key: None, shift: None, hold: None, alt: None };
+
+    /// a key that gives the same code whatever the shift state
+    const fn all(c: char) -> Self {
+        ScanCode { key: Some(c), shift: Some(c), hold: Some(c), alt: Some(c) }
+    }
+
+    fn plane(&mut self, plane: u8) -> Option<&mut Option<char>> {
+        match plane {
+            b'k' => Some(&mut self.key),
+            b's' => Some(&mut self.shift),
+            b'h' => Some(&mut self.hold),
+            b'a' => Some(&mut self.alt),
+            _ => None,
+        }
+    }
+}
+
+/// how long a key must read pressed before a keydown is reported
+pub const DEBOUNCE_PRESS_MS: u32 = 5;
+/// how long it must then read released before the keyup; releases bounce for longer
+pub const DEBOUNCE_RELEASE_MS: u32 = 10;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebounceStats {
+    /// snapshots where a key was held back because it might be a ghost
+    pub ghosted: u32,
+    /// snapshots ignored for having more keys down than the matrix can tell apart
+    pub overflowed: u32,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct DebounceKey {
+    /// the debounced state
+    down: bool,
+    /// when the matrix started disagreeing with `down`
+    changing: Option<Instant>,
+}
+
+/// Debouncer for a key matrix, fed with snapshots of which positions read as pressed.
+///
+/// Each key has a reported state, which only flips once the matrix has disagreed with it for the
+/// whole of the press or release time. A key that bounces back before then starts over, so a
+/// keydown is always followed by exactly one keyup.
+///
+/// Without a diode per key, pressing three corners of a rectangle in the matrix makes the
+/// fourth read as pressed too. A key that isn't already down is held back while it's part of
+/// such a rectangle, since it can't be told from a ghost. More than rows + cols - 1 keys always
+/// close a loop through the matrix, so snapshots with more than that are ignored outright.
+pub struct Debouncer {
+    rows: usize,
+    cols: usize,
+    press: Duration,
+    release: Duration,
+    keys: Vec<DebounceKey>,
+    stats: DebounceStats,
+}
+
+impl Debouncer {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Debouncer {
+            rows,
+            cols,
+            press: Duration::from_ms(DEBOUNCE_PRESS_MS),
+            release: Duration::from_ms(DEBOUNCE_RELEASE_MS),
+            keys: vec![DebounceKey::default(); rows * cols],
+            stats: DebounceStats::default(),
+        }
+    }
+
+    pub fn set_thresholds(&mut self, press: Duration, release: Duration) {
+        self.press = press;
+        self.release = release;
+    }
+
+    /// the most keys that can be down at once without closing a loop of rows and columns, such
+    /// as a whole row and a whole column
+    pub fn rollover(&self) -> usize {
+        self.rows + self.cols - 1
+    }
+
+    pub fn stats(&self) -> DebounceStats {
+        self.stats
+    }
+
+    /// the debounced keys that are down
+    pub fn pressed(&self) -> Vec<(usize, usize)> {
+        (0..self.keys.len()).filter(|&i| self.keys[i].down).map(|i| (i / self.cols, i % self.cols)).collect()
+    }
+
+    /// true if `key` is the corner of a rectangle of keys in `raw`
+    fn ambiguous(raw: &[(usize, usize)], key: (usize, usize)) -> bool {
+        let (r, c) = key;
+        raw.iter().any(|&(r2, c2)| r2 != r && c2 != c && raw.contains(&(r, c2)) && raw.contains(&(r2, c)))
+    }
+
+    /// Take the positions that read as pressed at `now`, and return the keys that have gone
+    /// down and come up since the last snapshot.
+    pub fn update(&mut self, now: Instant, raw: &[(usize, usize)]) -> (Vec<(usize, usize)>, Vec<(usize, usize)>) {
+        let raw: Vec<(usize, usize)> = raw.iter().cloned().filter(|&(r, c)| r < self.rows && c < self.cols).collect();
+        let mut reading: Vec<bool> = vec![false; self.keys.len()];
+        if raw.len() > self.rollover() {
+            self.stats.overflowed += 1;
+            // nothing in this snapshot can be trusted, so it says nothing has changed
+            for (i, key) in self.keys.iter().enumerate() {
+                reading[i] = key.down;
+            }
+        } else {
+            let mut ghosted: bool = false;
+            for &(r, c) in raw.iter() {
+                let i: usize = r * self.cols + c;
+                if !self.keys[i].down && Debouncer::ambiguous(&raw, (r, c)) {
+                    ghosted = true;
+                } else {
+                    reading[i] = true;
+                }
+            }
+            if ghosted {
+                self.stats.ghosted += 1;
+            }
+        }
+
+        let mut downs: Vec<(usize, usize)> = Vec::new();
+        let mut ups: Vec<(usize, usize)> = Vec::new();
+        for (i, key) in self.keys.iter_mut().enumerate() {
+            if reading[i] == key.down {
+                key.changing = None;
+                continue;
+            }
+            let since: Instant = *key.changing.get_or_insert(now);
+            let needed: Duration = if key.down { self.release } else { self.press };
+            if now.checked_duration_since(since).map_or(false, |d| d >= needed) {
+                key.down = !key.down;
+                key.changing = None;
+                if key.down {
+                    downs.push((i / self.cols, i % self.cols));
+                } else {
+                    ups.push((i / self.cols, i % self.cols));
+                }
+            }
+        }
+        (downs, ups)
+    }
+}
+
 /// This is the main keyboard manager construct.
 pub struct KeyManager {
     /// the peripheral access crate pointer
     p: betrusted_pac::Peripherals,
-    /// debounce counter array
-    debounce: [[u8; KBD_COLS]; KBD_ROWS],
-    /// threshold (in ms) for considering an up or down event to be debounced, in loop interations.
-    threshold: u8,
-    /// last timestamp (in ms) since last call
-    timestamp: u32,
-    /// remember the last keycode since a change event
-    lastcode: Option<Vec<(usize, usize)>>,
+    debouncer: Debouncer,
+    /// the keys that read as pressed at the last change event
+    lastcode: Vec<(usize, usize)>,
 }
 
 impl KeyManager {
@@ -92,10 +236,8 @@ impl KeyManager {
         unsafe{ 
             KeyManager{
                 p: betrusted_pac::Peripherals::steal(),
-                debounce: [[0; KBD_COLS]; KBD_ROWS],
-                threshold: 5,
-                timestamp: get_time_ms(&betrusted_pac::Peripherals::steal()),
-                lastcode: None,
+                debouncer: Debouncer::new(KBD_ROWS, KBD_COLS),
+                lastcode: Vec::new(),
             }
         }
     }
@@ -117,232 +259,498 @@ impl KeyManager {
     /// 
     /// returns a tuple of (keydown, keyup) scan codes, each of which are an Option-wrapped vector
     pub fn update(&mut self) -> (Option<Vec<(usize, usize)>>, Option<Vec<(usize,usize)>>) {
-        let mut downs: [[bool; KBD_COLS]; KBD_ROWS] = [[false; KBD_COLS]; KBD_ROWS];
-        let mut keydowns = Vec::new();
-        let mut keyups = Vec::new();
-
         if self.p.KEYBOARD.ev_pending.read().bits() != 0 {
             // only do the getcodes() call if we saw a change to key state
-            self.lastcode = kbd_getcodes(&self.p);
+            self.lastcode = kbd_getcodes(&self.p).unwrap_or_default();
             // clear the pending bit
             unsafe{ self.p.KEYBOARD.ev_pending.write(|w| w.bits(1)); }
         }
 
-        let elapsed: u32 = get_time_ms(&self.p) - self.timestamp;
-        if (elapsed == 0) && self.lastcode.is_none() {
-            // skip debounce processing if time elapsed is too short and there's no key updates
-            (None, None)
+        let (downs, ups) = self.debouncer.update(Instant::now(&self.p), &self.lastcode);
+        let retdowns = if downs.is_empty() { None } else { Some(downs) };
+        let retups = if ups.is_empty() { None } else { Some(ups) };
+        (retdowns, retups)
+    }
+
+    /// the debounced keys that are down
+    pub fn pressed(&self) -> Vec<(usize, usize)> {
+        self.debouncer.pressed()
+    }
+
+    pub fn debounce_stats(&self) -> DebounceStats {
+        self.debouncer.stats()
+    }
+}
+
+// Keyboard layouts
+//
+// The forty keys of the number row and the three letter rows form a 4x10 grid, whose left half
+// is wired to matrix rows 0-3 and right half to rows 4-7. A layout says what each of those keys
+// gives on each of the four planes. The space bar row, function keys and arrows are the same in
+// every layout, and are placed according to the board revision.
+//
+// Layouts are written as text, one line per plane and grid row:
+//
+//     base qwerty
+//     k1 qwertyuiop
+//     s1 QWERTYUIOP
+//
+// An optional first line names a built-in layout to start from; otherwise the grid starts out
+// empty. Each other line is the plane (`k`ey, `s`hift, `h`old or `a`lt), the grid row 0-3,
+// a space and exactly ten characters, replacing that row of that plane. A space means no code.
+// Control codes such as backspace (0x08) and enter (0x0d) are written as themselves, so lines are
+// split on `\n` alone. A whole layout comes to about 170 bytes, which fits in one config value.
+
+pub const GRID_ROWS: usize = 4;
+pub const GRID_COLS: usize = 10;
+
+/// names of the built-in layouts
+pub const LAYOUTS: [&str; 4] = ["qwerty", "dvorak", "azerty", "qwertz"];
+
+/// the blue shift key, which shifts the key after it or, held, gives the hold codes
+pub const BLUE_SHIFT: (usize, usize) = (8, 5);
+/// the orange key, for the alt codes; it also powers the unit on
+pub const ORANGE_SHIFT: (usize, usize) = (8, 9);
+
+/// the config key a layout is loaded from
+pub const LAYOUT_KEY: &[u8] = b"kbd.layout";
+
+const QWERTY: &str = "k0 1234567890\ns0 1234567890\n\
+k1 qwertyuiop\ns1 QWERTYUIOP\nh1 #&*-+()\\`~\n\
+k2 asdfghjkl\u{8}\ns2 ASDFGHJKL\u{8}\nh2 |[]<>{}_$\u{8}\na2          \u{8}\n\
+k3 'zxcvbnm?\r\ns3 'ZXCVBNM?\r\nh3 @\":;/^=%!\r\na3          \r\n";
+
+const DVORAK: &str = "k0 1234567890\ns0 1234567890\n\
+k1 \u{8}'pyfgcrl?\ns1 \u{8}'PYFGCRL?\nh1 \u{8}@#&*-+()!\na1 \u{8}         \n\
+k2 aoeuidhtns\ns2 AOEUIDHTNS\nh2 \\`~|[]<>{}\n\
+k3 qjkxbmwvz\r\ns3 QJKXBMWVZ\r\nh3 _$\":;/^=%\r\na3          \r\n";
+
+const AZERTY: &str = "k0 1234567890\ns0 1234567890\n\
+k1 azertyuiop\ns1 AZERTYUIOP\nh1 #&*-+()\\`~\na1 à é   ù   \n\
+k2 qsdfghjklm\ns2 QSDFGHJKLM\nh2 |[]<>{}_$\"\n\
+k3 wxcvbn'?\u{8}\r\ns3 WXCVBN'?\u{8}\r\nh3 :;/^=%@!\u{8}\r\na3   ç     \u{8}\r\n";
+
+const QWERTZ: &str = "k0 1234567890\ns0 1234567890\n\
+k1 qwertzuiop\ns1 QWERTZUIOP\nh1 #&*-+()\\`~\na1       ü ö \n\
+k2 asdfghjkl\u{8}\ns2 ASDFGHJKL\u{8}\nh2 |[]<>{}_$\u{8}\na2 äß       \u{8}\n\
+k3 'yxcvbnm?\r\ns3 'YXCVBNM?\r\nh3 @\":;/^=%!\r\na3          \r\n";
+
+/// keys outside the grid, common to all layouts
+#[cfg(not(feature = "dvt"))]
+const FIXED_KEYS: [((usize, usize), ScanCode); 14] = [
+    ((8, 5), ScanCode::all('\u{f}')), // shift in (blue shift)
+    ((8, 6), ScanCode { key: Some(','), shift: Some('\u{e}'), hold: Some('\u{e}'), alt: None }), // 0xe is shift out (sym)
+    ((8, 7), ScanCode { key: Some(' '), shift: Some(' '), hold: Some(' '), alt: None }),
+    ((8, 8), ScanCode { key: Some('.'), shift: Some('😃'), hold: Some('😃'), alt: None }),
+    // aliased for power-on
+    ((8, 9), ScanCode::all('\u{10}')), // DLE (orange shift)
+
+    // these are all bugged: row values are swapped on PCB
+    // the F0/tab key also doubles as a secondary power key (can't do UP5K UART rx at same time)
+    ((8, 0), ScanCode::all('\u{11}')), // DC1 (F1)
+    ((8, 1), ScanCode::all('\u{12}')), // DC2 (F2)
+    ((3, 8), ScanCode::all('\u{13}')), // DC3 (F3)
+    // the F4/ctrl key also doubles as a power key
+    ((3, 9), ScanCode::all('\u{14}')), // DC4 (F4)
+    ((8, 3), ScanCode::all('←')),
+    ((3, 6), ScanCode::all('→')),
+    ((6, 4), ScanCode::all('↑')),
+    ((8, 2), ScanCode::all('↓')),
+    // this one is OK
+    ((5, 2), ScanCode::all('∴')),
+];
+
+#[cfg(feature = "dvt")]
+const FIXED_KEYS: [((usize, usize), ScanCode); 14] = [
+    ((8, 5), ScanCode::all('\u{f}')), // shift in (blue shift)
+    ((8, 6), ScanCode { key: Some(','), shift: Some('\u{e}'), hold: Some('\u{e}'), alt: None }), // 0xe is shift out (sym)
+    ((8, 7), ScanCode { key: Some(' '), shift: Some(' '), hold: Some(' '), alt: None }),
+    ((8, 8), ScanCode { key: Some('.'), shift: Some('😃'), hold: Some('😃'), alt: None }),
+    ((8, 9), ScanCode::all('\u{10}')), // DLE (orange shift)
+
+    // these are all bugged: row values are swapped on PCB
+    // the F0/tab key also doubles as a secondary power key (can't do UP5K UART rx at same time)
+    ((4, 0), ScanCode::all('\u{11}')), // DC1 (F1)
+    ((4, 1), ScanCode::all('\u{12}')), // DC2 (F2)
+    ((3, 8), ScanCode::all('\u{13}')), // DC3 (F3)
+    // the F4/ctrl key also doubles as a power key
+    ((3, 9), ScanCode::all('\u{14}')), // DC4 (F4)
+    ((4, 3), ScanCode::all('←')),
+    ((3, 6), ScanCode::all('→')),
+    ((6, 4), ScanCode::all('↑')),
+    ((8, 2), ScanCode::all('↓')),
+    // this one is OK
+    ((5, 2), ScanCode::all('∴')),
+];
+
+/// matrix position of a key in the 4x10 grid
+pub fn grid_position(row: usize, col: usize) -> (usize, usize) {
+    if col < GRID_COLS / 2 {
+        (row, col)
+    } else {
+        (row + GRID_ROWS, col)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    Utf8,
+    /// the base line names no built-in layout
+    UnknownBase,
+    /// the line with this index isn't a base line or a plane row
+    Syntax(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// the built-in this was made from, or "custom"
+    name: String,
+    keys: [[ScanCode; KBD_COLS]; KBD_ROWS],
+}
+
+impl Layout {
+    /// the fixed keys, and nothing in the grid
+    fn blank(name: &str) -> Self {
+        let mut keys: [[ScanCode; KBD_COLS]; KBD_ROWS] = [[ScanCode::NONE; KBD_COLS]; KBD_ROWS];
+        for &((r, c), code) in FIXED_KEYS.iter() {
+            keys[r][c] = code;
+        }
+        Layout { name: String::from(name), keys }
+    }
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        let text: &str = match name {
+            "qwerty" => QWERTY,
+            "dvorak" => DVORAK,
+            "azerty" => AZERTY,
+            "qwertz" => QWERTZ,
+            _ => return None,
+        };
+        let mut layout: Layout = Layout::blank(name);
+        // the built-in tables are checked by the tests
+        layout.apply(text.split('\n'), 0).ok()?;
+        Some(layout)
+    }
+
+    pub fn dvorak() -> Self {
+        Layout::builtin("dvorak").unwrap()
+    }
+
+    /// parse a layout in the text format described above
+    pub fn parse(text: &str) -> Result<Self, LayoutError> {
+        let mut lines = text.split('\n');
+        if text.starts_with("base ") {
+            let name: &str = lines.next().unwrap()["base ".len()..].trim();
+            let mut layout: Layout = Layout::builtin(name).ok_or(LayoutError::UnknownBase)?;
+            if layout.apply(lines, 1)? > 0 {
+                // a built-in with changes is no longer that built-in
+                layout.name = String::from("custom");
+            }
+            Ok(layout)
         } else {
-            self.timestamp = get_time_ms(&self.p); // on a "real" pass, update the timestamp accordingly
-
-            // in case a lot of time has elapsed, saturate the debounce increment at the threshold so we don't
-            // overflow the debounce counter's u8
-            let increment: u8;
-            if elapsed > self.threshold as u32 {
-                increment = self.threshold;
-            } else {
-                increment = elapsed as u8;
+            let mut layout: Layout = Layout::blank("custom");
+            layout.apply(lines, 0)?;
+            Ok(layout)
+        }
+    }
+
+    /// as read back from the config store
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LayoutError> {
+        Layout::parse(core::str::from_utf8(bytes).map_err(|_| LayoutError::Utf8)?)
+    }
+
+    /// apply plane rows, returning how many there were; `first` is the index of the first line
+    fn apply<'t, I: Iterator<Item = &'t str>>(&mut self, lines: I, first: usize) -> Result<usize, LayoutError> {
+        let mut applied: usize = 0;
+        for (n, line) in lines.enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let b: &[u8] = line.as_bytes();
+            let row: usize = match b.get(1) {
+                Some(r @ b'0'..=b'3') => (r - b'0') as usize,
+                _ => return Err(LayoutError::Syntax(first + n)),
+            };
+            let chars: &str = match line.get(2..) {
+                Some(rest) if rest.starts_with(' ') && rest[1..].chars().count() == GRID_COLS => &rest[1..],
+                _ => return Err(LayoutError::Syntax(first + n)),
+            };
+            for (col, c) in chars.chars().enumerate() {
+                let (r, k) = grid_position(row, col);
+                let slot: &mut Option<char> = self.keys[r][k].plane(b[0]).ok_or(LayoutError::Syntax(first + n))?;
+                *slot = if c == ' ' { None } else { Some(c) };
             }
+            applied += 1;
+        }
+        Ok(applied)
+    }
 
-            // if there's keys pressed, continue to increment the debounce counter
-            if self.lastcode.is_some() {
-                // this is a little tricky because alloc::Vec doesn't implement a copy operator
-                // so we have to take the vector and create a new copy after unwrapping it.betrusted_pac
-
-                let code: Vec<(usize, usize)> = self.lastcode.take().unwrap();
-                let mut newcode = Vec::new(); // need to allocate a newcode because alloc::Vec doesn't implement copy
-                for key in code {
-                    let (row, col) = key;
-                    if self.debounce[row][col] < self.threshold {
-                        self.debounce[row][col] += increment;
-                        // now check if we've passed the debounce threshold, and report a keydown                        
-                        if self.debounce[row][col] >= self.threshold {
-                            keydowns.push((row,col));
-                        }
-                    }
-                    downs[row][col] = true;  // record that we processed the key
-                    newcode.push((row,col)); // manually copy to a new vect to satisfy lifetime rules
-                }
-                self.lastcode = Some(newcode);
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// the codes for the key at a matrix position
+    pub fn map(&self, code: (usize, usize)) -> ScanCode {
+        let (r, c) = code;
+        if r < KBD_ROWS && c < KBD_COLS {
+            self.keys[r][c]
+        } else {
+            ScanCode::NONE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal_config::CONFIG_MAX_VALUE;
+
+    /// every position wired to a key: the grid, then the fixed keys
+    fn wired() -> Vec<(usize, usize)> {
+        let mut keys: Vec<(usize, usize)> = Vec::new();
+        for row in 0..GRID_ROWS {
+            for col in 0..GRID_COLS {
+                keys.push(grid_position(row, col));
             }
-            
-            // now decrement debounce couter for all elements that don't have a press
+        }
+        keys.extend(FIXED_KEYS.iter().map(|&(pos, _)| pos));
+        keys
+    }
+
+    #[test]
+    fn builtins_map_every_position() {
+        let keys = wired();
+        for name in LAYOUTS.iter() {
+            let layout = Layout::builtin(name).unwrap();
+            assert_eq!(layout.name(), *name);
             for r in 0..KBD_ROWS {
                 for c in 0..KBD_COLS {
-                    if !downs[r][c] && (self.debounce[r][c] > 0) {
-                        if self.debounce[r][c] >= increment {
-                            self.debounce[r][c] -= increment;
-                        } else {
-                            self.debounce[r][c] = 0;
-                        }
-
-                        if self.debounce[r][c] == 0 {
-                            keyups.push((r, c));
-                        }
+                    let code = layout.map((r, c));
+                    if keys.contains(&(r, c)) {
+                        assert!(code.key.is_some() && code.shift.is_some(), "{} ({}, {})", name, r, c);
+                    } else {
+                        assert_eq!(code, ScanCode::NONE, "{} ({}, {})", name, r, c);
                     }
-               }
-            }
-
-            // formulate the keyup/keydown return values
-            let retdowns: Option<Vec<(usize, usize)>>;
-            if keydowns.len() > 0 {
-                retdowns = Some(keydowns);
-            } else {
-                retdowns = None;
+                }
             }
-            let retups: Option<Vec<(usize, usize)>>;
-            if keyups.len() > 0 {
-                retups = Some(keyups);
-            } else {
-                retups = None;
+            // every letter, once
+            for l in b'a'..=b'z' {
+                let n = keys.iter().filter(|&&pos| layout.map(pos).key == Some(l as char)).count();
+                assert_eq!(n, 1, "{} {}", name, l as char);
             }
+        }
+        assert_eq!(Layout::builtin("colemak"), None);
+    }
 
-            (retdowns, retups)
+    #[test]
+    fn builtins_fit_in_a_config_value() {
+        for text in [QWERTY, DVORAK, AZERTY, QWERTZ].iter() {
+            assert!(text.len() <= CONFIG_MAX_VALUE);
+            assert!(Layout::parse(text).is_ok());
         }
     }
-}
 
-    /// Compute the dvorak key mapping of row/col to key tuples
-    pub fn map_dvorak(code: (usize,usize)) -> ScanCode {
-        if cfg!(feature = "evt") {
-            match code {
-                (0, 0) => ScanCode{key: Some('1'), shift: Some('1'), hold: None, alt: None},
-                (0, 1) => ScanCode{key: Some('2'), shift: Some('2'), hold: None, alt: None},
-                (0, 2) => ScanCode{key: Some('3'), shift: Some('3'), hold: None, alt: None},
-                (0, 3) => ScanCode{key: Some('4'), shift: Some('4'), hold: None, alt: None},
-                (0, 4) => ScanCode{key: Some('5'), shift: Some('5'), hold: None, alt: None},
-                (4, 5) => ScanCode{key: Some('6'), shift: Some('6'), hold: None, alt: None},
-                (4, 6) => ScanCode{key: Some('7'), shift: Some('7'), hold: None, alt: None},
-                (4, 7) => ScanCode{key: Some('8'), shift: Some('8'), hold: None, alt: None},
-                (4, 8) => ScanCode{key: Some('9'), shift: Some('9'), hold: None, alt: None},
-                (4, 9) => ScanCode{key: Some('0'), shift: Some('0'), hold: None, alt: None},
-
-                (1, 0) => ScanCode{key: Some(0x8_u8.into()), shift: Some(0x8_u8.into()), hold: Some(0x8_u8.into()), alt: Some(0x8_u8.into())}, // backspace
-                (1, 1) => ScanCode{key: Some('\''), shift: Some('\''), hold: Some('@'), alt: None},
-                (1, 2) => ScanCode{key: Some('p'), shift: Some('P'), hold: Some('#'), alt: None},
-                (1, 3) => ScanCode{key: Some('y'), shift: Some('Y'), hold: Some('&'), alt: None},
-                (1, 4) => ScanCode{key: Some('f'), shift: Some('F'), hold: Some('*'), alt: None},
-                (5, 5) => ScanCode{key: Some('g'), shift: Some('G'), hold: Some('-'), alt: None},
-                (5, 6) => ScanCode{key: Some('c'), shift: Some('C'), hold: Some('+'), alt: None},
-                (5, 7) => ScanCode{key: Some('r'), shift: Some('R'), hold: Some('('), alt: None},
-                (5, 8) => ScanCode{key: Some('l'), shift: Some('L'), hold: Some(')'), alt: None},
-                (5, 9) => ScanCode{key: Some('?'), shift: Some('?'), hold: Some('!'), alt: None},
-
-                (2, 0) => ScanCode{key: Some('a'), shift: Some('A'), hold: Some('\\'), alt: None},
-                (2, 1) => ScanCode{key: Some('o'), shift: Some('O'), hold: Some('`'), alt: None},
-                (2, 2) => ScanCode{key: Some('e'), shift: Some('E'), hold: Some('~'), alt: None},
-                (2, 3) => ScanCode{key: Some('u'), shift: Some('U'), hold: Some('|'), alt: None},
-                (2, 4) => ScanCode{key: Some('i'), shift: Some('I'), hold: Some('['), alt: None},
-                (6, 5) => ScanCode{key: Some('d'), shift: Some('D'), hold: Some(']'), alt: None},
-                (6, 6) => ScanCode{key: Some('h'), shift: Some('H'), hold: Some('<'), alt: None},
-                (6, 7) => ScanCode{key: Some('t'), shift: Some('T'), hold: Some('>'), alt: None},
-                (6, 8) => ScanCode{key: Some('n'), shift: Some('N'), hold: Some('{'), alt: None},
-                (6, 9) => ScanCode{key: Some('s'), shift: Some('S'), hold: Some('}'), alt: None},
-
-                (3, 0) => ScanCode{key: Some('q'), shift: Some('Q'), hold: Some('_'), alt: None}, // aliased for power-on
-                (3, 1) => ScanCode{key: Some('j'), shift: Some('J'), hold: Some('$'), alt: None},
-                (3, 2) => ScanCode{key: Some('k'), shift: Some('K'), hold: Some('"'), alt: None},
-                (3, 3) => ScanCode{key: Some('x'), shift: Some('X'), hold: Some(':'), alt: None},
-                (3, 4) => ScanCode{key: Some('b'), shift: Some('B'), hold: Some(';'), alt: None},
-                (7, 5) => ScanCode{key: Some('m'), shift: Some('M'), hold: Some('/'), alt: None},
-                (7, 6) => ScanCode{key: Some('w'), shift: Some('W'), hold: Some('^'), alt: None},
-                (7, 7) => ScanCode{key: Some('v'), shift: Some('V'), hold: Some('='), alt: None},
-                (7, 8) => ScanCode{key: Some('z'), shift: Some('Z'), hold: Some('%'), alt: None},
-                (7, 9) => ScanCode{key: Some(0xd_u8.into()), shift: Some(0xd_u8.into()), hold: Some(0xd_u8.into()), alt: Some(0xd_u8.into())}, // carriage return
-
-                (8, 5) => ScanCode{key: Some(0xf_u8.into()), shift: Some(0xf_u8.into()), hold: Some(0xf_u8.into()), alt: Some(0xf_u8.into())}, // shift in (blue shift)
-                (8, 6) => ScanCode{key: Some(','), shift: Some(0xe_u8.into()), hold: Some(0xe_u8.into()), alt: None},  // 0xe is shift out (sym)
-                (8, 7) => ScanCode{key: Some(' '), shift: Some(' '), hold: Some(' '), alt: None},
-                (8, 8) => ScanCode{key: Some('.'), shift: Some('😃'), hold: Some('😃'), alt: None},
-                // aliased for power-on
-                (8, 9) => ScanCode{key: Some(0xf_u8.into()), shift: Some(0xf_u8.into()), hold: Some(0xf_u8.into()), alt: Some(0xf_u8.into())}, // shift in (blue shift)
-
-                // these are all bugged: row values are swapped on PCB
-                // the F0/tab key also doubles as a secondary power key (can't do UP5K UART rx at same time)
-                (8, 0) => ScanCode{key: Some(0x11_u8.into()), shift: Some(0x11_u8.into()), hold: Some(0x11_u8.into()), alt: Some(0x11_u8.into())}, // DC1 (F1)
-                (8, 1) => ScanCode{key: Some(0x12_u8.into()), shift: Some(0x12_u8.into()), hold: Some(0x12_u8.into()), alt: Some(0x12_u8.into())}, // DC2 (F2)
-                (3, 8) => ScanCode{key: Some(0x13_u8.into()), shift: Some(0x13_u8.into()), hold: Some(0x13_u8.into()), alt: Some(0x13_u8.into())}, // DC3 (F3)
-                // the F4/ctrl key also doubles as a power key
-                (3, 9) => ScanCode{key: Some(0x14_u8.into()), shift: Some(0x14_u8.into()), hold: Some(0x14_u8.into()), alt: Some(0x14_u8.into())}, // DC4 (F4)
-                (8, 3) => ScanCode{key: Some('←'), shift: Some('←'), hold: Some('←'), alt: Some('←')},
-                (3, 6) => ScanCode{key: Some('→'), shift: Some('→'), hold: Some('→'), alt: Some('→')},
-                (6, 4) => ScanCode{key: Some('↑'), shift: Some('↑'), hold: Some('↑'), alt: Some('↑')},
-                (8, 2) => ScanCode{key: Some('↓'), shift: Some('↓'), hold: Some('↓'), alt: Some('↓')},
-                // this one is OK
-                (5, 2) => ScanCode{key: Some('∴'), shift: Some('∴'), hold: Some('∴'), alt: Some('∴')},
-
-                _ => ScanCode {key: None, shift: None, hold: None, alt: None}
+    #[test]
+    fn dvorak_matches_the_keycaps() {
+        let d = Layout::dvorak();
+        assert_eq!(d.map((2, 0)), ScanCode { key: Some('a'), shift: Some('A'), hold: Some('\\'), alt: None });
+        assert_eq!(d.map((5, 9)), ScanCode { key: Some('?'), shift: Some('?'), hold: Some('!'), alt: None });
+        assert_eq!(d.map((1, 0)), ScanCode::all('\u{8}'));
+        assert_eq!(d.map((7, 9)), ScanCode::all('\r'));
+        assert_eq!(d.map((4, 9)), ScanCode { key: Some('0'), shift: Some('0'), hold: None, alt: None });
+        assert_eq!(d.map((3, 9)), ScanCode::all('\u{14}'));
+        assert_eq!(d.map(BLUE_SHIFT), ScanCode::all('\u{f}'));
+        assert_eq!(d.map(ORANGE_SHIFT), ScanCode::all('\u{10}'));
+        assert_eq!(d.map((9, 0)), ScanCode::NONE);
+    }
+
+    #[test]
+    fn parse_with_base() {
+        let l = Layout::from_bytes("base qwertz".as_bytes()).unwrap();
+        assert_eq!(l, Layout::builtin("qwertz").unwrap());
+        assert_eq!(l.map((4, 5)).key, Some('6'));
+        assert_eq!(l.map((6, 5)).alt, None);
+
+        // swap y and z back, and drop the umlaut on u
+        let l = Layout::parse("base qwertz\nk1 qwertyuiop\nk3 'zxcvbnm?\r\na1           \n").unwrap();
+        assert_eq!(l.name(), "custom");
+        assert_eq!(l.map(grid_position(1, 5)).key, Some('y'));
+        assert_eq!(l.map(grid_position(1, 5)).shift, Some('Z'));
+        assert_eq!(l.map(grid_position(1, 6)).alt, None);
+        assert_eq!(l.map(grid_position(2, 0)).alt, Some('ä'));
+    }
+
+    #[test]
+    fn parse_from_scratch() {
+        let l = Layout::parse("k2 ab       c").unwrap();
+        assert_eq!(l.name(), "custom");
+        assert_eq!(l.map((2, 1)).key, Some('b'));
+        assert_eq!(l.map((2, 2)).key, None);
+        assert_eq!(l.map((6, 9)).key, Some('c'));
+        // the fixed keys are there regardless
+        assert_eq!(l.map((8, 7)).key, Some(' '));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Layout::parse("base colemak"), Err(LayoutError::UnknownBase));
+        assert_eq!(Layout::parse("base qwerty\nk1 qwerty"), Err(LayoutError::Syntax(1)));
+        assert_eq!(Layout::parse("k4 1234567890"), Err(LayoutError::Syntax(0)));
+        assert_eq!(Layout::parse("\nx1 1234567890"), Err(LayoutError::Syntax(1)));
+        assert_eq!(Layout::parse("k11234567890"), Err(LayoutError::Syntax(0)));
+        assert_eq!(Layout::from_bytes(&[b'k', b'1', b' ', 0xff]), Err(LayoutError::Utf8));
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_ticks(ms)
+    }
+
+    /// xorshift32, so the bounce patterns are random but repeatable
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn below(&mut self, n: u32) -> u32 {
+            self.next() % n
+        }
+    }
+
+    #[test]
+    fn debounce_press_and_release_thresholds() {
+        let mut d = Debouncer::new(KBD_ROWS, KBD_COLS);
+        assert_eq!(d.update(at(0), &[(2, 3)]), (vec![], vec![]));
+        assert_eq!(d.update(at(4), &[(2, 3)]), (vec![], vec![]));
+        assert_eq!(d.update(at(5), &[(2, 3)]), (vec![(2, 3)], vec![]));
+        assert_eq!(d.pressed(), [(2, 3)]);
+        assert_eq!(d.update(at(6), &[(2, 3)]), (vec![], vec![]));
+        assert_eq!(d.update(at(20), &[]), (vec![], vec![]));
+        assert_eq!(d.update(at(29), &[]), (vec![], vec![]));
+        assert_eq!(d.update(at(30), &[]), (vec![], vec![(2, 3)]));
+        assert!(d.pressed().is_empty());
+        // out of the matrix: ignored
+        assert_eq!(d.update(at(40), &[(KBD_ROWS, 0)]), (vec![], vec![]));
+        assert_eq!(d.update(at(50), &[(KBD_ROWS, 0)]), (vec![], vec![]));
+    }
+
+    #[test]
+    fn debounce_bounce_restarts_the_count() {
+        let mut d = Debouncer::new(KBD_ROWS, KBD_COLS);
+        d.update(at(0), &[(1, 1)]);
+        d.update(at(3), &[]);
+        assert_eq!(d.update(at(4), &[(1, 1)]), (vec![], vec![]));
+        assert_eq!(d.update(at(8), &[(1, 1)]), (vec![], vec![]));
+        assert_eq!(d.update(at(9), &[(1, 1)]), (vec![(1, 1)], vec![]));
+        // a short dropout while held is not a release, nor a second press
+        d.update(at(12), &[]);
+        d.update(at(18), &[]);
+        assert_eq!(d.update(at(19), &[(1, 1)]), (vec![], vec![]));
+        assert_eq!(d.update(at(40), &[(1, 1)]), (vec![], vec![]));
+        assert_eq!(d.pressed(), [(1, 1)]);
+    }
+
+    #[test]
+    fn debounce_holds_back_ghosts() {
+        let mut d = Debouncer::new(KBD_ROWS, KBD_COLS);
+        d.set_thresholds(Duration::from_ms(0), Duration::from_ms(0));
+        assert_eq!(d.update(at(0), &[(0, 0), (0, 5)]).0, [(0, 0), (0, 5)]);
+        // pressing (2, 0) makes (2, 5) read as pressed too, and the two can't be told apart
+        let rect = [(0, 0), (0, 5), (2, 0), (2, 5)];
+        assert_eq!(d.update(at(1), &rect), (vec![], vec![]));
+        assert_eq!(d.stats().ghosted, 1);
+        assert_eq!(d.pressed(), [(0, 0), (0, 5)]);
+        // once the rectangle is broken, the real key comes through
+        assert_eq!(d.update(at(2), &[(0, 0), (2, 0)]), (vec![(2, 0)], vec![(0, 5)]));
+        // three in a row, or a diagonal, are fine
+        assert_eq!(d.update(at(3), &[(0, 0), (2, 0), (5, 0), (7, 9)]).0, [(5, 0), (7, 9)]);
+        assert_eq!(d.stats().ghosted, 1);
+    }
+
+    #[test]
+    fn debounce_ignores_more_than_rollover() {
+        let mut d = Debouncer::new(KBD_ROWS, KBD_COLS);
+        d.set_thresholds(Duration::from_ms(0), Duration::from_ms(0));
+        assert_eq!(d.rollover(), KBD_ROWS + KBD_COLS - 1);
+        d.update(at(0), &[(3, 0)]);
+        // a whole row and a whole column make no rectangle, and all come through
+        let cross: Vec<(usize, usize)> = (0..KBD_COLS).map(|c| (0, c)).chain((1..KBD_ROWS).map(|r| (r, 0))).collect();
+        assert_eq!(cross.len(), d.rollover());
+        let (downs, ups) = d.update(at(1), &cross);
+        assert_eq!((downs.len(), ups.len()), (cross.len() - 1, 0));
+        assert_eq!(d.stats(), DebounceStats::default());
+        // one more can't help but close a loop
+        let over: Vec<(usize, usize)> = cross.iter().cloned().chain(core::iter::once((5, 5))).collect();
+        d.update(at(2), &[(3, 0)]);
+        assert_eq!(d.update(at(3), &over), (vec![], vec![]));
+        assert_eq!(d.stats().overflowed, 1);
+        assert_eq!(d.pressed(), [(3, 0)]);
+    }
+
+    #[test]
+    fn debounce_property_bouncy_keystrokes() {
+        // Every keystroke bounces for up to 3ms at each edge, is held 10-60ms, and keys are
+        // polled every 1-2ms. Each keystroke must come out as exactly one keydown and one keyup.
+        let mut rng = Rng(0x1234_5678);
+        for _ in 0..200 {
+            let mut d = Debouncer::new(KBD_ROWS, KBD_COLS);
+            let key: (usize, usize) = (rng.below(KBD_ROWS as u32) as usize, rng.below(KBD_COLS as u32) as usize);
+            let strokes: u32 = 1 + rng.below(5);
+            // (time, pressed) edges of the raw signal
+            let mut edges: Vec<(u64, bool)> = Vec::new();
+            let mut t: u64 = 0;
+            for _ in 0..strokes {
+                t += 20 + rng.below(40) as u64;
+                for press in [true, false].iter() {
+                    let bounce_end: u64 = t + 3;
+                    while t < bounce_end {
+                        edges.push((t, *press));
+                        t += rng.below(2) as u64;
+                        edges.push((t, !*press));
+                        t += 1;
+                    }
+                    edges.push((t, *press));
+                    t += if *press { 10 + rng.below(50) as u64 } else { 0 };
+                }
             }
-        } else if cfg!(feature = "dvt") {
-            match code {
-                (0, 0) => ScanCode{key: Some('1'), shift: Some('1'), hold: None, alt: None},
-                (0, 1) => ScanCode{key: Some('2'), shift: Some('2'), hold: None, alt: None},
-                (0, 2) => ScanCode{key: Some('3'), shift: Some('3'), hold: None, alt: None},
-                (0, 3) => ScanCode{key: Some('4'), shift: Some('4'), hold: None, alt: None},
-                (0, 4) => ScanCode{key: Some('5'), shift: Some('5'), hold: None, alt: None},
-                (4, 5) => ScanCode{key: Some('6'), shift: Some('6'), hold: None, alt: None},
-                (4, 6) => ScanCode{key: Some('7'), shift: Some('7'), hold: None, alt: None},
-                (4, 7) => ScanCode{key: Some('8'), shift: Some('8'), hold: None, alt: None},
-                (4, 8) => ScanCode{key: Some('9'), shift: Some('9'), hold: None, alt: None},
-                (4, 9) => ScanCode{key: Some('0'), shift: Some('0'), hold: None, alt: None},
-
-                (1, 0) => ScanCode{key: Some(0x8_u8.into()), shift: Some(0x8_u8.into()), hold: Some(0x8_u8.into()), alt: Some(0x8_u8.into())}, // backspace
-                (1, 1) => ScanCode{key: Some('\''), shift: Some('\''), hold: Some('@'), alt: None},
-                (1, 2) => ScanCode{key: Some('p'), shift: Some('P'), hold: Some('#'), alt: None},
-                (1, 3) => ScanCode{key: Some('y'), shift: Some('Y'), hold: Some('&'), alt: None},
-                (1, 4) => ScanCode{key: Some('f'), shift: Some('F'), hold: Some('*'), alt: None},
-                (5, 5) => ScanCode{key: Some('g'), shift: Some('G'), hold: Some('-'), alt: None},
-                (5, 6) => ScanCode{key: Some('c'), shift: Some('C'), hold: Some('+'), alt: None},
-                (5, 7) => ScanCode{key: Some('r'), shift: Some('R'), hold: Some('('), alt: None},
-                (5, 8) => ScanCode{key: Some('l'), shift: Some('L'), hold: Some(')'), alt: None},
-                (5, 9) => ScanCode{key: Some('?'), shift: Some('?'), hold: Some('!'), alt: None},
-
-                (2, 0) => ScanCode{key: Some('a'), shift: Some('A'), hold: Some('\\'), alt: None},
-                (2, 1) => ScanCode{key: Some('o'), shift: Some('O'), hold: Some('`'), alt: None},
-                (2, 2) => ScanCode{key: Some('e'), shift: Some('E'), hold: Some('~'), alt: None},
-                (2, 3) => ScanCode{key: Some('u'), shift: Some('U'), hold: Some('|'), alt: None},
-                (2, 4) => ScanCode{key: Some('i'), shift: Some('I'), hold: Some('['), alt: None},
-                (6, 5) => ScanCode{key: Some('d'), shift: Some('D'), hold: Some(']'), alt: None},
-                (6, 6) => ScanCode{key: Some('h'), shift: Some('H'), hold: Some('<'), alt: None},
-                (6, 7) => ScanCode{key: Some('t'), shift: Some('T'), hold: Some('>'), alt: None},
-                (6, 8) => ScanCode{key: Some('n'), shift: Some('N'), hold: Some('{'), alt: None},
-                (6, 9) => ScanCode{key: Some('s'), shift: Some('S'), hold: Some('}'), alt: None},
-
-                (3, 0) => ScanCode{key: Some('q'), shift: Some('Q'), hold: Some('_'), alt: None},
-                (3, 1) => ScanCode{key: Some('j'), shift: Some('J'), hold: Some('$'), alt: None},
-                (3, 2) => ScanCode{key: Some('k'), shift: Some('K'), hold: Some('"'), alt: None},
-                (3, 3) => ScanCode{key: Some('x'), shift: Some('X'), hold: Some(':'), alt: None},
-                (3, 4) => ScanCode{key: Some('b'), shift: Some('B'), hold: Some(';'), alt: None},
-                (7, 5) => ScanCode{key: Some('m'), shift: Some('M'), hold: Some('/'), alt: None},
-                (7, 6) => ScanCode{key: Some('w'), shift: Some('W'), hold: Some('^'), alt: None},
-                (7, 7) => ScanCode{key: Some('v'), shift: Some('V'), hold: Some('='), alt: None},
-                (7, 8) => ScanCode{key: Some('z'), shift: Some('Z'), hold: Some('%'), alt: None},
-                (7, 9) => ScanCode{key: Some(0xd_u8.into()), shift: Some(0xd_u8.into()), hold: Some(0xd_u8.into()), alt: Some(0xd_u8.into())}, // carriage return
-
-                (8, 5) => ScanCode{key: Some(0xf_u8.into()), shift: Some(0xf_u8.into()), hold: Some(0xf_u8.into()), alt: Some(0xf_u8.into())}, // shift in (blue shift)
-                (8, 6) => ScanCode{key: Some(','), shift: Some(0xe_u8.into()), hold: Some(0xe_u8.into()), alt: None},  // 0xe is shift out (sym)
-                (8, 7) => ScanCode{key: Some(' '), shift: Some(' '), hold: Some(' '), alt: None},
-                (8, 8) => ScanCode{key: Some('.'), shift: Some('😃'), hold: Some('😃'), alt: None},
-                (8, 9) => ScanCode{key: Some(0xf_u8.into()), shift: Some(0xf_u8.into()), hold: Some(0xf_u8.into()), alt: Some(0xf_u8.into())}, // shift in (blue shift)
-
-                // these are all bugged: row values are swapped on PCB
-                // the F0/tab key also doubles as a secondary power key (can't do UP5K UART rx at same time)
-                (4, 0) => ScanCode{key: Some(0x11_u8.into()), shift: Some(0x11_u8.into()), hold: Some(0x11_u8.into()), alt: Some(0x11_u8.into())}, // DC1 (F1)
-                (4, 1) => ScanCode{key: Some(0x12_u8.into()), shift: Some(0x12_u8.into()), hold: Some(0x12_u8.into()), alt: Some(0x12_u8.into())}, // DC2 (F2)
-                (3, 8) => ScanCode{key: Some(0x13_u8.into()), shift: Some(0x13_u8.into()), hold: Some(0x13_u8.into()), alt: Some(0x13_u8.into())}, // DC3 (F3)
-                // the F4/ctrl key also doubles as a power key
-                (3, 9) => ScanCode{key: Some(0x14_u8.into()), shift: Some(0x14_u8.into()), hold: Some(0x14_u8.into()), alt: Some(0x14_u8.into())}, // DC4 (F4)
-                (4, 3) => ScanCode{key: Some('←'), shift: Some('←'), hold: Some('←'), alt: Some('←')},
-                (3, 6) => ScanCode{key: Some('→'), shift: Some('→'), hold: Some('→'), alt: Some('→')},
-                (6, 4) => ScanCode{key: Some('↑'), shift: Some('↑'), hold: Some('↑'), alt: Some('↑')},
-                (8, 2) => ScanCode{key: Some('↓'), shift: Some('↓'), hold: Some('↓'), alt: Some('↓')},
-                // this one is OK
-                (5, 2) => ScanCode{key: Some('∴'), shift: Some('∴'), hold: Some('∴'), alt: Some('∴')},
-
-                _ => ScanCode {key: None, shift: None, hold: None, alt: None}
+            t += 40;
+
+            let (mut downs, mut ups) = (0, 0);
+            let mut now: u64 = 0;
+            while now < t {
+                let pressed: bool = edges.iter().filter(|e| e.0 <= now).last().map_or(false, |e| e.1);
+                let raw: Vec<(usize, usize)> = if pressed { vec![key] } else { vec![] };
+                let (dn, up) = d.update(at(now), &raw);
+                downs += dn.len();
+                ups += up.len();
+                // never two downs in a row, nor an up without a down
+                assert!(downs - ups <= 1 && ups <= downs);
+                now += 1 + rng.below(2) as u64;
             }
-        } else {
-            assert!(false);  // should never get here
-            ScanCode {key: None, shift: None, hold: None, alt: None}
+            assert_eq!((downs, ups), (strokes as usize, strokes as usize));
         }
-    }
\ No newline at end of file
+    }
+
+    #[test]
+    fn debounce_property_short_glitches_never_count() {
+        let mut rng = Rng(0xdead_beef);
+        let mut d = Debouncer::new(KBD_ROWS, KBD_COLS);
+        let mut now: u64 = 0;
+        for _ in 0..2000 {
+            // a glitch shorter than the press time on a random key, then quiet for a while
+            let key: (usize, usize) = (rng.below(KBD_ROWS as u32) as usize, rng.below(KBD_COLS as u32) as usize);
+            let len: u64 = rng.below(DEBOUNCE_PRESS_MS) as u64;
+            let end: u64 = now + len;
+            while now < end {
+                assert_eq!(d.update(at(now), &[key]), (vec![], vec![]));
+                now += 1;
+            }
+            assert_eq!(d.update(at(now), &[]), (vec![], vec![]));
+            now += 1 + rng.below(5) as u64;
+        }
+    }
+}
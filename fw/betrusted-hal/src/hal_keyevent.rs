@@ -0,0 +1,364 @@
+//! Key events
+//!
+//! KeyEvents turns snapshots of which matrix positions are down into typed key events, using a
+//! Layout to pick the code. It runs the modifier state machines the keycaps describe:
+//!
+//! - tapping the blue shift key shifts the next key only; tapping it twice in a row is caps lock,
+//!   and a further tap releases caps lock
+//! - holding the blue shift key while pressing another gives that key's hold code
+//! - the orange key does the same for the alt codes, held or tapped, but has no lock
+//!
+//! A key that has nothing on the chosen plane falls back to its base code. Modifier keys don't
+//! produce events of their own. The last key pressed repeats while it's held, after a delay.
+//!
+//! Snapshots come from the debounced matrix, so nothing here touches hardware and the whole state
+//! machine can be driven from tests.
+
+use alloc::vec::Vec;
+use bitflags::*;
+use crate::hal_kbd::{Layout, ScanCode, BLUE_SHIFT, ORANGE_SHIFT};
+use crate::hal_time::{Duration, Instant};
+
+/// how long a key is held before it starts repeating
+pub const REPEAT_DELAY_MS: u32 = 500;
+/// time between repeats after that
+pub const REPEAT_INTERVAL_MS: u32 = 50;
+
+bitflags! {
+    pub struct Modifiers: u8 {
+        /// blue shift was tapped before the key
+        const SHIFT = 0b0000_0001;
+        /// blue shift is held down
+        const HOLD  = 0b0000_0010;
+        /// orange shift is held down, or was tapped before the key
+        const ALT   = 0b0000_0100;
+        const CAPS  = 0b0000_1000;
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    Press,
+    Repeat,
+    Release,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub char: char,
+    /// the modifiers in effect when the key went down
+    pub modifiers: Modifiers,
+    pub kind: KeyKind,
+    /// when the snapshot that produced the event was taken
+    pub timestamp: Instant,
+}
+
+/// a blue or orange shift key
+#[derive(Copy, Clone, Debug, Default)]
+struct ModifierKey {
+    down: bool,
+    /// another key was pressed while this one was down, so releasing it isn't a tap
+    used: bool,
+}
+
+/// a key that's down, and what it gave when it went down
+#[derive(Copy, Clone, Debug)]
+struct Held {
+    position: (usize, usize),
+    char: Option<char>,
+    modifiers: Modifiers,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Repeating {
+    position: (usize, usize),
+    char: char,
+    modifiers: Modifiers,
+    next: Instant,
+}
+
+pub struct KeyEvents {
+    delay: Duration,
+    interval: Duration,
+    down: Vec<Held>,
+    blue: ModifierKey,
+    orange: ModifierKey,
+    /// SHIFT and ALT left by taps, waiting for the next key
+    sticky: Modifiers,
+    caps: bool,
+    repeat: Option<Repeating>,
+}
+
+impl KeyEvents {
+    pub fn new() -> Self {
+        KeyEvents {
+            delay: Duration::from_ms(REPEAT_DELAY_MS),
+            interval: Duration::from_ms(REPEAT_INTERVAL_MS),
+            down: Vec::new(),
+            blue: ModifierKey::default(),
+            orange: ModifierKey::default(),
+            sticky: Modifiers::empty(),
+            caps: false,
+            repeat: None,
+        }
+    }
+
+    /// set how long a key is held before it repeats, and the time between repeats
+    pub fn set_repeat(&mut self, delay: Duration, interval: Duration) {
+        self.delay = delay;
+        self.interval = interval;
+    }
+
+    /// the modifiers the next key would get
+    pub fn modifiers(&self) -> Modifiers {
+        let mut m: Modifiers = self.sticky;
+        m.set(Modifiers::HOLD, self.blue.down);
+        m.set(Modifiers::ALT, self.orange.down || self.sticky.contains(Modifiers::ALT));
+        m.set(Modifiers::CAPS, self.caps);
+        m
+    }
+
+    /// Feed the positions that are down at `now`, and get back what happened since the last
+    /// snapshot. Call it regularly even when nothing has changed, so keys can repeat.
+    pub fn update(&mut self, now: Instant, pressed: &[(usize, usize)], layout: &Layout) -> Vec<KeyEvent> {
+        let mut events: Vec<KeyEvent> = Vec::new();
+
+        let mut i: usize = 0;
+        while i < self.down.len() {
+            let held: Held = self.down[i];
+            if pressed.contains(&held.position) {
+                i += 1;
+                continue;
+            }
+            self.down.remove(i);
+            if let Some(c) = held.char {
+                events.push(KeyEvent { char: c, modifiers: held.modifiers, kind: KeyKind::Release, timestamp: now });
+            }
+            if self.repeat.map(|r| r.position) == Some(held.position) {
+                self.repeat = None;
+            }
+        }
+        if self.blue.down && !pressed.contains(&BLUE_SHIFT) {
+            self.blue.down = false;
+            if !self.blue.used {
+                self.tap_blue();
+            }
+        }
+        if self.orange.down && !pressed.contains(&ORANGE_SHIFT) {
+            self.orange.down = false;
+            if !self.orange.used {
+                self.sticky.toggle(Modifiers::ALT);
+            }
+        }
+
+        // modifiers first, so one pressed in the same snapshot as a key applies to it
+        if !self.blue.down && pressed.contains(&BLUE_SHIFT) {
+            self.blue = ModifierKey { down: true, used: false };
+        }
+        if !self.orange.down && pressed.contains(&ORANGE_SHIFT) {
+            self.orange = ModifierKey { down: true, used: false };
+        }
+        for &position in pressed.iter() {
+            if position == BLUE_SHIFT || position == ORANGE_SHIFT || self.down.iter().any(|h| h.position == position) {
+                continue;
+            }
+            let modifiers: Modifiers = self.modifiers();
+            let c: Option<char> = pick(layout.map(position), modifiers);
+            self.down.push(Held { position, char: c, modifiers });
+            self.blue.used |= self.blue.down;
+            self.orange.used |= self.orange.down;
+            self.sticky = Modifiers::empty();
+            if let Some(c) = c {
+                events.push(KeyEvent { char: c, modifiers, kind: KeyKind::Press, timestamp: now });
+                self.repeat = now.checked_add(self.delay).map(|next| Repeating { position, char: c, modifiers, next });
+            }
+        }
+
+        if let Some(r) = self.repeat {
+            if now.checked_duration_since(r.next).is_some() {
+                events.push(KeyEvent { char: r.char, modifiers: r.modifiers, kind: KeyKind::Repeat, timestamp: now });
+                self.repeat = now.checked_add(self.interval).map(|next| Repeating { next, ..r });
+            }
+        }
+        events
+    }
+
+    fn tap_blue(&mut self) {
+        if self.caps {
+            self.caps = false;
+        } else if self.sticky.contains(Modifiers::SHIFT) {
+            self.sticky.remove(Modifiers::SHIFT);
+            self.caps = true;
+        } else {
+            self.sticky.insert(Modifiers::SHIFT);
+        }
+    }
+}
+
+/// the code a key gives under `modifiers`
+fn pick(code: ScanCode, modifiers: Modifiers) -> Option<char> {
+    let plane: Option<char> = if modifiers.contains(Modifiers::ALT) {
+        code.alt
+    } else if modifiers.contains(Modifiers::HOLD) {
+        code.hold
+    } else if modifiers.contains(Modifiers::SHIFT)
+        || (modifiers.contains(Modifiers::CAPS) && code.key.map_or(false, |c| c.is_alphabetic())) {
+        code.shift
+    } else {
+        code.key
+    };
+    plane.or(code.key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal_kbd::grid_position;
+
+    const A: (usize, usize) = (2, 0); // dvorak a
+    const P: (usize, usize) = (1, 2); // dvorak p
+    const ONE: (usize, usize) = (0, 0);
+
+    struct Rig {
+        events: KeyEvents,
+        layout: Layout,
+        now: u64,
+    }
+
+    impl Rig {
+        fn new() -> Self {
+            Rig { events: KeyEvents::new(), layout: Layout::dvorak(), now: 0 }
+        }
+
+        /// a snapshot 10ms after the last one
+        fn snap(&mut self, pressed: &[(usize, usize)]) -> Vec<KeyEvent> {
+            self.now += 10;
+            self.events.update(Instant::from_ticks(self.now), pressed, &self.layout)
+        }
+
+        /// press and release `keys` in turn, returning the chars pressed
+        fn type_keys(&mut self, keys: &[(usize, usize)]) -> Vec<char> {
+            let mut typed: Vec<char> = Vec::new();
+            for &k in keys {
+                typed.extend(self.snap(&[k]).iter().filter(|e| e.kind == KeyKind::Press).map(|e| e.char));
+                self.snap(&[]);
+            }
+            typed
+        }
+    }
+
+    #[test]
+    fn press_and_release() {
+        let mut rig = Rig::new();
+        let down = rig.snap(&[A]);
+        assert_eq!(down, [KeyEvent { char: 'a', modifiers: Modifiers::empty(), kind: KeyKind::Press, timestamp: Instant::from_ticks(10) }]);
+        assert!(rig.snap(&[A]).is_empty());
+        let up = rig.snap(&[]);
+        assert_eq!(up.len(), 1);
+        assert_eq!((up[0].char, up[0].kind), ('a', KeyKind::Release));
+        // modifier keys on their own say nothing
+        assert!(rig.snap(&[BLUE_SHIFT]).is_empty());
+        assert!(rig.snap(&[]).is_empty());
+    }
+
+    #[test]
+    fn tap_shift_is_sticky_for_one_key() {
+        let mut rig = Rig::new();
+        assert_eq!(rig.type_keys(&[BLUE_SHIFT, A, A]), ['A', 'a']);
+        let mut rig = Rig::new();
+        rig.type_keys(&[BLUE_SHIFT]);
+        assert_eq!(rig.events.modifiers(), Modifiers::SHIFT);
+        let e = rig.snap(&[P]);
+        assert_eq!((e[0].char, e[0].modifiers), ('P', Modifiers::SHIFT));
+        // the release reports what was pressed, even though the shift has gone
+        assert_eq!(rig.snap(&[])[0].char, 'P');
+    }
+
+    #[test]
+    fn hold_shift_gives_the_hold_plane() {
+        let mut rig = Rig::new();
+        rig.snap(&[BLUE_SHIFT]);
+        let e = rig.snap(&[BLUE_SHIFT, A]);
+        assert_eq!((e[0].char, e[0].modifiers), ('\\', Modifiers::HOLD));
+        rig.snap(&[BLUE_SHIFT]);
+        assert_eq!(rig.snap(&[BLUE_SHIFT, P])[0].char, '#');
+        // letting go after using it isn't a tap
+        rig.snap(&[]);
+        assert_eq!(rig.events.modifiers(), Modifiers::empty());
+        assert_eq!(rig.type_keys(&[A]), ['a']);
+        // pressed in the same snapshot still counts as held
+        assert_eq!(rig.snap(&[BLUE_SHIFT, P])[0].char, '#');
+        // nothing on the hold plane: the base code
+        rig.snap(&[]);
+        assert_eq!(rig.snap(&[BLUE_SHIFT, ONE])[0].char, '1');
+    }
+
+    #[test]
+    fn double_tap_is_caps_lock() {
+        let mut rig = Rig::new();
+        assert_eq!(rig.type_keys(&[BLUE_SHIFT, BLUE_SHIFT, A, P, ONE]), ['A', 'P', '1']);
+        assert_eq!(rig.events.modifiers(), Modifiers::CAPS);
+        // the apostrophe has nothing shifted to give
+        assert_eq!(rig.type_keys(&[grid_position(1, 1)]), ['\'']);
+        assert_eq!(rig.type_keys(&[BLUE_SHIFT, A]), ['a']);
+        assert_eq!(rig.events.modifiers(), Modifiers::empty());
+    }
+
+    #[test]
+    fn orange_gives_alt() {
+        let mut rig = Rig::new();
+        rig.layout = Layout::builtin("qwertz").unwrap();
+        let a = grid_position(2, 0);
+        rig.snap(&[ORANGE_SHIFT]);
+        let e = rig.snap(&[ORANGE_SHIFT, a]);
+        assert_eq!((e[0].char, e[0].modifiers), ('ä', Modifiers::ALT));
+        rig.snap(&[]);
+        // tapped, for one key
+        assert_eq!(rig.type_keys(&[ORANGE_SHIFT, a, a]), ['ä', 'a']);
+        // tapped twice cancels
+        assert_eq!(rig.type_keys(&[ORANGE_SHIFT, ORANGE_SHIFT, a]), ['a']);
+        // a key without an alt code falls back to its base
+        assert_eq!(rig.type_keys(&[ORANGE_SHIFT, grid_position(1, 3)]), ['r']);
+    }
+
+    #[test]
+    fn held_key_repeats() {
+        let mut rig = Rig::new();
+        rig.events.set_repeat(Duration::from_ms(100), Duration::from_ms(30));
+        let mut repeats: Vec<u64> = Vec::new();
+        for _ in 0..20 {
+            for e in rig.snap(&[A]) {
+                if e.kind == KeyKind::Repeat {
+                    assert_eq!(e.char, 'a');
+                    repeats.push(e.timestamp.ticks());
+                }
+            }
+        }
+        // pressed at 10, first repeat at 110, then every 30ms (rounded up to the 10ms snapshots)
+        assert_eq!(repeats, [110, 140, 170, 200]);
+        assert_eq!(rig.snap(&[])[0].kind, KeyKind::Release);
+        rig.now += 1000;
+        assert!(rig.snap(&[]).is_empty());
+    }
+
+    #[test]
+    fn newest_key_repeats() {
+        let mut rig = Rig::new();
+        rig.events.set_repeat(Duration::from_ms(50), Duration::from_ms(50));
+        rig.snap(&[A]);
+        rig.snap(&[A, P]);
+        let mut chars: Vec<char> = Vec::new();
+        for _ in 0..10 {
+            chars.extend(rig.snap(&[A, P]).iter().map(|e| e.char));
+        }
+        assert!(!chars.is_empty() && chars.iter().all(|&c| c == 'p'));
+        // letting go of an older key doesn't stop it
+        let e = rig.snap(&[P]);
+        assert_eq!((e[0].char, e[0].kind), ('a', KeyKind::Release));
+        rig.now += 100;
+        assert_eq!(rig.snap(&[P])[0].kind, KeyKind::Repeat);
+        rig.snap(&[]);
+        rig.now += 100;
+        assert!(rig.snap(&[]).is_empty());
+    }
+}
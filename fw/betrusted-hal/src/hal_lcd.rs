@@ -19,7 +19,8 @@
 /// 
 /// The BtDisplay and LockedBtDisplay objects are considered to be HAL-layer
 /// interfaces. They should not be directly called by untrusted programs, they are intended for
-/// the OS to manipluate the frame buffer directly.
+/// the OS to manipluate the frame buffer directly. Untrusted clients get clipped, bordered
+/// surfaces from the compositor in hal_compositor instead.
 
 extern crate embedded_graphics;
 use embedded_graphics::drawable::Pixel;
@@ -27,108 +28,339 @@ use embedded_graphics::geometry::Size;
 use embedded_graphics::pixelcolor::{BinaryColor};
 use embedded_graphics::DrawTarget;
 use spin::Mutex;
-use core::ops::Deref;
-use crate::hal_time::get_time_ms;
+use core::ops::{Deref, Range};
+use host_link::bitmap::Bitmap;
+use host_link::lcd::{fb_dark, FB_DIRTY};
+pub use host_link::lcd::{LcdPanel, SimLcd, FB_WIDTH_WORDS, FB_WIDTH_PIXELS, FB_LINES, FB_SIZE};
+use crate::hal_time::{Duration, Instant, TickSource};
 
 /// FIXME: figure out a way to get LCD_FB mapped to the _lcdfb symbol without crashing RLS
 const LCD_FB: *mut [u32; FB_SIZE] = 0xB000_0000 as *mut [u32; FB_SIZE];
-const FB_WIDTH_WORDS: usize = 11;
-const FB_WIDTH_PIXELS: usize = 336;
-const FB_LINES: usize = 536;
-const FB_SIZE: usize = FB_WIDTH_WORDS * FB_LINES; // 44 bytes by 536 lines
-    
+/// default shortest time between starting one update and the next
+pub const LCD_FRAME_BUDGET_MS: u32 = 25;
+
+/// The memlcd controller
+pub struct MemLcd {
+    p: betrusted_pac::Peripherals,
+}
+
+impl MemLcd {
+    pub fn new() -> Self {
+        unsafe{ MemLcd { p: betrusted_pac::Peripherals::steal() } }
+    }
+}
+
+impl LcdPanel for MemLcd {
+    fn ticks(&self) -> u64 {
+        self.p.ticks()
+    }
+
+    fn init(&mut self, clk_mhz: u32) {
+        lcd_init(&self.p, clk_mhz);
+        lcd_sync_clear(&self.p);
+    }
+
+    fn busy(&self) -> bool {
+        lcd_busy(&self.p)
+    }
+
+    fn write_lines(&mut self, fb: &[u32; FB_SIZE], lines: Range<usize>) {
+        let start: usize = lines.start * FB_WIDTH_WORDS;
+        for (words, &w) in fb[start..lines.end * FB_WIDTH_WORDS].iter().enumerate() {
+            unsafe {
+                (*LCD_FB)[start + words] = w;
+            }
+        }
+    }
+
+    fn update_dirty(&mut self) {
+        lcd_update_dirty(&self.p);
+    }
+
+    fn update_all(&mut self) {
+        lcd_update_all(&self.p);
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// updates sent to the panel
+    pub frames: u32,
+    /// lines sent, over all updates
+    pub lines: u32,
+    /// flushes put off because the last update started less than a frame budget ago
+    pub deferred: u32,
+    /// Time from starting an update to finding the panel idle again. It's only looked for
+    /// when flushing, so it's an upper bound at the rate flush() is called.
+    pub last_frame: Duration,
+    pub max_frame: Duration,
+    pub total_frame: Duration,
+}
+
+impl FrameStats {
+    pub fn mean_frame(&self) -> Duration {
+        if self.frames == 0 {
+            Duration::default()
+        } else {
+            Duration::from_us(self.total_frame.as_us() / self.frames as u64)
+        }
+    }
+}
+
 /// BtDisplay abstraction for embedded-graphics library
 /// See LockedBtDisplay for API docs
-pub struct BtDisplay {
-        interface: betrusted_pac::Peripherals,
+///
+/// Drawing sets a line's dirty bit only when it changes a pixel. flush() drops the dirty lines
+/// that have come back to what the glass already shows, copies the rest to the controller
+/// line by line and has it send them, no more often than once a frame budget. So clearing
+/// and redrawing the same picture every frame sends nothing.
+pub struct BtDisplay<P: LcdPanel = MemLcd> {
+        interface: P,
         fb: [u32; FB_SIZE],
-        timestamp: u32,
+        /// when the last update was started
+        timestamp: Instant,
+        /// the pixels as of the last update, dirty bits clear
+        shown: [u32; FB_SIZE],
+        /// lines copied for the last update, whose dirty bits the controller still has set
+        sent: [bool; FB_LINES],
+        budget: Duration,
+        /// an update has been started and not yet seen to finish
+        in_flight: bool,
+        stats: FrameStats,
 }
 
-impl BtDisplay {
+impl BtDisplay<MemLcd> {
     pub fn new() -> Self {
-        let mut ret: BtDisplay =
-        unsafe{ 
-            BtDisplay{ 
-                interface: betrusted_pac::Peripherals::steal(), 
+        BtDisplay::with_panel(MemLcd::new())
+    }
+}
+
+impl<P: LcdPanel> BtDisplay<P> {
+    pub fn with_panel(panel: P) -> Self {
+        let mut ret: BtDisplay<P> =
+            BtDisplay{
+                interface: panel,
                 fb: [0xFFFF_FFFF; FB_SIZE],
-                timestamp: 0,
-            } 
-        };
+                shown: [0xFFFF_FFFF; FB_SIZE],
+                timestamp: Instant::from_ticks(0),
+                sent: [false; FB_LINES],
+                budget: Duration::from_ms(LCD_FRAME_BUDGET_MS),
+                in_flight: false,
+                stats: FrameStats::default(),
+            };
         // unset the dirty bits in the local fb array copy
         for words in 0..FB_SIZE {
             if words % FB_WIDTH_WORDS == 10 {
                 ret.fb[words] = 0x0000_FFFF;
+                ret.shown[words] = 0x0000_FFFF;
             }
         }
-        ret.timestamp = get_time_ms(&ret.interface);
+        ret.timestamp = ret.now();
     
         ret
     }
 
-    pub fn init(&self, clk_mhz: u32) {
-        lcd_init(&self.interface, clk_mhz);
-        lcd_sync_clear(&self.interface);
+    pub fn init(&mut self, clk_mhz: u32) {
+        self.interface.init(clk_mhz);
+    }
+
+    pub fn panel(&self) -> &P {
+        &self.interface
+    }
+
+    pub fn panel_mut(&mut self) -> &mut P {
+        &mut self.interface
+    }
+
+    /// the local framebuffer: 11 words a line, pixel x of a line in bit x % 32 of word x / 32,
+    /// clear for dark, and the line's dirty bit in bit 16 of the last word
+    pub fn fb(&self) -> &[u32; FB_SIZE] {
+        &self.fb
+    }
+
+    pub fn line_dirty(&self, line: usize) -> bool {
+        self.fb[line * FB_WIDTH_WORDS + (FB_WIDTH_WORDS - 1)] & FB_DIRTY != 0
+    }
+
+    /// the first and last lines changed since the last update, if any
+    pub fn dirty_range(&self) -> Option<(usize, usize)> {
+        let first: usize = (0..FB_LINES).find(|&l| self.line_dirty(l))?;
+        let last: usize = (0..FB_LINES).rev().find(|&l| self.line_dirty(l))?;
+        Some((first, last))
+    }
+
+    /// shortest time between starting one update and the next; 25ms unless set
+    pub fn set_frame_budget(&mut self, budget: Duration) {
+        self.budget = budget;
+    }
+
+    pub fn frame_stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_ticks(self.interface.ticks())
+    }
+
+    fn since(&self, earlier: Instant) -> Duration {
+        self.now().checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    fn mark_dirty(&mut self, line: usize) {
+        self.fb[line * FB_WIDTH_WORDS + (FB_WIDTH_WORDS - 1)] |= FB_DIRTY;
+    }
+
+    fn clear_dirty(&mut self, line: usize) {
+        self.fb[line * FB_WIDTH_WORDS + (FB_WIDTH_WORDS - 1)] &= !FB_DIRTY;
+    }
+
+    /// Clear the dirty bit of every line that's back to what the glass shows, and return
+    /// whether any are left.
+    fn settle_dirty(&mut self) -> bool {
+        let mut any: bool = false;
+        for line in 0..FB_LINES {
+            if !self.line_dirty(line) {
+                continue;
+            }
+            let start: usize = line * FB_WIDTH_WORDS;
+            let end: usize = start + FB_WIDTH_WORDS - 1;
+            if self.fb[start..end] == self.shown[start..end] && self.fb[end] & !FB_DIRTY == self.shown[end] {
+                self.clear_dirty(line);
+            } else {
+                any = true;
+            }
+        }
+        any
+    }
+
+    /// note the dirty lines as shown and clear their bits, returning how many there were
+    fn take_dirty(&mut self) -> u32 {
+        let mut count: u32 = 0;
+        for line in 0..FB_LINES {
+            self.sent[line] = self.line_dirty(line);
+            if self.sent[line] {
+                self.clear_dirty(line);
+                let start: usize = line * FB_WIDTH_WORDS;
+                self.shown[start..start + FB_WIDTH_WORDS].copy_from_slice(&self.fb[start..start + FB_WIDTH_WORDS]);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn start_update(&mut self, lines: u32) {
+        self.timestamp = self.now();
+        self.in_flight = true;
+        self.stats.frames += 1;
+        self.stats.lines += lines;
+    }
+
+    /// if the update in flight has finished, note how long it took
+    fn finish_update(&mut self) {
+        if self.in_flight && !self.interface.busy() {
+            self.in_flight = false;
+            let t: Duration = self.since(self.timestamp);
+            self.stats.last_frame = t;
+            self.stats.max_frame = self.stats.max_frame.max(t);
+            self.stats.total_frame = Duration::from_us(self.stats.total_frame.as_us() + t.as_us());
+        }
     }
 
     pub fn flush(&mut self) -> Result<(), ()> {
-        if !lcd_busy(&self.interface) {
-            if get_time_ms(&self.interface) - self.timestamp > 25 { // limit update rate to 40Hz
-                // copy over the local framebuffer, then call an update
-                for words in 0..FB_SIZE {
-                    unsafe {
-                        (*LCD_FB)[words] = self.fb[words];
-                    }
-                }
-                lcd_update_dirty(&self.interface);
-                self.timestamp = get_time_ms(&self.interface);
+        if self.interface.busy() {
+            return Ok(());
+        }
+        self.finish_update();
+        if !self.settle_dirty() {
+            return Ok(());
+        }
+        if self.since(self.timestamp) <= self.budget {
+            self.stats.deferred += 1;
+            return Ok(());
+        }
 
-                // clear all the dirty bits, under the theory that it's time-wise cheaper on average
-                // to visit every line and clear the dirty bits than it is to do an update_all()
-                for lines in 0..FB_LINES {
-                    self.fb[lines * FB_WIDTH_WORDS + (FB_WIDTH_WORDS - 1)] &= 0x0000_FFFF;
-                }
+        // the dirty lines go with their dirty bits set, and the lines sent last time go again
+        // with theirs now clear, so the controller doesn't send them twice
+        let mut line: usize = 0;
+        while line < FB_LINES {
+            let write = |l: usize| self.sent[l] || self.line_dirty(l);
+            if !write(line) {
+                line += 1;
+                continue;
+            }
+            let start: usize = line;
+            while line < FB_LINES && write(line) {
+                line += 1;
             }
+            self.interface.write_lines(&self.fb, start..line);
         }
+        self.interface.update_dirty();
+        let lines: u32 = self.take_dirty();
+        self.start_update(lines);
         Ok(())
     }
 
     /// Blocking flush for emergency system messages and so forth
     pub fn blocking_flush(&mut self) {
-        while lcd_busy(&self.interface) {}  // wait until the last flush is done
+        while self.interface.busy() {}  // wait until the last flush is done
+        self.finish_update();
 
-        for words in 0..FB_SIZE {
-            unsafe {
-                (*LCD_FB)[words] = self.fb[words];
-            }
-        }
-        lcd_update_all(&self.interface);
-        // clear dirty bits in case there's more to come
-        for lines in 0..FB_LINES {
-            self.fb[lines * FB_WIDTH_WORDS + (FB_WIDTH_WORDS - 1)] &= 0x0000_FFFF;
+        // update_all() doesn't look at the dirty bits, so they're cleared on the way over
+        for line in 0..FB_LINES {
+            self.clear_dirty(line);
         }
+        self.shown.copy_from_slice(&self.fb);
+        self.sent = [false; FB_LINES];
+        self.interface.write_lines(&self.fb, 0..FB_LINES);
+        self.interface.update_all();
+        self.start_update(FB_LINES as u32);
 
-        while lcd_busy(&self.interface) {}  // wait until the last flush is done
+        while self.interface.busy() {}  // wait until the last flush is done
+        self.finish_update();
     }
     
     pub fn clear(&mut self) {
-        let mut line_dirty: bool = false;
-        for words in 0..FB_SIZE {
-            if words % FB_WIDTH_WORDS != 10 {
-                if self.fb[words] != 0xFFFF_FFFF {
-                    self.fb[words] = 0xFFFF_FFFF;
-                    line_dirty = true;
-                }
-            } else {
-                if ((self.fb[words] & 0xFFFF) != 0xFFFF) || line_dirty {
-                    self.fb[words] = 0x0001_FFFF;
+        self.clear_lines(0..FB_LINES);
+    }
+
+    /// clear some lines, leaving the rest alone, such as the rows a status bar keeps to itself
+    pub fn clear_lines(&mut self, lines: Range<usize>) {
+        for line in lines {
+            let start: usize = line * FB_WIDTH_WORDS;
+            let mut changed: bool = false;
+            for word in self.fb[start..start + FB_WIDTH_WORDS - 1].iter_mut() {
+                if *word != 0xFFFF_FFFF {
+                    *word = 0xFFFF_FFFF;
+                    changed = true;
                 }
-                line_dirty = false;
+            }
+            let last: &mut u32 = &mut self.fb[start + FB_WIDTH_WORDS - 1];
+            if *last & 0xFFFF != 0xFFFF {
+                *last |= 0xFFFF;
+                changed = true;
+            }
+            if changed {
+                self.mark_dirty(line);
             }
         }
     }
 }
 
+/// what has been drawn, whether or not it has been flushed
+impl<P: LcdPanel> Bitmap for BtDisplay<P> {
+    fn width(&self) -> u32 {
+        FB_WIDTH_PIXELS as u32
+    }
+
+    fn height(&self) -> u32 {
+        FB_LINES as u32
+    }
+
+    fn dark(&self, x: u32, y: u32) -> bool {
+        fb_dark(&self.fb, x, y)
+    }
+}
+
 /// LockedBtDisplay - Mutex-wrapped BtDisplay object
 /// 
 /// Refer to BtDisplay methods by calling the lock() method.
@@ -157,23 +389,22 @@ impl Deref for LockedBtDisplay {
     }
 }
 
-impl DrawTarget<BinaryColor> for BtDisplay {
+impl<P: LcdPanel> DrawTarget<BinaryColor> for BtDisplay<P> {
     fn size(&self) -> Size {
         Size::new(FB_WIDTH_PIXELS as u32, FB_LINES as u32)
     }
 
     fn draw_pixel(&mut self, pixel:Pixel<BinaryColor>) {
         let Pixel(coord, color) = pixel;
+        let word: &mut u32 = &mut self.fb[ (coord.x / 32 + coord.y * FB_WIDTH_WORDS as i32) as usize];
+        let old: u32 = *word;
         match color {
-            BinaryColor::Off => 
-                self.fb[ (coord.x / 32 + coord.y * FB_WIDTH_WORDS as i32) as usize] |= 
-                    1 << (coord.x % 32),
-            BinaryColor::On =>
-                self.fb[ (coord.x / 32 + coord.y * FB_WIDTH_WORDS as i32) as usize] &= 
-                    !(1 << (coord.x % 32)),
+            BinaryColor::Off => *word |= 1 << (coord.x % 32),
+            BinaryColor::On => *word &= !(1 << (coord.x % 32)),
+        }
+        if *word != old {
+            self.mark_dirty(coord.y as usize);
         }
-        // set the dirty bit on the line
-        self.fb[(coord.y * FB_WIDTH_WORDS as i32 + (FB_WIDTH_WORDS as i32 - 1)) as usize] |= 0x1_0000;
     }
 }
 
@@ -212,3 +443,201 @@ fn lcd_busy(p: &betrusted_pac::Peripherals) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use embedded_graphics::geometry::Point;
+    use host_link::bitmap::pbm_write;
+
+    fn display() -> BtDisplay<SimLcd> {
+        let mut d = BtDisplay::with_panel(SimLcd::new());
+        d.init(100);
+        d
+    }
+
+    fn dot(d: &mut BtDisplay<SimLcd>, x: i32, y: i32, color: BinaryColor) {
+        d.draw_pixel(Pixel(Point::new(x, y), color));
+    }
+
+    fn dirty_lines(d: &BtDisplay<SimLcd>) -> Vec<usize> {
+        (0..FB_LINES).filter(|&l| d.line_dirty(l)).collect()
+    }
+
+    fn pbm<B: Bitmap>(b: &B) -> Vec<u8> {
+        let mut v: Vec<u8> = Vec::new();
+        pbm_write(b, |d| v.extend_from_slice(d));
+        v
+    }
+
+    /// the image a list of dark pixels should make
+    struct Expected(&'static [(u32, u32)]);
+
+    impl Bitmap for Expected {
+        fn width(&self) -> u32 { FB_WIDTH_PIXELS as u32 }
+        fn height(&self) -> u32 { FB_LINES as u32 }
+        fn dark(&self, x: u32, y: u32) -> bool { self.0.contains(&(x, y)) }
+    }
+
+    #[test]
+    fn drawing_marks_lines_dirty() {
+        let mut d = display();
+        assert!(dirty_lines(&d).is_empty());
+        dot(&mut d, 0, 3, BinaryColor::On);
+        dot(&mut d, 335, 535, BinaryColor::On);
+        assert_eq!(dirty_lines(&d), vec![3, 535]);
+        assert!(d.dark(0, 3) && d.dark(335, 535) && !d.dark(1, 3));
+    }
+
+    #[test]
+    fn flush_sends_dirty_lines_at_most_every_25ms() {
+        let mut d = display();
+        dot(&mut d, 10, 20, BinaryColor::On);
+        d.flush().unwrap();
+        assert_eq!(d.panel().updates, 0);
+        d.panel().advance_ms(26);
+        d.flush().unwrap();
+        assert_eq!(d.panel().updates, 1);
+        assert_eq!(d.panel().lines_sent, 1);
+        assert!(dirty_lines(&d).is_empty());
+        assert!(d.panel().dark(10, 20));
+
+        d.blocking_flush();
+        assert_eq!(d.panel().lines_sent, 1 + FB_LINES as u32);
+    }
+
+    #[test]
+    fn clear_marks_only_changed_lines() {
+        let mut d = display();
+        dot(&mut d, 5, 1, BinaryColor::On);
+        dot(&mut d, 330, 2, BinaryColor::On); // in the word shared with the dirty bit
+        dot(&mut d, 7, 4, BinaryColor::On);
+        dot(&mut d, 7, 4, BinaryColor::Off); // drawn and erased, but not yet sent
+        dot(&mut d, 9, 6, BinaryColor::Off); // already white
+        assert_eq!(dirty_lines(&d), vec![1, 2, 4]);
+        d.panel().advance_ms(26);
+        d.flush().unwrap();
+        assert_eq!(d.panel().lines_sent, 2);
+
+        d.clear();
+        assert_eq!(dirty_lines(&d), vec![1, 2]);
+        assert!(!d.dark(5, 1) && !d.dark(330, 2));
+        assert!(d.fb().iter().enumerate().all(|(i, &w)| i % FB_WIDTH_WORDS == 10 || w == 0xFFFF_FFFF));
+
+        // and clearing a clear screen changes nothing
+        d.panel().advance_ms(26);
+        d.flush().unwrap();
+        d.clear();
+        assert!(dirty_lines(&d).is_empty());
+        assert!(!d.panel().dark(5, 1) && !d.panel().dark(330, 2));
+    }
+
+    #[test]
+    fn flush_copies_only_dirty_lines() {
+        let mut d = display();
+        dot(&mut d, 3, 100, BinaryColor::On);
+        dot(&mut d, 4, 102, BinaryColor::On);
+        assert_eq!(d.dirty_range(), Some((100, 102)));
+        d.panel().advance_ms(26);
+        d.flush().unwrap();
+        assert_eq!(d.dirty_range(), None);
+        // not the line in between
+        assert_eq!(d.panel().words_written, 2 * FB_WIDTH_WORDS as u32);
+        assert_eq!(d.panel().lines_sent, 2);
+
+        // the lines sent last time are rewritten clean and not sent again
+        dot(&mut d, 5, 300, BinaryColor::On);
+        d.panel().advance_ms(26);
+        d.flush().unwrap();
+        assert_eq!(d.panel().words_written, 5 * FB_WIDTH_WORDS as u32);
+        assert_eq!(d.panel().lines_sent, 3);
+        assert!(d.panel().dark(3, 100) && d.panel().dark(5, 300));
+
+        // nothing drawn, nothing copied
+        d.panel().advance_ms(26);
+        d.flush().unwrap();
+        assert_eq!(d.panel().updates, 2);
+        assert_eq!(d.panel().words_written, 5 * FB_WIDTH_WORDS as u32);
+    }
+
+    #[test]
+    fn redrawing_the_same_frame_sends_nothing() {
+        let mut d = display();
+        let frame = |d: &mut BtDisplay<SimLcd>, x: i32| {
+            d.clear_lines(32..FB_LINES);
+            for y in 40..500 {
+                dot(d, x, y, BinaryColor::On);
+            }
+        };
+        frame(&mut d, 10);
+        d.panel().advance_ms(26);
+        d.flush().unwrap();
+        assert_eq!(d.panel().lines_sent, 460);
+
+        for _ in 0..3 {
+            frame(&mut d, 10);
+            d.panel().advance_ms(26);
+            d.flush().unwrap();
+        }
+        assert_eq!(d.panel().updates, 1);
+
+        // moving along only sends the lines that changed
+        frame(&mut d, 10);
+        dot(&mut d, 11, 100, BinaryColor::On);
+        d.panel().advance_ms(26);
+        d.flush().unwrap();
+        assert_eq!(d.panel().lines_sent, 461);
+        assert!(d.panel().dark(11, 100));
+    }
+
+    #[test]
+    fn flush_keeps_to_the_frame_budget() {
+        let mut d = display();
+        d.set_frame_budget(Duration::from_ms(50));
+        d.panel().advance_ms(51);
+        for y in 0..FB_LINES {
+            dot(&mut d, 0, y as i32, BinaryColor::On);
+        }
+        d.flush().unwrap();
+        assert_eq!(d.frame_stats().frames, 1);
+        assert_eq!(d.frame_stats().lines, FB_LINES as u32);
+
+        // a whole screen takes the panel most of 100ms; flushes meanwhile wait for it
+        dot(&mut d, 1, 0, BinaryColor::On);
+        d.panel().advance_ms(60);
+        d.flush().unwrap(); // finds it busy, which takes 1ms
+        assert_eq!(d.panel().updates, 1);
+        d.panel().advance_ms(39);
+        d.flush().unwrap();
+        let stats = d.frame_stats();
+        assert_eq!(stats.frames, 2);
+        assert_eq!(stats.last_frame, Duration::from_ms(100));
+
+        // and a quick one waits out the rest of the budget
+        dot(&mut d, 2, 0, BinaryColor::On);
+        d.panel().advance_ms(10);
+        d.flush().unwrap();
+        assert_eq!(d.frame_stats().frames, 2);
+        assert_eq!(d.frame_stats().deferred, 1);
+        assert_eq!(d.frame_stats().last_frame, Duration::from_ms(10));
+        assert_eq!(d.frame_stats().max_frame, Duration::from_ms(100));
+        assert_eq!(d.frame_stats().mean_frame(), Duration::from_ms(55));
+    }
+
+    #[test]
+    fn golden_image() {
+        const PIXELS: &[(u32, u32)] = &[(0, 0), (1, 0), (0, 1), (31, 7), (32, 7), (335, 100), (200, 535)];
+        let mut d = display();
+        for &(x, y) in PIXELS {
+            dot(&mut d, x as i32, y as i32, BinaryColor::On);
+        }
+        let golden = pbm(&Expected(PIXELS));
+        assert_eq!(&golden[..11], b"P4\n336 536\n");
+        assert_eq!(pbm(&d), golden);
+        assert_ne!(pbm(d.panel()), golden); // not on the glass until flushed
+        d.blocking_flush();
+        assert_eq!(pbm(d.panel()), golden);
+    }
+}
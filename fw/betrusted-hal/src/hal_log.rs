@@ -0,0 +1,472 @@
+//! Leveled logging for the firmware crates
+//!
+//! Use the `error!`, `warn!`, `info!`, `debug!` and `trace!` macros exported from this crate
+//! like `format!`. Each record is tagged with its level, the module it came from and the time,
+//! filtered per module, and then:
+//!   * kept in a fixed-size RAM ring, oldest records overwritten first, until log_read()
+//!     takes them out (the "logdump" REPL command sends them in binary over the UART)
+//!   * handed to every sink added with log_add_sink(), e.g. log_uart_sink()
+//!   * available to polling consumers such as the LCD console through log_follow(), which
+//!     doesn't remove anything from the ring
+//!
+//! Messages are formatted into a fixed buffer and truncated to LOG_MSG_LEN bytes, so logging
+//! doesn't allocate. If the log is busy (e.g. an interrupt handler logging while the main loop
+//! holds the lock) the record is dropped and counted rather than waited for.
+
+use crate::hal_time::{get_time_ms, time_running};
+use crate::hal_uart::BtUart;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::Mutex;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERR",
+            Level::Warn => "WRN",
+            Level::Info => "INF",
+            Level::Debug => "DBG",
+            Level::Trace => "TRC",
+        }
+    }
+
+    pub fn from_u8(level: u8) -> Option<Level> {
+        match level {
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            5 => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+/// longest message kept per record, in bytes
+pub const LOG_MSG_LEN: usize = 80;
+/// number of records kept in the ring
+pub const LOG_ENTRIES: usize = 64;
+/// most sinks that can be added
+pub const LOG_MAX_SINKS: usize = 4;
+
+pub struct LogRecord<'a> {
+    /// increases by one for every record logged
+    pub seq: u32,
+    pub level: Level,
+    /// module_path!() of the code that logged it
+    pub module: &'static str,
+    pub time_ms: u32,
+    pub message: &'a str,
+}
+
+#[derive(Copy, Clone)]
+struct LogEntry {
+    seq: u32,
+    level: Level,
+    module: &'static str,
+    time_ms: u32,
+    msg: [u8; LOG_MSG_LEN],
+    len: u8,
+}
+
+impl LogEntry {
+    const EMPTY: LogEntry = LogEntry { seq: 0, level: Level::Error, module: "", time_ms: 0, msg: [0; LOG_MSG_LEN], len: 0 };
+
+    fn record(&self) -> LogRecord<'_> {
+        LogRecord {
+            seq: self.seq,
+            level: self.level,
+            module: self.module,
+            time_ms: self.time_ms,
+            // only ever filled in whole chars by MsgWriter
+            message: core::str::from_utf8(&self.msg[..self.len as usize]).unwrap_or(""),
+        }
+    }
+}
+
+/// formats into a LogEntry's message buffer, cutting off at a char boundary when it fills up
+struct MsgWriter<'a> {
+    buf: &'a mut [u8; LOG_MSG_LEN],
+    len: usize,
+}
+
+impl<'a> Write for MsgWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            let mut utf8: [u8; 4] = [0; 4];
+            let bytes = c.encode_utf8(&mut utf8).as_bytes();
+            if self.len + bytes.len() > LOG_MSG_LEN {
+                return Err(core::fmt::Error);
+            }
+            self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+        Ok(())
+    }
+}
+
+/// Per-module level filter. The rule with the longest module prefix wins; a prefix matches
+/// the module itself and anything nested below it.
+pub struct LogFilter {
+    default: Option<Level>,
+    rules: Vec<(&'static str, Option<Level>)>,
+}
+
+impl LogFilter {
+    pub const fn new(default: Option<Level>) -> Self {
+        LogFilter {
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn set_default(&mut self, level: Option<Level>) {
+        self.default = level;
+    }
+
+    /// show records from `module` up to `level`; None silences the module
+    pub fn set(&mut self, module: &'static str, level: Option<Level>) {
+        self.rules.retain(|(m, _)| *m != module);
+        self.rules.push((module, level));
+    }
+
+    pub fn enabled(&self, level: Level, module: &str) -> bool {
+        let mut best: Option<(usize, Option<Level>)> = None;
+        for (prefix, max) in self.rules.iter() {
+            let matches = module == *prefix ||
+                (module.starts_with(prefix) && module[prefix.len()..].starts_with("::"));
+            if matches && best.map_or(true, |(len, _)| prefix.len() > len) {
+                best = Some((prefix.len(), *max));
+            }
+        }
+        let max: Option<Level> = match best {
+            Some((_, max)) => max,
+            None => self.default,
+        };
+        max.map_or(false, |max| level <= max)
+    }
+}
+
+/// Fixed-size ring of log records; when full, new records overwrite the oldest.
+pub struct LogRing {
+    entries: [LogEntry; LOG_ENTRIES],
+    /// sequence number for the next record
+    next_seq: u32,
+    /// records currently held, up to LOG_ENTRIES
+    count: usize,
+    /// records overwritten before anyone read them
+    lost: u32,
+}
+
+impl LogRing {
+    pub const fn new() -> Self {
+        LogRing {
+            entries: [LogEntry::EMPTY; LOG_ENTRIES],
+            next_seq: 0,
+            count: 0,
+            lost: 0,
+        }
+    }
+
+    /// returns the sequence number given to the record
+    pub fn push(&mut self, level: Level, module: &'static str, time_ms: u32, args: core::fmt::Arguments) -> u32 {
+        let seq: u32 = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        if self.count == LOG_ENTRIES {
+            self.lost = self.lost.wrapping_add(1);
+        } else {
+            self.count += 1;
+        }
+
+        let entry: &mut LogEntry = &mut self.entries[seq as usize % LOG_ENTRIES];
+        let mut writer = MsgWriter { buf: &mut entry.msg, len: 0 };
+        // a too-long message just ends up truncated
+        writer.write_fmt(args).ok();
+        entry.len = writer.len as u8;
+        entry.seq = seq;
+        entry.level = level;
+        entry.module = module;
+        entry.time_ms = time_ms;
+        seq
+    }
+
+    fn oldest_seq(&self) -> u32 {
+        self.next_seq.wrapping_sub(self.count as u32)
+    }
+
+    /// visit every held record with seq at or after `from`, oldest first; returns the seq to
+    /// continue from next time
+    pub fn follow<F: FnMut(&LogRecord)>(&self, from: u32, mut f: F) -> u32 {
+        if (self.next_seq.wrapping_sub(from) as i32) < 0 {
+            // a cursor from the future; nothing to show
+            return self.next_seq;
+        }
+        let oldest: u32 = self.oldest_seq();
+        // a cursor that fell behind the ring just picks up at the oldest record still held
+        let mut seq: u32 = if (from.wrapping_sub(oldest) as i32) < 0 { oldest } else { from };
+        while seq != self.next_seq {
+            f(&self.entries[seq as usize % LOG_ENTRIES].record());
+            seq = seq.wrapping_add(1);
+        }
+        seq
+    }
+
+    /// visit every held record, oldest first, and empty the ring
+    pub fn read<F: FnMut(&LogRecord)>(&mut self, f: F) {
+        self.follow(self.oldest_seq(), f);
+        self.count = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+}
+
+/// Appends a record in the binary dump format: seq (u32), time_ms (u32), level (u8),
+/// module length (u8), module, message length (u8), message. Integers are little endian;
+/// the module name is cut to 255 bytes.
+pub fn log_encode(record: &LogRecord, out: &mut Vec<u8>) {
+    out.extend_from_slice(&record.seq.to_le_bytes());
+    out.extend_from_slice(&record.time_ms.to_le_bytes());
+    out.push(record.level as u8);
+    let module: &[u8] = &record.module.as_bytes()[..record.module.len().min(255)];
+    out.push(module.len() as u8);
+    out.extend_from_slice(module);
+    out.push(record.message.len() as u8);
+    out.extend_from_slice(record.message.as_bytes());
+}
+
+struct Logger {
+    ring: LogRing,
+    filter: LogFilter,
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    ring: LogRing::new(),
+    filter: LogFilter::new(Some(Level::Info)),
+});
+static LOG_SINKS: Mutex<[Option<fn(&LogRecord)>; LOG_MAX_SINKS]> = Mutex::new([None; LOG_MAX_SINKS]);
+/// records dropped because the logger was busy
+static LOG_DROPPED: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+/// called by the logging macros
+pub fn log_write(level: Level, module: &'static str, args: core::fmt::Arguments) {
+    // records from before time_init() (or from host tests, which never call it) are stamped 0
+    let time_ms: u32 = if time_running() { get_time_ms(unsafe{ &betrusted_pac::Peripherals::steal() }) } else { 0 };
+    let entry: LogEntry = match LOGGER.try_lock() {
+        Some(mut logger) => {
+            if !logger.filter.enabled(level, module) {
+                return;
+            }
+            let seq: u32 = logger.ring.push(level, module, time_ms, args);
+            logger.ring.entries[seq as usize % LOG_ENTRIES]
+        },
+        None => {
+            LOG_DROPPED.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+            return;
+        }
+    };
+
+    // sinks run without the logger locked, so they may log themselves without deadlocking
+    let sinks = match LOG_SINKS.try_lock() {
+        Some(sinks) => *sinks,
+        None => return,
+    };
+    for sink in sinks.iter() {
+        if let Some(sink) = sink {
+            sink(&entry.record());
+        }
+    }
+}
+
+/// returns false if all LOG_MAX_SINKS slots are taken
+pub fn log_add_sink(sink: fn(&LogRecord)) -> bool {
+    let mut sinks = LOG_SINKS.lock();
+    for slot in sinks.iter_mut() {
+        if slot.is_none() {
+            *slot = Some(sink);
+            return true;
+        }
+    }
+    false
+}
+
+pub fn log_remove_sink(sink: fn(&LogRecord)) {
+    let mut sinks = LOG_SINKS.lock();
+    for slot in sinks.iter_mut() {
+        if slot.map(|s| s as usize) == Some(sink as usize) {
+            *slot = None;
+        }
+    }
+}
+
+/// level for modules without a rule of their own; None turns logging off
+pub fn log_set_default(level: Option<Level>) {
+    LOGGER.lock().filter.set_default(level);
+}
+
+/// e.g. log_set_filter("betrusted_hal::hal_i2c", Some(Level::Trace))
+pub fn log_set_filter(module: &'static str, level: Option<Level>) {
+    LOGGER.lock().filter.set(module, level);
+}
+
+/// take every record out of the ring, oldest first
+pub fn log_read<F: FnMut(&LogRecord)>(f: F) {
+    LOGGER.lock().ring.read(f);
+}
+
+/// visit records logged since `cursor` without removing them, and move the cursor on
+pub fn log_follow<F: FnMut(&LogRecord)>(cursor: &mut u32, f: F) {
+    let next: u32 = LOGGER.lock().ring.follow(*cursor, f);
+    *cursor = next;
+}
+
+/// (records overwritten before being read, records dropped because the logger was busy)
+pub fn log_losses() -> (u32, u32) {
+    (LOGGER.lock().ring.lost(), LOG_DROPPED.load(core::sync::atomic::Ordering::Relaxed))
+}
+
+/// sink that prints records as text lines on the UART
+pub fn log_uart_sink(record: &LogRecord) {
+    let mut uart: BtUart = BtUart::new();
+    write!(uart, "[{:>8}] {} {}: {}\r\n", record.time_ms, record.level.as_str(), record.module, record.message).ok();
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::hal_log::log_write($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::hal_log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::hal_log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::hal_log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::hal_log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::hal_log::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+    use alloc::{format, vec};
+
+    fn messages(ring: &LogRing, from: u32) -> (Vec<String>, u32) {
+        let mut out: Vec<String> = Vec::new();
+        let next = ring.follow(from, |r| out.push(String::from(r.message)));
+        (out, next)
+    }
+
+    #[test]
+    fn filter_longest_prefix_wins() {
+        let mut filter = LogFilter::new(Some(Level::Info));
+        filter.set("betrusted_hal", Some(Level::Warn));
+        filter.set("betrusted_hal::hal_i2c", Some(Level::Trace));
+        filter.set("jtag", None);
+        assert!(filter.enabled(Level::Info, "betrusted_soc"));
+        assert!(!filter.enabled(Level::Debug, "betrusted_soc"));
+        assert!(!filter.enabled(Level::Info, "betrusted_hal::hal_lcd"));
+        assert!(filter.enabled(Level::Warn, "betrusted_hal::hal_lcd"));
+        assert!(filter.enabled(Level::Trace, "betrusted_hal::hal_i2c"));
+        assert!(!filter.enabled(Level::Error, "jtag"));
+        // a prefix only matches whole path segments
+        assert!(filter.enabled(Level::Info, "jtagger"));
+        assert!(!filter.enabled(Level::Info, "betrusted_hal"));
+        filter.set("jtag", Some(Level::Error));
+        assert!(filter.enabled(Level::Error, "jtag"));
+    }
+
+    #[test]
+    fn ring_keeps_newest() {
+        let mut ring = LogRing::new();
+        for i in 0..(LOG_ENTRIES + 3) {
+            ring.push(Level::Info, "m", i as u32, format_args!("msg {}", i));
+        }
+        assert_eq!(ring.len(), LOG_ENTRIES);
+        assert_eq!(ring.lost(), 3);
+        let (msgs, next) = messages(&ring, 0);
+        assert_eq!(msgs.len(), LOG_ENTRIES);
+        assert_eq!(msgs[0], "msg 3");
+        assert_eq!(msgs[LOG_ENTRIES - 1], format!("msg {}", LOG_ENTRIES + 2));
+        assert_eq!(next, (LOG_ENTRIES + 3) as u32);
+    }
+
+    #[test]
+    fn follow_only_returns_new_records() {
+        let mut ring = LogRing::new();
+        ring.push(Level::Info, "m", 0, format_args!("a"));
+        let (msgs, cursor) = messages(&ring, 0);
+        assert_eq!(msgs, vec!["a"]);
+        ring.push(Level::Warn, "m", 1, format_args!("b"));
+        let (msgs, cursor) = messages(&ring, cursor);
+        assert_eq!(msgs, vec!["b"]);
+        let (msgs, _) = messages(&ring, cursor);
+        assert!(msgs.is_empty());
+    }
+
+    #[test]
+    fn read_empties_the_ring() {
+        let mut ring = LogRing::new();
+        ring.push(Level::Info, "m", 0, format_args!("a"));
+        ring.push(Level::Info, "m", 0, format_args!("b"));
+        let mut seen: Vec<u32> = Vec::new();
+        ring.read(|r| seen.push(r.seq));
+        assert_eq!(seen, vec![0, 1]);
+        assert_eq!(ring.len(), 0);
+        ring.read(|_| panic!("ring should be empty"));
+        // followers carry on from where they were
+        ring.push(Level::Info, "m", 0, format_args!("c"));
+        let (msgs, _) = messages(&ring, 1);
+        assert_eq!(msgs, vec!["c"]);
+    }
+
+    #[test]
+    fn long_messages_truncate_on_char_boundary() {
+        let mut ring = LogRing::new();
+        let long: String = core::iter::repeat('\u{e9}').take(LOG_MSG_LEN).collect();
+        ring.push(Level::Debug, "m", 0, format_args!("x{}", long));
+        let (msgs, _) = messages(&ring, 0);
+        assert_eq!(msgs[0].len(), LOG_MSG_LEN - 1);
+        assert!(msgs[0].starts_with('x'));
+    }
+
+    #[test]
+    fn binary_encoding() {
+        let record = LogRecord { seq: 0x01020304, level: Level::Warn, module: "ab", time_ms: 0x0A0B0C0D, message: "hi" };
+        let mut out: Vec<u8> = Vec::new();
+        log_encode(&record, &mut out);
+        assert_eq!(out, vec![4, 3, 2, 1, 0x0D, 0x0C, 0x0B, 0x0A, 2, 2, b'a', b'b', 2, b'h', b'i']);
+    }
+}
@@ -1,6 +1,6 @@
 use bitflags::*;
-use crate::hal_i2c::i2c_master;
-use crate::hal_time::get_ticks;
+use crate::hal_i2c::{i2c_master, BtI2c, I2cTransaction, I2cTicket, I2cPoll};
+use crate::hal_time::{Duration, Instant};
 
 pub const ABRTCMC_I2C_ADR: u8 = 0x68;
 
@@ -173,7 +173,10 @@ pub struct BtRtc {
     pub months: u8,
     pub years: u8,
     pub weekday: Weekdays,
-    updated_ticks: u64,
+    updated: Instant,
+    i2c: BtI2c,
+    /// outstanding time readout, if any
+    pending: Option<I2cTicket>,
 }
 
 const I2C_TIMEOUT: u32 = 5;
@@ -221,7 +224,9 @@ impl BtRtc {
                 months: 0,
                 years: 0,
                 weekday: Weekdays::SUNDAY,
-                updated_ticks: 0,
+                updated: Instant::from_ticks(0),
+                i2c: BtI2c::new(),
+                pending: None,
                 p: betrusted_pac::Peripherals::steal(),
             }
         }
@@ -235,7 +240,7 @@ impl BtRtc {
 
         txbuf = [ABRTCMC_SECONDS, to_bcd(secs)];
         i2c_master(&self.p, ABRTCMC_I2C_ADR, Some(&txbuf), None, I2C_TIMEOUT);
-        self.updated_ticks = get_ticks(&self.p);
+        self.updated = Instant::now(&self.p);
         self.seconds = secs;
 
         txbuf = [ABRTCMC_MINUTES, to_bcd(mins)];
@@ -265,25 +270,32 @@ impl BtRtc {
         true  // sanity check args would return false on fail
     }
 
+    /// refresh the time from the RTC. This doesn't block: the readout is queued on the I2C
+    /// engine, and the fields are updated on a later call once the transaction completes.
     pub fn rtc_update(&mut self) {
-        let txbuf: [u8; 1];
-        let mut rxbuf: [u8; 7] = [0; 7];
-
-        // only update from RTC if more than 1 second has passed since the last update
-        if get_ticks(&self.p) - self.updated_ticks > 1000 {
+        if let Some(ticket) = self.pending {
+            match self.i2c.poll(ticket) {
+                I2cPoll::Pending => return,
+                I2cPoll::Done(Ok(rxbuf)) => {
+                    if rxbuf.len() == 7 {
+                        self.seconds = to_binary(rxbuf[0] & 0x7f);
+                        self.minutes = to_binary(rxbuf[1] & 0x7f);
+                        self.hours = to_binary(rxbuf[2] & 0x3f);
+                        self.days = to_binary(rxbuf[3] & 0x3f);
+                        self.weekday = to_weekday(rxbuf[4] & 0x7f);
+                        self.months = to_binary(rxbuf[5] & 0x1f);
+                        self.years = to_binary(rxbuf[6]);
+                    }
+                },
+                _ => {}, // failed readouts just wait for the next interval
+            }
+            self.pending = None;
+            self.updated = Instant::now(&self.p);
+        } else if self.updated.elapsed(&self.p) > Duration::from_ms(1000) {
+            // only update from RTC if more than 1 second has passed since the last update
             // read as a single block to make the time readout atomic
-            txbuf = [ABRTCMC_SECONDS];
-            i2c_master(&self.p, ABRTCMC_I2C_ADR, Some(&txbuf), Some(&mut rxbuf), I2C_TIMEOUT);
-
-            self.seconds = to_binary(rxbuf[0] & 0x7f);
-            self.minutes = to_binary(rxbuf[1] & 0x7f);
-            self.hours = to_binary(rxbuf[2] & 0x3f);
-            self.days = to_binary(rxbuf[3] & 0x3f);
-            self.weekday = to_weekday(rxbuf[4] & 0x7f);
-            self.months = to_binary(rxbuf[5] & 0x1f);
-            self.years = to_binary(rxbuf[6]);
-
-            self.updated_ticks = get_ticks(&self.p);
+            let txbuf: [u8; 1] = [ABRTCMC_SECONDS];
+            self.pending = self.i2c.submit(I2cTransaction::write_read(ABRTCMC_I2C_ADR, &txbuf, 7)).ok();
         }
     }
 
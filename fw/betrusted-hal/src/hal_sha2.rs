@@ -123,3 +123,27 @@ impl BtSha2 {
         }
     }
 }
+
+/// hashes firmware images in hardware
+impl fw_image::ImageHash for BtSha2 {
+    fn start(&mut self) {
+        self.config = Sha2Config::ENDIAN_SWAP | Sha2Config::DIGEST_SWAP | Sha2Config::SHA256_EN;
+        self.keys = [0; 8];
+        self.init();
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        BtSha2::update(self, data);
+    }
+
+    fn finish(&mut self) -> [u8; 32] {
+        let mut words: [u32; 8] = [0; 8];
+        self.digest(&mut words);
+        // with DIGEST_SWAP the words come out in big-endian digest order
+        let mut digest: [u8; 32] = [0; 32];
+        for (bytes, word) in digest.chunks_mut(4).zip(words.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
@@ -0,0 +1,888 @@
+use crate::hal_time::{Deadline, Duration, TickSource};
+use vexriscv::register::mstatus;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+/// SPI NOR opcodes, 4-byte address variants
+pub const NOR_RDID: u8 = 0x9F;
+pub const NOR_RDSR: u8 = 0x05;
+pub const NOR_WREN: u8 = 0x06;
+pub const NOR_WRDI: u8 = 0x04;
+pub const NOR_SE4B: u8 = 0x21;
+pub const NOR_BE4B: u8 = 0xDC;
+pub const NOR_PP4B: u8 = 0x12;
+/// enter and exit the secured OTP area, which then takes the main array's place
+pub const NOR_ENSO: u8 = 0xB1;
+pub const NOR_EXSO: u8 = 0xC1;
+
+/// status register: write in progress
+pub const NOR_SR_WIP: u8 = 0x01;
+/// status register: write enable latch
+pub const NOR_SR_WEL: u8 = 0x02;
+
+pub const NOR_PAGE: u32 = 256;
+pub const NOR_SECTOR: u32 = 4096;
+pub const NOR_BLOCK: u32 = 65536;
+/// the secured OTP area, 4K bits; it can be programmed but never erased
+pub const NOR_OTP_LEN: u32 = 512;
+
+/// size of the flash on the board; see SPI_FLASH_SIZE in betrusted-soc.py
+pub const SPINOR_SIZE: u32 = 128 * 1024 * 1024;
+/// where the flash shows up in the CPU's address space
+pub const SPINOR_MMAP_BASE: usize = 0x2000_0000;
+
+/// worst case times from the datasheet, with margin
+const NOR_PROGRAM_TIMEOUT_MS: u32 = 10;
+const NOR_SECTOR_TIMEOUT_MS: u32 = 1000;
+const NOR_BLOCK_TIMEOUT_MS: u32 = 4000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlashError {
+    /// the chip stayed busy past the operation's worst case time
+    Timeout,
+    /// read back differs from what was programmed, at this address
+    Verify(u32),
+    /// erase address or length not on a sector boundary
+    Alignment,
+    /// access runs past the end of the flash or partition
+    Bounds,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct JedecId {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    pub capacity: u8,
+}
+
+/// A named region of the flash
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub name: &'static str,
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// The board's flash layout. The FPGA loads its bitstream from 0, and the CPU resets into the
+/// loader at loader_offset (betrusted-soc.py), which starts firmware A or B as the boot control
+/// log in "bootctl" says. Everything is block aligned.
+pub const FLASH_PARTITIONS: [Partition; 6] = [
+    Partition { name: "gateware",   offset: 0x0000_0000, len: 0x0028_0000 },
+    Partition { name: "bootloader", offset: 0x0028_0000, len: 0x0028_0000 },
+    Partition { name: "firmware_a", offset: 0x0050_0000, len: 0x0100_0000 },
+    Partition { name: "firmware_b", offset: 0x0150_0000, len: 0x0100_0000 },
+    Partition { name: "config",     offset: 0x0250_0000, len: 0x0010_0000 },
+    Partition { name: "bootctl",    offset: 0x0260_0000, len: 0x0001_0000 },
+];
+
+pub fn flash_partition(name: &str) -> Option<Partition> {
+    FLASH_PARTITIONS.iter().find(|p| p.name == name).copied()
+}
+
+/// The command interface of a SPI NOR chip
+pub trait NorPhy {
+    /// Issue `opcode`, with `addr` if it takes one, followed by `tx`, then clock in `rx`.
+    fn command(&mut self, opcode: u8, addr: Option<u32>, tx: &[u8], rx: &mut [u8]);
+    /// bulk read; may go through a faster path than command()
+    fn read(&mut self, addr: u32, buf: &mut [u8]);
+
+    /// Set the write enable latch, issue `opcode` (an erase or a page program) with `tx`, and
+    /// poll the status register until the chip is idle again. Returns false if it was still
+    /// busy after `timeout`.
+    ///
+    /// The chip can't be read while it's busy, so a phy for the flash the CPU executes from
+    /// has to do all of this without fetching from it.
+    fn write_and_wait(&mut self, opcode: u8, addr: u32, tx: &[u8], timeout: Duration) -> bool
+    where
+        Self: TickSource + Sized,
+    {
+        self.command(NOR_WREN, None, &[], &mut []);
+        self.command(opcode, Some(addr), tx, &mut []);
+        let deadline: Deadline = Deadline::new(self, timeout);
+        loop {
+            let mut sr: [u8; 1] = [0];
+            self.command(NOR_RDSR, None, &[], &mut sr);
+            if sr[0] & NOR_SR_WIP == 0 {
+                return true;
+            }
+            if deadline.expired(self) {
+                return false;
+            }
+        }
+    }
+
+    /// read() from the secured OTP area rather than the main array
+    fn otp_read(&mut self, addr: u32, buf: &mut [u8]) {
+        self.command(NOR_ENSO, None, &[], &mut []);
+        self.read(addr, buf);
+        self.command(NOR_EXSO, None, &[], &mut []);
+    }
+
+    /// write_and_wait() a page program into the secured OTP area
+    fn otp_program(&mut self, addr: u32, data: &[u8], timeout: Duration) -> bool
+    where
+        Self: TickSource + Sized,
+    {
+        self.command(NOR_ENSO, None, &[], &mut []);
+        let idle: bool = self.write_and_wait(NOR_PP4B, addr, data, timeout);
+        self.command(NOR_EXSO, None, &[], &mut []);
+        idle
+    }
+}
+
+/// Command interface of the S7SPIOPI controller. The flash runs in OPI DTR mode, so the
+/// controller sends each opcode with its complement, moves data 16 bits at a time, and
+/// status/ID reads take an address of 0 and dummy cycles.
+pub struct BtSpiNorPhy {
+    p: betrusted_pac::Peripherals,
+}
+
+// command register fields
+const OPI_EXEC_CMD: u32 = 1 << 1;
+const OPI_CMD_CODE_SHIFT: u32 = 2;
+const OPI_HAS_ARG: u32 = 1 << 10;
+const OPI_DUMMY_SHIFT: u32 = 11;
+const OPI_DATA_WORDS_SHIFT: u32 = 16;
+const OPI_LOCK_READS: u32 = 1 << 24;
+/// dummy cycles for register reads in OPI mode
+const OPI_REG_DUMMY: u32 = 4;
+
+fn opi_command(opcode: u8, has_arg: bool, register_read: bool, words: u32) -> u32 {
+    OPI_EXEC_CMD | OPI_LOCK_READS
+        | (opcode as u32) << OPI_CMD_CODE_SHIFT
+        | if has_arg { OPI_HAS_ARG } else { 0 }
+        | if register_read { OPI_REG_DUMMY << OPI_DUMMY_SHIFT } else { 0 }
+        | (words & 0xFF) << OPI_DATA_WORDS_SHIFT
+}
+
+/// Everything opi_write_and_wait() touches, worked out beforehand so that it doesn't have to
+/// call into code in flash.
+struct OpiWrite {
+    /// commands to issue before and after, or 0 for none
+    enter: u32,
+    exit: u32,
+    wdata: *mut u32,
+    cmd_arg: *mut u32,
+    command: *mut u32,
+    status: *const u32,
+    cmd_rbk_data: *const u32,
+    /// low word of the TICKTIMER
+    time: *const u32,
+    wren: u32,
+    op: u32,
+    addr: u32,
+    data: [u32; NOR_PAGE as usize / 2],
+    data_words: usize,
+    rdsr: u32,
+    timeout_ticks: u32,
+}
+
+/// The WREN, erase or program, and status polling of BtSpiNorPhy::write_and_wait(). This runs
+/// from RAM, with interrupts masked by the caller, as the flash the firmware executes from
+/// can't be read until the operation is over: only volatile accesses to the registers in `w`,
+/// which the release build inlines, and no indexing that could panic.
+#[inline(never)]
+#[cfg_attr(target_arch = "riscv32", link_section = ".data.spinor_ram")]
+unsafe fn opi_write_and_wait(w: &OpiWrite) -> bool {
+    if w.enter != 0 {
+        core::ptr::write_volatile(w.command, w.enter);
+        while core::ptr::read_volatile(w.status) & 1 != 0 {}
+    }
+    core::ptr::write_volatile(w.command, w.wren);
+    while core::ptr::read_volatile(w.status) & 1 != 0 {}
+
+    let data: *const u32 = &w.data as *const _ as *const u32;
+    let mut i: usize = 0;
+    while i < w.data_words {
+        core::ptr::write_volatile(w.wdata, core::ptr::read_volatile(data.add(i)));
+        i += 1;
+    }
+    core::ptr::write_volatile(w.cmd_arg, w.addr);
+    core::ptr::write_volatile(w.command, w.op);
+    while core::ptr::read_volatile(w.status) & 1 != 0 {}
+
+    let start: u32 = core::ptr::read_volatile(w.time);
+    let idle: bool = loop {
+        core::ptr::write_volatile(w.cmd_arg, 0);
+        core::ptr::write_volatile(w.command, w.rdsr);
+        while core::ptr::read_volatile(w.status) & 1 != 0 {}
+        if core::ptr::read_volatile(w.cmd_rbk_data) as u8 & NOR_SR_WIP == 0 {
+            break true;
+        }
+        if core::ptr::read_volatile(w.time).wrapping_sub(start) > w.timeout_ticks {
+            break false;
+        }
+    };
+    if w.exit != 0 {
+        core::ptr::write_volatile(w.command, w.exit);
+        while core::ptr::read_volatile(w.status) & 1 != 0 {}
+    }
+    idle
+}
+
+/// Read `len` bytes of the secured OTP area from `src`, its address in the flash window, into
+/// `dst`. While the OTP area is entered it replaces the main array, so like opi_write_and_wait()
+/// this runs from RAM with interrupts masked.
+#[inline(never)]
+#[cfg_attr(target_arch = "riscv32", link_section = ".data.spinor_ram")]
+unsafe fn opi_otp_read(command: *mut u32, status: *const u32, enso: u32, exso: u32, src: *const u8, dst: *mut u8, len: usize) {
+    core::ptr::write_volatile(command, enso);
+    while core::ptr::read_volatile(status) & 1 != 0 {}
+    let mut i: usize = 0;
+    while i < len {
+        core::ptr::write_volatile(dst.add(i), core::ptr::read_volatile(src.add(i)));
+        i += 1;
+    }
+    core::ptr::write_volatile(command, exso);
+    while core::ptr::read_volatile(status) & 1 != 0 {}
+}
+
+impl BtSpiNorPhy {
+    pub fn new() -> Self {
+        unsafe {
+            BtSpiNorPhy {
+                p: betrusted_pac::Peripherals::steal(),
+            }
+        }
+    }
+}
+
+impl NorPhy for BtSpiNorPhy {
+    fn command(&mut self, opcode: u8, addr: Option<u32>, tx: &[u8], rx: &mut [u8]) {
+        // odd lengths are padded with 0xFF, which programs nothing
+        for pair in tx.chunks(2) {
+            let word: u32 = pair[0] as u32 | (*pair.get(1).unwrap_or(&0xFF) as u32) << 8;
+            unsafe{ self.p.SPINOR.wdata.write(|w| w.bits(word)); }
+        }
+        let register_read: bool = !rx.is_empty();
+        let arg: Option<u32> = if register_read { Some(addr.unwrap_or(0)) } else { addr };
+        if let Some(a) = arg {
+            unsafe{ self.p.SPINOR.cmd_arg.write(|w| w.bits(a)); }
+        }
+        let words: u32 = ((tx.len() + rx.len() + 1) / 2) as u32;
+        let cmd: u32 = opi_command(opcode, arg.is_some(), register_read, words);
+        unsafe{ self.p.SPINOR.command.write(|w| w.bits(cmd)); }
+        while self.p.SPINOR.status.read().bits() & 1 != 0 {}
+
+        let data: u32 = self.p.SPINOR.cmd_rbk_data.read().bits();
+        for (i, b) in rx.iter_mut().take(4).enumerate() {
+            *b = (data >> (8 * i)) as u8;
+        }
+    }
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) {
+        let base: *const u8 = (SPINOR_MMAP_BASE + addr as usize) as *const u8;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = unsafe{ core::ptr::read_volatile(base.add(i)) };
+        }
+    }
+
+    /// The firmware executes in place from this flash, so the operation is run from RAM with
+    /// interrupts masked: a handler in flash could otherwise be fetched while the chip is busy.
+    fn write_and_wait(&mut self, opcode: u8, addr: u32, tx: &[u8], timeout: Duration) -> bool {
+        self.write_in_ram(0, 0, opcode, addr, tx, timeout)
+    }
+
+    fn otp_read(&mut self, addr: u32, buf: &mut [u8]) {
+        unsafe {
+            mstatus::clear_mie();
+            opi_otp_read(
+                &self.p.SPINOR.command as *const _ as *mut u32,
+                &self.p.SPINOR.status as *const _ as *const u32,
+                opi_command(NOR_ENSO, false, false, 0),
+                opi_command(NOR_EXSO, false, false, 0),
+                (SPINOR_MMAP_BASE + addr as usize) as *const u8,
+                buf.as_mut_ptr(),
+                buf.len(),
+            );
+            mstatus::set_mie();
+        }
+    }
+
+    fn otp_program(&mut self, addr: u32, data: &[u8], timeout: Duration) -> bool {
+        let enso: u32 = opi_command(NOR_ENSO, false, false, 0);
+        let exso: u32 = opi_command(NOR_EXSO, false, false, 0);
+        self.write_in_ram(enso, exso, NOR_PP4B, addr, data, timeout)
+    }
+}
+
+impl BtSpiNorPhy {
+    fn write_in_ram(&mut self, enter: u32, exit: u32, opcode: u8, addr: u32, tx: &[u8], timeout: Duration) -> bool {
+        let tx: &[u8] = &tx[..tx.len().min(NOR_PAGE as usize)];
+        let data_words: usize = tx.len().div_ceil(2);
+        let mut w: OpiWrite = OpiWrite {
+            enter,
+            exit,
+            wdata: &self.p.SPINOR.wdata as *const _ as *mut u32,
+            cmd_arg: &self.p.SPINOR.cmd_arg as *const _ as *mut u32,
+            command: &self.p.SPINOR.command as *const _ as *mut u32,
+            status: &self.p.SPINOR.status as *const _ as *const u32,
+            cmd_rbk_data: &self.p.SPINOR.cmd_rbk_data as *const _ as *const u32,
+            time: &self.p.TICKTIMER.time0 as *const _ as *const u32,
+            wren: opi_command(NOR_WREN, false, false, 0),
+            op: opi_command(opcode, true, false, data_words as u32),
+            addr,
+            data: [0; NOR_PAGE as usize / 2],
+            data_words,
+            rdsr: opi_command(NOR_RDSR, true, true, 1),
+            timeout_ticks: timeout.as_ticks().min(u32::MAX as u64) as u32,
+        };
+        // odd lengths are padded with 0xFF, as in command()
+        for (word, pair) in w.data.iter_mut().zip(tx.chunks(2)) {
+            *word = pair[0] as u32 | (*pair.get(1).unwrap_or(&0xFF) as u32) << 8;
+        }
+        unsafe {
+            mstatus::clear_mie();
+            let idle: bool = opi_write_and_wait(&w);
+            mstatus::set_mie();
+            idle
+        }
+    }
+}
+
+impl TickSource for BtSpiNorPhy {
+    fn ticks(&self) -> u64 {
+        self.p.ticks()
+    }
+}
+
+/// SPI NOR flash driver
+///
+/// Programs are verified by reading back, so programming over data that wasn't erased first
+/// shows up as FlashError::Verify rather than silently leaving the AND of old and new.
+pub struct SpiNor<T: NorPhy + TickSource> {
+    phy: T,
+    size: u32,
+}
+
+impl<T: NorPhy + TickSource> SpiNor<T> {
+    pub fn new(phy: T, size: u32) -> Self {
+        SpiNor { phy, size }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn phy(&self) -> &T {
+        &self.phy
+    }
+
+    pub fn phy_mut(&mut self) -> &mut T {
+        &mut self.phy
+    }
+
+    pub fn jedec_id(&mut self) -> JedecId {
+        let mut id: [u8; 3] = [0; 3];
+        self.phy.command(NOR_RDID, None, &[], &mut id);
+        JedecId { manufacturer: id[0], memory_type: id[1], capacity: id[2] }
+    }
+
+    pub fn status(&mut self) -> u8 {
+        let mut sr: [u8; 1] = [0];
+        self.phy.command(NOR_RDSR, None, &[], &mut sr);
+        sr[0]
+    }
+
+    fn check_bounds(&self, addr: u32, len: u32) -> Result<(), FlashError> {
+        match addr.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(FlashError::Bounds),
+        }
+    }
+
+    fn erase_unit(&mut self, opcode: u8, addr: u32, timeout_ms: u32) -> Result<(), FlashError> {
+        if self.phy.write_and_wait(opcode, addr, &[], Duration::from_ms(timeout_ms)) {
+            Ok(())
+        } else {
+            Err(FlashError::Timeout)
+        }
+    }
+
+    /// Erase `len` bytes from `addr`, both sector aligned, using block erases where they fit.
+    pub fn erase(&mut self, addr: u32, len: u32) -> Result<(), FlashError> {
+        if addr % NOR_SECTOR != 0 || len % NOR_SECTOR != 0 {
+            return Err(FlashError::Alignment);
+        }
+        self.check_bounds(addr, len)?;
+        crate::debug!("erase 0x{:08x}+0x{:x}", addr, len);
+        let end: u32 = addr + len;
+        let mut a: u32 = addr;
+        while a < end {
+            if a % NOR_BLOCK == 0 && end - a >= NOR_BLOCK {
+                self.erase_unit(NOR_BE4B, a, NOR_BLOCK_TIMEOUT_MS)?;
+                a += NOR_BLOCK;
+            } else {
+                self.erase_unit(NOR_SE4B, a, NOR_SECTOR_TIMEOUT_MS)?;
+                a += NOR_SECTOR;
+            }
+        }
+        Ok(())
+    }
+
+    /// Program `data` at `addr`, which must already be erased, and verify it.
+    pub fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.check_bounds(addr, data.len() as u32)?;
+        self.program_pages(addr, data, false)
+    }
+
+    /// Read from the secured OTP area.
+    pub fn otp_read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        match addr.checked_add(buf.len() as u32) {
+            Some(end) if end <= NOR_OTP_LEN => {
+                self.phy.otp_read(addr, buf);
+                Ok(())
+            },
+            _ => Err(FlashError::Bounds),
+        }
+    }
+
+    /// Program the secured OTP area and verify it. It can't be erased, so bits only ever go
+    /// from 1 to 0, and `data` has to keep the ones that already have, or it fails to verify.
+    pub fn otp_program(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        match addr.checked_add(data.len() as u32) {
+            Some(end) if end <= NOR_OTP_LEN => self.program_pages(addr, data, true),
+            _ => Err(FlashError::Bounds),
+        }
+    }
+
+    fn program_pages(&mut self, addr: u32, data: &[u8], otp: bool) -> Result<(), FlashError> {
+        let mut readback: [u8; NOR_PAGE as usize] = [0; NOR_PAGE as usize];
+        let mut a: u32 = addr;
+        let mut rest: &[u8] = data;
+        while !rest.is_empty() {
+            // a program wraps around within its page, so never cross a page boundary
+            let n: usize = ((NOR_PAGE - a % NOR_PAGE) as usize).min(rest.len());
+            let (page, tail) = rest.split_at(n);
+            let timeout: Duration = Duration::from_ms(NOR_PROGRAM_TIMEOUT_MS);
+            let idle: bool = if otp {
+                self.phy.otp_program(a, page, timeout)
+            } else {
+                self.phy.write_and_wait(NOR_PP4B, a, page, timeout)
+            };
+            if !idle {
+                return Err(FlashError::Timeout);
+            }
+
+            if otp {
+                self.phy.otp_read(a, &mut readback[..n]);
+            } else {
+                self.phy.read(a, &mut readback[..n]);
+            }
+            if let Some(i) = readback[..n].iter().zip(page.iter()).position(|(r, w)| r != w) {
+                return Err(FlashError::Verify(a + i as u32));
+            }
+            a += n as u32;
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    pub fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.check_bounds(addr, buf.len() as u32)?;
+        self.phy.read(addr, buf);
+        Ok(())
+    }
+
+    /// Write `data` at `addr`, erasing only where it has to.
+    ///
+    /// Each sector touched is compared with the new data first: it's left alone if it already
+    /// matches, programmed in place if the change only clears bits, and only otherwise erased and
+    /// rewritten with its old contents merged in. Unchanged pages are never reprogrammed.
+    pub fn update(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.check_bounds(addr, data.len() as u32)?;
+        let mut sector: Vec<u8> = vec![0; NOR_SECTOR as usize];
+        let mut a: u32 = addr;
+        let mut rest: &[u8] = data;
+        while !rest.is_empty() {
+            let base: u32 = a - a % NOR_SECTOR;
+            let start: usize = (a - base) as usize;
+            let n: usize = (NOR_SECTOR as usize - start).min(rest.len());
+            let (chunk, tail) = rest.split_at(n);
+
+            self.phy.read(base, &mut sector);
+            let old: &[u8] = &sector[start..start + n];
+            let needs_erase: bool = old.iter().zip(chunk.iter()).any(|(o, c)| o & c != *c);
+            if needs_erase {
+                sector[start..start + n].copy_from_slice(chunk);
+                self.erase(base, NOR_SECTOR)?;
+                self.program_changed(base, &sector, None)?;
+            } else {
+                let mut merged: [u8; NOR_SECTOR as usize] = [0; NOR_SECTOR as usize];
+                merged.copy_from_slice(&sector);
+                merged[start..start + n].copy_from_slice(chunk);
+                self.program_changed(base, &merged, Some(&sector))?;
+            }
+            a += n as u32;
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    /// program the pages of a sector image that differ from `old`, or aren't blank if there is no `old`
+    fn program_changed(&mut self, base: u32, new: &[u8], old: Option<&[u8]>) -> Result<(), FlashError> {
+        for (i, page) in new.chunks(NOR_PAGE as usize).enumerate() {
+            let offset: usize = i * NOR_PAGE as usize;
+            let unchanged: bool = match old {
+                Some(old) => old[offset..offset + page.len()] == *page,
+                None => page.iter().all(|&b| b == 0xFF),
+            };
+            if !unchanged {
+                self.program(base + offset as u32, page)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// access confined to one partition, with partition-relative addresses
+    pub fn region(&mut self, partition: Partition) -> Region<'_, T> {
+        Region { flash: self, partition }
+    }
+}
+
+/// A partition of the flash. Addresses are relative to its start, and nothing outside it can
+/// be touched.
+pub struct Region<'a, T: NorPhy + TickSource> {
+    flash: &'a mut SpiNor<T>,
+    partition: Partition,
+}
+
+impl<'a, T: NorPhy + TickSource> Region<'a, T> {
+    pub fn partition(&self) -> Partition {
+        self.partition
+    }
+
+    fn absolute(&self, offset: u32, len: usize) -> Result<u32, FlashError> {
+        match offset.checked_add(len as u32) {
+            Some(end) if end <= self.partition.len => Ok(self.partition.offset + offset),
+            _ => Err(FlashError::Bounds),
+        }
+    }
+
+    pub fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        let addr: u32 = self.absolute(offset, buf.len())?;
+        self.flash.read(addr, buf)
+    }
+
+    pub fn erase(&mut self, offset: u32, len: u32) -> Result<(), FlashError> {
+        let addr: u32 = self.absolute(offset, len as usize)?;
+        self.flash.erase(addr, len)
+    }
+
+    pub fn erase_all(&mut self) -> Result<(), FlashError> {
+        self.flash.erase(self.partition.offset, self.partition.len)
+    }
+
+    pub fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        let addr: u32 = self.absolute(offset, data.len())?;
+        self.flash.program(addr, data)
+    }
+
+    pub fn update(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        let addr: u32 = self.absolute(offset, data.len())?;
+        self.flash.update(addr, data)
+    }
+}
+
+/// Simulated SPI NOR chip in RAM, for testing flash users off the board.
+///
+/// Follows the NOR rules: programming can only clear bits, erase sets whole sectors to 0xFF,
+/// program and erase need the write enable latch and clear it, programs wrap within their
+/// page, and commands other than RDSR are ignored while an operation is in progress. Time only
+/// passes when the status register is read, one tick per read.
+///
+/// Setting `power_budget` simulates losing power part way through a write: each programmed
+/// byte and each half of an erase uses up one unit, and once it runs out nothing more changes.
+pub struct MockNor {
+    pub mem: Vec<u8>,
+    /// number of times each sector has been erased
+    pub erase_counts: Vec<u32>,
+    /// number of page program commands accepted
+    pub programs: u32,
+    /// never finish an operation
+    pub stuck: bool,
+    pub jedec: [u8; 3],
+    /// units of writing left before the power fails, or None to never fail
+    pub power_budget: Option<u32>,
+    /// the secured OTP area
+    pub otp: Vec<u8>,
+    secured: bool,
+    wel: bool,
+    now: Cell<u64>,
+    busy_until: Cell<u64>,
+}
+
+/// ticks a simulated operation keeps the chip busy
+const MOCK_PROGRAM_TICKS: u64 = 1;
+const MOCK_SECTOR_TICKS: u64 = 40;
+const MOCK_BLOCK_TICKS: u64 = 400;
+
+impl MockNor {
+    pub fn new(size: u32) -> Self {
+        MockNor {
+            mem: vec![0xFF; size as usize],
+            erase_counts: vec![0; (size / NOR_SECTOR) as usize],
+            programs: 0,
+            stuck: false,
+            jedec: [0xC2, 0x80, 0x3B],
+            power_budget: None,
+            otp: vec![0xFF; NOR_OTP_LEN as usize],
+            secured: false,
+            wel: false,
+            now: Cell::new(0),
+            busy_until: Cell::new(0),
+        }
+    }
+
+    fn busy(&self) -> bool {
+        self.stuck || self.now.get() < self.busy_until.get()
+    }
+
+    fn start_op(&mut self, ticks: u64) {
+        self.wel = false;
+        self.busy_until.set(self.now.get() + ticks);
+    }
+
+    /// take one unit of the power budget; false once the power has gone
+    fn spend(&mut self) -> bool {
+        match self.power_budget {
+            Some(0) => false,
+            Some(ref mut n) => {
+                *n -= 1;
+                true
+            },
+            None => true,
+        }
+    }
+
+    fn erase_range(&mut self, addr: u32, len: u32) {
+        let base: usize = (addr - addr % len) as usize;
+        let half: usize = len as usize / 2;
+        for start in [base, base + half].iter() {
+            if !self.spend() {
+                return;
+            }
+            for b in self.mem[*start..*start + half].iter_mut() {
+                *b = 0xFF;
+            }
+        }
+        for s in base / NOR_SECTOR as usize..(base + len as usize) / NOR_SECTOR as usize {
+            self.erase_counts[s] += 1;
+        }
+    }
+}
+
+impl NorPhy for MockNor {
+    fn command(&mut self, opcode: u8, addr: Option<u32>, tx: &[u8], rx: &mut [u8]) {
+        if opcode == NOR_RDSR {
+            let sr: u8 = if self.busy() { NOR_SR_WIP } else { 0 } | if self.wel { NOR_SR_WEL } else { 0 };
+            for b in rx.iter_mut() {
+                *b = sr;
+            }
+            self.now.set(self.now.get() + 1);
+            return;
+        }
+        if self.busy() {
+            return;
+        }
+        let addr: u32 = addr.unwrap_or(0) % self.mem.len() as u32;
+        match opcode {
+            NOR_RDID => {
+                for (b, id) in rx.iter_mut().zip(self.jedec.iter()) {
+                    *b = *id;
+                }
+            },
+            NOR_WREN => self.wel = true,
+            NOR_WRDI => self.wel = false,
+            NOR_ENSO => self.secured = true,
+            NOR_EXSO => self.secured = false,
+            // there's no erasing the OTP area
+            NOR_SE4B | NOR_BE4B if self.secured => self.wel = false,
+            NOR_PP4B if self.wel && self.secured => {
+                let page: u32 = addr - addr % NOR_PAGE;
+                for (i, b) in tx.iter().enumerate() {
+                    let a: usize = (page + (addr + i as u32) % NOR_PAGE) as usize;
+                    if let Some(o) = self.otp.get_mut(a) {
+                        *o &= *b;
+                    }
+                }
+                self.start_op(MOCK_PROGRAM_TICKS);
+            },
+            NOR_SE4B if self.wel => {
+                self.erase_range(addr, NOR_SECTOR);
+                self.start_op(MOCK_SECTOR_TICKS);
+            },
+            NOR_BE4B if self.wel => {
+                self.erase_range(addr, NOR_BLOCK);
+                self.start_op(MOCK_BLOCK_TICKS);
+            },
+            NOR_PP4B if self.wel => {
+                let page: u32 = addr - addr % NOR_PAGE;
+                for (i, b) in tx.iter().enumerate() {
+                    if !self.spend() {
+                        break;
+                    }
+                    let a: usize = (page + (addr + i as u32) % NOR_PAGE) as usize;
+                    self.mem[a] &= *b;
+                }
+                self.programs += 1;
+                self.start_op(MOCK_PROGRAM_TICKS);
+            },
+            _ => (),
+        }
+    }
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) {
+        let a: usize = addr as usize;
+        let from: &[u8] = if self.secured { &self.otp } else { &self.mem };
+        buf.copy_from_slice(&from[a..a + buf.len()]);
+    }
+}
+
+impl TickSource for MockNor {
+    fn ticks(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOCK_SIZE: u32 = 4 * NOR_BLOCK;
+
+    fn flash() -> SpiNor<MockNor> {
+        SpiNor::new(MockNor::new(MOCK_SIZE), MOCK_SIZE)
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn jedec_id_and_idle_status() {
+        let mut f = flash();
+        assert_eq!(f.jedec_id(), JedecId { manufacturer: 0xC2, memory_type: 0x80, capacity: 0x3B });
+        assert_eq!(f.status(), 0);
+    }
+
+    #[test]
+    fn program_spans_pages() {
+        let mut f = flash();
+        let data = pattern(1000, 1);
+        f.program(NOR_SECTOR + 100, &data).unwrap();
+        let mut back = vec![0u8; 1000];
+        f.read(NOR_SECTOR + 100, &mut back).unwrap();
+        assert_eq!(back, data);
+        // 100..256, then three more pages
+        assert_eq!(f.phy().programs, 5);
+    }
+
+    #[test]
+    fn programming_unerased_flash_fails_verify() {
+        let mut f = flash();
+        f.program(0, &[0x0F]).unwrap();
+        // clearing more bits is fine, setting them isn't
+        assert_eq!(f.program(0, &[0x0E]), Ok(()));
+        assert_eq!(f.program(0, &[0xF0]), Err(FlashError::Verify(0)));
+    }
+
+    #[test]
+    fn erase_uses_blocks_where_it_can() {
+        let mut f = flash();
+        f.program(0, &pattern(100, 2)).unwrap();
+        f.erase(NOR_BLOCK - 2 * NOR_SECTOR, NOR_BLOCK + 2 * NOR_SECTOR).unwrap();
+        let counts = &f.phy().erase_counts;
+        assert_eq!(counts[0], 0);
+        assert_eq!(counts.iter().sum::<u32>(), 16 + 2);
+        assert_eq!(f.erase(100, NOR_SECTOR), Err(FlashError::Alignment));
+        assert_eq!(f.erase(MOCK_SIZE, NOR_SECTOR), Err(FlashError::Bounds));
+    }
+
+    #[test]
+    fn stuck_chip_times_out() {
+        let mut f = flash();
+        f.phy.stuck = true;
+        assert_eq!(f.erase(0, NOR_SECTOR), Err(FlashError::Timeout));
+        assert_eq!(f.program(0, &[0]), Err(FlashError::Timeout));
+    }
+
+    #[test]
+    fn update_avoids_needless_erases() {
+        let mut f = flash();
+        let data = pattern(3000, 3);
+        f.update(500, &data).unwrap();
+        assert_eq!(f.phy().erase_counts.iter().sum::<u32>(), 0);
+
+        // same data again: nothing to do at all
+        let programs = f.phy().programs;
+        f.update(500, &data).unwrap();
+        assert_eq!(f.phy().programs, programs);
+
+        // only clearing bits: programmed in place
+        let cleared: Vec<u8> = data.iter().map(|b| b & 0xF0).collect();
+        f.update(500, &cleared).unwrap();
+        assert_eq!(f.phy().erase_counts.iter().sum::<u32>(), 0);
+
+        // setting bits needs an erase, and the rest of the sector survives it
+        f.update(2500, &[0xFF; 20]).unwrap();
+        assert_eq!(f.phy().erase_counts[0], 1);
+        assert_eq!(f.phy().erase_counts.iter().sum::<u32>(), 1);
+        let mut back = vec![0u8; 3000];
+        f.read(500, &mut back).unwrap();
+        let boundary = 2000;
+        assert_eq!(&back[..boundary], &cleared[..boundary]);
+        assert!(back[boundary..boundary + 20].iter().all(|&b| b == 0xFF));
+        assert_eq!(&back[boundary + 20..], &cleared[boundary + 20..]);
+    }
+
+    #[test]
+    fn region_is_confined() {
+        let mut f = flash();
+        let part = Partition { name: "test", offset: NOR_BLOCK, len: NOR_BLOCK };
+        let mut r = f.region(part);
+        r.erase_all().unwrap();
+        r.program(10, b"abc").unwrap();
+        assert_eq!(r.program(NOR_BLOCK - 2, b"abc"), Err(FlashError::Bounds));
+        assert_eq!(r.erase(NOR_BLOCK, NOR_SECTOR), Err(FlashError::Bounds));
+        r.update(10, b"xyz").unwrap();
+        let mut back = [0u8; 3];
+        f.read(NOR_BLOCK + 10, &mut back).unwrap();
+        assert_eq!(&back, b"xyz");
+    }
+
+    #[test]
+    fn otp_only_clears_bits() {
+        let mut f = flash();
+        f.otp_program(8, &[0xF0, 0x0F]).unwrap();
+        let mut otp = [0; 4];
+        f.otp_read(7, &mut otp).unwrap();
+        assert_eq!(otp, [0xFF, 0xF0, 0x0F, 0xFF]);
+        // the main array is untouched, and erasing doesn't reach the OTP area
+        assert!(f.phy().mem.iter().all(|&b| b == 0xFF));
+        f.erase(0, NOR_SECTOR).unwrap();
+        f.phy_mut().command(NOR_ENSO, None, &[], &mut []);
+        assert_eq!(f.erase(0, NOR_SECTOR), Ok(()));
+        f.phy_mut().command(NOR_EXSO, None, &[], &mut []);
+        f.otp_read(8, &mut otp[..2]).unwrap();
+        assert_eq!(otp[..2], [0xF0, 0x0F]);
+        // so a bit that has gone can't come back
+        assert_eq!(f.otp_program(8, &[0xFF]), Err(FlashError::Verify(8)));
+        assert_eq!(f.otp_program(NOR_OTP_LEN - 1, &[0, 0]), Err(FlashError::Bounds));
+    }
+
+    #[test]
+    fn partition_table_is_sane() {
+        let mut end = 0;
+        for p in FLASH_PARTITIONS.iter() {
+            assert!(p.offset >= end, "{} overlaps", p.name);
+            assert_eq!(p.offset % NOR_BLOCK, 0);
+            assert_eq!(p.len % NOR_BLOCK, 0);
+            end = p.offset + p.len;
+        }
+        assert!(end <= SPINOR_SIZE);
+        assert_eq!(flash_partition("firmware_a").map(|p| p.offset), Some(0x0050_0000));
+        assert_eq!(flash_partition("nope"), None);
+    }
+}
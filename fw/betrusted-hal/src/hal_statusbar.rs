@@ -0,0 +1,258 @@
+//! Secure status bar
+//!
+//! The status bar fills the trusted rows at the top of the screen with the time, USB and
+//! battery state, and the secure-mode padlock. It only ever draws through a TrustedSurface,
+//! and the padlock's bitmap is private to this module, so nothing else can put the padlock
+//! where the user expects to see it. Clients drawing a lookalike inside a surface are given
+//! away by the surface's border.
+//!
+//! The bar remembers what it last drew and only redraws when something it shows has changed,
+//! so the rows it owns must not be cleared behind its back; if they are, invalidate() it.
+
+use alloc::format;
+use alloc::string::String;
+use embedded_graphics::prelude::*;
+use embedded_graphics::fonts::{Font8x16, Font12x16};
+use embedded_graphics::pixelcolor::BinaryColor;
+use crate::hal_compositor::{TrustedSurface, TRUSTED_ROWS};
+use crate::hal_usbc::{TypeCState, Orientation};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UsbAttach {
+    Detached,
+    /// VBUS is up but neither CC line has a pull-up: a legacy cable or charger
+    Powered,
+    /// a Type-C source, on the CC line given
+    Cc1,
+    Cc2,
+}
+
+impl UsbAttach {
+    /// from the debounced Type-C sink state
+    pub fn from_state(state: TypeCState) -> Self {
+        match state {
+            TypeCState::Unattached => UsbAttach::Detached,
+            TypeCState::Attached { orientation: None, .. } => UsbAttach::Powered,
+            TypeCState::Attached { orientation: Some(Orientation::Cc1), .. } => UsbAttach::Cc1,
+            TypeCState::Attached { orientation: Some(Orientation::Cc2), .. } => UsbAttach::Cc2,
+        }
+    }
+}
+
+/// everything the status bar shows
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SystemStatus {
+    pub hours: u8,
+    pub minutes: u8,
+    /// None until the gas gauge has been read
+    pub soc_percent: Option<u8>,
+    pub charging: bool,
+    pub usb: UsbAttach,
+    /// what's on screen below the bar is the system's own
+    pub secure: bool,
+}
+
+/// padlock, shown light on a dark tile
+const PADLOCK: [u16; 16] = [
+    0b0000011111100000,
+    0b0000110000110000,
+    0b0001100000011000,
+    0b0001000000001000,
+    0b0001000000001000,
+    0b0001000000001000,
+    0b0111111111111110,
+    0b0111111111111110,
+    0b0111111001111110,
+    0b0111110000111110,
+    0b0111110000111110,
+    0b0111111001111110,
+    0b0111111001111110,
+    0b0111111111111110,
+    0b0111111111111110,
+    0b0000000000000000,
+];
+const PADLOCK_TILE: Point = Point::new(2, 2);
+/// side of the dark square behind the padlock
+const PADLOCK_TILE_SIZE: i32 = 27;
+
+const BOLT: [u16; 12] = [
+    0b00001100,
+    0b00011000,
+    0b00110000,
+    0b01100000,
+    0b11111110,
+    0b00001100,
+    0b00011000,
+    0b00110000,
+    0b01100000,
+    0b11000000,
+    0b00000000,
+    0b00000000,
+];
+
+const TIME_AT: Point = Point::new(40, 8);
+const USB_AT: Point = Point::new(120, 8);
+const BATTERY_AT: Point = Point::new(236, 10);
+const BATTERY_WIDTH: i32 = 24;
+const BATTERY_HEIGHT: i32 = 12;
+const PERCENT_AT: Point = Point::new(268, 8);
+const BOLT_AT: Point = Point::new(308, 10);
+
+pub struct StatusBar {
+    /// what's on screen, if the bar is known to be intact
+    shown: Option<SystemStatus>,
+}
+
+impl StatusBar {
+    pub fn new() -> Self {
+        StatusBar { shown: None }
+    }
+
+    /// the trusted rows have been cleared or drawn over; draw everything next time
+    pub fn invalidate(&mut self) {
+        self.shown = None;
+    }
+
+    /// Show `status`, if it isn't already. Returns true if anything was drawn.
+    pub fn render<D: DrawTarget<BinaryColor>>(&mut self, status: &SystemStatus, t: &mut TrustedSurface<'_, D>) -> bool {
+        if self.shown == Some(*status) {
+            return false;
+        }
+        let width: i32 = t.size().width as i32;
+        fill(t, Point::new(0, 0), Point::new(width, TRUSTED_ROWS), BinaryColor::Off);
+        // the bottom edge marks where trusted content ends
+        fill(t, Point::new(0, TRUSTED_ROWS - 1), Point::new(width, TRUSTED_ROWS), BinaryColor::On);
+
+        if status.secure {
+            let end: Point = Point::new(PADLOCK_TILE.x + PADLOCK_TILE_SIZE, PADLOCK_TILE.y + PADLOCK_TILE_SIZE);
+            fill(t, PADLOCK_TILE, end, BinaryColor::On);
+            let inset: i32 = (PADLOCK_TILE_SIZE - 16) / 2;
+            blit(t, &PADLOCK, 16, Point::new(PADLOCK_TILE.x + inset, PADLOCK_TILE.y + inset), BinaryColor::Off);
+        }
+
+        let time: String = format!("{:02}:{:02}", status.hours, status.minutes);
+        Font12x16::render_str(&time).stroke_color(Some(BinaryColor::On)).translate(TIME_AT).draw(t);
+
+        let usb: &str = match status.usb {
+            UsbAttach::Detached => "",
+            UsbAttach::Powered => "USB",
+            UsbAttach::Cc1 | UsbAttach::Cc2 => "USB-C",
+        };
+        Font8x16::render_str(usb).stroke_color(Some(BinaryColor::On)).translate(USB_AT).draw(t);
+
+        // battery outline with a nub on the right, filled in proportion to the charge
+        let b: Point = BATTERY_AT;
+        fill(t, b, Point::new(b.x + BATTERY_WIDTH, b.y + BATTERY_HEIGHT), BinaryColor::On);
+        fill(t, Point::new(b.x + 1, b.y + 1), Point::new(b.x + BATTERY_WIDTH - 1, b.y + BATTERY_HEIGHT - 1), BinaryColor::Off);
+        fill(t, Point::new(b.x + BATTERY_WIDTH, b.y + 3), Point::new(b.x + BATTERY_WIDTH + 2, b.y + BATTERY_HEIGHT - 3), BinaryColor::On);
+        let percent: String = match status.soc_percent {
+            Some(soc) => {
+                let level: i32 = (BATTERY_WIDTH - 4) * soc.min(100) as i32 / 100;
+                fill(t, Point::new(b.x + 2, b.y + 2), Point::new(b.x + 2 + level, b.y + BATTERY_HEIGHT - 2), BinaryColor::On);
+                format!("{}%", soc)
+            },
+            None => String::from("--%"),
+        };
+        Font8x16::render_str(&percent).stroke_color(Some(BinaryColor::On)).translate(PERCENT_AT).draw(t);
+
+        if status.charging {
+            blit(t, &BOLT, 8, BOLT_AT, BinaryColor::On);
+        }
+
+        self.shown = Some(*status);
+        true
+    }
+}
+
+/// fill the rectangle from `from` up to but not including `to`
+fn fill<D: DrawTarget<BinaryColor>>(t: &mut D, from: Point, to: Point, color: BinaryColor) {
+    for y in from.y..to.y {
+        for x in from.x..to.x {
+            t.draw_pixel(Pixel(Point::new(x, y), color));
+        }
+    }
+}
+
+/// draw the set bits of a bitmap `width` wide, most significant bit on the left
+fn blit<D: DrawTarget<BinaryColor>>(t: &mut D, rows: &[u16], width: i32, at: Point, color: BinaryColor) {
+    for (y, row) in rows.iter().enumerate() {
+        for x in 0..width {
+            if row & (1 << (width - 1 - x)) != 0 {
+                t.draw_pixel(Pixel(Point::new(at.x + x, at.y + y as i32), color));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal_lcd::*;
+    use host_link::bitmap::Bitmap;
+
+    fn status() -> SystemStatus {
+        SystemStatus { hours: 9, minutes: 41, soc_percent: Some(50), charging: false, usb: UsbAttach::Detached, secure: true }
+    }
+
+    fn display() -> BtDisplay<SimLcd> {
+        let mut d = BtDisplay::with_panel(SimLcd::new());
+        d.init(100);
+        d
+    }
+
+    #[test]
+    fn usb_attach_from_state() {
+        use crate::hal_usbc::SourceCurrent;
+        assert_eq!(UsbAttach::from_state(TypeCState::Unattached), UsbAttach::Detached);
+        assert_eq!(UsbAttach::from_state(TypeCState::Attached { orientation: None, current: SourceCurrent::Default }), UsbAttach::Powered);
+        assert_eq!(
+            UsbAttach::from_state(TypeCState::Attached { orientation: Some(Orientation::Cc2), current: SourceCurrent::Current1A5 }),
+            UsbAttach::Cc2
+        );
+    }
+
+    #[test]
+    fn redraws_only_on_change() {
+        let mut d = display();
+        let mut bar = StatusBar::new();
+        assert!(bar.render(&status(), &mut TrustedSurface::new(&mut d)));
+        // nothing below the trusted rows is touched
+        let (_, last) = d.dirty_range().unwrap();
+        assert_eq!(last, TRUSTED_ROWS as usize - 1);
+        d.blocking_flush();
+
+        assert!(!bar.render(&status(), &mut TrustedSurface::new(&mut d)));
+        assert_eq!(d.dirty_range(), None);
+
+        let charging = SystemStatus { charging: true, ..status() };
+        assert!(bar.render(&charging, &mut TrustedSurface::new(&mut d)));
+        assert!(d.dark(BOLT_AT.x as u32 + 4, BOLT_AT.y as u32));
+
+        bar.invalidate();
+        assert!(bar.render(&charging, &mut TrustedSurface::new(&mut d)));
+    }
+
+    #[test]
+    fn padlock_only_in_secure_mode() {
+        let mut d = display();
+        let mut bar = StatusBar::new();
+        let corner = |d: &BtDisplay<SimLcd>| d.dark(PADLOCK_TILE.x as u32, PADLOCK_TILE.y as u32);
+        bar.render(&status(), &mut TrustedSurface::new(&mut d));
+        assert!(corner(&d));
+        // the padlock itself is light on the dark tile
+        assert!(!d.dark(PADLOCK_TILE.x as u32 + 5 + 6, PADLOCK_TILE.y as u32 + 5));
+        bar.render(&SystemStatus { secure: false, ..status() }, &mut TrustedSurface::new(&mut d));
+        assert!(!corner(&d));
+    }
+
+    #[test]
+    fn battery_level_is_filled() {
+        let mut d = display();
+        let mut bar = StatusBar::new();
+        let inside = |d: &BtDisplay<SimLcd>, x: i32| d.dark((BATTERY_AT.x + x) as u32, (BATTERY_AT.y + 5) as u32);
+        bar.render(&SystemStatus { soc_percent: Some(50), ..status() }, &mut TrustedSurface::new(&mut d));
+        assert!(inside(&d, 2) && inside(&d, 11) && !inside(&d, 12) && inside(&d, BATTERY_WIDTH - 1));
+        bar.render(&SystemStatus { soc_percent: None, ..status() }, &mut TrustedSurface::new(&mut d));
+        assert!(!inside(&d, 2));
+    }
+}
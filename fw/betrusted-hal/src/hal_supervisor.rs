@@ -0,0 +1,323 @@
+//! Thermal and supply supervision
+//!
+//! The XADC compares the die temperature and the supply rails against thresholds by itself,
+//! and the gateware raises the xadcalarm interrupt whenever one of its alarms changes. The
+//! interrupt handler only notes that something changed, since the XADC's DRP port may be in
+//! the middle of a transaction; service(), called from the main loop, then reads the sensors
+//! and works out which alarms have gone up or down. Alarms are held until the reading is back
+//! inside its limits by a margin, so a rail sitting on a threshold doesn't chatter.
+//!
+//! Events go to the registered handlers. The default handler asks for an orderly shutdown on
+//! any alarm, which the main loop carries out with the EC power command; a unit left in a hot
+//! pocket powers itself down rather than cooking.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::hal_xadc::*;
+
+/// the last interrupt betrusted-soc.py adds, so it comes after usb where there is one
+#[cfg(not(feature = "dvt"))]
+pub const XADC_ALARM_IRQ: usize = 8;
+#[cfg(feature = "dvt")]
+pub const XADC_ALARM_IRQ: usize = 9;
+/// xadcalarm "change" event
+const XADC_ALARM_EV_CHANGE: u32 = 1;
+
+static ALARM_PENDING: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// xadcalarm interrupt handler, registered by `Supervisor::init()`
+pub fn xadc_alarm_handle_irq(_irq: usize) {
+    let p: betrusted_pac::Peripherals = unsafe{ betrusted_pac::Peripherals::steal() };
+    let pending: u32 = p.XADCALARM.ev_pending.read().bits();
+    unsafe{ p.XADCALARM.ev_pending.write(|w| w.bits(pending)); }
+    ALARM_PENDING.store(true, Ordering::Release);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum XadcAlarm {
+    OverTemp,
+    VccIntLow,
+    VccIntHigh,
+    VccAuxLow,
+    VccAuxHigh,
+    VccBramLow,
+    VccBramHigh,
+}
+
+const ALARMS: [XadcAlarm; 7] = [
+    XadcAlarm::OverTemp,
+    XadcAlarm::VccIntLow,
+    XadcAlarm::VccIntHigh,
+    XadcAlarm::VccAuxLow,
+    XadcAlarm::VccAuxHigh,
+    XadcAlarm::VccBramLow,
+    XadcAlarm::VccBramHigh,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum XadcEvent {
+    Raised(XadcAlarm),
+    Cleared(XadcAlarm),
+}
+
+pub type AlarmHandler = fn(XadcEvent);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SupervisorError {
+    /// the xadcalarm interrupt is already claimed; service() falls back to polling
+    IrqUnavailable,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RailLimits {
+    pub lower: Millivolts,
+    pub upper: Millivolts,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SupervisorLimits {
+    /// OverTemp goes up at `temp_upper`, and comes down below `temp_reset`
+    pub temp_upper: MilliCelsius,
+    pub temp_reset: MilliCelsius,
+    pub vccint: RailLimits,
+    pub vccaux: RailLimits,
+    pub vccbram: RailLimits,
+    /// how far back inside its limits a rail has to come for its alarm to clear
+    pub hysteresis: Millivolts,
+}
+
+impl Default for SupervisorLimits {
+    /// the recommended operating ranges of the rails, and a margin under the 85C the part is
+    /// rated to. The xc7s50 is a -1L part, whose VCCINT and VCCBRAM run at 0.95V within
+    /// 0.92-0.98V; those limits get another 20mV either side for the XADC's own error.
+    fn default() -> Self {
+        SupervisorLimits {
+            temp_upper: MilliCelsius(80_000),
+            temp_reset: MilliCelsius(70_000),
+            vccint: RailLimits { lower: Millivolts(900), upper: Millivolts(1000) },
+            vccaux: RailLimits { lower: Millivolts(1710), upper: Millivolts(1890) },
+            vccbram: RailLimits { lower: Millivolts(900), upper: Millivolts(1000) },
+            hysteresis: Millivolts(20),
+        }
+    }
+}
+
+/// the default handler: any alarm going up asks for a shutdown
+pub fn shutdown_on_alarm(event: XadcEvent) {
+    if let XadcEvent::Raised(_) = event {
+        SHUTDOWN_REQUESTED.store(true, Ordering::Release);
+    }
+}
+
+/// true once, after shutdown_on_alarm() has asked for a shutdown
+pub fn take_shutdown_request() -> bool {
+    SHUTDOWN_REQUESTED.swap(false, Ordering::AcqRel)
+}
+
+pub struct Supervisor {
+    limits: SupervisorLimits,
+    handlers: Vec<AlarmHandler>,
+    /// one bit per entry of ALARMS
+    active: u8,
+    irq: bool,
+}
+
+impl Supervisor {
+    pub fn new(limits: SupervisorLimits) -> Self {
+        Supervisor {
+            limits,
+            handlers: vec![shutdown_on_alarm as AlarmHandler],
+            active: 0,
+            irq: false,
+        }
+    }
+
+    /// Program the XADC's alarm thresholds, claim the xadcalarm interrupt and enable its event.
+    pub fn init(&mut self, xadc: &mut BtXadc) -> Result<(), SupervisorError> {
+        let l: SupervisorLimits = self.limits;
+        xadc.set_temperature_alarm(l.temp_upper, l.temp_reset);
+        xadc.set_supply_alarm(XadcSupply::VccInt, l.vccint.lower, l.vccint.upper);
+        xadc.set_supply_alarm(XadcSupply::VccAux, l.vccaux.lower, l.vccaux.upper);
+        xadc.set_supply_alarm(XadcSupply::VccBram, l.vccbram.lower, l.vccbram.upper);
+
+        if xous_nommu::syscalls::sys_interrupt_claim(XADC_ALARM_IRQ, xadc_alarm_handle_irq).is_err() {
+            return Err(SupervisorError::IrqUnavailable);
+        }
+        let p: betrusted_pac::Peripherals = unsafe{ betrusted_pac::Peripherals::steal() };
+        unsafe{ p.XADCALARM.ev_pending.write(|w| w.bits(p.XADCALARM.ev_pending.read().bits())); }
+        unsafe{ p.XADCALARM.ev_enable.write(|w| w.bits(XADC_ALARM_EV_CHANGE)); }
+        self.irq = true;
+        // look once, in case something was already out of range
+        ALARM_PENDING.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn limits(&self) -> SupervisorLimits {
+        self.limits
+    }
+
+    /// add a handler, to be called after those already registered
+    pub fn on_event(&mut self, handler: AlarmHandler) {
+        self.handlers.push(handler);
+    }
+
+    /// drop all the handlers, including the default shutdown policy
+    pub fn clear_handlers(&mut self) {
+        self.handlers.clear();
+    }
+
+    pub fn is_active(&self, alarm: XadcAlarm) -> bool {
+        self.active & Supervisor::bit(alarm) != 0
+    }
+
+    fn bit(alarm: XadcAlarm) -> u8 {
+        1 << ALARMS.iter().position(|&a| a == alarm).unwrap()
+    }
+
+    /// whether `alarm` should be up, given whether it is now
+    fn assess(&self, alarm: XadcAlarm, r: &XadcReadings, up: bool) -> bool {
+        let l: &SupervisorLimits = &self.limits;
+        let h: u32 = l.hysteresis.0;
+        let low = |v: Millivolts, rail: &RailLimits| if up { v.0 < rail.lower.0 + h } else { v < rail.lower };
+        let high = |v: Millivolts, rail: &RailLimits| if up { v.0 + h > rail.upper.0 } else { v > rail.upper };
+        match alarm {
+            XadcAlarm::OverTemp => if up { r.temperature >= l.temp_reset } else { r.temperature >= l.temp_upper },
+            XadcAlarm::VccIntLow => low(r.vccint, &l.vccint),
+            XadcAlarm::VccIntHigh => high(r.vccint, &l.vccint),
+            XadcAlarm::VccAuxLow => low(r.vccaux, &l.vccaux),
+            XadcAlarm::VccAuxHigh => high(r.vccaux, &l.vccaux),
+            XadcAlarm::VccBramLow => low(r.vccbram, &l.vccbram),
+            XadcAlarm::VccBramHigh => high(r.vccbram, &l.vccbram),
+        }
+    }
+
+    /// Compare `readings` against the limits, and dispatch and return the alarms that have
+    /// gone up or down.
+    pub fn update(&mut self, readings: &XadcReadings) -> Vec<XadcEvent> {
+        let mut events: Vec<XadcEvent> = Vec::new();
+        for &alarm in ALARMS.iter() {
+            let up: bool = self.is_active(alarm);
+            let now: bool = self.assess(alarm, readings, up);
+            if now != up {
+                self.active ^= Supervisor::bit(alarm);
+                events.push(if now { XadcEvent::Raised(alarm) } else { XadcEvent::Cleared(alarm) });
+            }
+        }
+        for &event in events.iter() {
+            for handler in self.handlers.iter() {
+                handler(event);
+            }
+        }
+        events
+    }
+
+    /// Call regularly from the main loop. The sensors are read when the alarm interrupt has
+    /// fired, while any alarm is up (to see it clear), and on every call if there's no
+    /// interrupt to wait for.
+    pub fn service(&mut self, xadc: &mut BtXadc) -> Vec<XadcEvent> {
+        let pending: bool = ALARM_PENDING.swap(false, Ordering::AcqRel);
+        if self.irq && !pending && self.active == 0 {
+            return Vec::new();
+        }
+        let readings: XadcReadings = xadc.readings();
+        self.update(&readings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    fn nominal() -> XadcReadings {
+        XadcReadings {
+            temperature: MilliCelsius(35_000),
+            vccint: Millivolts(950),
+            vccaux: Millivolts(1800),
+            vccbram: Millivolts(950),
+        }
+    }
+
+    fn quiet() -> Supervisor {
+        let mut s = Supervisor::new(SupervisorLimits::default());
+        s.clear_handlers();
+        s
+    }
+
+    #[test]
+    fn nominal_is_quiet() {
+        let mut s = quiet();
+        assert!(s.update(&nominal()).is_empty());
+        assert!(ALARMS.iter().all(|&a| !s.is_active(a)));
+    }
+
+    #[test]
+    fn minus_1l_operating_range_is_quiet() {
+        let mut s = quiet();
+        for mv in 920..=980 {
+            let r = XadcReadings { vccint: Millivolts(mv), vccbram: Millivolts(1900 - mv), ..nominal() };
+            assert!(s.update(&r).is_empty(), "{}mV", mv);
+        }
+    }
+
+    #[test]
+    fn over_temperature_with_hysteresis() {
+        let mut s = quiet();
+        let at = |mc: i32| XadcReadings { temperature: MilliCelsius(mc), ..nominal() };
+        assert!(s.update(&at(79_900)).is_empty());
+        assert_eq!(s.update(&at(80_000)), [XadcEvent::Raised(XadcAlarm::OverTemp)]);
+        assert!(s.update(&at(81_000)).is_empty());
+        // cooling below the upper threshold isn't enough
+        assert!(s.update(&at(75_000)).is_empty());
+        assert!(s.update(&at(70_000)).is_empty());
+        assert!(s.is_active(XadcAlarm::OverTemp));
+        assert_eq!(s.update(&at(69_999)), [XadcEvent::Cleared(XadcAlarm::OverTemp)]);
+        assert!(s.update(&at(75_000)).is_empty());
+    }
+
+    #[test]
+    fn rails_with_hysteresis() {
+        let mut s = quiet();
+        let int = |mv: u32| XadcReadings { vccint: Millivolts(mv), ..nominal() };
+        assert!(s.update(&int(900)).is_empty());
+        assert_eq!(s.update(&int(899)), [XadcEvent::Raised(XadcAlarm::VccIntLow)]);
+        // hovering around the threshold doesn't chatter
+        assert!(s.update(&int(901)).is_empty());
+        assert!(s.update(&int(898)).is_empty());
+        assert_eq!(s.update(&int(920)), [XadcEvent::Cleared(XadcAlarm::VccIntLow)]);
+
+        let aux = |mv: u32| XadcReadings { vccaux: Millivolts(mv), ..nominal() };
+        assert_eq!(s.update(&aux(1891)), [XadcEvent::Raised(XadcAlarm::VccAuxHigh)]);
+        assert!(s.update(&aux(1880)).is_empty());
+        assert_eq!(s.update(&aux(1870)), [XadcEvent::Cleared(XadcAlarm::VccAuxHigh)]);
+
+        // several at once
+        let bad = XadcReadings { vccbram: Millivolts(1100), temperature: MilliCelsius(90_000), ..nominal() };
+        assert_eq!(s.update(&bad), [XadcEvent::Raised(XadcAlarm::OverTemp), XadcEvent::Raised(XadcAlarm::VccBramHigh)]);
+    }
+
+    static SEEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(event: XadcEvent) {
+        if event == XadcEvent::Raised(XadcAlarm::VccBramLow) {
+            SEEN.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn handlers_see_events_and_default_shuts_down() {
+        let mut s = Supervisor::new(SupervisorLimits::default());
+        s.on_event(count);
+        let low = XadcReadings { vccbram: Millivolts(880), ..nominal() };
+        s.update(&low);
+        s.update(&low);
+        assert_eq!(SEEN.load(Ordering::Relaxed), 1);
+        assert!(take_shutdown_request());
+        assert!(!take_shutdown_request());
+        // clearing isn't a reason to shut down
+        s.update(&nominal());
+        assert!(!take_shutdown_request());
+    }
+}
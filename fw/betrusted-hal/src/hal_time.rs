@@ -1,30 +1,710 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spin::Mutex;
+
 const TICKS_PER_MS: u32 = 1;
+const US_PER_TICK: u64 = 1000 / TICKS_PER_MS as u64;
+
+/// system clock in MHz, recorded by time_init() for delay_us()
+static TIME_CLOCK_MHZ: AtomicU32 = AtomicU32::new(0);
 
-pub fn time_init(p: &betrusted_pac::Peripherals) {
+/// Resets the TICKTIMER, and starts timer0 free-running with a 1 ms period. timer0's count
+/// gives delay_us() its resolution, and its reload event drives the timer service.
+pub fn time_init(p: &betrusted_pac::Peripherals, clock_mhz: u32) {
+    TIME_CLOCK_MHZ.store(clock_mhz, Ordering::Relaxed);
     p.TICKTIMER.control.write( |w| {w.reset().bit(true)});
+
+    let reload: u32 = clock_mhz * 1000 - 1;
+    unsafe {
+        p.TIMER0.en.write(|w| w.bits(0));
+        p.TIMER0.load.write(|w| w.bits(reload));
+        p.TIMER0.reload.write(|w| w.bits(reload));
+        p.TIMER0.en.write(|w| w.bits(1));
+    }
+}
+
+/// true once time_init() has run, i.e. the timers can be read
+pub fn time_running() -> bool {
+    TIME_CLOCK_MHZ.load(Ordering::Relaxed) != 0
 }
 
 // time APIs needed (ideally)
-/// get current time - in milliseconds, as u32
+/// get current time - in milliseconds, as u32. This wraps after ~49 days, so use Instant
+/// to measure intervals.
 pub fn get_time_ms(p: &betrusted_pac::Peripherals) -> u32 {
     let time: u32;
-    
+
     time = p.TICKTIMER.time0.read().bits();
 
     time / TICKS_PER_MS
 }
 
 pub fn get_ticks(p: &betrusted_pac::Peripherals) -> u64 {
-    (p.TICKTIMER.time0.read().bits() as u64) | ((p.TICKTIMER.time1.read().bits() as u64) << 32)
+    // the two halves aren't latched together, so re-read if the upper half moved underneath us
+    loop {
+        let hi: u32 = p.TICKTIMER.time1.read().bits();
+        let lo: u32 = p.TICKTIMER.time0.read().bits();
+        if p.TICKTIMER.time1.read().bits() == hi {
+            return (lo as u64) | ((hi as u64) << 32);
+        }
+    }
+}
+
+/// Anything that can report a free-running tick count: the TICKTIMER, or a fake one in tests.
+pub trait TickSource {
+    fn ticks(&self) -> u64;
+}
+
+impl TickSource for betrusted_pac::Peripherals {
+    fn ticks(&self) -> u64 {
+        get_ticks(self)
+    }
+}
+
+/// A span of time, with microsecond resolution.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration {
+    us: u64,
+}
+
+impl Duration {
+    pub fn from_us(us: u64) -> Self {
+        Duration { us }
+    }
+
+    pub fn from_ms(ms: u32) -> Self {
+        Duration { us: ms as u64 * 1000 }
+    }
+
+    fn from_ticks(ticks: u64) -> Self {
+        Duration { us: ticks.saturating_mul(US_PER_TICK) }
+    }
+
+    pub fn as_us(&self) -> u64 {
+        self.us
+    }
+
+    pub fn as_ms(&self) -> u64 {
+        self.us / 1000
+    }
+
+    /// in TICKTIMER ticks, rounded up
+    pub fn as_ticks(&self) -> u64 {
+        self.us.div_ceil(US_PER_TICK)
+    }
+
+    pub fn checked_add(&self, other: Duration) -> Option<Duration> {
+        self.us.checked_add(other.us).map(Duration::from_us)
+    }
+
+    pub fn checked_sub(&self, other: Duration) -> Option<Duration> {
+        self.us.checked_sub(other.us).map(Duration::from_us)
+    }
+
+    pub fn saturating_sub(&self, other: Duration) -> Duration {
+        Duration { us: self.us.saturating_sub(other.us) }
+    }
+}
+
+/// A point in time, in TICKTIMER ticks.
+///
+/// Differences are taken with wrapping arithmetic, so comparisons stay correct across a
+/// counter wrap as long as the two instants are less than half the counter range apart.
+/// For the same reason Instant doesn't implement Ord; use checked_duration_since().
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    pub fn now<T: TickSource>(src: &T) -> Self {
+        Instant { ticks: src.ticks() }
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Instant { ticks }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// time from `earlier` to self, or None if `earlier` is actually after self
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        let delta: u64 = self.ticks.wrapping_sub(earlier.ticks);
+        if delta > i64::max_value() as u64 {
+            None
+        } else {
+            Some(Duration::from_ticks(delta))
+        }
+    }
+
+    pub fn elapsed<T: TickSource>(&self, src: &T) -> Duration {
+        Instant::now(src).checked_duration_since(*self).unwrap_or_default()
+    }
+
+    /// None if `d` is too long to compare against afterwards. Sub-tick remainders are dropped.
+    pub fn checked_add(&self, d: Duration) -> Option<Instant> {
+        let delta: u64 = d.as_us() / US_PER_TICK;
+        if delta > i64::max_value() as u64 {
+            None
+        } else {
+            Some(Instant { ticks: self.ticks.wrapping_add(delta) })
+        }
+    }
+}
+
+/// A timeout that started at a given instant.
+#[derive(Copy, Clone, Debug)]
+pub struct Deadline {
+    start: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    pub fn new<T: TickSource>(src: &T, timeout: Duration) -> Self {
+        Deadline {
+            start: Instant::now(src),
+            timeout,
+        }
+    }
+
+    pub fn from_ms<T: TickSource>(src: &T, ms: u32) -> Self {
+        Deadline::new(src, Duration::from_ms(ms))
+    }
+
+    /// true once strictly more than the timeout has been counted. Since the start was sampled
+    /// somewhere inside a tick, this guarantees at least the full timeout has really passed.
+    pub fn expired<T: TickSource>(&self, src: &T) -> bool {
+        self.start.elapsed(src) > self.timeout
+    }
+
+    pub fn remaining<T: TickSource>(&self, src: &T) -> Duration {
+        self.timeout.saturating_sub(self.start.elapsed(src))
+    }
 }
 
 /// delay for milliseconds
 pub fn delay_ms(p: &betrusted_pac::Peripherals, ms: u32) {
-    let starttime: u32 = get_time_ms(p);
+    let deadline: Deadline = Deadline::from_ms(p, ms);
 
-    loop {
-        if get_time_ms(p) > (starttime + ms) {
-            break;
+    while !deadline.expired(p) {}
+}
+
+/// delay for microseconds, busy waiting on timer0's count.
+/// Falls back to rounding up to whole milliseconds if time_init() hasn't been called.
+pub fn delay_us(p: &betrusted_pac::Peripherals, us: u32) {
+    let clock_mhz: u32 = TIME_CLOCK_MHZ.load(Ordering::Relaxed);
+    if clock_mhz == 0 {
+        delay_ms(p, (us + 999) / 1000);
+        return;
+    }
+
+    // timer0 counts down from its reload value and starts over, so only look at the
+    // difference between samples; this doesn't disturb the timer service.
+    let period: u32 = clock_mhz * 1000;
+    let mut remaining: u64 = us as u64 * clock_mhz as u64;
+    let mut last: u32 = timer0_value(p);
+    while remaining > 0 {
+        let now: u32 = timer0_value(p);
+        let delta: u32 = if now <= last { last - now } else { last + period - now };
+        remaining = remaining.saturating_sub(delta as u64);
+        last = now;
+    }
+}
+
+fn timer0_value(p: &betrusted_pac::Peripherals) -> u32 {
+    unsafe{ p.TIMER0.update_value.write(|w| w.bits(1)); }
+    p.TIMER0.value.read().bits()
+}
+
+pub type TimerId = u32;
+/// Timer callbacks are run from the main loop by BtTimers::service(), so they may allocate,
+/// but should still be short: set a flag, queue some work, or schedule another timer.
+pub type TimerCallback = fn(TimerId);
+
+/// number of slots in the timer wheel, one tick each; must divide 2^64
+pub const TIMER_WHEEL_SLOTS: usize = 64;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TimerStats {
+    /// callbacks fired
+    pub fired: u32,
+    /// periods skipped because a periodic timer was serviced more than a period late
+    pub missed: u32,
+    /// the latest any timer has fired relative to its deadline
+    pub max_jitter: Duration,
+    /// sum of lateness over all fired timers
+    pub total_jitter: Duration,
+}
+
+impl TimerStats {
+    pub fn mean_jitter(&self) -> Duration {
+        if self.fired == 0 {
+            Duration::default()
+        } else {
+            Duration::from_us(self.total_jitter.as_us() / self.fired as u64)
+        }
+    }
+}
+
+struct TimerEntry {
+    id: TimerId,
+    /// tick at which to fire
+    expires: u64,
+    /// 0 for one-shot timers
+    period: u64,
+    callback: TimerCallback,
+}
+
+/// true if `tick` is at or after `reference`, allowing for counter wrap
+fn tick_reached(tick: u64, reference: u64) -> bool {
+    (tick.wrapping_sub(reference) as i64) >= 0
+}
+
+/// rounds up to whole ticks, and to at least one tick
+fn duration_to_ticks(d: Duration) -> u64 {
+    let ticks: u64 = (d.as_us() + US_PER_TICK - 1) / US_PER_TICK;
+    if ticks == 0 { 1 } else { ticks }
+}
+
+/// A hashed timer wheel: each timer lives in the slot for its expiry tick modulo
+/// TIMER_WHEEL_SLOTS, so servicing a tick only looks at one slot. Timers more than a lap
+/// away just stay in their slot until their tick comes around.
+///
+/// The wheel has no notion of a clock of its own; advance() is handed the current time,
+/// by BtTimers::service() on hardware or by a simulated clock in tests.
+pub struct TimerWheel {
+    slots: Vec<Vec<TimerEntry>>,
+    /// last tick that was serviced
+    now: u64,
+    next_id: TimerId,
+    stats: TimerStats,
+}
+
+impl TimerWheel {
+    pub const fn new() -> Self {
+        TimerWheel {
+            slots: Vec::new(),
+            now: 0,
+            next_id: 1,
+            stats: TimerStats {
+                fired: 0,
+                missed: 0,
+                max_jitter: Duration { us: 0 },
+                total_jitter: Duration { us: 0 },
+            },
+        }
+    }
+
+    fn insert(&mut self, mut entry: TimerEntry) {
+        if self.slots.is_empty() {
+            self.slots.resize_with(TIMER_WHEEL_SLOTS, Vec::new);
+        }
+        // a slot that has already been serviced won't be looked at again for a whole lap
+        if !tick_reached(entry.expires, self.now.wrapping_add(1)) {
+            entry.expires = self.now.wrapping_add(1);
         }
+        let slot: usize = (entry.expires % TIMER_WHEEL_SLOTS as u64) as usize;
+        self.slots[slot].push(entry);
+    }
+
+    /// Schedules `callback` to run `delay` after `now`, rounded up to the next tick, and then
+    /// every `period` after that if one is given. Returns an id for cancel().
+    pub fn schedule(&mut self, now: Instant, delay: Duration, period: Option<Duration>, callback: TimerCallback) -> TimerId {
+        let id: TimerId = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.insert(TimerEntry {
+            id,
+            expires: now.ticks().wrapping_add(duration_to_ticks(delay)),
+            period: period.map_or(0, duration_to_ticks),
+            callback,
+        });
+        id
+    }
+
+    /// returns false if the timer had already fired (one-shot) or been cancelled
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        for slot in self.slots.iter_mut() {
+            if let Some(index) = slot.iter().position(|entry| entry.id == id) {
+                slot.remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn pending(&self) -> usize {
+        self.slots.iter().map(|slot| slot.len()).sum()
+    }
+
+    pub fn stats(&self) -> TimerStats {
+        self.stats
+    }
+
+    /// Services every tick up to `now`, and returns the callbacks that came due, most overdue
+    /// first. The caller runs them, so that they can schedule or cancel timers themselves.
+    /// Periodic timers keep their phase; if whole periods went by unserviced they fire once
+    /// and the skipped periods are counted in TimerStats::missed.
+    pub fn advance(&mut self, now: Instant) -> Vec<(TimerCallback, TimerId)> {
+        let target: u64 = now.ticks();
+        let mut fired: Vec<(TimerCallback, TimerId)> = Vec::new();
+        if self.slots.is_empty() {
+            // nothing was ever scheduled, so just catch up with the clock
+            self.now = target;
+            return fired;
+        }
+        let steps: u64 = target.wrapping_sub(self.now);
+        if (steps as i64) <= 0 {
+            return fired;
+        }
+
+        // after a lap every slot has been visited, so there's no point going further
+        let steps: u64 = if steps > TIMER_WHEEL_SLOTS as u64 { TIMER_WHEEL_SLOTS as u64 } else { steps };
+        let mut due: Vec<TimerEntry> = Vec::new();
+        for step in 1..=steps {
+            let slot: usize = (self.now.wrapping_add(step) % TIMER_WHEEL_SLOTS as u64) as usize;
+            let mut i: usize = 0;
+            while i < self.slots[slot].len() {
+                if tick_reached(target, self.slots[slot][i].expires) {
+                    due.push(self.slots[slot].remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.now = target;
+
+        due.sort_by_key(|entry| core::cmp::Reverse(target.wrapping_sub(entry.expires)));
+        for mut entry in due {
+            let late: u64 = target.wrapping_sub(entry.expires);
+            let jitter: Duration = Duration::from_ticks(late);
+            self.stats.fired = self.stats.fired.wrapping_add(1);
+            self.stats.total_jitter = self.stats.total_jitter.checked_add(jitter).unwrap_or(jitter);
+            if jitter > self.stats.max_jitter {
+                self.stats.max_jitter = jitter;
+            }
+            fired.push((entry.callback, entry.id));
+
+            if entry.period != 0 {
+                let skipped: u64 = late / entry.period;
+                self.stats.missed = self.stats.missed.wrapping_add(skipped as u32);
+                entry.expires = entry.expires.wrapping_add((skipped + 1) * entry.period);
+                self.insert(entry);
+            }
+        }
+        fired
+    }
+}
+
+/// timer0 interrupt number, as assigned by the SoC interrupt map (uart = 0, timer0 = 1, i2c = 2)
+pub const TIMER0_IRQ: usize = 1;
+/// timer0 "zero" event, raised each time the count reloads
+const TIMER0_EV_ZERO: u32 = 1;
+
+static TIMER_WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+/// set by the timer0 interrupt, taken by BtTimers::service()
+static TIMER_TICKED: AtomicBool = AtomicBool::new(false);
+
+/// Run `f` on the timer wheel with the timer0 event masked, so the interrupt handler can't
+/// try to take the lock while we hold it.
+fn with_wheel<R, F: FnOnce(&mut TimerWheel) -> R>(p: &betrusted_pac::Peripherals, f: F) -> R {
+    let enabled: u32 = p.TIMER0.ev_enable.read().bits();
+    unsafe{ p.TIMER0.ev_enable.write( |w| {w.bits(0)}); }
+    let ret: R = f(&mut *TIMER_WHEEL.lock());
+    unsafe{ p.TIMER0.ev_enable.write( |w| {w.bits(enabled)}); }
+    ret
+}
+
+/// timer0 interrupt handler, registered by `BtTimers::init()`. It only notes the tick: the
+/// wheel allocates as it goes, and the heap's lock isn't safe to take in interrupt context.
+pub fn timer_handle_irq(_irq: usize) {
+    let p: betrusted_pac::Peripherals = unsafe{ betrusted_pac::Peripherals::steal() };
+    let pending: u32 = p.TIMER0.ev_pending.read().bits();
+    unsafe{ p.TIMER0.ev_pending.write( |w| {w.bits(pending)}); }
+    TIMER_TICKED.store(true, Ordering::Release);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimerError {
+    /// time_init() hasn't started timer0
+    NotRunning,
+    /// the timer0 interrupt is already claimed
+    IrqUnavailable,
+}
+
+/// Software timer service
+///
+/// One-shot and periodic callbacks are kept on a TimerWheel. The timer0 interrupt flags each
+/// millisecond, and service(), called from the main loop, advances the wheel against the
+/// TICKTIMER and runs what came due. Deadlines are therefore in TICKTIMER time, timer0 only
+/// provides the tick, and callbacks are as late as the main loop is slow.
+///
+/// The TICKTIMER itself has no compare or interrupt in the current gateware; if one is added,
+/// only init() and the handler need to move over to it.
+pub struct BtTimers {
+    p: betrusted_pac::Peripherals,
+}
+
+impl BtTimers {
+    pub fn new() -> Self {
+        unsafe {
+            BtTimers {
+                p: betrusted_pac::Peripherals::steal(),
+            }
+        }
+    }
+
+    /// claim the timer0 interrupt and enable its event; time_init() must have been called
+    pub fn init(&mut self) -> Result<(), TimerError> {
+        if TIME_CLOCK_MHZ.load(Ordering::Relaxed) == 0 {
+            return Err(TimerError::NotRunning);
+        }
+        if xous_nommu::syscalls::sys_interrupt_claim(TIMER0_IRQ, timer_handle_irq).is_err() {
+            return Err(TimerError::IrqUnavailable);
+        }
+        unsafe{ self.p.TIMER0.ev_pending.write( |w| {w.bits(self.p.TIMER0.ev_pending.read().bits())}); }
+        unsafe{ self.p.TIMER0.ev_enable.write( |w| {w.bits(TIMER0_EV_ZERO)}); }
+        Ok(())
+    }
+
+    /// run `callback` once, `delay` from now
+    pub fn after(&mut self, delay: Duration, callback: TimerCallback) -> TimerId {
+        let p = &self.p;
+        with_wheel(p, |wheel| wheel.schedule(Instant::now(p), delay, None, callback))
+    }
+
+    /// run `callback` every `period`, starting one period from now
+    pub fn every(&mut self, period: Duration, callback: TimerCallback) -> TimerId {
+        let p = &self.p;
+        with_wheel(p, |wheel| wheel.schedule(Instant::now(p), period, Some(period), callback))
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        with_wheel(&self.p, |wheel| wheel.cancel(id))
+    }
+
+    /// run the callbacks that have come due since the last call, if timer0 has ticked
+    pub fn service(&mut self) {
+        if !TIMER_TICKED.swap(false, Ordering::Acquire) {
+            return;
+        }
+        let p = &self.p;
+        let fired: Vec<(TimerCallback, TimerId)> = with_wheel(p, |wheel| wheel.advance(Instant::now(p)));
+        for (f, id) in fired {
+            f(id);
+        }
+    }
+
+    pub fn stats(&self) -> TimerStats {
+        with_wheel(&self.p, |wheel| wheel.stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use alloc::vec;
+
+    struct FakeTicks {
+        now: Cell<u64>,
+    }
+
+    impl FakeTicks {
+        fn new(start: u64) -> Self {
+            FakeTicks { now: Cell::new(start) }
+        }
+        fn advance(&self, ticks: u64) {
+            self.now.set(self.now.get().wrapping_add(ticks));
+        }
+    }
+
+    impl TickSource for FakeTicks {
+        fn ticks(&self) -> u64 {
+            self.now.get()
+        }
+    }
+
+    #[test]
+    fn duration_arithmetic() {
+        assert_eq!(Duration::from_ms(3).as_us(), 3000);
+        assert_eq!(Duration::from_us(2999).as_ms(), 2);
+        assert_eq!(Duration::from_ms(1).checked_add(Duration::from_us(1)), Some(Duration::from_us(1001)));
+        assert_eq!(Duration::from_us(u64::max_value()).checked_add(Duration::from_us(1)), None);
+        assert_eq!(Duration::from_ms(1).checked_sub(Duration::from_ms(2)), None);
+        assert_eq!(Duration::from_ms(1).saturating_sub(Duration::from_ms(2)), Duration::default());
+        assert!(Duration::from_ms(1) < Duration::from_us(1001));
+    }
+
+    #[test]
+    fn elapsed_across_wrap() {
+        let clock = FakeTicks::new(u64::max_value() - 2);
+        let start = Instant::now(&clock);
+        clock.advance(5);
+        assert_eq!(clock.ticks(), 2);
+        assert_eq!(start.elapsed(&clock), Duration::from_ms(5));
+        assert_eq!(Instant::now(&clock).checked_duration_since(start), Some(Duration::from_ms(5)));
+        // the other way around is in the past
+        assert_eq!(start.checked_duration_since(Instant::now(&clock)), None);
+    }
+
+    #[test]
+    fn elapsed_across_u32_boundary() {
+        // the boundary where get_time_ms() used to wrap
+        let clock = FakeTicks::new(u32::max_value() as u64 - 1);
+        let start = Instant::now(&clock);
+        clock.advance(10);
+        assert_eq!(start.elapsed(&clock).as_ms(), 10);
+    }
+
+    #[test]
+    fn checked_add_wraps() {
+        let start = Instant::from_ticks(u64::max_value());
+        let later = start.checked_add(Duration::from_ms(2)).unwrap();
+        assert_eq!(later.ticks(), 1);
+        assert_eq!(later.checked_duration_since(start), Some(Duration::from_ms(2)));
+    }
+
+    #[test]
+    fn deadline_expires_strictly_after_timeout() {
+        let clock = FakeTicks::new(u64::max_value() - 1);
+        let deadline = Deadline::from_ms(&clock, 3);
+        assert!(!deadline.expired(&clock));
+        assert_eq!(deadline.remaining(&clock), Duration::from_ms(3));
+        clock.advance(3);
+        assert!(!deadline.expired(&clock));
+        assert_eq!(deadline.remaining(&clock), Duration::default());
+        clock.advance(1);
+        assert!(deadline.expired(&clock));
+    }
+
+    #[test]
+    fn clock_going_backwards_is_not_expired() {
+        let clock = FakeTicks::new(1000);
+        let deadline = Deadline::from_ms(&clock, 5);
+        clock.now.set(10);
+        assert!(!deadline.expired(&clock));
+    }
+
+    fn nop(_id: TimerId) {}
+
+    /// steps the simulated clock one tick at a time, the way the interrupt would, and
+    /// collects (tick, id) for everything that fires
+    fn run(wheel: &mut TimerWheel, clock: &FakeTicks, ticks: u64) -> Vec<(u64, TimerId)> {
+        let mut log: Vec<(u64, TimerId)> = Vec::new();
+        for _ in 0..ticks {
+            clock.advance(1);
+            for (_, id) in wheel.advance(Instant::now(clock)) {
+                log.push((clock.ticks(), id));
+            }
+        }
+        log
+    }
+
+    #[test]
+    fn one_shot_fires_once_on_time() {
+        let clock = FakeTicks::new(100);
+        let mut wheel = TimerWheel::new();
+        wheel.advance(Instant::now(&clock));
+        let id = wheel.schedule(Instant::now(&clock), Duration::from_ms(5), None, nop);
+        assert_eq!(run(&mut wheel, &clock, 20), vec![(105, id)]);
+        assert_eq!(wheel.pending(), 0);
+        assert_eq!(wheel.stats().fired, 1);
+        assert_eq!(wheel.stats().max_jitter, Duration::default());
+    }
+
+    #[test]
+    fn sub_tick_delays_round_up() {
+        let clock = FakeTicks::new(0);
+        let mut wheel = TimerWheel::new();
+        let a = wheel.schedule(Instant::now(&clock), Duration::from_us(0), None, nop);
+        let b = wheel.schedule(Instant::now(&clock), Duration::from_us(1001), None, nop);
+        assert_eq!(run(&mut wheel, &clock, 5), vec![(1, a), (2, b)]);
+    }
+
+    #[test]
+    fn periodic_keeps_phase() {
+        let clock = FakeTicks::new(0);
+        let mut wheel = TimerWheel::new();
+        let id = wheel.schedule(Instant::now(&clock), Duration::from_ms(3), Some(Duration::from_ms(10)), nop);
+        let log = run(&mut wheel, &clock, 35);
+        assert_eq!(log, vec![(3, id), (13, id), (23, id), (33, id)]);
+        assert_eq!(wheel.pending(), 1);
+    }
+
+    #[test]
+    fn cancel_stops_timers() {
+        let clock = FakeTicks::new(0);
+        let mut wheel = TimerWheel::new();
+        let once = wheel.schedule(Instant::now(&clock), Duration::from_ms(5), None, nop);
+        let periodic = wheel.schedule(Instant::now(&clock), Duration::from_ms(2), Some(Duration::from_ms(2)), nop);
+        assert!(wheel.cancel(once));
+        assert!(!wheel.cancel(once));
+        assert_eq!(run(&mut wheel, &clock, 4), vec![(2, periodic), (4, periodic)]);
+        assert!(wheel.cancel(periodic));
+        assert!(run(&mut wheel, &clock, 10).is_empty());
+        assert_eq!(wheel.pending(), 0);
+    }
+
+    #[test]
+    fn timers_beyond_one_lap() {
+        let clock = FakeTicks::new(0);
+        let mut wheel = TimerWheel::new();
+        let far = TIMER_WHEEL_SLOTS as u32 * 3 + 7;
+        let id = wheel.schedule(Instant::now(&clock), Duration::from_ms(far), None, nop);
+        let log = run(&mut wheel, &clock, far as u64 + 10);
+        assert_eq!(log, vec![(far as u64, id)]);
+    }
+
+    #[test]
+    fn late_service_records_jitter_and_missed_periods() {
+        let clock = FakeTicks::new(0);
+        let mut wheel = TimerWheel::new();
+        let once = wheel.schedule(Instant::now(&clock), Duration::from_ms(2), None, nop);
+        let periodic = wheel.schedule(Instant::now(&clock), Duration::from_ms(4), Some(Duration::from_ms(4)), nop);
+        // interrupts held off for 11 ticks: periodic was due at 4 and 8
+        clock.advance(11);
+        let fired: Vec<TimerId> = wheel.advance(Instant::now(&clock)).iter().map(|(_, id)| *id).collect();
+        assert_eq!(fired, vec![once, periodic]);
+        let stats = wheel.stats();
+        assert_eq!(stats.fired, 2);
+        assert_eq!(stats.missed, 1);
+        assert_eq!(stats.max_jitter, Duration::from_ms(9));
+        assert_eq!(stats.mean_jitter(), Duration::from_us(8000));
+        // back in phase afterwards
+        assert_eq!(run(&mut wheel, &clock, 5), vec![(12, periodic), (16, periodic)]);
+    }
+
+    #[test]
+    fn long_gap_catches_everything() {
+        let clock = FakeTicks::new(0);
+        let mut wheel = TimerWheel::new();
+        for ms in 1..(TIMER_WHEEL_SLOTS as u32 * 2) {
+            wheel.schedule(Instant::now(&clock), Duration::from_ms(ms), None, nop);
+        }
+        clock.advance(TIMER_WHEEL_SLOTS as u64 * 5);
+        assert_eq!(wheel.advance(Instant::now(&clock)).len(), TIMER_WHEEL_SLOTS * 2 - 1);
+        assert_eq!(wheel.pending(), 0);
+    }
+
+    #[test]
+    fn wheel_across_counter_wrap() {
+        let clock = FakeTicks::new(u64::max_value() - 5);
+        let mut wheel = TimerWheel::new();
+        wheel.advance(Instant::now(&clock));
+        let id = wheel.schedule(Instant::now(&clock), Duration::from_ms(4), Some(Duration::from_ms(4)), nop);
+        let log = run(&mut wheel, &clock, 12);
+        assert_eq!(log, vec![(u64::max_value() - 1, id), (2, id), (6, id)]);
+    }
+
+    #[test]
+    fn scheduling_behind_the_wheel_is_not_lost() {
+        let clock = FakeTicks::new(50);
+        let mut wheel = TimerWheel::new();
+        wheel.advance(Instant::now(&clock));
+        // a stale timestamp, as if taken just before the wheel was advanced
+        let id = wheel.schedule(Instant::from_ticks(40), Duration::from_ms(1), None, nop);
+        assert_eq!(run(&mut wheel, &clock, 2), vec![(51, id)]);
     }
 }
@@ -1,13 +1,123 @@
 const EV_TX: u32 = 1;
 const EV_RX: u32 = 2;
 
-use crate::hal_time::get_time_ms;
+use crate::hal_time::{Deadline, Duration};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+/// UART interrupt number, as assigned by the SoC interrupt map (uart = 0, timer0 = 1, i2c = 2)
+pub const UART_IRQ: usize = 0;
+
+/// size of each of the RX and TX rings; must be a power of two
+pub const UART_RING_SIZE: usize = 256;
+
+/// Single-producer, single-consumer byte ring. head and tail run freely and are only reduced
+/// modulo the size when indexing, so a full ring is head - tail == UART_RING_SIZE.
+struct UartRing {
+    buf: UnsafeCell<[u8; UART_RING_SIZE]>,
+    /// next slot to write; only advanced by the producer
+    head: AtomicUsize,
+    /// next slot to read; only advanced by the consumer
+    tail: AtomicUsize,
+}
+
+// the producer and consumer never touch the same slot at the same time
+unsafe impl Sync for UartRing {}
+
+impl UartRing {
+    const fn new() -> Self {
+        UartRing {
+            buf: UnsafeCell::new([0; UART_RING_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, c: u8) -> bool {
+        let head: usize = self.head.load(Ordering::Relaxed);
+        let tail: usize = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) >= UART_RING_SIZE {
+            return false;
+        }
+        unsafe{ (*self.buf.get())[head % UART_RING_SIZE] = c; }
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let tail: usize = self.tail.load(Ordering::Relaxed);
+        let head: usize = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let c: u8 = unsafe{ (*self.buf.get())[tail % UART_RING_SIZE] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(c)
+    }
+
+    fn len(&self) -> usize {
+        self.head.load(Ordering::Acquire).wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+}
+
+static UART_RX: UartRing = UartRing::new();
+static UART_TX: UartRing = UartRing::new();
+/// bytes dropped because the RX ring was full
+static UART_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+/// set once the interrupt handler is installed; until then everything is polled
+static UART_IRQ_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UartError {
+    /// the ring is full (write) or empty (read)
+    WouldBlock,
+    Timeout,
+    /// the UART interrupt is claimed by someone else; the UART stays polled
+    IrqUnavailable,
+}
+
+/// move bytes from the TX ring into the hardware FIFO until either runs out
+fn uart_tx_pump(p: &betrusted_pac::Peripherals) {
+    while p.UART.txfull.read().bits() == 0 {
+        match UART_TX.pop() {
+            Some(c) => unsafe{ p.UART.rxtx.write(|w| w.bits(c as u32)); },
+            None => break,
+        }
+    }
+}
+
+/// UART interrupt handler, registered by `BtUart::init()`
+pub fn uart_handle_irq(_irq: usize) {
+    let p: betrusted_pac::Peripherals = unsafe{ betrusted_pac::Peripherals::steal() };
+    let pending: u32 = p.UART.ev_pending.read().bits();
+
+    // acking EV_RX is what pops the RX FIFO, so drain it byte by byte
+    while p.UART.rxempty.read().bits() == 0 {
+        let c: u8 = p.UART.rxtx.read().bits() as u8;
+        unsafe{ p.UART.ev_pending.write(|w| w.bits(EV_RX)); }
+        if !UART_RX.push(c) {
+            UART_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    if pending & EV_TX != 0 {
+        unsafe{ p.UART.ev_pending.write(|w| w.bits(EV_TX)); }
+        uart_tx_pump(&p);
+    }
+}
 
 pub struct BtUart {
     p: betrusted_pac::Peripherals,
 }
 
-/// A simple polled implementation of a UART interface
+/// Interrupt-driven UART
+///
+/// Received bytes are moved into an RX ring by the interrupt handler, and writes go into a TX
+/// ring which the handler feeds to the hardware FIFO as it drains. The rings are global, so any
+/// number of BtUart handles (the REPL, log output, the JTAG UART PHY) share the port. Bytes
+/// that arrive while the RX ring is full are dropped and counted in overruns().
+///
+/// Until init() has installed the handler, reads and writes go straight to the hardware.
 impl BtUart {
     pub fn new() -> Self {
         unsafe {
@@ -17,32 +127,174 @@ impl BtUart {
         }
     }
 
-    pub fn init(&mut self) {
-            unsafe { self.p.UART.ev_pending.write(|w| w.bits(self.p.UART.ev_pending.read().bits())); }
-            unsafe { self.p.UART.ev_enable.write(|w| w.bits(EV_TX | EV_RX)); }
+    /// Claim the UART interrupt and enable RX and TX events. Only the first call does anything,
+    /// so every user of the port can call it.
+    pub fn init(&mut self) -> Result<(), UartError> {
+        if UART_IRQ_ACTIVE.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        if xous_nommu::syscalls::sys_interrupt_claim(UART_IRQ, uart_handle_irq).is_err() {
+            return Err(UartError::IrqUnavailable);
+        }
+        UART_IRQ_ACTIVE.store(true, Ordering::Release);
+        unsafe { self.p.UART.ev_pending.write(|w| w.bits(self.p.UART.ev_pending.read().bits())); }
+        unsafe { self.p.UART.ev_enable.write(|w| w.bits(EV_TX | EV_RX)); }
+        Ok(())
+    }
+
+    /// Run `f` with UART events masked, so that the interrupt handler and the caller
+    /// don't both consume from the TX ring.
+    fn with_irq_masked<R, F: FnOnce(&betrusted_pac::Peripherals) -> R>(&self, f: F) -> R {
+        let enabled: u32 = self.p.UART.ev_enable.read().bits();
+        unsafe{ self.p.UART.ev_enable.write(|w| w.bits(0)); }
+        let ret: R = f(&self.p);
+        unsafe{ self.p.UART.ev_enable.write(|w| w.bits(enabled)); }
+        ret
     }
-    
+
+    /// queue a byte without waiting; WouldBlock if the TX ring is full
+    pub fn try_write(&mut self, c: u8) -> Result<(), UartError> {
+        if !UART_IRQ_ACTIVE.load(Ordering::Acquire) {
+            if self.p.UART.txfull.read().bits() != 0 {
+                return Err(UartError::WouldBlock);
+            }
+            unsafe { self.p.UART.rxtx.write(|w| w.bits(c as u32)); }
+            return Ok(());
+        }
+        let queued: bool = UART_TX.push(c);
+        // the TX event only fires when the FIFO drains, so the first bytes have to be
+        // pushed into an idle FIFO from here
+        self.with_irq_masked(|p| uart_tx_pump(p));
+        if queued { Ok(()) } else { Err(UartError::WouldBlock) }
+    }
+
+    /// queue a byte, waiting for room if the TX ring is full
     pub fn write(&mut self, c: u8) {
-        while self.p.UART.txfull.read().bits() != 0 {}
-        unsafe { self.p.UART.rxtx.write(|w| w.bits(c as u32)); }
-        unsafe { self.p.UART.ev_pending.write(|w| w.bits(EV_TX)); }
+        while self.try_write(c).is_err() {}
     }
 
-    pub fn read(&mut self) -> u8 {
-        let starttime: u32 = get_time_ms(&self.p);
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        for c in data {
+            self.write(*c);
+        }
+    }
+
+    /// take a received byte without waiting; WouldBlock if nothing has arrived
+    pub fn try_read(&mut self) -> Result<u8, UartError> {
+        if !UART_IRQ_ACTIVE.load(Ordering::Acquire) {
+            if self.p.UART.rxempty.read().bits() != 0 {
+                return Err(UartError::WouldBlock);
+            }
+            let c: u8 = self.p.UART.rxtx.read().bits() as u8;
+            unsafe { self.p.UART.ev_pending.write(|w| w.bits(EV_RX)); }
+            return Ok(c);
+        }
+        UART_RX.pop().ok_or(UartError::WouldBlock)
+    }
 
-        while (self.p.UART.rxempty.read().bits() != 0) && (get_time_ms(&self.p) - starttime) < 3 {}
-        let c: u8 = self.p.UART.rxtx.read().bits() as u8;
-        unsafe { self.p.UART.ev_pending.write(|w| w.bits(EV_RX)); }
+    /// wait for a received byte
+    pub fn read(&mut self) -> u8 {
+        loop {
+            if let Ok(c) = self.try_read() {
+                return c;
+            }
+        }
+    }
 
-        c
+    /// wait at most `timeout` for a received byte
+    pub fn read_timeout(&mut self, timeout: Duration) -> Result<u8, UartError> {
+        let deadline: Deadline = Deadline::new(&self.p, timeout);
+        loop {
+            if let Ok(c) = self.try_read() {
+                return Ok(c);
+            }
+            if deadline.expired(&self.p) {
+                return Err(UartError::Timeout);
+            }
+        }
     }
 
+    /// true if a received byte is waiting
     pub fn read_nonblock(&self) -> bool {
-        self.p.UART.rxempty.read().bits() == 0
+        if UART_IRQ_ACTIVE.load(Ordering::Acquire) {
+            UART_RX.len() != 0
+        } else {
+            self.p.UART.rxempty.read().bits() == 0
+        }
+    }
+
+    /// number of received bytes dropped since boot because they weren't read in time
+    pub fn overruns(&self) -> u32 {
+        UART_OVERRUNS.load(Ordering::Relaxed)
     }
 
+    /// wait until everything queued has been handed to the hardware
     pub fn uart_sync(&self) {
+        while UART_TX.len() != 0 {}
         while self.p.UART.txfull.read().bits() != 0 {}
     }
-}
\ No newline at end of file
+}
+
+/// lets the host link run over the UART
+impl host_link::LinkIo for BtUart {
+    fn write(&mut self, data: &[u8]) {
+        self.write_bytes(data);
+    }
+
+    fn read(&mut self, timeout_ms: u32) -> Option<u8> {
+        self.read_timeout(Duration::from_ms(timeout_ms)).ok()
+    }
+}
+
+impl core::fmt::Write for BtUart {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_is_fifo() {
+        let ring = UartRing::new();
+        assert_eq!(ring.pop(), None);
+        for c in 0..10 {
+            assert!(ring.push(c));
+        }
+        assert_eq!(ring.len(), 10);
+        for c in 0..10 {
+            assert_eq!(ring.pop(), Some(c));
+        }
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn ring_reports_full() {
+        let ring = UartRing::new();
+        for c in 0..UART_RING_SIZE {
+            assert!(ring.push(c as u8));
+        }
+        assert!(!ring.push(0xFF));
+        assert_eq!(ring.pop(), Some(0));
+        assert!(ring.push(0xFF));
+        assert_eq!(ring.len(), UART_RING_SIZE);
+    }
+
+    #[test]
+    fn ring_indices_wrap() {
+        let ring = UartRing::new();
+        ring.head.store(usize::max_value() - 2, Ordering::Relaxed);
+        ring.tail.store(usize::max_value() - 2, Ordering::Relaxed);
+        for c in 0..8 {
+            assert!(ring.push(c));
+        }
+        assert_eq!(ring.len(), 8);
+        for c in 0..8 {
+            assert_eq!(ring.pop(), Some(c));
+        }
+        assert_eq!(ring.len(), 0);
+    }
+}
@@ -0,0 +1,423 @@
+//! USB Type-C sink detection
+//!
+//! Works out from the XADC's readings of VBUS and the two CC lines whether a source is
+//! attached, which way round the plug is, and how much current the source offers. A source
+//! pulls up the CC line the cable connects through against our Rd, and the voltage that
+//! results tells its current rating. The attach is debounced for tCCDebounce as the Type-C
+//! spec asks, and detach and changes of advertisement for tPDDebounce.
+//!
+//! The CC inputs saturate at 1.0V, below the spec's 1.23V boundary between the 1.5A and 3.0A
+//! bands. A 1.5A source pulls up to about 0.94V nominally, and one at the top of its tolerance
+//! saturates the input as a 3.0A source does, so anything above the 1.5A threshold is taken as
+//! 1.5A; a 3.0A source is never drawn on for more than it would be safe to draw from a 1.5A one.
+//!
+//! BoostPolicy decides what the charger's boost mode should do as sources come and go.
+
+use crate::hal_time::{Duration, Instant};
+
+/// VBUS above this, with a source on CC, is an attach
+pub const VBUS_PRESENT_MV: u16 = 4000;
+/// VBUS below this is a detach (vSinkDisconnect)
+pub const VBUS_DISCONNECT_MV: u16 = 3670;
+/// a CC line above this sees a source's pull-up: default USB current
+pub const CC_RP_MIN_MV: u16 = 200;
+/// above this, 1.5A, or 3.0A, which the CC inputs can't tell apart
+pub const CC_RP_1A5_MV: u16 = 660;
+
+/// tCCDebounce
+pub const ATTACH_DEBOUNCE_MS: u32 = 150;
+/// tPDDebounce
+pub const PD_DEBOUNCE_MS: u32 = 15;
+
+/// which CC line the source is on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    Cc1,
+    Cc2,
+}
+
+/// the current a source advertises
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SourceCurrent {
+    /// whatever USB itself allows
+    Default,
+    /// 1.5A, or more
+    Current1A5,
+}
+
+impl SourceCurrent {
+    /// from the voltage on the CC line the source is on; None if there's no pull-up
+    pub fn from_cc_mv(mv: u16) -> Option<Self> {
+        if mv >= CC_RP_1A5_MV {
+            Some(SourceCurrent::Current1A5)
+        } else if mv >= CC_RP_MIN_MV {
+            Some(SourceCurrent::Default)
+        } else {
+            None
+        }
+    }
+
+    /// what may be drawn, taking the default as USB 2.0's 500mA
+    pub fn ma(&self) -> u16 {
+        match self {
+            SourceCurrent::Default => 500,
+            SourceCurrent::Current1A5 => 1500,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TypeCState {
+    Unattached,
+    /// orientation is None for VBUS without a pull-up on either CC line, as from a legacy
+    /// charger, which is only good for the default current
+    Attached { orientation: Option<Orientation>, current: SourceCurrent },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TypeCEvent {
+    Attached { orientation: Option<Orientation>, current: SourceCurrent },
+    /// the source changed its advertisement while attached
+    CurrentChanged(SourceCurrent),
+    Detached,
+}
+
+pub struct TypeCSink {
+    state: TypeCState,
+    /// a different state the readings have shown, and since when
+    candidate: Option<(TypeCState, Instant)>,
+}
+
+impl TypeCSink {
+    pub fn new() -> Self {
+        TypeCSink { state: TypeCState::Unattached, candidate: None }
+    }
+
+    pub fn state(&self) -> TypeCState {
+        self.state
+    }
+
+    /// what one set of readings says, before debouncing
+    fn sample(&self, vbus_mv: u16, cc1_mv: u16, cc2_mv: u16) -> TypeCState {
+        match self.state {
+            TypeCState::Unattached => {
+                if vbus_mv < VBUS_PRESENT_MV {
+                    return TypeCState::Unattached;
+                }
+                // the other line sees only our Rd, or a cable's Ra
+                let (orientation, cc) = if cc1_mv >= cc2_mv { (Orientation::Cc1, cc1_mv) } else { (Orientation::Cc2, cc2_mv) };
+                match SourceCurrent::from_cc_mv(cc) {
+                    Some(current) => TypeCState::Attached { orientation: Some(orientation), current },
+                    None => TypeCState::Attached { orientation: None, current: SourceCurrent::Default },
+                }
+            },
+            TypeCState::Attached { orientation, current } => {
+                if vbus_mv < VBUS_DISCONNECT_MV {
+                    return TypeCState::Unattached;
+                }
+                // VBUS alone may be our own boost; a source that turns up on CC meanwhile is an attach
+                if orientation.is_none() {
+                    let (orientation, cc) = if cc1_mv >= cc2_mv { (Orientation::Cc1, cc1_mv) } else { (Orientation::Cc2, cc2_mv) };
+                    if let Some(current) = SourceCurrent::from_cc_mv(cc) {
+                        return TypeCState::Attached { orientation: Some(orientation), current };
+                    }
+                }
+                // the plug can't turn over without a detach, so only the line in use matters
+                let current: SourceCurrent = match orientation {
+                    Some(Orientation::Cc1) => SourceCurrent::from_cc_mv(cc1_mv).unwrap_or(current),
+                    Some(Orientation::Cc2) => SourceCurrent::from_cc_mv(cc2_mv).unwrap_or(current),
+                    None => current,
+                };
+                TypeCState::Attached { orientation, current }
+            },
+        }
+    }
+
+    /// Feed a set of readings taken at `now`; returns an event once a change has been
+    /// seen for its debounce time.
+    pub fn update(&mut self, now: Instant, vbus_mv: u16, cc1_mv: u16, cc2_mv: u16) -> Option<TypeCEvent> {
+        let seen: TypeCState = self.sample(vbus_mv, cc1_mv, cc2_mv);
+        if seen == self.state {
+            self.candidate = None;
+            return None;
+        }
+        // while attaching, the advertisement may still be settling; it's taken from the last reading
+        let attaching: bool = matches!((self.state, seen),
+            (TypeCState::Unattached, TypeCState::Attached { .. })
+            | (TypeCState::Attached { orientation: None, .. }, TypeCState::Attached { orientation: Some(_), .. }));
+        let key: TypeCState = match seen {
+            TypeCState::Attached { orientation, .. } if attaching => {
+                TypeCState::Attached { orientation, current: SourceCurrent::Default }
+            },
+            _ => seen,
+        };
+        let since: Instant = match self.candidate {
+            Some((state, since)) if state == key => since,
+            _ => {
+                self.candidate = Some((key, now));
+                now
+            },
+        };
+        let debounce: Duration = if attaching {
+            Duration::from_ms(ATTACH_DEBOUNCE_MS)
+        } else {
+            Duration::from_ms(PD_DEBOUNCE_MS)
+        };
+        if now.checked_duration_since(since).map_or(true, |d| d < debounce) {
+            return None;
+        }
+
+        let was: TypeCState = self.state;
+        self.state = seen;
+        self.candidate = None;
+        Some(match (was, seen) {
+            (_, TypeCState::Unattached) => TypeCEvent::Detached,
+            (_, TypeCState::Attached { orientation, current }) if attaching => TypeCEvent::Attached { orientation, current },
+            (_, TypeCState::Attached { current, .. }) => TypeCEvent::CurrentChanged(current),
+        })
+    }
+}
+
+/// Boost mode powers the USB port from the battery, which can't be done against a source. The
+/// policy holds boost off while a source is attached and puts back what was last asked for once
+/// it's gone. Each method returns the setting the charger should be given now, if it changes.
+///
+/// While boosting, VBUS is our own, so VBUS without a pull-up on CC isn't taken for a source;
+/// only one that shows up on CC is. A legacy charger plugged in meanwhile goes unnoticed.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BoostPolicy {
+    /// boost as last asked for
+    wanted: bool,
+    /// a source is attached
+    source: bool,
+}
+
+impl BoostPolicy {
+    pub fn new() -> Self {
+        BoostPolicy::default()
+    }
+
+    /// whether the charger should be boosting
+    pub fn boosting(&self) -> bool {
+        self.wanted && !self.source
+    }
+
+    /// ask for boost on or off; while a source is attached, it's remembered for the detach
+    pub fn request(&mut self, boost: bool) -> Option<bool> {
+        self.wanted = boost;
+        if self.source {
+            None
+        } else {
+            Some(boost)
+        }
+    }
+
+    pub fn event(&mut self, event: TypeCEvent) -> Option<bool> {
+        match event {
+            TypeCEvent::Attached { orientation: None, .. } if self.boosting() => None,
+            TypeCEvent::Attached { .. } => {
+                self.source = true;
+                Some(false)
+            },
+            TypeCEvent::Detached if self.source => {
+                self.source = false;
+                if self.wanted { Some(true) } else { None }
+            },
+            TypeCEvent::Detached => None,
+            TypeCEvent::CurrentChanged(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// (ms, vbus, cc1, cc2), as the XADC read them
+    type Trace = [(u64, u16, u16, u16)];
+
+    /// a default-current charger on CC1 with contact bounce on plugging, later raising its
+    /// offer to 1.5A and then 3.0A, which saturates the input, a sag in VBUS under load, then
+    /// unplugged
+    const SESSION: &Trace = &[
+        (0, 20, 0, 0),
+        (10, 20, 410, 0),
+        (20, 20, 0, 0),
+        (30, 25, 412, 0),
+        (140, 4700, 415, 0),
+        (200, 5030, 411, 2),
+        (290, 5020, 414, 1),
+        (300, 5010, 410, 0),
+        (310, 5010, 940, 0),
+        (320, 5000, 412, 0),
+        (400, 5010, 940, 0),
+        (410, 5000, 941, 0),
+        (420, 5010, 939, 0),
+        (430, 5010, 1000, 0),
+        (450, 5010, 1000, 0),
+        (500, 3500, 1000, 0),
+        (510, 4950, 1000, 0),
+        (600, 4200, 1000, 0),
+        (610, 3100, 990, 0),
+        (620, 1800, 400, 0),
+        (630, 600, 0, 0),
+        (700, 20, 0, 0),
+        (800, 20, 0, 0),
+    ];
+
+    /// a 3.0A source on CC2, with the CC reading noisy around saturation while VBUS comes up
+    const PLUG_3A_CC2: &Trace = &[
+        (0, 30, 0, 0),
+        (10, 30, 0, 1000),
+        (20, 30, 0, 8),
+        (30, 35, 0, 1000),
+        (160, 2400, 0, 1000),
+        (170, 4850, 0, 998),
+        (200, 5010, 0, 1000),
+        (250, 5000, 0, 975),
+        (300, 5020, 0, 999),
+        (320, 5010, 0, 1000),
+        (400, 5010, 0, 1000),
+    ];
+
+    /// a USB-A to C cable, whose 56k pull-up advertises the default current
+    const LEGACY_CABLE: &Trace = &[
+        (0, 0, 0, 0),
+        (20, 0, 410, 0),
+        (40, 5000, 415, 0),
+        (100, 5000, 420, 0),
+        (190, 5000, 418, 1),
+        (200, 5000, 421, 0),
+    ];
+
+    /// a charger that puts VBUS on without any pull-up on CC
+    const NO_CC: &Trace = &[
+        (0, 0, 0, 0),
+        (50, 4900, 3, 2),
+        (100, 5000, 2, 1),
+        (150, 5000, 0, 3),
+        (200, 5010, 1, 1),
+        (210, 5000, 2, 2),
+    ];
+
+    fn run(trace: &Trace) -> (TypeCSink, Vec<(u64, TypeCEvent)>) {
+        let mut sink = TypeCSink::new();
+        let mut events = Vec::new();
+        for &(ms, vbus, cc1, cc2) in trace {
+            if let Some(event) = sink.update(Instant::from_ticks(ms), vbus, cc1, cc2) {
+                events.push((ms, event));
+            }
+        }
+        (sink, events)
+    }
+
+    #[test]
+    fn cc_bands() {
+        assert_eq!(SourceCurrent::from_cc_mv(0), None);
+        assert_eq!(SourceCurrent::from_cc_mv(CC_RP_MIN_MV - 1), None);
+        assert_eq!(SourceCurrent::from_cc_mv(CC_RP_MIN_MV), Some(SourceCurrent::Default));
+        assert_eq!(SourceCurrent::from_cc_mv(610), Some(SourceCurrent::Default));
+        assert_eq!(SourceCurrent::from_cc_mv(CC_RP_1A5_MV), Some(SourceCurrent::Current1A5));
+        assert_eq!(SourceCurrent::from_cc_mv(940), Some(SourceCurrent::Current1A5));
+        // saturated: a 3.0A source, or a 1.5A one at the top of its tolerance
+        assert_eq!(SourceCurrent::from_cc_mv(1000), Some(SourceCurrent::Current1A5));
+        assert_eq!(SourceCurrent::from_cc_mv(u16::MAX), Some(SourceCurrent::Current1A5));
+        assert_eq!(SourceCurrent::Current1A5.ma(), 1500);
+    }
+
+    #[test]
+    fn charger_session() {
+        let (sink, events) = run(SESSION);
+        assert_eq!(
+            events,
+            [
+                (290, TypeCEvent::Attached { orientation: Some(Orientation::Cc1), current: SourceCurrent::Default }),
+                (420, TypeCEvent::CurrentChanged(SourceCurrent::Current1A5)),
+                (630, TypeCEvent::Detached),
+            ]
+        );
+        assert_eq!(sink.state(), TypeCState::Unattached);
+    }
+
+    #[test]
+    fn attach_waits_for_vbus_and_settles() {
+        // the noisy reading at 250ms doesn't restart the wait, and the last reading sets the
+        // current, which for a saturated input is 1.5A
+        let (sink, events) = run(PLUG_3A_CC2);
+        let attached = TypeCState::Attached { orientation: Some(Orientation::Cc2), current: SourceCurrent::Current1A5 };
+        assert_eq!(
+            events,
+            [(320, TypeCEvent::Attached { orientation: Some(Orientation::Cc2), current: SourceCurrent::Current1A5 })]
+        );
+        assert_eq!(sink.state(), attached);
+    }
+
+    #[test]
+    fn legacy_sources() {
+        let (_, events) = run(LEGACY_CABLE);
+        assert_eq!(events, [(190, TypeCEvent::Attached { orientation: Some(Orientation::Cc1), current: SourceCurrent::Default })]);
+        let (_, events) = run(NO_CC);
+        assert_eq!(events, [(200, TypeCEvent::Attached { orientation: None, current: SourceCurrent::Default })]);
+    }
+
+    #[test]
+    fn cc_without_vbus_never_attaches() {
+        let mut sink = TypeCSink::new();
+        for ms in (0..2000).step_by(10) {
+            assert_eq!(sink.update(Instant::from_ticks(ms), 40, 940, 0), None);
+        }
+        assert_eq!(sink.state(), TypeCState::Unattached);
+    }
+
+    #[test]
+    fn boost_waits_out_a_source() {
+        let attach = TypeCEvent::Attached { orientation: Some(Orientation::Cc1), current: SourceCurrent::Default };
+        let mut policy = BoostPolicy::new();
+        assert_eq!(policy.request(true), Some(true));
+        assert_eq!(policy.event(attach), Some(false));
+        assert!(!policy.boosting());
+        assert_eq!(policy.event(TypeCEvent::CurrentChanged(SourceCurrent::Current1A5)), None);
+        assert_eq!(policy.event(TypeCEvent::Detached), Some(true));
+        assert!(policy.boosting());
+
+        // asking while a source is attached takes effect on the detach
+        assert_eq!(policy.request(false), Some(false));
+        policy.event(attach);
+        assert_eq!(policy.request(true), None);
+        assert_eq!(policy.event(TypeCEvent::Detached), Some(true));
+        policy.event(attach);
+        assert_eq!(policy.request(false), None);
+        assert_eq!(policy.event(TypeCEvent::Detached), None);
+        assert!(!policy.boosting());
+    }
+
+    #[test]
+    fn boost_ignores_its_own_vbus() {
+        let mut sink = TypeCSink::new();
+        let mut policy = BoostPolicy::new();
+        let mut boost: bool = policy.request(true).unwrap();
+        let mut changes: u32 = 0;
+        // VBUS is up whenever boost is on; a charger on CC1 comes along at 1s
+        for ms in (0..2000).step_by(10) {
+            let (vbus, cc1) = match (boost, ms >= 1000) {
+                (_, true) => (5000, 940),
+                (true, false) => (5000, 0),
+                (false, false) => (20, 0),
+            };
+            if let Some(b) = sink.update(Instant::from_ticks(ms), vbus, cc1, 0).and_then(|e| policy.event(e)) {
+                boost = b;
+                changes += 1;
+            }
+        }
+        // it never turns itself off, until the charger does it
+        assert_eq!(changes, 1);
+        assert!(!boost && !policy.boosting());
+        assert_eq!(sink.state(), TypeCState::Attached { orientation: Some(Orientation::Cc1), current: SourceCurrent::Current1A5 });
+
+        // without boost, VBUS alone is a legacy charger
+        let mut policy = BoostPolicy::new();
+        assert_eq!(policy.event(TypeCEvent::Attached { orientation: None, current: SourceCurrent::Default }), Some(false));
+        assert_eq!(policy.request(true), None);
+    }
+}
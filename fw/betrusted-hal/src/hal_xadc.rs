@@ -1,3 +1,6 @@
+use core::fmt;
+use crate::hal_time::Deadline;
+
 
 pub enum XadcRegs {
     Temperature = 0x0,
@@ -8,6 +11,11 @@ pub enum XadcRegs {
     VrefN = 5,
     VccBram = 6,
 
+    // calibration coefficients, measured when the calibration channel is in the sequence
+    CalSupplyOffset = 0x08,
+    CalAdcOffset = 0x09,
+    CalAdcGain = 0x0A,
+
     Vaux0 = 16,
     Vaux1 = 17,
     Vaux2 = 18,
@@ -182,6 +190,177 @@ pub enum XadcCalBitMask {
     SupplyOffsetAndGain = 4,
 }
 
+/// Config1 apart from the sequencer mode: disables the alarms for supplies not present on
+/// this chip and enables all the others. The calibration bits are left clear, so results come
+/// out uncorrected and XadcCalibration applies the coefficients; that way readings, the MinMax
+/// registers and alarm thresholds all go through the same conversion.
+const XADC_CONFIG1: u16 = 0x0E00;
+
+/// full scale of the supply sensors
+const SUPPLY_FULL_SCALE_MV: u64 = 3000;
+/// full scale of the auxiliary inputs
+const AUX_FULL_SCALE_UV: u64 = 1_000_000;
+/// temperature transfer function: code * 503.975 / 2^16 - 273.15 in degrees C
+const TEMP_FULL_SCALE_MC: u64 = 503_975;
+const TEMP_OFFSET_MC: i64 = 273_150;
+/// VBUS reaches the ADC through a divider of 0.0485, here in parts per 10,000
+const VBUS_DIVIDER: u64 = 485;
+/// A pass through the sequence takes well under a millisecond; one that hasn't finished by
+/// this long means the XADC isn't running.
+pub const XADC_UPDATE_TIMEOUT_MS: u32 = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum XadcError {
+    /// the sequence didn't finish within XADC_UPDATE_TIMEOUT_MS
+    Timeout,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Millivolts(pub u32);
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MilliCelsius(pub i32);
+
+impl fmt::Display for Millivolts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:03}V", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+impl fmt::Display for MilliCelsius {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign: &str = if self.0 < 0 { "-" } else { "" };
+        let m: u32 = self.0.wrapping_abs() as u32;
+        write!(f, "{}{}.{:02}C", sign, m / 1000, m % 1000 / 10)
+    }
+}
+
+/// The supply rails with their own sensor and MinMax registers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum XadcSupply {
+    VccInt,
+    VccAux,
+    VccBram,
+}
+
+impl XadcSupply {
+    fn reg(self) -> XadcRegs {
+        match self {
+            XadcSupply::VccInt => XadcRegs::VccInt,
+            XadcSupply::VccAux => XadcRegs::VccAux,
+            XadcSupply::VccBram => XadcRegs::VccBram,
+        }
+    }
+
+    fn min_reg(self) -> XadcRegs {
+        match self {
+            XadcSupply::VccInt => XadcRegs::MinVccInt,
+            XadcSupply::VccAux => XadcRegs::MinVccAux,
+            XadcSupply::VccBram => XadcRegs::MinVccBram,
+        }
+    }
+
+    fn alarm_regs(self) -> (XadcRegs, XadcRegs) {
+        match self {
+            XadcSupply::VccInt => (XadcRegs::AlarmVccIntLower, XadcRegs::AlarmVccIntUpper),
+            XadcSupply::VccAux => (XadcRegs::AlarmVccAuxLower, XadcRegs::AlarmVccAuxUpper),
+            XadcSupply::VccBram => (XadcRegs::AlarmVccBramLower, XadcRegs::AlarmVccBramUpper),
+        }
+    }
+
+    fn max_reg(self) -> XadcRegs {
+        match self {
+            XadcSupply::VccInt => XadcRegs::MaxVccInt,
+            XadcSupply::VccAux => XadcRegs::MaxVccAux,
+            XadcSupply::VccBram => XadcRegs::MaxVccBram,
+        }
+    }
+}
+
+/// one pass over the sensors the system is supervised by
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct XadcReadings {
+    pub temperature: MilliCelsius,
+    pub vccint: Millivolts,
+    pub vccaux: Millivolts,
+    pub vccbram: Millivolts,
+}
+
+/// The XADC's offset and gain calibration coefficients, and the conversions from its 16-bit
+/// result registers (12-bit codes, MSB-justified, with averaging filling in the low bits) to
+/// engineering units, all in integer arithmetic.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct XadcCalibration {
+    /// offset of the supply sensors, in 16-bit result units
+    pub supply_offset: i32,
+    /// offset of the ADC for everything else
+    pub adc_offset: i32,
+    /// gain error, in tenths of a percent
+    pub gain_tenths: i32,
+}
+
+impl XadcCalibration {
+    /// from the CalSupplyOffset, CalAdcOffset and CalAdcGain registers
+    pub fn from_regs(supply_offset: u16, adc_offset: u16, gain: u16) -> Self {
+        // offsets are 12-bit two's complement in the top bits, as results are
+        let offset = |reg: u16| (reg & 0xFFF0) as i16 as i32;
+        // gain is sign and magnitude: bit 6 set for positive, bits 5:0 in 0.1% steps
+        let magnitude: i32 = (gain & 0x3F) as i32;
+        XadcCalibration {
+            supply_offset: offset(supply_offset),
+            adc_offset: offset(adc_offset),
+            gain_tenths: if gain & 0x40 != 0 { magnitude } else { -magnitude },
+        }
+    }
+
+    /// the result register with the coefficients applied, still in 16-bit units
+    fn correct(&self, raw: u16, supply: bool) -> u64 {
+        let offset: i64 = if supply { self.supply_offset } else { self.adc_offset } as i64;
+        let gain: i64 = 1000 + self.gain_tenths as i64;
+        let code: i64 = ((raw as i64 - offset) * 1000 + gain / 2) / gain;
+        code.clamp(0, 0xFFFF) as u64
+    }
+
+    /// the inverse of correct(), for a reading of `value` out of `full_scale`
+    fn uncorrect(&self, value: i64, full_scale: u64, supply: bool) -> u16 {
+        let offset: i64 = if supply { self.supply_offset } else { self.adc_offset } as i64;
+        let num: i64 = value * 0x1_0000 * (1000 + self.gain_tenths as i64);
+        let den: i64 = full_scale as i64 * 1000;
+        let code: i64 = (num + den / 2) / den + offset;
+        code.clamp(0, 0xFFFF) as u16
+    }
+
+    /// the result register that would read as `mv` on a supply sensor, for alarm thresholds
+    pub fn supply_code(&self, mv: Millivolts) -> u16 {
+        self.uncorrect(mv.0 as i64, SUPPLY_FULL_SCALE_MV, true)
+    }
+
+    /// the result register that would read as `t` on the temperature sensor
+    pub fn temperature_code(&self, t: MilliCelsius) -> u16 {
+        self.uncorrect(t.0 as i64 + TEMP_OFFSET_MC, TEMP_FULL_SCALE_MC, false)
+    }
+
+    pub fn supply(&self, raw: u16) -> Millivolts {
+        Millivolts(((self.correct(raw, true) * SUPPLY_FULL_SCALE_MV + 0x8000) >> 16) as u32)
+    }
+
+    pub fn temperature(&self, raw: u16) -> MilliCelsius {
+        let mc: i64 = ((self.correct(raw, false) * TEMP_FULL_SCALE_MC + 0x8000) >> 16) as i64;
+        MilliCelsius((mc - TEMP_OFFSET_MC) as i32)
+    }
+
+    /// an auxiliary input, unipolar with 1V full scale
+    pub fn aux(&self, raw: u16) -> Millivolts {
+        Millivolts(((self.correct(raw, false) * AUX_FULL_SCALE_UV / 1000 + 0x8000) >> 16) as u32)
+    }
+
+    /// VBUS, through its divider on Vaux9
+    pub fn vbus(&self, raw: u16) -> Millivolts {
+        let uv: u64 = (self.correct(raw, false) * AUX_FULL_SCALE_UV + 0x8000) >> 16;
+        Millivolts((uv * 10 / VBUS_DIVIDER) as u32)
+    }
+}
+
 pub enum BtXadcMode {
     RoundRobin, // round robin sampling of active ports
     Stream,     // streaming of just one channel -- TODO
@@ -190,6 +369,7 @@ pub enum BtXadcMode {
 pub struct BtXadc {
     p: betrusted_pac::Peripherals,
     mode: BtXadcMode,
+    cal: XadcCalibration,
 }
 
 impl BtXadc {
@@ -200,18 +380,18 @@ impl BtXadc {
         const CC2: XadcSeq1Mask = XadcSeq1Mask::Vaux11;
         const VBUS: XadcSeq1Mask = XadcSeq1Mask::Vaux9;
 
-        let ret: BtXadc;
+        let mut ret: BtXadc;
         unsafe {
             ret = BtXadc {
                 p: betrusted_pac::Peripherals::steal(),
                 mode: BtXadcMode::RoundRobin,
+                cal: XadcCalibration::default(),
             };
         }
         xadc_enable(&ret.p, true);
 
-        // 0x0EF0 is constant -- disables alarms not present on this chip, enables calibration, enables all other alarms
         // set to default before updating the sequence table
-        xadc_write(&ret.p, XadcRegs::Config1, ((XadcSeq::Default_ as u16) << 12) | 0x0EF0 );
+        xadc_write(&ret.p, XadcRegs::Config1, ((XadcSeq::Default_ as u16) << 12) | XADC_CONFIG1 );
 
         // setup the sequencing registers
         xadc_write(&ret.p, XadcRegs::Seq0, XadcSeq0Mask::VccBram as u16 | XadcSeq0Mask::Dedicated as u16 |
@@ -235,16 +415,33 @@ impl BtXadc {
         xadc_write(&ret.p, XadcRegs::SeqSettling1, 0);
 
         // once sequence is set, move to continuous mode. XADC is reset upon changing sequence mode
-        // 0x0EF0 is constant -- disables alarms not present on this chip, enables calibration, enables all other alarms
-        xadc_write(&ret.p, XadcRegs::Config1, ((XadcSeq::Continuous as u16) << 12) | 0x0EF0 );
+        xadc_write(&ret.p, XadcRegs::Config1, ((XadcSeq::Continuous as u16) << 12) | XADC_CONFIG1 );
         // 0x8000 is constant -- disables averaging of cal bit
         xadc_write(&ret.p, XadcRegs::Config0, 0x8000 | (XadcFilter::Avg16 as u16) << 12);
         // 0x0400 is constant -- sets DCLK to SYSCLK/4 = 25MHz
         xadc_write(&ret.p, XadcRegs::Config2, 0x0400 | (XadcPower::AdcbOff as u16) << 4); 
 
+        // the first pass through the sequence measures the coefficients; if it never finishes,
+        // readings stay uncalibrated rather than hanging the boot
+        if ret.wait_update().is_ok() {
+            ret.calibrate();
+        }
         ret
     }
 
+    /// pick up the calibration coefficients the XADC last measured
+    pub fn calibrate(&mut self) {
+        self.cal = XadcCalibration::from_regs(
+            xadc_read(&self.p, XadcRegs::CalSupplyOffset),
+            xadc_read(&self.p, XadcRegs::CalAdcOffset),
+            xadc_read(&self.p, XadcRegs::CalAdcGain),
+        );
+    }
+
+    pub fn calibration(&self) -> XadcCalibration {
+        self.cal
+    }
+
     pub fn noise_only(&mut self, noise_on: bool) {
         const NOISE0: XadcSeq1Mask = XadcSeq1Mask::Vaux0;
         const NOISE1: XadcSeq1Mask = XadcSeq1Mask::Vaux8;
@@ -252,9 +449,8 @@ impl BtXadc {
         const CC2: XadcSeq1Mask = XadcSeq1Mask::Vaux11;
         const VBUS: XadcSeq1Mask = XadcSeq1Mask::Vaux9;
     
-        // 0x0EF0 is constant -- disables alarms not present on this chip, enables calibration, enables all other alarms
         // set to default before updating the sequence table
-        xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Default_ as u16) << 12) | 0x0EF0 );
+        xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Default_ as u16) << 12) | XADC_CONFIG1 );
 
         // setup the sequencing registers
         if noise_on {
@@ -262,8 +458,7 @@ impl BtXadc {
             xadc_write(&self.p, XadcRegs::Seq1, NOISE0 as u16 | NOISE1 as u16 );
     
             // once sequence is set, move to continuous mode. XADC is reset upon changing sequence mode
-            // 0x0EF0 is constant -- disables alarms not present on this chip, enables calibration, enables all other alarms
-            xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Continuous as u16) << 12) | 0x0EF0 );
+            xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Continuous as u16) << 12) | XADC_CONFIG1 );
             // 0x8000 is constant -- disables averaging of cal bit
             xadc_write(&self.p, XadcRegs::Config0, 0x8000 | (XadcFilter::Avg16 as u16) << 12);
             // 0x0400 is constant -- sets DCLK to SYSCLK/4 = 25MHz
@@ -290,8 +485,7 @@ impl BtXadc {
             xadc_write(&self.p, XadcRegs::SeqSettling1, 0);
     
             // once sequence is set, move to continuous mode. XADC is reset upon changing sequence mode
-            // 0x0EF0 is constant -- disables alarms not present on this chip, enables calibration, enables all other alarms
-            xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Continuous as u16) << 12) | 0x0EF0 );
+            xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Continuous as u16) << 12) | XADC_CONFIG1 );
             // 0x8000 is constant -- disables averaging of cal bit
             xadc_write(&self.p, XadcRegs::Config0, 0x8000 | (XadcFilter::Avg16 as u16) << 12);
             // 0x0400 is constant -- sets DCLK to SYSCLK/4 = 25MHz
@@ -302,17 +496,15 @@ impl BtXadc {
     pub fn audio_only(&mut self) {
         const AUDIO: XadcSeq0Mask = XadcSeq0Mask::Dedicated;
     
-        // 0x0EF0 is constant -- disables alarms not present on this chip, enables calibration, enables all other alarms
         // set to default before updating the sequence table
-        xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Default_ as u16) << 12) | 0x0EF0 );
+        xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Default_ as u16) << 12) | XADC_CONFIG1 );
 
         // setup the sequencing registers
         xadc_write(&self.p, XadcRegs::Seq0, AUDIO as u16 );
         xadc_write(&self.p, XadcRegs::Seq1, 0 );
 
         // once sequence is set, move to continuous mode. XADC is reset upon changing sequence mode
-        // 0x0EF0 is constant -- disables alarms not present on this chip, enables calibration, enables all other alarms
-        xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Continuous as u16) << 12) | 0x0EF0 );
+        xadc_write(&self.p, XadcRegs::Config1, ((XadcSeq::Continuous as u16) << 12) | XADC_CONFIG1 );
         // 0x8000 is constant -- disables averaging of cal bit
         xadc_write(&self.p, XadcRegs::Config0, 0x8000 | (XadcFilter::Avg16 as u16) << 12);
         // 0x0400 is constant -- sets DCLK to SYSCLK/4 = 25MHz
@@ -328,8 +520,14 @@ impl BtXadc {
     }
 
     /// blocks until the latest sequence finishes, guarantees the values have been updated
-    pub fn wait_update(&mut self) {
-        while self.p.INFO.xadc_eos.read().bits() == 0 {}
+    pub fn wait_update(&mut self) -> Result<(), XadcError> {
+        let deadline: Deadline = Deadline::from_ms(&self.p, XADC_UPDATE_TIMEOUT_MS);
+        while self.p.INFO.xadc_eos.read().bits() == 0 {
+            if deadline.expired(&self.p) {
+                return Err(XadcError::Timeout);
+            }
+        }
+        Ok(())
     }
 
     pub fn noise0(&mut self) -> u16 {
@@ -339,32 +537,126 @@ impl BtXadc {
         xadc_read(&self.p, XadcRegs::Vaux8) >> 4
     }
     pub fn vbus_mv(&mut self) -> u16 {
-        // voltage is 0.0485 * VBUS
-        // ADC code is 1/4096 of a volt
-        let code: u32 = xadc_read(&self.p, XadcRegs::Vaux9) as u32 >> 4;
-
-        // e.g., code of 993 = 5V will return 4997mV
-        ((code * 5033) / 1000) as u16
+        self.vbus().0 as u16
     }
     pub fn cc1_mv(&mut self) -> u16 {
-        let code: u32 = xadc_read(&self.p, XadcRegs::Vaux10) as u32 >> 4;
-
         // voltage is 1.0 * CC level (safely saturates due to HW protection above 1.0V)
-        // ADC code is 1/4096 of a volt
-        (code * 1000 / 4096) as u16
+        self.cal.aux(xadc_read(&self.p, XadcRegs::Vaux10)).0 as u16
     }
     pub fn cc2_mv(&mut self) -> u16 {
-        let code: u32 = xadc_read(&self.p, XadcRegs::Vaux11) as u32 >> 4;
-
-        // voltage is 1.0 * CC level (safely saturates due to HW protection above 1.0V)
-        // ADC code is 1/4096 of a volt
-        (code * 1000 / 4096) as u16
+        self.cal.aux(xadc_read(&self.p, XadcRegs::Vaux11)).0 as u16
+    }
+    pub fn vbus(&mut self) -> Millivolts {
+        self.cal.vbus(xadc_read(&self.p, XadcRegs::Vaux9))
+    }
+    pub fn supply(&mut self, rail: XadcSupply) -> Millivolts {
+        self.cal.supply(xadc_read(&self.p, rail.reg()))
+    }
+    pub fn vccint(&mut self) -> Millivolts { self.supply(XadcSupply::VccInt) }
+    pub fn vccaux(&mut self) -> Millivolts { self.supply(XadcSupply::VccAux) }
+    pub fn vccbram(&mut self) -> Millivolts { self.supply(XadcSupply::VccBram) }
+    pub fn temperature(&mut self) -> MilliCelsius {
+        self.cal.temperature(xadc_read(&self.p, XadcRegs::Temperature))
+    }
+    pub fn readings(&mut self) -> XadcReadings {
+        XadcReadings {
+            temperature: self.temperature(),
+            vccint: self.vccint(),
+            vccaux: self.vccaux(),
+            vccbram: self.vccbram(),
+        }
+    }
+    /// The temperature alarm goes up at `upper` and comes down again below `reset`.
+    pub fn set_temperature_alarm(&mut self, upper: MilliCelsius, reset: MilliCelsius) {
+        xadc_write(&self.p, XadcRegs::AlarmTempUpper, self.cal.temperature_code(upper));
+        xadc_write(&self.p, XadcRegs::AlarmTempLower, self.cal.temperature_code(reset));
+    }
+    /// A supply alarm is up while the rail is outside `lower`..=`upper`.
+    pub fn set_supply_alarm(&mut self, rail: XadcSupply, lower: Millivolts, upper: Millivolts) {
+        let (lower_reg, upper_reg) = rail.alarm_regs();
+        xadc_write(&self.p, lower_reg, self.cal.supply_code(lower));
+        xadc_write(&self.p, upper_reg, self.cal.supply_code(upper));
+    }
+    /// lowest and highest readings of a rail since the XADC was last reset
+    pub fn supply_range(&mut self, rail: XadcSupply) -> (Millivolts, Millivolts) {
+        (self.cal.supply(xadc_read(&self.p, rail.min_reg())), self.cal.supply(xadc_read(&self.p, rail.max_reg())))
+    }
+    pub fn temperature_range(&mut self) -> (MilliCelsius, MilliCelsius) {
+        (self.cal.temperature(xadc_read(&self.p, XadcRegs::MinTemp)), self.cal.temperature(xadc_read(&self.p, XadcRegs::MaxTemp)))
     }
-    pub fn vccint(&mut self) -> u16 { xadc_read(&self.p, XadcRegs::VccInt) >> 4 }
-    pub fn vccaux(&mut self) -> u16 { xadc_read(&self.p, XadcRegs::VccAux) >> 4 }
-    pub fn vccbram(&mut self) -> u16 { xadc_read(&self.p, XadcRegs::VccBram) >> 4 }
-    pub fn temp(&mut self) -> u16 { xadc_read(&self.p, XadcRegs::Temperature) >> 4 }
     pub fn audio_sample(&mut self) -> u16 { xadc_read(&self.p, XadcRegs::Dedicated) >> 4 }
 
     pub fn flags(&mut self) -> u16 { xadc_read(&self.p, XadcRegs::Flag) }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    /// a 12-bit code as the result register holds it
+    fn code(c: u16) -> u16 {
+        c << 4
+    }
+
+    #[test]
+    fn uncalibrated_conversions() {
+        let cal = XadcCalibration::default();
+        // 1365 / 4096 * 3V
+        assert_eq!(cal.supply(code(1365)), Millivolts(1000));
+        assert_eq!(cal.supply(code(2457)), Millivolts(1800));
+        assert_eq!(cal.supply(0xFFFF), Millivolts(3000));
+        assert_eq!(cal.temperature(0), MilliCelsius(-273_150));
+        // 2420 * 0.12304 - 273.15 = 24.6C
+        assert_eq!(cal.temperature(code(2420)), MilliCelsius(24_609));
+        assert_eq!(cal.aux(code(2048)), Millivolts(500));
+        // code of 993 is 5V on VBUS
+        assert_eq!(cal.vbus(code(993)), Millivolts(4998));
+        // the low bits averaging fills in count
+        assert_eq!(cal.supply(code(1365) - 8), Millivolts(999));
+    }
+
+    #[test]
+    fn coefficients_from_registers() {
+        // +2 and -3 codes of offset, gain error +1.2%
+        let cal = XadcCalibration::from_regs(code(2), 0xFFD0, 0x4C);
+        assert_eq!(cal, XadcCalibration { supply_offset: 32, adc_offset: -48, gain_tenths: 12 });
+        // negative gain error
+        assert_eq!(XadcCalibration::from_regs(0, 0, 0x05).gain_tenths, -5);
+    }
+
+    #[test]
+    fn calibration_is_applied() {
+        let offset = XadcCalibration::from_regs(code(10), code(0x1000 - 10), 0);
+        // supply channels see the supply offset taken off
+        assert_eq!(offset.supply(code(1375)), Millivolts(1000));
+        // everything else the ADC offset
+        assert_eq!(offset.aux(code(2038)), Millivolts(500));
+        // readings below the offset bottom out rather than wrap
+        assert_eq!(offset.supply(code(3)), Millivolts(0));
+
+        let gain = XadcCalibration::from_regs(0, 0, 0x40 | 20);
+        // reads 2% high
+        assert_eq!(gain.supply(code(2507)), Millivolts(1800));
+    }
+
+    #[test]
+    fn threshold_codes_invert_the_conversion() {
+        for cal in [XadcCalibration::default(), XadcCalibration::from_regs(code(7), code(0x1000 - 4), 0x40 | 15)].iter() {
+            for &mv in [950, 1000, 1050, 1800, 2500].iter() {
+                let back: u32 = cal.supply(cal.supply_code(Millivolts(mv))).0;
+                assert!((back as i32 - mv as i32).abs() <= 1, "{:?} {} {}", cal, mv, back);
+            }
+            for &mc in [-20_000, 0, 85_000, 100_000].iter() {
+                let back: i32 = cal.temperature(cal.temperature_code(MilliCelsius(mc))).0;
+                assert!((back - mc).abs() <= 8, "{:?} {} {}", cal, mc, back);
+            }
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", Millivolts(1005)), "1.005V");
+        assert_eq!(format!("{}", MilliCelsius(24_609)), "24.60C");
+        assert_eq!(format!("{}", MilliCelsius(-5_250)), "-5.25C");
+    }
+}
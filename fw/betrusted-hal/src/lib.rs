@@ -4,17 +4,29 @@ extern crate alloc;
 extern crate bitflags;
 extern crate volatile;
 
+pub mod hal_log;
 pub mod hal_i2c;
 pub mod hal_time;
 pub mod hal_lcd;
+pub mod hal_compositor;
+pub mod hal_console;
+pub mod hal_statusbar;
 pub mod hal_com;
+pub mod hal_ec;
+pub mod hal_battery;
 pub mod hal_kbd;
+pub mod hal_keyevent;
 pub mod hal_uart;
 pub mod hal_xadc;
+pub mod hal_supervisor;
+pub mod hal_usbc;
 pub mod hal_audio;
 pub mod hal_rtc;
 pub mod hal_aes;
 pub mod hal_sha2;
+pub mod hal_spinor;
+pub mod hal_config;
+pub mod hal_boot;
 
 #[cfg(test)]
 mod tests {
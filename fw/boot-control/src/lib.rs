@@ -0,0 +1,509 @@
+#![cfg_attr(not(test), no_std)]
+
+//! A/B firmware slot selection.
+//!
+//! The boot control block records which slot is known good, which slot (if any) holds an update
+//! on trial, how many more boots the trial gets, and whether the new firmware has confirmed
+//! itself. It also carries the anti-rollback floor: no image older than `min_version` boots, and
+//! the floor itself is never allowed below the device's monotonic counter, so rolling the block
+//! back doesn't roll the floor back with it.
+//!
+//! The flow of an update:
+//!
+//!   1. the firmware writes the new image to the inactive slot and calls `stage_update()`
+//!   2. the loader calls `boot_decide()`, which spends one try and boots the pending slot
+//!   3. the new firmware passes its self-test and calls `confirm()`
+//!   4. the next `boot_decide()` promotes the pending slot to active and raises the floor to its
+//!      version; if it ran out of tries unconfirmed instead, the old slot takes over again
+//!
+//! Nothing here touches hardware. The caller reads and writes the block, checks the images and
+//! supplies their versions, and advances the monotonic counter when told to. What is here is
+//! the layout of both in flash:
+//!
+//!   * the block is kept in a log of records across two sectors, so that writing a new one
+//!     never erases the sector holding the current one (see `log_latest()` and `log_next()`)
+//!   * the counter is a thermometer code in one-time programmable memory: its value is the
+//!     number of bits programmed to 0, and as those can't be erased it can only go up
+//!
+//! The block is 16 bytes, little endian:
+//!
+//!   0   magic "BTBC"
+//!   4   active slot u8
+//!   5   pending slot u8, 0xFF for none
+//!   6   tries remaining u8
+//!   7   confirmed u8
+//!   8   minimum version u32
+//!   12  CRC-32 of bytes 0..12
+
+use host_link::crc32;
+
+pub const BOOT_MAGIC: [u8; 4] = *b"BTBC";
+pub const BOOT_BLOCK_LEN: usize = 16;
+/// boots an unconfirmed update gets before it's given up on
+pub const BOOT_TRIES: u8 = 3;
+
+const NO_SLOT: u8 = 0xFF;
+
+/// One entry in the block's log, 32 bytes so that a page holds a whole number of them:
+///
+///   0   sequence number u32, one more than the record before
+///   4   the block, BOOT_BLOCK_LEN bytes
+///   20  CRC-32 of bytes 0..20
+///   24  unused, left erased
+pub const BOOT_RECORD_LEN: usize = 32;
+/// the log takes two sectors of this size
+pub const BOOT_LOG_SECTOR: usize = 4096;
+pub const BOOT_LOG_LEN: usize = 2 * BOOT_LOG_SECTOR;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Slot {
+    A = 0,
+    B = 1,
+}
+
+impl Slot {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Slot::A),
+            1 => Some(Slot::B),
+            _ => None,
+        }
+    }
+
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BootError {
+    Magic,
+    Crc,
+    /// a slot number other than A or B
+    Slot,
+    /// an update can't go into the slot that's running
+    ActiveSlot,
+    /// the image is older than the anti-rollback floor
+    Rollback,
+    /// there's no update waiting to be confirmed
+    NotPending,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BootBlock {
+    /// the last slot known to work
+    pub active: Slot,
+    /// the slot holding an update on trial
+    pub pending: Option<Slot>,
+    /// boots the pending slot has left
+    pub tries: u8,
+    /// the pending slot's firmware has passed its self-test
+    pub confirmed: bool,
+    /// anti-rollback floor
+    pub min_version: u32,
+}
+
+impl Default for BootBlock {
+    /// the state of a freshly provisioned device: slot A, nothing pending
+    fn default() -> Self {
+        BootBlock { active: Slot::A, pending: None, tries: 0, confirmed: false, min_version: 0 }
+    }
+}
+
+impl BootBlock {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BootError> {
+        if bytes.len() < BOOT_BLOCK_LEN || bytes[0..4] != BOOT_MAGIC {
+            return Err(BootError::Magic);
+        }
+        let crc: u32 = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+        if crc != crc32(&bytes[..12]) {
+            return Err(BootError::Crc);
+        }
+        let active: Slot = Slot::from_u8(bytes[4]).ok_or(BootError::Slot)?;
+        let pending: Option<Slot> = match bytes[5] {
+            NO_SLOT => None,
+            s => Some(Slot::from_u8(s).ok_or(BootError::Slot)?),
+        };
+        Ok(BootBlock {
+            active,
+            pending,
+            tries: bytes[6],
+            confirmed: bytes[7] != 0,
+            min_version: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; BOOT_BLOCK_LEN] {
+        let mut bytes: [u8; BOOT_BLOCK_LEN] = [0; BOOT_BLOCK_LEN];
+        bytes[0..4].copy_from_slice(&BOOT_MAGIC);
+        bytes[4] = self.active as u8;
+        bytes[5] = self.pending.map_or(NO_SLOT, |s| s as u8);
+        bytes[6] = self.tries;
+        bytes[7] = self.confirmed as u8;
+        bytes[8..12].copy_from_slice(&self.min_version.to_le_bytes());
+        let crc: u32 = crc32(&bytes[..12]);
+        bytes[12..16].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Put the update just written to `slot` on trial. The image's version is checked against
+    /// the floor here so a too-old image is turned away before it costs a reboot.
+    pub fn stage_update(&mut self, slot: Slot, version: u32) -> Result<(), BootError> {
+        if slot == self.active {
+            return Err(BootError::ActiveSlot);
+        }
+        if version < self.min_version {
+            return Err(BootError::Rollback);
+        }
+        self.pending = Some(slot);
+        self.tries = BOOT_TRIES;
+        self.confirmed = false;
+        Ok(())
+    }
+
+    /// Called by firmware on trial once its self-test has passed.
+    pub fn confirm(&mut self) -> Result<(), BootError> {
+        if self.pending.is_none() {
+            return Err(BootError::NotPending);
+        }
+        self.confirmed = true;
+        Ok(())
+    }
+
+    /// a trial boot is in progress: the pending slot is running but hasn't confirmed yet
+    pub fn on_trial(&self) -> bool {
+        self.pending.is_some() && !self.confirmed
+    }
+
+    /// The slot the loader started, going by the block as boot_decide() left it: an update is
+    /// only still pending if it was started.
+    pub fn running_slot(&self) -> Slot {
+        self.pending.unwrap_or(self.active)
+    }
+}
+
+/// the newest block in the log
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BootRecord {
+    pub seq: u32,
+    pub block: BootBlock,
+    /// where the record sits in the log
+    pub offset: usize,
+}
+
+/// the record at `offset` of `log`, if one was completely written there
+fn log_record(log: &[u8], offset: usize) -> Option<BootRecord> {
+    let r: &[u8] = &log[offset..offset + BOOT_RECORD_LEN];
+    let crc: u32 = u32::from_le_bytes([r[20], r[21], r[22], r[23]]);
+    if crc != crc32(&r[..20]) {
+        return None;
+    }
+    Some(BootRecord {
+        seq: u32::from_le_bytes([r[0], r[1], r[2], r[3]]),
+        block: BootBlock::from_bytes(&r[4..20]).ok()?,
+        offset,
+    })
+}
+
+fn erased(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&b| b == 0xFF)
+}
+
+/// The newest intact record in `log`, the BOOT_LOG_LEN bytes of both sectors. Records torn by
+/// a power failure fail their CRC and are passed over.
+pub fn log_latest(log: &[u8]) -> Option<BootRecord> {
+    let mut latest: Option<BootRecord> = None;
+    for offset in (0..BOOT_LOG_LEN).step_by(BOOT_RECORD_LEN) {
+        if let Some(r) = log_record(log, offset) {
+            if latest.is_none_or(|l| r.seq.wrapping_sub(l.seq) as i32 > 0) {
+                latest = Some(r);
+            }
+        }
+    }
+    latest
+}
+
+/// where and how the next record goes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LogWrite {
+    /// offset of a sector to erase first
+    pub erase: Option<usize>,
+    pub offset: usize,
+    pub record: [u8; BOOT_RECORD_LEN],
+}
+
+/// Where to write `block` into `log`: the slot after the newest record if its sector has room,
+/// otherwise the start of the other sector, which is erased first. The sector with the newest
+/// record is never the one erased, so a power failure part way through loses at most the new
+/// record.
+pub fn log_next(log: &[u8], block: &BootBlock) -> LogWrite {
+    let latest: Option<BootRecord> = log_latest(log);
+    let seq: u32 = latest.map_or(0, |l| l.seq.wrapping_add(1));
+    let (erase, offset): (Option<usize>, usize) = match latest {
+        Some(l) if (l.offset + BOOT_RECORD_LEN) / BOOT_LOG_SECTOR == l.offset / BOOT_LOG_SECTOR
+            && erased(&log[l.offset + BOOT_RECORD_LEN..l.offset + 2 * BOOT_RECORD_LEN]) => (None, l.offset + BOOT_RECORD_LEN),
+        Some(l) => {
+            let other: usize = BOOT_LOG_SECTOR - l.offset / BOOT_LOG_SECTOR * BOOT_LOG_SECTOR;
+            (Some(other), other)
+        },
+        None if erased(&log[..BOOT_RECORD_LEN]) => (None, 0),
+        None => (Some(0), 0),
+    };
+
+    let mut record: [u8; BOOT_RECORD_LEN] = [0xFF; BOOT_RECORD_LEN];
+    record[0..4].copy_from_slice(&seq.to_le_bytes());
+    record[4..20].copy_from_slice(&block.to_bytes());
+    let crc: u32 = crc32(&record[..20]);
+    record[20..24].copy_from_slice(&crc.to_le_bytes());
+    LogWrite { erase, offset, record }
+}
+
+/// the monotonic counter's value: the number of bits programmed to 0 in `otp`
+pub fn counter_value(otp: &[u8]) -> u32 {
+    otp.iter().map(|b| b.count_zeros()).sum()
+}
+
+/// Clear bits of `otp`, lowest first, until counter_value() reaches `to`. Programming the
+/// result over the old contents only ever clears bits. False if `otp` can't count that high.
+pub fn counter_advance(otp: &mut [u8], to: u32) -> bool {
+    if to as usize > otp.len() * 8 {
+        return false;
+    }
+    let mut value: u32 = counter_value(otp);
+    for bit in 0..otp.len() * 8 {
+        if value >= to {
+            break;
+        }
+        if otp[bit / 8] & (1 << (bit % 8)) != 0 {
+            otp[bit / 8] &= !(1 << (bit % 8));
+            value += 1;
+        }
+    }
+    true
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BootDecision {
+    /// the slot to start, or None if neither holds anything bootable
+    pub slot: Option<Slot>,
+    /// the block after this boot, to be written back if `changed`
+    pub block: BootBlock,
+    pub changed: bool,
+    /// advance the monotonic counter to this before starting the slot
+    pub counter: Option<u32>,
+}
+
+/// Pick the slot to boot.
+///
+/// `block` is the stored block, or None if it's missing or damaged. `counter` is the monotonic
+/// counter's value. `versions` has the version of each slot's image if its signature and hash
+/// check out, and None otherwise.
+pub fn boot_decide(block: Option<BootBlock>, counter: u32, versions: [Option<u32>; 2]) -> BootDecision {
+    let mut b: BootBlock = block.unwrap_or_default();
+    b.min_version = b.min_version.max(counter);
+    let floor: u32 = b.min_version;
+    let bootable = |s: Slot| versions[s.index()].is_some_and(|v| v >= floor);
+
+    let mut slot: Option<Slot> = None;
+    if let Some(p) = b.pending {
+        if b.confirmed && bootable(p) {
+            // the update proved itself: it's the new known good, and nothing older may follow it
+            b.active = p;
+            b.min_version = versions[p.index()].unwrap_or(floor);
+            b.pending = None;
+            b.tries = 0;
+            b.confirmed = false;
+        } else if !b.confirmed && b.tries > 0 && bootable(p) {
+            b.tries -= 1;
+            slot = Some(p);
+        } else {
+            // out of tries, or the image went bad: fall back
+            b.pending = None;
+            b.tries = 0;
+            b.confirmed = false;
+        }
+    }
+
+    if slot.is_none() {
+        if !bootable(b.active) && bootable(b.active.other()) {
+            b.active = b.active.other();
+        }
+        if bootable(b.active) {
+            slot = Some(b.active);
+        }
+    }
+
+    BootDecision {
+        slot,
+        block: b,
+        changed: block != Some(b),
+        counter: if b.min_version > counter { Some(b.min_version) } else { None },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// run the loader's side of a boot, returning the slot started
+    fn boot(block: &mut Option<BootBlock>, counter: &mut u32, versions: [Option<u32>; 2]) -> Option<Slot> {
+        let d = boot_decide(*block, *counter, versions);
+        if d.changed {
+            *block = Some(BootBlock::from_bytes(&d.block.to_bytes()).unwrap());
+        }
+        if let Some(c) = d.counter {
+            assert!(c > *counter);
+            *counter = c;
+        }
+        d.slot
+    }
+
+    #[test]
+    fn block_roundtrip() {
+        let b = BootBlock { active: Slot::B, pending: Some(Slot::A), tries: 2, confirmed: true, min_version: 7 };
+        assert_eq!(BootBlock::from_bytes(&b.to_bytes()), Ok(b));
+        let mut bytes = b.to_bytes();
+        bytes[8] ^= 1;
+        assert_eq!(BootBlock::from_bytes(&bytes), Err(BootError::Crc));
+        assert_eq!(BootBlock::from_bytes(&[0xFF; BOOT_BLOCK_LEN]), Err(BootError::Magic));
+    }
+
+    #[test]
+    fn fresh_device_boots_a() {
+        let mut block = None;
+        let mut counter = 0;
+        assert_eq!(boot(&mut block, &mut counter, [Some(1), None]), Some(Slot::A));
+        assert_eq!(block, Some(BootBlock::default()));
+    }
+
+    #[test]
+    fn confirmed_update_is_promoted() {
+        let mut block = Some(BootBlock { min_version: 1, ..BootBlock::default() });
+        let mut counter = 1;
+        let versions = [Some(1), Some(2)];
+        let mut b = block.unwrap();
+        b.stage_update(Slot::B, 2).unwrap();
+        block = Some(b);
+
+        assert_eq!(boot(&mut block, &mut counter, versions), Some(Slot::B));
+        let mut b = block.unwrap();
+        assert!(b.on_trial());
+        assert_eq!(b.running_slot(), Slot::B);
+        b.confirm().unwrap();
+        assert_eq!(b.running_slot(), Slot::B);
+        block = Some(b);
+
+        assert_eq!(boot(&mut block, &mut counter, versions), Some(Slot::B));
+        assert_eq!(block.unwrap().active, Slot::B);
+        assert_eq!(block.unwrap().pending, None);
+        assert_eq!(counter, 2);
+        // and it stays that way without rewriting the block
+        assert!(!boot_decide(block, counter, versions).changed);
+    }
+
+    #[test]
+    fn unconfirmed_update_rolls_back() {
+        let mut b = BootBlock::default();
+        b.stage_update(Slot::B, 5).unwrap();
+        let mut block = Some(b);
+        let mut counter = 0;
+        for _ in 0..BOOT_TRIES {
+            assert_eq!(boot(&mut block, &mut counter, [Some(4), Some(5)]), Some(Slot::B));
+        }
+        assert_eq!(boot(&mut block, &mut counter, [Some(4), Some(5)]), Some(Slot::A));
+        assert_eq!(block.unwrap().pending, None);
+        assert_eq!(block.unwrap().running_slot(), Slot::A);
+        assert_eq!(counter, 0);
+    }
+
+    #[test]
+    fn old_images_are_refused() {
+        let mut b = BootBlock { min_version: 3, ..BootBlock::default() };
+        assert_eq!(b.stage_update(Slot::B, 2), Err(BootError::Rollback));
+        assert_eq!(b.stage_update(Slot::A, 9), Err(BootError::ActiveSlot));
+
+        // resetting the block doesn't lower the floor below the counter
+        let mut counter = 3;
+        assert_eq!(boot(&mut None, &mut counter, [Some(2), Some(3)]), Some(Slot::B));
+        assert_eq!(boot(&mut None, &mut counter, [Some(2), None]), None);
+    }
+
+    /// write `block` the way the store does, returning the sector erased, if any
+    fn log_store(log: &mut [u8], block: &BootBlock) -> Option<usize> {
+        let w = log_next(log, block);
+        if let Some(sector) = w.erase {
+            log[sector..sector + BOOT_LOG_SECTOR].fill(0xFF);
+        }
+        for (dst, src) in log[w.offset..w.offset + BOOT_RECORD_LEN].iter_mut().zip(w.record.iter()) {
+            *dst &= *src;
+        }
+        w.erase
+    }
+
+    #[test]
+    fn log_keeps_the_newest_block() {
+        let mut log = vec![0xFF; BOOT_LOG_LEN];
+        assert_eq!(log_latest(&log), None);
+        let mut erases = 0;
+        for i in 0..(2 * BOOT_LOG_SECTOR / BOOT_RECORD_LEN + 3) as u32 {
+            let block = BootBlock { min_version: i, ..BootBlock::default() };
+            erases += log_store(&mut log, &block).is_some() as u32;
+            assert_eq!(log_latest(&log).map(|r| r.block), Some(block));
+        }
+        // one erase each time a sector filled up
+        assert_eq!(erases, 2);
+    }
+
+    #[test]
+    fn torn_record_keeps_the_last_good_one() {
+        let mut log = vec![0xFF; BOOT_LOG_LEN];
+        let old = BootBlock { min_version: 4, ..BootBlock::default() };
+        log_store(&mut log, &old);
+        let w = log_next(&log, &BootBlock { min_version: 5, ..BootBlock::default() });
+        // the power failed with only half the record programmed
+        log[w.offset..w.offset + 12].copy_from_slice(&w.record[..12]);
+        assert_eq!(log_latest(&log).map(|r| r.block), Some(old));
+        // and the next write steps past it rather than programming over it
+        let new = BootBlock { min_version: 6, ..BootBlock::default() };
+        log_store(&mut log, &new);
+        assert_eq!(log_latest(&log).map(|r| r.block), Some(new));
+    }
+
+    #[test]
+    fn garbage_log_is_erased() {
+        let mut log = vec![0; BOOT_LOG_LEN];
+        assert_eq!(log_latest(&log), None);
+        assert_eq!(log_store(&mut log, &BootBlock::default()), Some(0));
+        assert_eq!(log_latest(&log).map(|r| r.block), Some(BootBlock::default()));
+    }
+
+    #[test]
+    fn counter_only_counts_up() {
+        let mut otp = [0xFF; 4];
+        assert_eq!(counter_value(&otp), 0);
+        assert!(counter_advance(&mut otp, 9));
+        assert_eq!(otp, [0x00, 0xFE, 0xFF, 0xFF]);
+        assert_eq!(counter_value(&otp), 9);
+        // going backwards leaves it alone
+        assert!(counter_advance(&mut otp, 3));
+        assert_eq!(counter_value(&otp), 9);
+        assert!(counter_advance(&mut otp, 32));
+        assert_eq!(counter_value(&otp), 32);
+        assert!(!counter_advance(&mut otp, 33));
+    }
+
+    #[test]
+    fn broken_active_slot_falls_over() {
+        let mut block = Some(BootBlock::default());
+        let mut counter = 0;
+        assert_eq!(boot(&mut block, &mut counter, [None, Some(1)]), Some(Slot::B));
+        assert_eq!(block.unwrap().active, Slot::B);
+    }
+}
@@ -7,7 +7,13 @@ fn main() {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     println!("cargo:rustc-link-search={}", out_dir.display());
 
+    // memory.x is for slot A; the image header takes the first sector of the slot, and the
+    // code starts at hal_boot::SLOT_CODE_OFFSET
+    let mut memory: String = String::from(include_str!("memory.x"));
+    if env::var_os("CARGO_FEATURE_SLOT_B").is_some() {
+        memory = memory.replace("ORIGIN = 0x20501000", "ORIGIN = 0x21501000");
+    }
     fs::File::create(out_dir.join("memory.x")).unwrap()
-        .write_all(include_bytes!("memory.x")).unwrap();
+        .write_all(memory.as_bytes()).unwrap();
     println!("cargo:rerun-if-changed=memory.x");
-}
\ No newline at end of file
+}
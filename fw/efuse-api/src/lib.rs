@@ -37,6 +37,7 @@
 
 use jtag::*;
 use efuse_ecc::efuse_ecc::*;
+use betrusted_hal::{error, info};
 
 /// There are 13 banks of fuses, 12 of which (key/user) are "hamming" ECC, 1 of which (config) is "dup" ECC.
 pub struct EfusePhy {
@@ -287,6 +288,7 @@ impl EfuseApi {
             (JtagChain::DR, 64, 0x0, "KEY_BANK_WAIT"),
         ];
         self.jtag_seq(jm, jp, &bank_fuse);
+        info!("burning bank {}: 0x{:08x}", bank, ones);
         let mut curbit = ones;
         for i in 0..32 {
             if (curbit & 0x1) == 1 {
@@ -334,8 +336,10 @@ impl EfuseApi {
 
         // first check if we're valid
         if !self.is_valid() {
+            error!("burn refused: proposed fuse state is not reachable from the current one");
             return false;
         }
+        info!("burn: key/user/cntl update, user 0x{:08x} cntl 0x{:02x}", self.user, self.cntl);
 
         // reset the machine before doing any burning
         jp.pause(2000); 
@@ -380,6 +384,7 @@ impl EfuseApi {
         self.jtag_seq(jm, jp, &COMMIT_SEQ);
         jp.pause(2000); 
         jm.reset(jp);
+        info!("burn: committed");
         ok
     }
 
@@ -0,0 +1,25 @@
+use std::{env, fs};
+use std::path::PathBuf;
+use std::io::Write;
+
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    // The key firmware images are verified against, as 64 hex digits. Without one, uploads
+    // are refused and the loader doesn't check images, unless the dev-signing feature is on.
+    println!("cargo:rerun-if-env-changed=BETRUSTED_FW_PUBKEY");
+    let pubkey: String = match env::var("BETRUSTED_FW_PUBKEY") {
+        Ok(hex) => {
+            let hex: &str = hex.trim();
+            assert!(hex.len() == 64, "BETRUSTED_FW_PUBKEY must be 64 hex digits");
+            let bytes: Vec<String> = (0..32)
+                .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).expect("BETRUSTED_FW_PUBKEY must be hex"))
+                .map(|b| format!("0x{:02x}", b))
+                .collect();
+            format!("Some([{}])", bytes.join(", "))
+        },
+        Err(_) => String::from("None"),
+    };
+    fs::File::create(out_dir.join("fw_pubkey.rs")).unwrap()
+        .write_all(format!("#[allow(dead_code)] // unused with dev-signing\nconst FW_RELEASE_PUBKEY: Option<[u8; 32]> = {};\n", pubkey).as_bytes()).unwrap();
+}
@@ -0,0 +1,72 @@
+//! Host side: software hashing and signing, for the tools that build and check images.
+
+use crate::*;
+use ed25519_dalek::{Keypair, SecretKey, Signer};
+use sha2::Digest;
+
+/// software SHA-256
+#[derive(Default)]
+pub struct SoftSha256 {
+    state: sha2::Sha256,
+}
+
+impl SoftSha256 {
+    pub fn new() -> Self {
+        SoftSha256 { state: sha2::Sha256::new() }
+    }
+}
+
+impl ImageHash for SoftSha256 {
+    fn start(&mut self) {
+        self.state = sha2::Sha256::new();
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.state.update(data);
+    }
+
+    fn finish(&mut self) -> [u8; 32] {
+        let mut digest: [u8; 32] = [0; 32];
+        digest.copy_from_slice(&self.state.finalize_reset());
+        digest
+    }
+}
+
+fn keypair(secret: &[u8; 32]) -> Keypair {
+    let secret: SecretKey = SecretKey::from_bytes(secret).unwrap(); // any 32 bytes will do
+    let public: PublicKey = (&secret).into();
+    Keypair { secret, public }
+}
+
+/// the public key that verifies images signed with `secret`
+pub fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    keypair(secret).public.to_bytes()
+}
+
+/// build a complete image around `payload`, linked for slot A
+pub fn sign_image(secret: &[u8; 32], version: u32, payload: &[u8]) -> Vec<u8> {
+    sign_image_flags(secret, 0, version, payload)
+}
+
+/// build a complete image around `payload` with header `flags`, e.g. IMAGE_FLAG_SLOT_B
+pub fn sign_image_flags(secret: &[u8; 32], flags: u16, version: u32, payload: &[u8]) -> Vec<u8> {
+    let mut hash: SoftSha256 = SoftSha256::new();
+    hash.update(payload);
+    let mut header: ImageHeader = ImageHeader {
+        flags,
+        version,
+        length: payload.len() as u32,
+        sha256: hash.finish(),
+        signature: [0; 64],
+    };
+    header.signature = keypair(secret).sign(&header.to_bytes()[..IMAGE_SIGNED_LEN]).to_bytes();
+
+    let mut image: Vec<u8> = header.to_bytes().to_vec();
+    image.extend_from_slice(payload);
+    image
+}
+
+/// verify_image() with software hashing
+pub fn verify_image_soft(image: &[u8], pubkey: &[u8; 32]) -> Result<ImageHeader, ImageError> {
+    verify_image(image, pubkey, &mut SoftSha256::new())
+}
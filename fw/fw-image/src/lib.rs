@@ -0,0 +1,414 @@
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+//! Signed firmware images.
+//!
+//! An image is a fixed 112-byte header followed by the payload. All fields are little endian:
+//!
+//!   0   magic "BTFW"
+//!   4   header version u16, currently 1
+//!   6   flags u16: IMAGE_FLAG_SLOT_B, all others reserved and 0
+//!   8   firmware version u32
+//!   12  payload length u32
+//!   16  SHA-256 of the payload, 32 bytes
+//!   48  Ed25519 signature over bytes 0..48, 64 bytes
+//!
+//! Signing the header rather than the payload lets a receiver turn away an image from the wrong
+//! key before the payload has even arrived; the payload is then held to the signed hash.
+//!
+//! Firmware executes in place, so an image only runs from the slot it was linked for. The flag
+//! saying which that is falls under the signature, like the rest of the first 48 bytes.
+
+extern crate alloc;
+
+#[cfg(any(test, feature = "std"))]
+pub mod host;
+
+use alloc::vec::Vec;
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+
+pub const IMAGE_MAGIC: [u8; 4] = *b"BTFW";
+pub const IMAGE_HEADER_VERSION: u16 = 1;
+pub const IMAGE_HEADER_LEN: usize = 112;
+/// the part of the header covered by the signature
+pub const IMAGE_SIGNED_LEN: usize = 48;
+/// filler tolerated after the payload; XMODEM pads out its last block with it
+pub const IMAGE_PAD: u8 = 0x1A;
+/// the payload is linked to run from firmware slot B rather than A
+pub const IMAGE_FLAG_SLOT_B: u16 = 0x0001;
+const IMAGE_FLAGS_KNOWN: u16 = IMAGE_FLAG_SLOT_B;
+
+// FW_RELEASE_PUBKEY, from BETRUSTED_FW_PUBKEY at build time
+include!(concat!(env!("OUT_DIR"), "/fw_pubkey.rs"));
+
+/// Public half of the development signing key, whose secret is b"betrusted development signing!!!".
+/// Good for bring-up only: anybody can sign images for it.
+#[cfg(feature = "dev-signing")]
+pub const FW_PUBKEY: Option<[u8; 32]> = Some([
+    0x44, 0xf9, 0xb9, 0x2e, 0xa5, 0x50, 0x0b, 0xea, 0xd1, 0x8f, 0x16, 0xf8, 0x54, 0x7a, 0xa1, 0x2f,
+    0xf9, 0xe3, 0xf1, 0x9d, 0xb5, 0xaa, 0x48, 0x97, 0x73, 0x43, 0xd7, 0x75, 0xae, 0xdb, 0x18, 0x0f,
+]);
+/// key that firmware images must be signed with, for uploads and for the loader to boot them
+#[cfg(not(feature = "dev-signing"))]
+pub const FW_PUBKEY: Option<[u8; 32]> = FW_RELEASE_PUBKEY;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageError {
+    Magic,
+    /// header version this code doesn't understand
+    HeaderVersion,
+    /// flags this code doesn't know are set
+    Flags,
+    /// payload longer than the staging area
+    TooLarge,
+    /// image is short, or has something other than padding after the payload
+    Length,
+    /// the verification key isn't a valid Ed25519 key
+    Key,
+    Signature,
+    /// payload doesn't match the hash in the header
+    Hash,
+    /// the staging area refused the image
+    Staging,
+}
+
+/// SHA-256 engine used to check payloads; the BtSha2 block on the board, software on the host
+pub trait ImageHash {
+    fn start(&mut self);
+    fn update(&mut self, data: &[u8]);
+    fn finish(&mut self) -> [u8; 32];
+}
+
+/// Where verified images go. Nothing is handed over until the image has checked out.
+pub trait ImageStaging {
+    /// largest payload that fits
+    fn capacity(&self) -> usize;
+    /// store the header and payload, replacing whatever was staged before
+    fn stage(&mut self, header: &ImageHeader, payload: &[u8]) -> bool;
+}
+
+/// staging in RAM, as the raw image bytes
+impl ImageStaging for Vec<u8> {
+    fn capacity(&self) -> usize {
+        usize::MAX
+    }
+
+    fn stage(&mut self, header: &ImageHeader, payload: &[u8]) -> bool {
+        self.clear();
+        self.extend_from_slice(&header.to_bytes());
+        self.extend_from_slice(payload);
+        true
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub flags: u16,
+    pub version: u32,
+    pub length: u32,
+    pub sha256: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+impl ImageHeader {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        if bytes.len() < IMAGE_HEADER_LEN {
+            return Err(ImageError::Length);
+        }
+        if bytes[0..4] != IMAGE_MAGIC {
+            return Err(ImageError::Magic);
+        }
+        if u16::from_le_bytes([bytes[4], bytes[5]]) != IMAGE_HEADER_VERSION {
+            return Err(ImageError::HeaderVersion);
+        }
+        let flags: u16 = u16::from_le_bytes([bytes[6], bytes[7]]);
+        if flags & !IMAGE_FLAGS_KNOWN != 0 {
+            return Err(ImageError::Flags);
+        }
+        let mut header = ImageHeader {
+            flags,
+            version: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            length: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+            sha256: [0; 32],
+            signature: [0; 64],
+        };
+        header.sha256.copy_from_slice(&bytes[16..48]);
+        header.signature.copy_from_slice(&bytes[48..112]);
+        Ok(header)
+    }
+
+    pub fn to_bytes(&self) -> [u8; IMAGE_HEADER_LEN] {
+        let mut bytes: [u8; IMAGE_HEADER_LEN] = [0; IMAGE_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&IMAGE_MAGIC);
+        bytes[4..6].copy_from_slice(&IMAGE_HEADER_VERSION.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.length.to_le_bytes());
+        bytes[16..48].copy_from_slice(&self.sha256);
+        bytes[48..112].copy_from_slice(&self.signature);
+        bytes
+    }
+
+    /// the payload is linked for slot B
+    pub fn slot_b(&self) -> bool {
+        self.flags & IMAGE_FLAG_SLOT_B != 0
+    }
+
+    /// check the header signature against `pubkey`
+    pub fn verify_signature(&self, pubkey: &[u8; 32]) -> Result<(), ImageError> {
+        let key: PublicKey = PublicKey::from_bytes(pubkey).map_err(|_| ImageError::Key)?;
+        let signature: Signature = Signature::from_bytes(&self.signature).map_err(|_| ImageError::Signature)?;
+        key.verify(&self.to_bytes()[..IMAGE_SIGNED_LEN], &signature).map_err(|_| ImageError::Signature)
+    }
+}
+
+/// only padding may follow the payload
+fn check_trailer(trailer: &[u8]) -> Result<(), ImageError> {
+    if trailer.iter().all(|&c| c == IMAGE_PAD) {
+        Ok(())
+    } else {
+        Err(ImageError::Length)
+    }
+}
+
+/// Check a complete image held in memory, such as one already staged, and return its header.
+pub fn verify_image<H: ImageHash>(image: &[u8], pubkey: &[u8; 32], hash: &mut H) -> Result<ImageHeader, ImageError> {
+    let header: ImageHeader = ImageHeader::from_bytes(image)?;
+    header.verify_signature(pubkey)?;
+    let body: &[u8] = &image[IMAGE_HEADER_LEN..];
+    if body.len() < header.length as usize {
+        return Err(ImageError::Length);
+    }
+    let (payload, trailer) = body.split_at(header.length as usize);
+    check_trailer(trailer)?;
+    hash.start();
+    hash.update(payload);
+    if hash.finish() != header.sha256 {
+        return Err(ImageError::Hash);
+    }
+    Ok(header)
+}
+
+/// Takes an image in whatever pieces the transport delivers, and stages it once it has been
+/// verified.
+///
+/// The header signature is checked as soon as the header is in, and the first error sticks: later
+/// pieces are ignored and finish() reports it. That lets push() be called from a transport's
+/// per-chunk callback, which has no way to return an error.
+pub struct ImageReceiver {
+    pubkey: [u8; 32],
+    max_len: usize,
+    header_buf: [u8; IMAGE_HEADER_LEN],
+    header_fill: usize,
+    header: Option<ImageHeader>,
+    payload: Vec<u8>,
+    error: Option<ImageError>,
+}
+
+impl ImageReceiver {
+    /// `max_len` is the largest payload accepted, usually the staging area's capacity()
+    pub fn new(pubkey: &[u8; 32], max_len: usize) -> Self {
+        ImageReceiver {
+            pubkey: *pubkey,
+            max_len,
+            header_buf: [0; IMAGE_HEADER_LEN],
+            header_fill: 0,
+            header: None,
+            payload: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// feed the next piece of the image
+    pub fn push<H: ImageHash>(&mut self, hash: &mut H, data: &[u8]) {
+        if self.error.is_none() {
+            if let Err(e) = self.push_inner(hash, data) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn push_inner<H: ImageHash>(&mut self, hash: &mut H, data: &[u8]) -> Result<(), ImageError> {
+        let mut data: &[u8] = data;
+        if self.header.is_none() {
+            let n: usize = (IMAGE_HEADER_LEN - self.header_fill).min(data.len());
+            self.header_buf[self.header_fill..self.header_fill + n].copy_from_slice(&data[..n]);
+            self.header_fill += n;
+            data = &data[n..];
+            if self.header_fill < IMAGE_HEADER_LEN {
+                return Ok(());
+            }
+
+            let header: ImageHeader = ImageHeader::from_bytes(&self.header_buf)?;
+            if header.length as usize > self.max_len {
+                return Err(ImageError::TooLarge);
+            }
+            header.verify_signature(&self.pubkey)?;
+            self.payload.reserve_exact(header.length as usize);
+            hash.start();
+            self.header = Some(header);
+        }
+
+        let length: usize = self.header.map_or(0, |h| h.length as usize);
+        let (body, trailer) = data.split_at((length - self.payload.len()).min(data.len()));
+        hash.update(body);
+        self.payload.extend_from_slice(body);
+        check_trailer(trailer)
+    }
+
+    /// the header, once it has arrived and its signature checked out
+    pub fn header(&self) -> Option<ImageHeader> {
+        if self.error.is_none() { self.header } else { None }
+    }
+
+    /// Check the payload against the signed hash and, if it matches, hand the image to `staging`.
+    pub fn finish<H: ImageHash, S: ImageStaging>(self, hash: &mut H, staging: &mut S) -> Result<ImageHeader, ImageError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let header: ImageHeader = self.header.ok_or(ImageError::Length)?;
+        if self.payload.len() != header.length as usize {
+            return Err(ImageError::Length);
+        }
+        if hash.finish() != header.sha256 {
+            return Err(ImageError::Hash);
+        }
+        if header.length as usize > staging.capacity() {
+            return Err(ImageError::TooLarge);
+        }
+        if !staging.stage(&header, &self.payload) {
+            return Err(ImageError::Staging);
+        }
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::*;
+
+    const SECRET: [u8; 32] = [7; 32];
+    const OTHER_SECRET: [u8; 32] = [9; 32];
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 % 251) as u8).collect()
+    }
+
+    /// run `image` through a receiver in `chunk` sized pieces
+    fn receive(image: &[u8], chunk: usize, max_len: usize) -> (Result<ImageHeader, ImageError>, Vec<u8>) {
+        let mut rx = ImageReceiver::new(&public_key(&SECRET), max_len);
+        let mut hash = SoftSha256::new();
+        for piece in image.chunks(chunk) {
+            rx.push(&mut hash, piece);
+        }
+        let mut staged: Vec<u8> = Vec::new();
+        (rx.finish(&mut hash, &mut staged), staged)
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let image = sign_image(&SECRET, 0x0102_0304, &payload(100));
+        let header = ImageHeader::from_bytes(&image).unwrap();
+        assert_eq!(header.version, 0x0102_0304);
+        assert_eq!(header.length, 100);
+        assert_eq!(&header.to_bytes()[..], &image[..IMAGE_HEADER_LEN]);
+    }
+
+    #[test]
+    fn good_image_is_staged() {
+        let data = payload(5000);
+        let image = sign_image(&SECRET, 3, &data);
+        for &chunk in &[1, 7, 112, 512, 1024, 10_000] {
+            let (result, staged) = receive(&image, chunk, 8192);
+            assert_eq!(result.map(|h| h.version), Ok(3));
+            assert_eq!(staged, image);
+        }
+        assert_eq!(verify_image_soft(&image, &public_key(&SECRET)).map(|h| h.length), Ok(5000));
+    }
+
+    #[test]
+    fn padding_is_tolerated() {
+        let mut image = sign_image(&SECRET, 1, &payload(1000));
+        image.resize(IMAGE_HEADER_LEN + 2048, IMAGE_PAD);
+        let (result, staged) = receive(&image, 1024, 8192);
+        assert!(result.is_ok());
+        assert_eq!(staged.len(), IMAGE_HEADER_LEN + 1000);
+        assert!(verify_image_soft(&image, &public_key(&SECRET)).is_ok());
+
+        image.push(0);
+        assert_eq!(receive(&image, 1024, 8192).0, Err(ImageError::Length));
+    }
+
+    #[test]
+    fn tampered_payload_is_refused() {
+        let mut image = sign_image(&SECRET, 1, &payload(2000));
+        image[IMAGE_HEADER_LEN + 1500] ^= 0x10;
+        let (result, staged) = receive(&image, 512, 8192);
+        assert_eq!(result, Err(ImageError::Hash));
+        assert!(staged.is_empty());
+        assert_eq!(verify_image_soft(&image, &public_key(&SECRET)), Err(ImageError::Hash));
+    }
+
+    #[test]
+    fn tampered_header_is_refused() {
+        // a rollback attempt: same payload, older version number
+        let mut image = sign_image(&SECRET, 5, &payload(200));
+        image[8] = 4;
+        assert_eq!(receive(&image, 64, 8192).0, Err(ImageError::Signature));
+
+        let mut image = sign_image(&SECRET, 5, &payload(200));
+        image[0] = b'X';
+        assert_eq!(receive(&image, 64, 8192).0, Err(ImageError::Magic));
+
+        let mut image = sign_image(&SECRET, 5, &payload(200));
+        image[4] = 2;
+        assert_eq!(receive(&image, 64, 8192).0, Err(ImageError::HeaderVersion));
+    }
+
+    #[test]
+    fn flags_are_signed() {
+        let image = sign_image_flags(&SECRET, IMAGE_FLAG_SLOT_B, 5, &payload(200));
+        assert_eq!(receive(&image, 64, 8192).0.map(|h| h.slot_b()), Ok(true));
+        assert!(!ImageHeader::from_bytes(&sign_image(&SECRET, 5, &payload(200))).unwrap().slot_b());
+
+        // moving an image to the other slot breaks the signature
+        let mut image = sign_image(&SECRET, 5, &payload(200));
+        image[6] ^= IMAGE_FLAG_SLOT_B as u8;
+        assert_eq!(receive(&image, 64, 8192).0, Err(ImageError::Signature));
+        assert_eq!(verify_image_soft(&image, &public_key(&SECRET)), Err(ImageError::Signature));
+
+        // and unknown flags are turned away before the signature is even looked at
+        let mut image = sign_image(&SECRET, 5, &payload(200));
+        image[7] ^= 0x80;
+        assert_eq!(receive(&image, 64, 8192).0, Err(ImageError::Flags));
+    }
+
+    #[test]
+    fn wrong_key_is_refused() {
+        let image = sign_image(&OTHER_SECRET, 1, &payload(300));
+        assert_eq!(receive(&image, 300, 8192).0, Err(ImageError::Signature));
+        assert!(verify_image_soft(&image, &public_key(&SECRET)).is_err());
+    }
+
+    #[test]
+    fn oversized_and_short_images_are_refused() {
+        let image = sign_image(&SECRET, 1, &payload(4096));
+        assert_eq!(receive(&image, 512, 4095).0, Err(ImageError::TooLarge));
+        assert_eq!(receive(&image[..image.len() - 1], 512, 8192).0, Err(ImageError::Length));
+        assert_eq!(receive(&image[..50], 512, 8192).0, Err(ImageError::Length));
+    }
+
+    #[test]
+    fn staging_capacity_is_respected() {
+        struct Tiny;
+        impl ImageStaging for Tiny {
+            fn capacity(&self) -> usize { 10 }
+            fn stage(&mut self, _header: &ImageHeader, _payload: &[u8]) -> bool { true }
+        }
+        let image = sign_image(&SECRET, 1, &payload(11));
+        let mut rx = ImageReceiver::new(&public_key(&SECRET), 100);
+        let mut hash = SoftSha256::new();
+        rx.push(&mut hash, &image);
+        assert_eq!(rx.finish(&mut hash, &mut Tiny), Err(ImageError::TooLarge));
+    }
+}
@@ -0,0 +1,352 @@
+//! 1-bit images as PBM and PNG.
+//!
+//! Both writers stream their output through a closure a piece at a time and need no
+//! allocation, so the board can send an image straight down the link. PNG output is 1-bit
+//! greyscale with the image data in stored (uncompressed) deflate blocks: larger than it could
+//! be, but every viewer reads it and it takes no memory to make.
+//!
+//! `rle_write` is the screenshot format sent over the link: much smaller than either, with a
+//! checksum over the whole image. The host turns it back into a picture with
+//! `host::Screenshot`.
+//!
+//!   0   magic "BTSS"
+//!   4   width u16 LE
+//!   6   height u16 LE
+//!   8   runs, each a LEB128 length, alternating light and dark and starting with light;
+//!       they cover the image in raster order
+//!   ..  CRC-32 u32 LE of everything before it
+
+use crate::crc32_update;
+
+/// widest image the writers handle
+pub const BITMAP_MAX_WIDTH: u32 = 1024;
+const MAX_ROW_BYTES: usize = BITMAP_MAX_WIDTH as usize / 8;
+
+/// largest stored deflate block
+const STORED_MAX: usize = 65535;
+/// first bytes of a run-length encoded screenshot
+pub const RLE_MAGIC: [u8; 4] = *b"BTSS";
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// A 1-bit image
+pub trait Bitmap {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    /// true for a dark pixel
+    fn dark(&self, x: u32, y: u32) -> bool;
+}
+
+/// Row `y` packed 8 pixels to a byte, leftmost pixel in the top bit, set for dark. Returns
+/// the number of bytes used.
+fn pack_row<B: Bitmap>(b: &B, y: u32, row: &mut [u8; MAX_ROW_BYTES]) -> usize {
+    let len: usize = b.width().div_ceil(8) as usize;
+    for byte in row[..len].iter_mut() {
+        *byte = 0;
+    }
+    for x in 0..b.width() {
+        if b.dark(x, y) {
+            row[(x / 8) as usize] |= 0x80 >> (x % 8);
+        }
+    }
+    len
+}
+
+/// decimal digits of `v` without allocating
+fn write_decimal<F: FnMut(&[u8])>(v: u32, out: &mut F) {
+    let mut digits: [u8; 10] = [0; 10];
+    let mut n: usize = 0;
+    let mut v: u32 = v;
+    loop {
+        digits[9 - n] = b'0' + (v % 10) as u8;
+        n += 1;
+        v /= 10;
+        if v == 0 {
+            break;
+        }
+    }
+    out(&digits[10 - n..]);
+}
+
+/// Binary PBM (P4)
+pub fn pbm_write<B: Bitmap, F: FnMut(&[u8])>(b: &B, mut out: F) {
+    assert!(b.width() <= BITMAP_MAX_WIDTH);
+    out(b"P4\n");
+    write_decimal(b.width(), &mut out);
+    out(b" ");
+    write_decimal(b.height(), &mut out);
+    out(b"\n");
+    let mut row: [u8; MAX_ROW_BYTES] = [0; MAX_ROW_BYTES];
+    for y in 0..b.height() {
+        let len: usize = pack_row(b, y, &mut row);
+        out(&row[..len]);
+    }
+}
+
+/// Run-length encoded, as described above
+pub fn rle_write<B: Bitmap, F: FnMut(&[u8])>(b: &B, mut out: F) {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    let mut emit = |data: &[u8]| {
+        crc = crc32_update(crc, data);
+        out(data);
+    };
+    emit(&RLE_MAGIC);
+    emit(&(b.width() as u16).to_le_bytes());
+    emit(&(b.height() as u16).to_le_bytes());
+
+    let mut emit_run = |mut run: u32| {
+        let mut leb: [u8; 5] = [0; 5];
+        let mut n: usize = 0;
+        loop {
+            leb[n] = (run & 0x7F) as u8;
+            run >>= 7;
+            if run == 0 {
+                break;
+            }
+            leb[n] |= 0x80;
+            n += 1;
+        }
+        emit(&leb[..=n]);
+    };
+    let mut dark: bool = false;
+    let mut run: u32 = 0;
+    for y in 0..b.height() {
+        for x in 0..b.width() {
+            if b.dark(x, y) != dark {
+                emit_run(run);
+                dark = !dark;
+                run = 0;
+            }
+            run += 1;
+        }
+    }
+    emit_run(run);
+    out(&(!crc).to_le_bytes());
+}
+
+/// PNG chunk writer: keeps the running CRC as the chunk goes out
+struct Chunk<'a, F: FnMut(&[u8])> {
+    out: &'a mut F,
+    crc: u32,
+}
+
+impl<'a, F: FnMut(&[u8])> Chunk<'a, F> {
+    fn start(out: &'a mut F, kind: &[u8; 4], len: u32) -> Self {
+        out(&len.to_be_bytes());
+        out(kind);
+        Chunk { out, crc: crc32_update(0xFFFF_FFFF, kind) }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.crc = crc32_update(self.crc, data);
+        (self.out)(data);
+    }
+
+    fn end(self) {
+        (self.out)(&(!self.crc).to_be_bytes());
+    }
+}
+
+fn adler32_update(adler: (u32, u32), data: &[u8]) -> (u32, u32) {
+    let (mut a, mut b) = adler;
+    for &c in data {
+        a = (a + c as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (a, b)
+}
+
+/// PNG, 1-bit greyscale
+pub fn png_write<B: Bitmap, F: FnMut(&[u8])>(b: &B, mut out: F) {
+    assert!(b.width() <= BITMAP_MAX_WIDTH);
+    out(&PNG_SIGNATURE);
+
+    let mut ihdr: [u8; 13] = [0; 13];
+    ihdr[0..4].copy_from_slice(&b.width().to_be_bytes());
+    ihdr[4..8].copy_from_slice(&b.height().to_be_bytes());
+    ihdr[8] = 1; // bit depth
+    ihdr[9] = 0; // greyscale; compression, filter and interlace all 0
+    let mut chunk = Chunk::start(&mut out, b"IHDR", ihdr.len() as u32);
+    chunk.write(&ihdr);
+    chunk.end();
+
+    // each row is a filter type byte (0, none) and the packed pixels, where PNG has 1 for white
+    let row_len: usize = 1 + b.width().div_ceil(8) as usize;
+    let raw_len: usize = row_len * b.height() as usize;
+    let blocks: usize = if raw_len == 0 { 1 } else { raw_len.div_ceil(STORED_MAX) };
+    let idat_len: usize = 2 + blocks * 5 + raw_len + 4;
+    let mut chunk = Chunk::start(&mut out, b"IDAT", idat_len as u32);
+    chunk.write(&[0x78, 0x01]); // zlib header: deflate, 32K window, no dictionary
+
+    let mut adler: (u32, u32) = (1, 0);
+    let mut raw_left: usize = raw_len;
+    let mut block_left: usize = 0;
+    if raw_len == 0 {
+        chunk.write(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    let mut row: [u8; MAX_ROW_BYTES] = [0; MAX_ROW_BYTES];
+    let mut line: [u8; MAX_ROW_BYTES + 1] = [0; MAX_ROW_BYTES + 1];
+    for y in 0..b.height() {
+        let len: usize = pack_row(b, y, &mut row);
+        line[0] = 0;
+        for (l, r) in line[1..=len].iter_mut().zip(row[..len].iter()) {
+            *l = !r;
+        }
+        let mut rest: &[u8] = &line[..row_len];
+        while !rest.is_empty() {
+            if block_left == 0 {
+                block_left = raw_left.min(STORED_MAX);
+                let last: u8 = (block_left == raw_left) as u8;
+                let n: u16 = block_left as u16;
+                chunk.write(&[last]);
+                chunk.write(&n.to_le_bytes());
+                chunk.write(&(!n).to_le_bytes());
+            }
+            let n: usize = rest.len().min(block_left);
+            chunk.write(&rest[..n]);
+            adler = adler32_update(adler, &rest[..n]);
+            block_left -= n;
+            raw_left -= n;
+            rest = &rest[n..];
+        }
+    }
+    chunk.write(&((adler.1 << 16) | adler.0).to_be_bytes());
+    chunk.end();
+
+    Chunk::start(&mut out, b"IEND", 0).end();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_chunk_crc(kind: &[u8; 4], data: &[u8]) -> u32 {
+        !crc32_update(crc32_update(0xFFFF_FFFF, kind), data)
+    }
+
+    fn adler32(data: &[u8]) -> u32 {
+        let (a, b) = adler32_update((1, 0), data);
+        (b << 16) | a
+    }
+
+    struct Checker {
+        w: u32,
+        h: u32,
+    }
+
+    impl Bitmap for Checker {
+        fn width(&self) -> u32 { self.w }
+        fn height(&self) -> u32 { self.h }
+        fn dark(&self, x: u32, y: u32) -> bool { (x + y).is_multiple_of(3) }
+    }
+
+    fn collect<W: FnOnce(&mut dyn FnMut(&[u8]))>(w: W) -> Vec<u8> {
+        let mut v: Vec<u8> = Vec::new();
+        w(&mut |d: &[u8]| v.extend_from_slice(d));
+        v
+    }
+
+    #[test]
+    fn pbm_layout() {
+        let b = Checker { w: 10, h: 2 };
+        let pbm = collect(|out| pbm_write(&b, out));
+        assert_eq!(&pbm[..], &b"P4\n10 2\n\x92\x40\x24\x80"[..]);
+    }
+
+    /// take a PNG apart, checking every CRC, and return the inflated image data
+    fn png_unpack(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        let mut at = 8;
+        let (mut w, mut h) = (0, 0);
+        let mut idat: Vec<u8> = Vec::new();
+        loop {
+            let len = u32::from_be_bytes([png[at], png[at + 1], png[at + 2], png[at + 3]]) as usize;
+            let mut kind = [0u8; 4];
+            kind.copy_from_slice(&png[at + 4..at + 8]);
+            let data = &png[at + 8..at + 8 + len];
+            let crc = u32::from_be_bytes([png[at + 8 + len], png[at + 9 + len], png[at + 10 + len], png[at + 11 + len]]);
+            assert_eq!(crc, png_chunk_crc(&kind, data));
+            at += 12 + len;
+            match &kind {
+                b"IHDR" => {
+                    w = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                    h = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+                    assert_eq!(&data[8..], &[1, 0, 0, 0, 0]);
+                },
+                b"IDAT" => idat.extend_from_slice(data),
+                b"IEND" => break,
+                _ => panic!("unexpected chunk"),
+            }
+        }
+        assert_eq!(at, png.len());
+
+        // zlib with stored blocks only
+        assert_eq!(&idat[..2], &[0x78, 0x01]);
+        let mut raw: Vec<u8> = Vec::new();
+        let mut p = 2;
+        loop {
+            let last = idat[p] & 1;
+            assert_eq!(idat[p] & 6, 0);
+            let n = u16::from_le_bytes([idat[p + 1], idat[p + 2]]);
+            assert_eq!(!n, u16::from_le_bytes([idat[p + 3], idat[p + 4]]));
+            raw.extend_from_slice(&idat[p + 5..p + 5 + n as usize]);
+            p += 5 + n as usize;
+            if last == 1 {
+                break;
+            }
+        }
+        assert_eq!(u32::from_be_bytes([idat[p], idat[p + 1], idat[p + 2], idat[p + 3]]), adler32(&raw));
+        assert_eq!(p + 4, idat.len());
+        (w, h, raw)
+    }
+
+    #[test]
+    fn png_roundtrip() {
+        let b = Checker { w: 13, h: 5 };
+        let (w, h, raw) = png_unpack(&collect(|out| png_write(&b, out)));
+        assert_eq!((w, h), (13, 5));
+        assert_eq!(raw.len(), 5 * 3);
+        for y in 0..5 {
+            let row = &raw[y * 3..y * 3 + 3];
+            assert_eq!(row[0], 0);
+            for x in 0..13u32 {
+                let white = row[1 + x as usize / 8] & (0x80 >> (x % 8)) != 0;
+                assert_eq!(white, !b.dark(x, y as u32));
+            }
+        }
+    }
+
+    #[test]
+    fn png_spans_stored_blocks() {
+        let b = Checker { w: BITMAP_MAX_WIDTH, h: 600 };
+        let (_, _, raw) = png_unpack(&collect(|out| png_write(&b, out)));
+        assert_eq!(raw.len(), 600 * (1 + BITMAP_MAX_WIDTH as usize / 8));
+    }
+
+    #[test]
+    fn rle_layout() {
+        let b = Checker { w: 200, h: 1 };
+        let rle = collect(|out| rle_write(&Row(200), out));
+        assert_eq!(&rle[..8], b"BTSS\xC8\x00\x01\x00");
+        // all light: one run of 200, which takes two bytes
+        assert_eq!(&rle[8..10], &[0xC8, 0x01]);
+        assert_eq!(rle.len(), 14);
+        assert_eq!(&rle[10..], &crate::crc32(&rle[..10]).to_le_bytes());
+
+        // starts dark, so the first light run is empty
+        let rle = collect(|out| rle_write(&b, out));
+        assert_eq!(&rle[8..12], &[0, 1, 2, 1]);
+    }
+
+    struct Row(u32);
+
+    impl Bitmap for Row {
+        fn width(&self) -> u32 { self.0 }
+        fn height(&self) -> u32 { 1 }
+        fn dark(&self, _x: u32, _y: u32) -> bool { false }
+    }
+
+    #[test]
+    fn adler32_check_value() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
@@ -0,0 +1,365 @@
+//! Host side helpers, for tools running on a PC.
+//!
+//! A `Link` works the same on the host as on the board; wrap the serial port in `StdIo` to get
+//! one. `decode` and `Assembler` are for taking apart a captured byte stream offline, and
+//! `Screenshot` turns what arrives on the screenshot channel into a PNG.
+
+use crate::*;
+use crate::bitmap::*;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// LinkIo over any std reader/writer, such as an opened serial port. Reads block for as long
+/// as the underlying port does, so configure its read timeout to taste.
+pub struct StdIo<T: Read + Write> {
+    inner: T,
+}
+
+impl<T: Read + Write> StdIo<T> {
+    pub fn new(inner: T) -> Self {
+        StdIo { inner }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read + Write> LinkIo for StdIo<T> {
+    fn write(&mut self, data: &[u8]) {
+        self.inner.write_all(data).ok();
+        self.inner.flush().ok();
+    }
+
+    fn read(&mut self, _timeout_ms: u32) -> Option<u8> {
+        let mut c: [u8; 1] = [0];
+        match self.inner.read(&mut c) {
+            Ok(1) => Some(c[0]),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedFrame {
+    pub channel: Channel,
+    pub kind: FrameKind,
+    pub seq: u8,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+impl OwnedFrame {
+    pub fn as_frame(&self) -> Frame<'_> {
+        Frame { channel: self.channel, kind: self.kind, seq: self.seq, flags: self.flags, payload: &self.payload }
+    }
+}
+
+/// encode a frame for the wire, delimiter included
+pub fn encode(frame: &Frame) -> Result<Vec<u8>, LinkError> {
+    let mut out: [u8; MAX_ENCODED] = [0; MAX_ENCODED];
+    let n: usize = frame_encode(frame, &mut out)?;
+    Ok(out[..n].to_vec())
+}
+
+/// split a captured byte stream into frames; damaged frames show up as errors in stream order
+pub fn decode(bytes: &[u8]) -> Vec<Result<OwnedFrame, LinkError>> {
+    let mut dec: FrameDecoder = FrameDecoder::new();
+    let mut frames: Vec<Result<OwnedFrame, LinkError>> = Vec::new();
+    for &c in bytes {
+        if let Some(r) = dec.push(c) {
+            frames.push(r.map(|f| OwnedFrame {
+                channel: f.channel,
+                kind: f.kind,
+                seq: f.seq,
+                flags: f.flags,
+                payload: f.payload.to_vec(),
+            }));
+        }
+    }
+    frames
+}
+
+/// Rebuilds transfers from the data frames of a captured stream, one per channel at a time.
+/// Resends are recognised by their repeated sequence number and only counted once.
+#[derive(Default)]
+pub struct Assembler {
+    streams: HashMap<Channel, (Option<u8>, Vec<u8>)>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler { streams: HashMap::new() }
+    }
+
+    /// returns the channel and contents of a transfer when its last frame goes by
+    pub fn push(&mut self, frame: &Frame) -> Option<(Channel, Vec<u8>)> {
+        if frame.kind != FrameKind::Data {
+            return None;
+        }
+        let (last, data) = self.streams.entry(frame.channel).or_insert((None, Vec::new()));
+        if *last == Some(frame.seq) {
+            return None;
+        }
+        *last = Some(frame.seq);
+        data.extend_from_slice(frame.payload);
+        if frame.flags & FLAG_END != 0 {
+            let (_, data) = self.streams.remove(&frame.channel).unwrap();
+            Some((frame.channel, data))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScreenshotError {
+    Magic,
+    Crc,
+    /// the runs stop short of the image, or run past its end
+    Length,
+}
+
+/// A screenshot taken apart from its `rle_write` form
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    /// raster order, true for dark
+    pub pixels: Vec<bool>,
+}
+
+impl Screenshot {
+    pub fn decode(data: &[u8]) -> Result<Self, ScreenshotError> {
+        if data.len() < 12 || data[..4] != RLE_MAGIC {
+            return Err(ScreenshotError::Magic);
+        }
+        let (body, crc) = data.split_at(data.len() - 4);
+        if crc32(body) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+            return Err(ScreenshotError::Crc);
+        }
+        let width: u32 = u16::from_le_bytes([body[4], body[5]]) as u32;
+        let height: u32 = u16::from_le_bytes([body[6], body[7]]) as u32;
+        let total: usize = width as usize * height as usize;
+        let mut pixels: Vec<bool> = Vec::with_capacity(total);
+        let mut dark: bool = false;
+        let mut run: usize = 0;
+        let mut shift: u32 = 0;
+        for &c in &body[8..] {
+            if shift > 28 {
+                return Err(ScreenshotError::Length);
+            }
+            run |= ((c & 0x7F) as usize) << shift;
+            shift += 7;
+            if c & 0x80 != 0 {
+                continue;
+            }
+            if pixels.len() + run > total {
+                return Err(ScreenshotError::Length);
+            }
+            pixels.resize(pixels.len() + run, dark);
+            dark = !dark;
+            run = 0;
+            shift = 0;
+        }
+        if shift != 0 || pixels.len() != total {
+            return Err(ScreenshotError::Length);
+        }
+        Ok(Screenshot { width, height, pixels })
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut png: Vec<u8> = Vec::new();
+        png_write(self, |d: &[u8]| png.extend_from_slice(d));
+        png
+    }
+
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let mut pbm: Vec<u8> = Vec::new();
+        pbm_write(self, |d: &[u8]| pbm.extend_from_slice(d));
+        pbm
+    }
+}
+
+impl Bitmap for Screenshot {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn dark(&self, x: u32, y: u32) -> bool {
+        self.pixels[(y * self.width + x) as usize]
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// one end of an in-memory wire, with optional damage on the way out
+    pub(crate) struct WireIo {
+        tx: mpsc::Sender<u8>,
+        rx: mpsc::Receiver<u8>,
+        /// number of leading writes to throw away
+        pub(crate) drop_writes: usize,
+        /// index in the outgoing byte stream to corrupt
+        pub(crate) corrupt_at: Option<usize>,
+        written: usize,
+    }
+
+    impl LinkIo for WireIo {
+        fn write(&mut self, data: &[u8]) {
+            if self.drop_writes > 0 {
+                self.drop_writes -= 1;
+                return;
+            }
+            for &c in data {
+                // never turn a byte into a delimiter, that's a different failure
+                let c = if Some(self.written) == self.corrupt_at { if c == 0x5A { 0xA5 } else { 0x5A } } else { c };
+                self.written += 1;
+                self.tx.send(c).ok();
+            }
+        }
+
+        fn read(&mut self, timeout_ms: u32) -> Option<u8> {
+            self.rx.recv_timeout(Duration::from_millis(timeout_ms as u64)).ok()
+        }
+    }
+
+    pub(crate) fn wire() -> (WireIo, WireIo) {
+        let (atx, brx) = mpsc::channel();
+        let (btx, arx) = mpsc::channel();
+        (WireIo { tx: atx, rx: arx, drop_writes: 0, corrupt_at: None, written: 0 },
+         WireIo { tx: btx, rx: brx, drop_writes: 0, corrupt_at: None, written: 0 })
+    }
+
+    pub(crate) fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 256) as u8).collect()
+    }
+
+    /// send `data` from a to b over the wire, returning what b got and both ends' stats
+    fn transfer(a: WireIo, b: WireIo, data: &[u8]) -> (Vec<u8>, LinkStats, LinkStats) {
+        let receiver = thread::spawn(move || {
+            let mut link = Link::new(b);
+            let mut got: Vec<u8> = Vec::new();
+            link.recv_stream(Channel::FileUpload, 1000, |chunk| got.extend_from_slice(chunk)).unwrap();
+            (got, link.stats())
+        });
+        let mut link = Link::new(a);
+        link.set_ack_timeout(20);
+        link.send_stream(Channel::FileUpload, data).unwrap();
+        let (got, rx_stats) = receiver.join().unwrap();
+        (got, link.stats(), rx_stats)
+    }
+
+    #[test]
+    fn stream_roundtrip() {
+        let (a, b) = wire();
+        let data = test_data(MAX_PAYLOAD * 3 + 17);
+        let (got, tx, rx) = transfer(a, b, &data);
+        assert_eq!(got, data);
+        assert_eq!(tx.sent, 4);
+        assert_eq!(tx.retries, 0);
+        assert_eq!(rx.received, 4);
+    }
+
+    #[test]
+    fn empty_stream() {
+        let (a, b) = wire();
+        let (got, _, rx) = transfer(a, b, &[]);
+        assert!(got.is_empty());
+        assert_eq!(rx.received, 1);
+    }
+
+    #[test]
+    fn damaged_frame_is_resent() {
+        let (mut a, b) = wire();
+        a.corrupt_at = Some(MAX_ENCODED + 20); // somewhere in the second frame
+        let data = test_data(MAX_PAYLOAD * 2);
+        let (got, tx, rx) = transfer(a, b, &data);
+        assert_eq!(got, data);
+        assert!(tx.retries >= 1);
+        assert!(rx.rejected >= 1);
+    }
+
+    #[test]
+    fn lost_ack_is_not_delivered_twice() {
+        let (a, mut b) = wire();
+        b.drop_writes = 1; // the first ACK goes missing
+        let data = test_data(MAX_PAYLOAD + 100);
+        let (got, tx, rx) = transfer(a, b, &data);
+        assert_eq!(got, data);
+        assert_eq!(tx.retries, 1);
+        assert_eq!(rx.duplicates, 1);
+    }
+
+    #[test]
+    fn silent_peer_gives_up() {
+        let (a, _b) = wire();
+        let mut link = Link::new(a);
+        link.set_ack_timeout(1);
+        link.set_retries(2);
+        assert_eq!(link.send(Channel::Log, 0, b"anyone?"), Err(LinkError::NoAck));
+        assert_eq!(link.stats().retries, 2);
+    }
+
+    #[test]
+    fn capture_is_reassembled() {
+        let mut capture: Vec<u8> = Vec::new();
+        let frames = [
+            (Channel::Log, 0u8, 0u8, &b"one "[..]),
+            (Channel::Noise, 1, FLAG_END, &b"\x01\x02"[..]),
+            (Channel::Log, 2, 0, &b"two "[..]),
+            (Channel::Log, 2, 0, &b"two "[..]), // resend
+            (Channel::Log, 3, FLAG_END, &b"three"[..]),
+        ];
+        for (channel, seq, flags, payload) in frames.iter() {
+            let f = Frame { channel: *channel, kind: FrameKind::Data, seq: *seq, flags: *flags, payload };
+            capture.extend(encode(&f).unwrap());
+            capture.extend(encode(&Frame { channel: Channel::Control, kind: FrameKind::Ack, seq: *seq, flags: 0, payload: &[] }).unwrap());
+        }
+        capture.extend(&[0x11, 0x22, 0x00]); // trailing noise
+
+        let decoded = decode(&capture);
+        assert_eq!(decoded.len(), 11);
+        assert!(decoded[10].is_err());
+
+        let mut asm = Assembler::new();
+        let done: Vec<(Channel, Vec<u8>)> = decoded.iter()
+            .filter_map(|r| r.as_ref().ok())
+            .filter_map(|f| asm.push(&f.as_frame()))
+            .collect();
+        assert_eq!(done, vec![(Channel::Noise, vec![1, 2]), (Channel::Log, b"one two three".to_vec())]);
+    }
+
+    #[test]
+    fn screenshot_roundtrip() {
+        let width = 336;
+        let height = 40;
+        let pixels: Vec<bool> = (0..width * height).map(|i| (i / 7) % 5 == 0 || i % 1000 < 300).collect();
+        let shot = Screenshot { width, height, pixels };
+        let mut rle: Vec<u8> = Vec::new();
+        rle_write(&shot, |d| rle.extend_from_slice(d));
+        assert!(rle.len() < (width * height / 8) as usize);
+        assert_eq!(Screenshot::decode(&rle), Ok(shot.clone()));
+        assert_eq!(&shot.to_pbm()[..10], b"P4\n336 40\n");
+        assert_eq!(&shot.to_png()[1..4], b"PNG");
+
+        let mut bad = rle.clone();
+        bad[20] ^= 0x04;
+        assert_eq!(Screenshot::decode(&bad), Err(ScreenshotError::Crc));
+        assert_eq!(Screenshot::decode(&rle[4..]), Err(ScreenshotError::Magic));
+
+        // runs that stop short, with a good checksum over them
+        let mut short = rle[..rle.len() - 6].to_vec();
+        let crc = crc32(&short);
+        short.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(Screenshot::decode(&short), Err(ScreenshotError::Length));
+    }
+}
@@ -0,0 +1,198 @@
+//! The memory LCD as display code sees it, and a simulated one for running that code on the
+//! host.
+//!
+//! The framebuffer is 11 words a line: pixel x of a line in bit x % 32 of word x / 32, clear
+//! for dark, and the line's dirty bit in bit 16 of the last word, above the 16 pixels it
+//! carries. The controller copies the lines marked dirty to the glass on an update.
+
+use core::cell::Cell;
+use core::ops::Range;
+use crate::bitmap::Bitmap;
+
+pub const FB_WIDTH_WORDS: usize = 11;
+pub const FB_WIDTH_PIXELS: usize = 336;
+pub const FB_LINES: usize = 536;
+pub const FB_SIZE: usize = FB_WIDTH_WORDS * FB_LINES; // 44 bytes by 536 lines
+/// in the last word of each line, above the 16 pixels it carries
+pub const FB_DIRTY: u32 = 0x1_0000;
+
+/// The memory LCD and its controller, as BtDisplay drives it: MemLcd on the board, SimLcd
+/// on the host.
+pub trait LcdPanel {
+    /// free-running tick count, the clock BtDisplay keeps its frame budget by
+    fn ticks(&self) -> u64;
+    /// bring the panel up and make it agree with an all-white framebuffer
+    fn init(&mut self, clk_mhz: u32);
+    fn busy(&self) -> bool;
+    /// copy some lines of a framebuffer into the controller's memory
+    fn write_lines(&mut self, fb: &[u32; FB_SIZE], lines: Range<usize>);
+    /// send the lines marked dirty in the controller's memory to the glass
+    fn update_dirty(&mut self);
+    fn update_all(&mut self);
+}
+
+/// pixel (x, y) of a framebuffer in the layout above is dark
+pub fn fb_dark(fb: &[u32], x: u32, y: u32) -> bool {
+    fb[(x / 32) as usize + y as usize * FB_WIDTH_WORDS] & (1 << (x % 32)) == 0
+}
+
+/// A memory LCD in RAM, for running display code on the host.
+///
+/// Like the real thing, it keeps what it was last sent: `memory` is the controller's copy of
+/// the framebuffer and `glass` what the panel shows, which only changes on an update. Time
+/// stands still unless advanced, except that waiting on a busy panel moves it along.
+pub struct SimLcd {
+    pub memory: [u32; FB_SIZE],
+    pub glass: [u32; FB_SIZE],
+    /// lines sent to the glass, over all updates
+    pub lines_sent: u32,
+    pub updates: u32,
+    /// words copied into `memory`
+    pub words_written: u32,
+    now: Cell<u64>,
+    busy_until: u64,
+}
+
+/// how long the panel takes to clock out one line: 352 bits at 2MHz
+const SIM_LINE_US: u64 = 176;
+
+impl Default for SimLcd {
+    fn default() -> Self {
+        SimLcd::new()
+    }
+}
+
+impl SimLcd {
+    pub fn new() -> Self {
+        let mut sim: SimLcd = SimLcd {
+            memory: [0; FB_SIZE],
+            glass: [0; FB_SIZE],
+            lines_sent: 0,
+            updates: 0,
+            words_written: 0,
+            now: Cell::new(0),
+            busy_until: 0,
+        };
+        sim.blank();
+        sim
+    }
+
+    /// white, with no dirty bits, as lcd_sync_clear() leaves the real one
+    fn blank(&mut self) {
+        for (words, w) in self.memory.iter_mut().enumerate() {
+            *w = if words % FB_WIDTH_WORDS != 10 { 0xFFFF_FFFF } else { 0x0000_FFFF };
+        }
+        self.glass = self.memory;
+    }
+
+    pub fn advance_ms(&self, ms: u32) {
+        self.now.set(self.now.get() + ms as u64); // ticks are milliseconds
+    }
+
+    fn send_line(&mut self, line: usize) {
+        let start: usize = line * FB_WIDTH_WORDS;
+        self.glass[start..start + FB_WIDTH_WORDS].copy_from_slice(&self.memory[start..start + FB_WIDTH_WORDS]);
+        self.lines_sent += 1;
+    }
+
+    /// busy for as long as the lines sent this update take to go out
+    fn start_update(&mut self, lines: u32) {
+        let ms: u64 = (lines as u64 * SIM_LINE_US).div_ceil(1000);
+        self.busy_until = self.now.get() + ms;
+        self.updates += 1;
+    }
+}
+
+impl LcdPanel for SimLcd {
+    fn ticks(&self) -> u64 {
+        self.now.get()
+    }
+
+    fn init(&mut self, _clk_mhz: u32) {
+        self.blank();
+    }
+
+    /// each look at a busy panel takes a millisecond
+    fn busy(&self) -> bool {
+        if self.now.get() < self.busy_until {
+            self.advance_ms(1);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn write_lines(&mut self, fb: &[u32; FB_SIZE], lines: Range<usize>) {
+        let words: Range<usize> = lines.start * FB_WIDTH_WORDS..lines.end * FB_WIDTH_WORDS;
+        self.words_written += words.len() as u32;
+        self.memory[words.clone()].copy_from_slice(&fb[words]);
+    }
+
+    fn update_dirty(&mut self) {
+        let before: u32 = self.lines_sent;
+        for line in 0..FB_LINES {
+            if self.memory[line * FB_WIDTH_WORDS + (FB_WIDTH_WORDS - 1)] & FB_DIRTY != 0 {
+                self.send_line(line);
+            }
+        }
+        self.start_update(self.lines_sent - before);
+    }
+
+    fn update_all(&mut self) {
+        for line in 0..FB_LINES {
+            self.send_line(line);
+        }
+        self.start_update(FB_LINES as u32);
+    }
+}
+
+/// what the panel is showing
+impl Bitmap for SimLcd {
+    fn width(&self) -> u32 {
+        FB_WIDTH_PIXELS as u32
+    }
+
+    fn height(&self) -> u32 {
+        FB_LINES as u32
+    }
+
+    fn dark(&self, x: u32, y: u32) -> bool {
+        fb_dark(&self.glass, x, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirty_line(fb: &mut [u32; FB_SIZE], line: usize, x: u32) {
+        fb[line * FB_WIDTH_WORDS + (x / 32) as usize] &= !(1 << (x % 32));
+        fb[line * FB_WIDTH_WORDS + (FB_WIDTH_WORDS - 1)] |= FB_DIRTY;
+    }
+
+    #[test]
+    fn glass_changes_only_on_update() {
+        let mut sim = SimLcd::new();
+        let mut fb = sim.memory;
+        dirty_line(&mut fb, 7, 40);
+        dirty_line(&mut fb, 9, 41);
+        sim.write_lines(&fb, 7..10);
+        assert!(!sim.dark(40, 7));
+
+        sim.update_dirty();
+        assert_eq!(sim.lines_sent, 2);
+        assert!(sim.dark(40, 7) && sim.dark(41, 9) && !sim.dark(40, 8));
+    }
+
+    #[test]
+    fn busy_for_the_lines_sent() {
+        let mut sim = SimLcd::new();
+        sim.update_all(); // 536 lines at 176us is just over 94ms
+        let mut waited: u64 = 0;
+        while sim.busy() {
+            waited += 1;
+        }
+        assert_eq!(waited, 95);
+        assert_eq!(sim.ticks(), 95);
+    }
+}
@@ -0,0 +1,584 @@
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+//! Framed, acknowledged link for moving bulk data between the board and a host over the UART.
+//!
+//! Every frame is a 6-byte header, the payload and a CRC32, COBS encoded and terminated by a
+//! zero byte:
+//!
+//!   channel u8 | kind u8 | seq u8 | flags u8 | length u16 LE | payload | crc32 u32 LE
+//!
+//! The CRC covers the header and payload. Data frames are stop-and-wait: the sender waits for
+//! an ACK carrying the frame's sequence number, and resends on a NAK (the receiver saw a damaged
+//! frame) or when no answer arrives in time. A receiver that sees the same sequence number twice
+//! ACKs it again, since its first ACK was lost, but doesn't deliver it twice.
+//!
+//! A transfer is a run of Data frames on one channel, the last of which carries FLAG_END.
+//!
+//! The protocol core is no_std and allocation free; the `host` module (feature "std") adds the
+//! conveniences a host tool wants. `xmodem` carries plain XMODEM-1K over the same LinkIo, for
+//! hosts without a tool that speaks the framed link. `bitmap` writes 1-bit images out as PBM
+//! or PNG, for screenshots and golden-image tests. `lcd` describes the memory LCD and
+//! simulates one, so display code runs on the host.
+
+#[cfg(any(test, feature = "std"))]
+pub mod host;
+pub mod xmodem;
+pub mod bitmap;
+pub mod lcd;
+
+/// largest payload carried by a single frame
+pub const MAX_PAYLOAD: usize = 512;
+const HEADER_LEN: usize = 6;
+const CRC_LEN: usize = 4;
+/// largest frame before COBS encoding
+pub const MAX_RAW: usize = HEADER_LEN + MAX_PAYLOAD + CRC_LEN;
+/// largest frame on the wire, including the zero delimiter
+pub const MAX_ENCODED: usize = cobs_max_len(MAX_RAW) + 1;
+
+/// set on the last frame of a transfer
+pub const FLAG_END: u8 = 0x01;
+
+/// how long a sender waits for an ACK before resending
+pub const LINK_ACK_TIMEOUT_MS: u32 = 100;
+/// how many times a frame is resent before giving up
+pub const LINK_RETRIES: u32 = 5;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Channel {
+    /// ACKs and NAKs
+    Control = 0,
+    /// raw noise source samples, 16 bits LE each
+    Noise = 1,
+    /// log records, in `hal_log::log_encode` format
+    Log = 2,
+    Screenshot = 3,
+    /// host to board file transfer
+    FileUpload = 4,
+}
+
+impl Channel {
+    pub fn from_u8(c: u8) -> Option<Channel> {
+        match c {
+            0 => Some(Channel::Control),
+            1 => Some(Channel::Noise),
+            2 => Some(Channel::Log),
+            3 => Some(Channel::Screenshot),
+            4 => Some(Channel::FileUpload),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Data = 0,
+    Ack = 1,
+    Nak = 2,
+}
+
+impl FrameKind {
+    pub fn from_u8(k: u8) -> Option<FrameKind> {
+        match k {
+            0 => Some(FrameKind::Data),
+            1 => Some(FrameKind::Ack),
+            2 => Some(FrameKind::Nak),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LinkError {
+    /// frame checksum mismatch
+    Crc,
+    /// malformed COBS encoding
+    Cobs,
+    /// frame too short or too long, or length field doesn't match
+    Length,
+    /// unknown channel, or a frame on a channel other than the one being received
+    Channel,
+    /// unknown frame kind
+    Kind,
+    /// nothing arrived in time
+    Timeout,
+    /// the peer never acknowledged the frame
+    NoAck,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub channel: Channel,
+    pub kind: FrameKind,
+    pub seq: u8,
+    pub flags: u8,
+    pub payload: &'a [u8],
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib)
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(0xFFFF_FFFF, data)
+}
+
+/// Run `data` through the CRC-32 register, for CRCs computed a piece at a time: start from
+/// 0xFFFF_FFFF and invert the result.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// worst case COBS encoded length of `len` bytes, without the delimiter
+pub const fn cobs_max_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// COBS encode `src` into `dst`, which must hold cobs_max_len(src.len()) bytes.
+/// Returns the encoded length; no delimiter is added.
+pub fn cobs_encode(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut code_idx: usize = 0;
+    let mut out: usize = 1;
+    let mut code: u8 = 1;
+    for &b in src {
+        if b == 0 {
+            dst[code_idx] = code;
+            code_idx = out;
+            out += 1;
+            code = 1;
+        } else {
+            dst[out] = b;
+            out += 1;
+            code += 1;
+            if code == 0xFF {
+                dst[code_idx] = code;
+                code_idx = out;
+                out += 1;
+                code = 1;
+            }
+        }
+    }
+    dst[code_idx] = code;
+    out
+}
+
+/// decode a COBS block (without its delimiter) into `dst`, returning the decoded length
+pub fn cobs_decode(src: &[u8], dst: &mut [u8]) -> Result<usize, LinkError> {
+    let mut i: usize = 0;
+    let mut out: usize = 0;
+    while i < src.len() {
+        let code: u8 = src[i];
+        if code == 0 {
+            return Err(LinkError::Cobs);
+        }
+        i += 1;
+        let run: usize = code as usize - 1;
+        if i + run > src.len() {
+            return Err(LinkError::Cobs);
+        }
+        if out + run > dst.len() {
+            return Err(LinkError::Length);
+        }
+        dst[out..out + run].copy_from_slice(&src[i..i + run]);
+        out += run;
+        i += run;
+        // every block but a full one stands for a zero, except at the very end
+        if code != 0xFF && i < src.len() {
+            if out >= dst.len() {
+                return Err(LinkError::Length);
+            }
+            dst[out] = 0;
+            out += 1;
+        }
+    }
+    Ok(out)
+}
+
+/// Encode `frame` for the wire, including the trailing delimiter, and return its length.
+pub fn frame_encode(frame: &Frame, out: &mut [u8; MAX_ENCODED]) -> Result<usize, LinkError> {
+    let len: usize = frame.payload.len();
+    if len > MAX_PAYLOAD {
+        return Err(LinkError::Length);
+    }
+    let mut raw: [u8; MAX_RAW] = [0; MAX_RAW];
+    raw[0] = frame.channel as u8;
+    raw[1] = frame.kind as u8;
+    raw[2] = frame.seq;
+    raw[3] = frame.flags;
+    raw[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+    raw[HEADER_LEN..HEADER_LEN + len].copy_from_slice(frame.payload);
+    let crc: u32 = crc32(&raw[..HEADER_LEN + len]);
+    raw[HEADER_LEN + len..HEADER_LEN + len + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+
+    let n: usize = cobs_encode(&raw[..HEADER_LEN + len + CRC_LEN], &mut out[..]);
+    out[n] = 0;
+    Ok(n + 1)
+}
+
+/// check and split up a decoded frame
+fn frame_parse(raw: &[u8]) -> Result<Frame<'_>, LinkError> {
+    if raw.len() < HEADER_LEN + CRC_LEN {
+        return Err(LinkError::Length);
+    }
+    let (body, crc) = raw.split_at(raw.len() - CRC_LEN);
+    if crc32(body) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        return Err(LinkError::Crc);
+    }
+    let len: usize = u16::from_le_bytes([body[4], body[5]]) as usize;
+    if len != body.len() - HEADER_LEN {
+        return Err(LinkError::Length);
+    }
+    Ok(Frame {
+        channel: Channel::from_u8(body[0]).ok_or(LinkError::Channel)?,
+        kind: FrameKind::from_u8(body[1]).ok_or(LinkError::Kind)?,
+        seq: body[2],
+        flags: body[3],
+        payload: &body[HEADER_LEN..],
+    })
+}
+
+/// Reassembles frames from a byte stream. Anything between two delimiters that doesn't check
+/// out is reported as an error, after which decoding picks up at the next frame.
+pub struct FrameDecoder {
+    enc: [u8; MAX_ENCODED],
+    len: usize,
+    overflow: bool,
+    raw: [u8; MAX_RAW],
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder {
+            enc: [0; MAX_ENCODED],
+            len: 0,
+            overflow: false,
+            raw: [0; MAX_RAW],
+        }
+    }
+
+    /// feed one received byte; returns a frame, or an error, when a delimiter completes one
+    pub fn push(&mut self, c: u8) -> Option<Result<Frame<'_>, LinkError>> {
+        if c != 0 {
+            if self.len < self.enc.len() {
+                self.enc[self.len] = c;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let len: usize = self.len;
+        self.len = 0;
+        if self.overflow {
+            self.overflow = false;
+            return Some(Err(LinkError::Length));
+        }
+        if len == 0 {
+            return None; // back to back delimiters
+        }
+        match cobs_decode(&self.enc[..len], &mut self.raw) {
+            Ok(n) => Some(frame_parse(&self.raw[..n])),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Byte transport underneath a Link
+pub trait LinkIo {
+    fn write(&mut self, data: &[u8]);
+    /// next received byte, or None if nothing arrived within `timeout_ms`
+    fn read(&mut self, timeout_ms: u32) -> Option<u8>;
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// data frames acknowledged by the peer
+    pub sent: u32,
+    /// data frames resent after a NAK or timeout
+    pub retries: u32,
+    /// data frames delivered
+    pub received: u32,
+    /// resent data frames that had already been delivered
+    pub duplicates: u32,
+    /// damaged frames that were NAKed
+    pub rejected: u32,
+}
+
+pub struct Received {
+    pub channel: Channel,
+    pub flags: u8,
+    /// number of payload bytes copied into the caller's buffer
+    pub len: usize,
+}
+
+/// One end of the link. Both ends run the same code; which one sends is up to the caller.
+pub struct Link<T: LinkIo> {
+    io: T,
+    dec: FrameDecoder,
+    txbuf: [u8; MAX_ENCODED],
+    tx_seq: u8,
+    /// sequence number of the last frame delivered, to spot resends
+    rx_last: Option<u8>,
+    ack_timeout_ms: u32,
+    retries: u32,
+    stats: LinkStats,
+}
+
+impl<T: LinkIo> Link<T> {
+    pub fn new(io: T) -> Self {
+        Link {
+            io,
+            dec: FrameDecoder::new(),
+            txbuf: [0; MAX_ENCODED],
+            tx_seq: 0,
+            rx_last: None,
+            ack_timeout_ms: LINK_ACK_TIMEOUT_MS,
+            retries: LINK_RETRIES,
+            stats: LinkStats::default(),
+        }
+    }
+
+    pub fn set_ack_timeout(&mut self, timeout_ms: u32) {
+        self.ack_timeout_ms = timeout_ms;
+    }
+
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    pub fn io_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    fn write_frame(&mut self, channel: Channel, kind: FrameKind, seq: u8, flags: u8, payload: &[u8]) -> Result<(), LinkError> {
+        let n: usize = frame_encode(&Frame { channel, kind, seq, flags, payload }, &mut self.txbuf)?;
+        self.io.write(&self.txbuf[..n]);
+        Ok(())
+    }
+
+    /// true if the peer ACKed `seq`; false on a NAK or timeout, both of which mean resend
+    fn wait_ack(&mut self, seq: u8) -> bool {
+        // bound the wait even if the peer keeps talking without answering
+        for _ in 0..MAX_ENCODED * 2 {
+            let c: u8 = match self.io.read(self.ack_timeout_ms) {
+                Some(c) => c,
+                None => return false,
+            };
+            match self.dec.push(c) {
+                Some(Ok(f)) if f.kind == FrameKind::Ack && f.seq == seq => return true,
+                Some(Ok(f)) if f.kind == FrameKind::Nak => return false,
+                _ => (), // stale ACKs and noise
+            }
+        }
+        false
+    }
+
+    /// send one frame and wait for it to be acknowledged
+    pub fn send(&mut self, channel: Channel, flags: u8, payload: &[u8]) -> Result<(), LinkError> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(LinkError::Length);
+        }
+        let seq: u8 = self.tx_seq;
+        self.tx_seq = seq.wrapping_add(1);
+        for attempt in 0..=self.retries {
+            if attempt != 0 {
+                self.stats.retries += 1;
+            }
+            self.write_frame(channel, FrameKind::Data, seq, flags, payload)?;
+            if self.wait_ack(seq) {
+                self.stats.sent += 1;
+                return Ok(());
+            }
+        }
+        Err(LinkError::NoAck)
+    }
+
+    /// send `data` as one transfer, split into as many frames as it takes
+    pub fn send_stream(&mut self, channel: Channel, data: &[u8]) -> Result<(), LinkError> {
+        if data.is_empty() {
+            return self.send(channel, FLAG_END, &[]);
+        }
+        let mut chunks = data.chunks(MAX_PAYLOAD).peekable();
+        while let Some(chunk) = chunks.next() {
+            let flags: u8 = if chunks.peek().is_none() { FLAG_END } else { 0 };
+            self.send(channel, flags, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next new data frame, acknowledge it, and copy its payload into `buf`.
+    /// `timeout_ms` is the longest gap allowed between received bytes.
+    pub fn recv(&mut self, buf: &mut [u8; MAX_PAYLOAD], timeout_ms: u32) -> Result<Received, LinkError> {
+        loop {
+            let c: u8 = self.io.read(timeout_ms).ok_or(LinkError::Timeout)?;
+            let (seq, rx) = match self.dec.push(c) {
+                None => continue,
+                Some(Err(_)) => {
+                    self.stats.rejected += 1;
+                    let last: u8 = self.rx_last.unwrap_or(0);
+                    self.write_frame(Channel::Control, FrameKind::Nak, last, 0, &[])?;
+                    continue;
+                },
+                Some(Ok(f)) => {
+                    if f.kind != FrameKind::Data {
+                        continue; // nothing of ours is waiting for an answer
+                    }
+                    buf[..f.payload.len()].copy_from_slice(f.payload);
+                    (f.seq, Received { channel: f.channel, flags: f.flags, len: f.payload.len() })
+                },
+            };
+            self.write_frame(Channel::Control, FrameKind::Ack, seq, 0, &[])?;
+            if self.rx_last == Some(seq) {
+                self.stats.duplicates += 1;
+                continue;
+            }
+            self.rx_last = Some(seq);
+            self.stats.received += 1;
+            return Ok(rx);
+        }
+    }
+
+    /// Receive a whole transfer on `channel`, handing each chunk to `f` as it arrives, and
+    /// return its length. The sequence history is forgotten first, so a new transfer can't be
+    /// mistaken for a resend of the end of the previous one.
+    ///
+    /// If the ACK for the last frame is lost the sender reports NoAck although the transfer
+    /// arrived intact; nobody is listening for its resends any more.
+    pub fn recv_stream<F: FnMut(&[u8])>(&mut self, channel: Channel, timeout_ms: u32, mut f: F) -> Result<usize, LinkError> {
+        self.rx_last = None;
+        let mut buf: [u8; MAX_PAYLOAD] = [0; MAX_PAYLOAD];
+        let mut total: usize = 0;
+        loop {
+            let rx: Received = self.recv(&mut buf, timeout_ms)?;
+            if rx.channel != channel {
+                return Err(LinkError::Channel);
+            }
+            f(&buf[..rx.len]);
+            total += rx.len;
+            if rx.flags & FLAG_END != 0 {
+                return Ok(total);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cobs_roundtrip(data: &[u8]) {
+        let mut enc = vec![0u8; cobs_max_len(data.len())];
+        let n = cobs_encode(data, &mut enc);
+        assert!(!enc[..n].contains(&0));
+        let mut dec = vec![0u8; data.len()];
+        assert_eq!(cobs_decode(&enc[..n], &mut dec), Ok(data.len()));
+        assert_eq!(&dec[..], data);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn cobs_edge_cases() {
+        cobs_roundtrip(&[]);
+        cobs_roundtrip(&[0]);
+        cobs_roundtrip(&[0, 0, 0]);
+        cobs_roundtrip(&[1, 0, 2, 0]);
+        let long: Vec<u8> = (0..1000).map(|i| (i % 255 + 1) as u8).collect();
+        cobs_roundtrip(&long[..254]);
+        cobs_roundtrip(&long[..255]);
+        cobs_roundtrip(&long);
+        let mixed: Vec<u8> = (0..MAX_RAW).map(|i| (i * 7 % 13) as u8).collect();
+        cobs_roundtrip(&mixed);
+    }
+
+    #[test]
+    fn cobs_rejects_truncation() {
+        let mut dec = [0u8; 16];
+        assert_eq!(cobs_decode(&[5, 1, 2], &mut dec), Err(LinkError::Cobs));
+        assert_eq!(cobs_decode(&[3, 1, 2], &mut dec[..1]), Err(LinkError::Length));
+    }
+
+    #[test]
+    fn frame_roundtrip() {
+        let payload: Vec<u8> = (0..MAX_PAYLOAD).map(|i| i as u8).collect();
+        let frame = Frame { channel: Channel::Noise, kind: FrameKind::Data, seq: 0xAB, flags: FLAG_END, payload: &payload };
+        let mut enc = [0u8; MAX_ENCODED];
+        let n = frame_encode(&frame, &mut enc).unwrap();
+        assert_eq!(enc[n - 1], 0);
+
+        let mut dec = FrameDecoder::new();
+        for &c in &enc[..n - 1] {
+            assert!(dec.push(c).is_none());
+        }
+        assert_eq!(dec.push(0), Some(Ok(frame)));
+    }
+
+    #[test]
+    fn oversized_payload_is_refused() {
+        let payload = [1u8; MAX_PAYLOAD + 1];
+        let frame = Frame { channel: Channel::Log, kind: FrameKind::Data, seq: 0, flags: 0, payload: &payload };
+        let mut enc = [0u8; MAX_ENCODED];
+        assert_eq!(frame_encode(&frame, &mut enc), Err(LinkError::Length));
+    }
+
+    #[test]
+    fn damage_is_detected_and_decoder_resyncs() {
+        let frame = Frame { channel: Channel::Log, kind: FrameKind::Data, seq: 1, flags: 0, payload: b"hello" };
+        let mut enc = [0u8; MAX_ENCODED];
+        let n = frame_encode(&frame, &mut enc).unwrap();
+
+        let mut dec = FrameDecoder::new();
+        // line noise, then a frame with a flipped bit, then a good one
+        for &c in &[0x12u8, 0x34] {
+            dec.push(c);
+        }
+        assert!(dec.push(0).unwrap().is_err());
+        let h = enc.iter().position(|&c| c == b'h').unwrap();
+        for (i, &c) in enc[..n].iter().enumerate() {
+            let c = if i == h { c ^ 0x02 } else { c };
+            if let Some(r) = dec.push(c) {
+                assert_eq!(r, Err(LinkError::Crc));
+            }
+        }
+        let mut got = None;
+        for &c in &enc[..n] {
+            if let Some(r) = dec.push(c) {
+                got = Some(r.map(|f| f.payload.to_vec()));
+            }
+        }
+        assert_eq!(got, Some(Ok(b"hello".to_vec())));
+    }
+
+    #[test]
+    fn decoder_survives_overflow() {
+        let mut dec = FrameDecoder::new();
+        for _ in 0..MAX_ENCODED * 2 {
+            assert!(dec.push(0x55).is_none());
+        }
+        assert_eq!(dec.push(0).map(|r| r.map(|f| f.seq)), Some(Err(LinkError::Length)));
+        assert!(dec.push(0).is_none());
+    }
+}
@@ -0,0 +1,213 @@
+//! XMODEM-1K, CRC-16 variant, for hosts that only have stock terminal programs (`sx -k`).
+//!
+//! Only the CRC mode is spoken; a sender that insists on the old additive checksum never gets
+//! started. The last block is padded out with XMODEM_PAD, so whatever is being transferred has to
+//! carry its own length.
+
+use crate::LinkIo;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// sent by the receiver in place of NAK to ask for CRC-16 mode
+const CRC_MODE: u8 = b'C';
+
+pub const XMODEM_BLOCK: usize = 128;
+pub const XMODEM_BLOCK_1K: usize = 1024;
+/// filler for the unused end of the last block
+pub const XMODEM_PAD: u8 = 0x1A;
+/// how often the receiver repeats itself while waiting
+pub const XMODEM_POLL_MS: u32 = 1000;
+/// consecutive bad blocks tolerated before giving up
+pub const XMODEM_RETRIES: u32 = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum XmodemError {
+    /// the other end went quiet
+    Timeout,
+    /// the other end sent CAN
+    Cancelled,
+    /// a block arrived out of order
+    Sequence,
+    /// too many damaged blocks in a row
+    Retries,
+}
+
+/// CRC-16/XMODEM
+pub fn crc16_xmodem(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Receive a file, handing each block's data to `f`, and return the number of bytes received
+/// (padding included). Gives up if the sender is silent for `timeout_ms`.
+pub fn xmodem_receive<T: LinkIo, F: FnMut(&[u8])>(io: &mut T, timeout_ms: u32, mut f: F) -> Result<usize, XmodemError> {
+    // block number, its complement, data, CRC
+    let mut block: [u8; XMODEM_BLOCK_1K + 4] = [0; XMODEM_BLOCK_1K + 4];
+    let mut expected: u8 = 1;
+    let mut total: usize = 0;
+    let mut errors: u32 = 0;
+    let mut waited: u32 = 0;
+    let mut started: bool = false;
+
+    io.write(&[CRC_MODE]);
+    loop {
+        let c: u8 = match io.read(XMODEM_POLL_MS) {
+            Some(c) => c,
+            None => {
+                waited += XMODEM_POLL_MS;
+                if waited >= timeout_ms {
+                    io.write(&[CAN, CAN]);
+                    return Err(XmodemError::Timeout);
+                }
+                io.write(&[if started { NAK } else { CRC_MODE }]);
+                continue;
+            },
+        };
+        waited = 0;
+        let len: usize = match c {
+            SOH => XMODEM_BLOCK,
+            STX => XMODEM_BLOCK_1K,
+            EOT => {
+                io.write(&[ACK]);
+                return Ok(total);
+            },
+            CAN => return Err(XmodemError::Cancelled),
+            _ => continue, // line noise between blocks
+        };
+        started = true;
+
+        let mut complete: bool = true;
+        for b in block[..len + 4].iter_mut() {
+            match io.read(XMODEM_POLL_MS) {
+                Some(c) => *b = c,
+                None => {
+                    complete = false;
+                    break;
+                },
+            }
+        }
+        let data: &[u8] = &block[2..2 + len];
+        let crc: u16 = u16::from_be_bytes([block[2 + len], block[3 + len]]);
+        if complete && block[0] == !block[1] && crc16_xmodem(data) == crc {
+            if block[0] == expected {
+                f(data);
+                total += len;
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                io.write(&[ACK]);
+                continue;
+            }
+            if block[0] == expected.wrapping_sub(1) {
+                io.write(&[ACK]); // our ACK got lost and the sender repeated itself
+                continue;
+            }
+            io.write(&[CAN, CAN]);
+            return Err(XmodemError::Sequence);
+        }
+
+        errors += 1;
+        if errors > XMODEM_RETRIES {
+            io.write(&[CAN, CAN]);
+            return Err(XmodemError::Retries);
+        }
+        // let the rest of a garbled block go by before asking for it again
+        while io.read(XMODEM_POLL_MS / 10).is_some() {}
+        io.write(&[NAK]);
+    }
+}
+
+/// wait for the receiver's verdict on a block, skipping anything else it has to say
+fn xmodem_reply<T: LinkIo>(io: &mut T, timeout_ms: u32) -> Option<u8> {
+    loop {
+        match io.read(timeout_ms)? {
+            c @ ACK | c @ NAK | c @ CAN => return Some(c),
+            _ => (),
+        }
+    }
+}
+
+/// Send `data` in 1K blocks to a receiver asking for CRC mode.
+pub fn xmodem_send<T: LinkIo>(io: &mut T, data: &[u8], timeout_ms: u32) -> Result<(), XmodemError> {
+    loop {
+        match io.read(timeout_ms) {
+            Some(CRC_MODE) => break,
+            Some(CAN) => return Err(XmodemError::Cancelled),
+            Some(_) => (),
+            None => return Err(XmodemError::Timeout),
+        }
+    }
+
+    let mut block: [u8; XMODEM_BLOCK_1K + 5] = [0; XMODEM_BLOCK_1K + 5];
+    for (i, chunk) in data.chunks(XMODEM_BLOCK_1K).enumerate() {
+        let seq: u8 = (i + 1) as u8;
+        block[0] = STX;
+        block[1] = seq;
+        block[2] = !seq;
+        block[3..3 + chunk.len()].copy_from_slice(chunk);
+        for b in block[3 + chunk.len()..3 + XMODEM_BLOCK_1K].iter_mut() {
+            *b = XMODEM_PAD;
+        }
+        let crc: u16 = crc16_xmodem(&block[3..3 + XMODEM_BLOCK_1K]);
+        block[3 + XMODEM_BLOCK_1K..].copy_from_slice(&crc.to_be_bytes());
+
+        let mut attempt: u32 = 0;
+        loop {
+            io.write(&block);
+            match xmodem_reply(io, timeout_ms) {
+                Some(ACK) => break,
+                Some(CAN) => return Err(XmodemError::Cancelled),
+                _ => (),
+            }
+            attempt += 1;
+            if attempt > XMODEM_RETRIES {
+                io.write(&[CAN, CAN]);
+                return Err(XmodemError::Retries);
+            }
+        }
+    }
+
+    for _ in 0..=XMODEM_RETRIES {
+        io.write(&[EOT]);
+        if xmodem_reply(io, timeout_ms) == Some(ACK) {
+            return Ok(());
+        }
+    }
+    Err(XmodemError::Retries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::tests::{test_data, wire};
+    use std::thread;
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16_xmodem(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn xmodem_roundtrip() {
+        let (mut a, mut b) = wire();
+        a.corrupt_at = Some(XMODEM_BLOCK_1K + 40); // damage the second block once
+        let data = test_data(XMODEM_BLOCK_1K * 2 + 300);
+        let sent = data.clone();
+        let sender = thread::spawn(move || xmodem_send(&mut a, &sent, 5000));
+        let mut got: Vec<u8> = Vec::new();
+        let len = xmodem_receive(&mut b, 5000, |d| got.extend_from_slice(d)).unwrap();
+        assert_eq!(sender.join().unwrap(), Ok(()));
+        assert_eq!(len, XMODEM_BLOCK_1K * 3);
+        assert_eq!(&got[..data.len()], &data[..]);
+        assert!(got[data.len()..].iter().all(|&c| c == XMODEM_PAD));
+    }
+}
@@ -248,7 +248,7 @@ impl JtagUartPhy {
             uart: BtUart::new(),
         };
 
-        ret.uart.init();
+        ret.uart.init().ok(); // stays polled if the interrupt can't be had
         ret
     }
 }
@@ -257,13 +257,9 @@ impl JtagUartPhy {
 impl JtagPhy for JtagUartPhy {
     /// pause for a given number of microseconds.
     fn pause(&mut self, us: u32) {
-        let mut delay: u32 = us/1000;
-        if delay == 0 {
-            delay = 1;
-        }
         unsafe {
             let p: betrusted_pac::Peripherals = betrusted_pac::Peripherals::steal();
-            delay_ms(&p, delay);
+            delay_us(&p, us);
         }
 }
 
@@ -274,7 +270,7 @@ impl JtagPhy for JtagUartPhy {
         if tms { c |= JtagUartPhy::MASK_TMS; }
         self.uart.write(c);
 
-        if self.uart.read() == 0x31 {  // 0x31 is '1', incidentally
+        if self.uart.read_timeout(Duration::from_ms(3)) == Ok(0x31) {  // 0x31 is '1', incidentally
             true
         } else {
             false
@@ -289,7 +285,7 @@ impl JtagPhy for JtagUartPhy {
         if tck { c |= JtagUartPhy::MASK_TCK; }
         self.uart.write(c);
 
-        if self.uart.read() == 0x31 {
+        if self.uart.read_timeout(Duration::from_ms(3)) == Ok(0x31) {
             true
         } else {
             false
@@ -318,11 +314,7 @@ impl JtagGpioPhy {
 impl JtagPhy for JtagGpioPhy {
     /// pause for a given number of microseconds.
     fn pause(&mut self, us: u32) {
-        let mut delay: u32 = us/1000;
-        if delay == 0 {
-            delay = 1;
-        }
-        delay_ms(&self.p, delay);
+        delay_us(&self.p, us);
 }
 
     /// given a tdi and tms value, pulse the clock, and then return the tdo that comes out 
@@ -0,0 +1,14 @@
+MEMORY
+{
+  RAM : ORIGIN = 0x40000000, LENGTH = 16M
+  FLASH : ORIGIN = 0x20280000, LENGTH = 0x280000
+}
+
+REGION_ALIAS("REGION_TEXT", FLASH);
+REGION_ALIAS("REGION_RODATA", FLASH);
+REGION_ALIAS("REGION_DATA", RAM);
+REGION_ALIAS("REGION_BSS", RAM);
+REGION_ALIAS("REGION_HEAP", RAM);
+REGION_ALIAS("REGION_STACK", RAM);
+
+_heap_size = LENGTH(RAM) - 1M;
@@ -0,0 +1,92 @@
+#![no_main]
+
+#![feature(alloc_error_handler)]
+
+#![no_std]
+
+//! The stage the CPU resets into. It checks both firmware slots against the signing key, lets
+//! boot-control's boot_decide() pick one, records the decision, and starts it.
+
+use core::panic::PanicInfo;
+use betrusted_rt::entry;
+
+// pull in external symbols to define heap start and stop
+// defined in memory.x
+extern "C" {
+    static _sheap: u8;
+    static _heap_size: u8;
+}
+
+// Plug in the allocator crate
+extern crate alloc;
+extern crate alloc_riscv;
+
+use alloc_riscv::RiscvHeap;
+
+#[global_allocator]
+static ALLOCATOR: RiscvHeap = RiscvHeap::empty();
+
+use betrusted_hal::hal_time::*;
+use betrusted_hal::hal_sha2::*;
+use betrusted_hal::hal_spinor::*;
+use betrusted_hal::hal_boot::*;
+use boot_control::*;
+use fw_image::FW_PUBKEY;
+
+const CONFIG_CLOCK_FREQUENCY: u32 = 100_000_000;
+
+#[panic_handler]
+fn panic(_panic_info: &PanicInfo<'_>) -> ! {
+    loop {}
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(_layout: alloc::alloc::Layout) -> ! {
+    panic!()
+}
+
+/// the slot to start, after recording the decision; None if there's nothing fit to boot
+fn choose(boot: &mut BootStore<BtSpiNorPhy>, pubkey: &[u8; 32]) -> Option<Slot> {
+    let mut sha2: BtSha2 = BtSha2::new();
+    let versions: [Option<u32>; 2] = [
+        boot.slot_check(Slot::A, pubkey, &mut sha2),
+        boot.slot_check(Slot::B, pubkey, &mut sha2),
+    ];
+    // without the counter there's no telling what's a rollback
+    let counter: u32 = boot.counter().ok()?;
+    let d: BootDecision = boot_decide(boot.load(), counter, versions);
+    if let Some(to) = d.counter {
+        boot.advance_counter(to).ok()?;
+    }
+    if d.changed && boot.store(&d.block).is_err() && d.slot != Some(d.block.active) {
+        // a trial boot that wasn't counted could go on forever, so take the known good slot
+        return versions[d.block.active.index()].map(|_| d.block.active);
+    }
+    d.slot
+}
+
+#[entry]
+fn main() -> ! {
+    let p = betrusted_pac::Peripherals::take().unwrap();
+    time_init(&p, CONFIG_CLOCK_FREQUENCY / 1_000_000);
+    unsafe {
+        let heap_start = &_sheap as *const u8 as usize;
+        let heap_size = &_heap_size as *const u8 as usize;
+        ALLOCATOR.init(heap_start, heap_size);
+    }
+
+    let mut flash: SpiNor<BtSpiNorPhy> = SpiNor::new(BtSpiNorPhy::new(), SPINOR_SIZE);
+    let slot: Option<Slot> = match FW_PUBKEY {
+        Some(key) => choose(&mut BootStore::new(&mut flash), &key),
+        // nothing to check images against, and the firmware takes no updates: start slot A as is
+        None => Some(Slot::A),
+    };
+
+    match slot {
+        Some(slot) => {
+            let start: fn() -> ! = unsafe { core::mem::transmute(slot_entry(slot)) };
+            start()
+        },
+        None => loop {},
+    }
+}
@@ -6,6 +6,8 @@
 #![no_std]
 
 use core::panic::PanicInfo;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 use betrusted_rt::entry;
 
 // pull in external symbols to define heap start and stop
@@ -33,6 +35,13 @@ const CONFIG_CLOCK_FREQUENCY: u32 = 100_000_000;
 #[used] // This is necessary to keep DBGSTR from being optimized out
 static mut DBGSTR: [u32; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
 
+/// set by a periodic timer when it's time to poll the EC again
+static EC_POLL_DUE: AtomicBool = AtomicBool::new(false);
+
+fn ec_poll_timer(_id: TimerId) {
+    EC_POLL_DUE.store(true, Ordering::Relaxed);
+}
+
 macro_rules! readpac32 {
     ($self:ident, $func:ident, $reg:ident) => {
         $self.p.$func.$reg.read().bits()
@@ -68,13 +77,27 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 use betrusted_hal::hal_i2c::*;
 use betrusted_hal::hal_time::*;
 use betrusted_hal::hal_lcd::*;
+use betrusted_hal::hal_console::*;
+use betrusted_hal::hal_compositor::{Compositor, TRUSTED_ROWS};
+use betrusted_hal::hal_statusbar::*;
 use betrusted_hal::hal_com::*;
+use betrusted_hal::hal_ec::*;
+use betrusted_hal::hal_battery::*;
 use betrusted_hal::hal_kbd::*;
+use betrusted_hal::hal_keyevent::*;
 use betrusted_hal::hal_xadc::*;
+use betrusted_hal::hal_supervisor::*;
+use betrusted_hal::hal_usbc::*;
 use betrusted_hal::hal_audio::*;
 use betrusted_hal::hal_rtc::*;
 use betrusted_hal::hal_aes::*;
 use betrusted_hal::hal_sha2::*;
+use betrusted_hal::hal_spinor::*;
+use betrusted_hal::hal_config::*;
+use betrusted_hal::hal_boot::*;
+use betrusted_hal::hal_uart::*;
+use betrusted_hal::hal_log::*;
+use betrusted_hal::{info, warn};
 use embedded_graphics::prelude::*;
 use embedded_graphics::egcircle;
 use embedded_graphics::pixelcolor::BinaryColor;
@@ -88,6 +111,11 @@ use alloc::string::String;
 
 use jtag::*;
 use efuse_api::*;
+use host_link::*;
+use host_link::xmodem::*;
+use host_link::bitmap::{Bitmap, rle_write};
+use fw_image::*;
+use boot_control::*;
 
 #[cfg(feature = "evt")]
 use jtag::JtagUartPhy as JtagPhy;
@@ -102,6 +130,13 @@ use aes_test::*;
 const SHA_DATA: &[u8; 142] = b"Every one suspects himself of at least one of the cardinal virtues, and this is mine: I am one of the few honest people that I have ever known";
 const SHA_DIGEST: [u32; 8] = [0xdc96c23d, 0xaf36e268, 0xcb68ff71, 0xe92f76e2, 0xb8a8379d, 0x426dc745, 0x19f5cff7, 0x4ec9c6d6];
 
+/// largest firmware image payload accepted for staging
+const FW_STAGING_LEN: usize = 4 * 1024 * 1024;
+/// how long an upload may sit idle before it's abandoned
+const FW_UPLOAD_TIMEOUT_MS: u32 = 30_000;
+/// the slot this build is linked to run from
+const RUNNING_SLOT: Slot = if cfg!(feature = "slot-b") { Slot::B } else { Slot::A };
+
 pub struct Bounce {
     vector: Point,
     radius: u32,
@@ -179,7 +214,7 @@ pub struct Repl {
     /// last fully-formed line
     cmd: String,
     /// output response
-    text: TextArea,
+    text: Console,
     /// power state variable
     power: bool,
     /// JTAG state variable
@@ -196,13 +231,27 @@ pub struct Repl {
     update_noise: bool,
     audio: BtAudio,
     audio_run: bool,
+    /// send the next frame down the link before it's flushed
+    screenshot: bool,
     rtc: BtRtc,
     aes: BtAes,
     sha2: BtSha2,
+    ec: BtEc<BtCom>,
+    /// boost mode as asked for, held off while a USB source is attached
+    boost: BoostPolicy,
+    uart: BtUart,
+    link: Link<BtUart>,
+    staged: Vec<u8>,
+    flash: SpiNor<BtSpiNorPhy>,
+    /// settings store, if the config partition could be mounted
+    config: Option<ConfigStore>,
+    /// keyboard layout, from the config store if one is set there
+    layout: Layout,
 }
 
 const PROMPT: &str = "bt> ";
-const NUM_LINES: usize = 6;
+/// rows of console: scrollback, command echo and the prompt
+const NUM_LINES: usize = 8;
 
 impl Repl {
     pub fn new() -> Self {
@@ -212,7 +261,7 @@ impl Repl {
                     p: betrusted_pac::Peripherals::steal(),
                     input: String::from(PROMPT),
                     cmd: String::from(" "),
-                    text: TextArea::new(NUM_LINES),
+                    text: Console::new(ConsoleFont::Font8x16, Size::new(FB_WIDTH_PIXELS as u32 - 20, NUM_LINES as u32 * 16)),
                     power: true,
                     jtag: JtagMach::new(),
                     jtagphy: JtagPhy::new(),
@@ -223,39 +272,76 @@ impl Repl {
                     update_noise: false,
                     audio: BtAudio::new(),
                     audio_run: false,
+                    screenshot: false,
                     rtc: BtRtc::new(),
                     aes: BtAes::new(),
                     sha2: BtSha2::new(),
+                    ec: BtEc::new(BtCom::new()),
+                    boost: BoostPolicy::new(),
+                    uart: BtUart::new(),
+                    link: Link::new(BtUart::new()),
+                    staged: Vec::new(),
+                    flash: SpiNor::new(BtSpiNorPhy::new(), SPINOR_SIZE),
+                    config: None,
+                    layout: Layout::dvorak(),
                 }
             };
-        r.text.add_text(&mut String::from("Awaiting input."));
+        r.uart.init().ok(); // stays polled if the interrupt can't be had
+        r.mount_config();
+        r.boot_confirm();
+        r.load_layout();
+        r.text.add_line("Awaiting input.");
+        r.text.write(PROMPT);
 
         r
     }
 
     pub fn input_char(&mut self, c: char) {
+        if self.text.key(c) { // paging through the scrollback
+            return;
+        }
+        self.text.scroll_to_bottom();
         if c.is_ascii() && !c.is_control() {
             self.input.push(c);
+            self.text.write(c.encode_utf8(&mut [0; 4]));
         } else if c == 0x8_u8.into() { // backspace
             if self.input.len() > PROMPT.len() {
                 self.input.pop();
+                self.text.write("\x08\x1b[K");
             }
         } else if c == 0xd_u8.into() { // carriage return
             self.cmd = self.input.clone();
             self.cmd.drain(..PROMPT.len());
             self.input = String::from(PROMPT);
 
+            self.text.write("\n");
             self.parse_cmd(); // now try parsing the command
+            self.text.write(PROMPT);
         }
     }
 
+    /// A line of output from outside a command, such as a log message: it goes in above the
+    /// prompt, which is redrawn with whatever has been typed so far.
+    pub fn print(&mut self, line: &str) {
+        self.text.write("\r\x1b[2K");
+        self.text.add_line(line);
+        self.text.write(&self.input);
+    }
+
+    /// the console cursor stands in for a blinking insertion carat
+    pub fn blink(&mut self, on: bool) {
+        self.text.set_cursor_visible(on);
+    }
+
     pub fn get_noise0(&self) -> [u16; 300] { self.noise0 }
     pub fn get_noise1(&self) -> [u16; 300] { self.noise1 }
     pub fn get_update_noise(&self) -> bool {self.update_noise}
     pub fn sample_noise(&mut self) {
         self.xadc.noise_only(true); // cut out other round-robin sensor readings
         for i in 0..300 {
-            self.xadc.wait_update();
+            if self.xadc.wait_update().is_err() {
+                break; // keeps the last graph's samples from here on
+            }
             self.noise0[i] = self.xadc.noise0();
             self.noise1[i] = self.xadc.noise1();
         }
@@ -267,26 +353,166 @@ impl Repl {
     /// is about 2-5x under the bandwidth of the noise, so this should effectively "whiten"
     /// the noise at the expense of absolute noise bitrate.
     pub fn dump_noise(&mut self) {
-        let mut noise: Vec<u16> = Vec::new();
+        let mut noise: Vec<u8> = Vec::new();
 
         self.xadc.noise_only(true); // cut out other round-robin sensor readings
 
         for _ in 0..100_000 {
-            self.xadc.wait_update();
-            noise.push(self.xadc.noise0() as u16);
+            if let Err(e) = self.xadc.wait_update() {
+                self.xadc.noise_only(false);
+                self.text.add_line(&format!("noise dump failed: {:?}", e));
+                return;
+            }
+            noise.extend_from_slice(&(self.xadc.noise0() as u16).to_le_bytes());
         }
-        self.uart_tx_u8(0x4E); // 'N'
-        self.uart_tx_u8(0x4F); // 'O'
-        for n in noise {
-            self.uart_tx_u8((n & 0xFF) as u8);
-            self.uart_tx_u8(((n >> 8) & 0xFF) as u8);
+        if let Err(e) = self.link.send_stream(Channel::Noise, &noise) {
+            self.text.add_line(&format!("noise dump failed: {:?}", e));
         }
-        self.uart_tx_u8(0x4F); // 'O'
-        self.uart_tx_u8(0x4E); // 'N'
 
         self.xadc.noise_only(false); // bring them back
     }
 
+    /// The run-length encoded image, as one transfer on the screenshot channel. The host side
+    /// turns it back into a picture with host_link::host::Screenshot.
+    pub fn send_screenshot<B: Bitmap>(&mut self, image: &B) {
+        let mut shot: Vec<u8> = Vec::new();
+        rle_write(image, |d| shot.extend_from_slice(d));
+        if let Err(e) = self.link.send_stream(Channel::Screenshot, &shot) {
+            self.print(&format!("screenshot failed: {:?}", e));
+        }
+    }
+
+    /// Take a firmware image over the framed link, or XMODEM-1K, and stage it if it checks out.
+    pub fn receive_image(&mut self, xmodem: bool) {
+        let pubkey: [u8; 32] = match FW_PUBKEY {
+            Some(key) => key,
+            None => {
+                warn!("no firmware signing key built in, uploads are disabled");
+                return;
+            },
+        };
+        let mut receiver: ImageReceiver = ImageReceiver::new(&pubkey, FW_STAGING_LEN);
+        let sha2: &mut BtSha2 = &mut self.sha2;
+        let transfer: Result<usize, String> = if xmodem {
+            xmodem_receive(&mut self.uart, FW_UPLOAD_TIMEOUT_MS, |data| receiver.push(sha2, data)).map_err(|e| format!("{:?}", e))
+        } else {
+            self.link.recv_stream(Channel::FileUpload, FW_UPLOAD_TIMEOUT_MS, |data| receiver.push(sha2, data)).map_err(|e| format!("{:?}", e))
+        };
+        if let Err(e) = transfer {
+            warn!("image transfer failed: {}", e);
+            return;
+        }
+        match receiver.finish(&mut self.sha2, &mut self.staged) {
+            Ok(header) => info!("staged image version {}, {} bytes", header.version, header.length),
+            Err(e) => warn!("image rejected: {:?}", e),
+        }
+    }
+
+    /// mount the settings store; flash is only written here to format a blank store
+    pub fn mount_config(&mut self) {
+        let part: Partition = flash_partition("config").unwrap();
+        let mut region = self.flash.region(part);
+        match ConfigStore::mount(&mut region) {
+            Ok(store) => self.config = Some(store),
+            Err(e) => warn!("config: mount failed: {:?}", e),
+        }
+    }
+
+    fn config_get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut region = self.flash.region(flash_partition("config").unwrap());
+        self.config.as_mut()?.get(&mut region, key).ok()?
+    }
+
+    fn config_set(&mut self, key: &[u8], value: &[u8]) -> bool {
+        let mut region = self.flash.region(flash_partition("config").unwrap());
+        match self.config.as_mut().map(|c| c.set(&mut region, key, value)) {
+            Some(Ok(())) => true,
+            Some(Err(e)) => {
+                warn!("config: {:?}", e);
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// use the layout kept in the config store, if it parses
+    fn load_layout(&mut self) {
+        if let Some(bytes) = self.config_get(LAYOUT_KEY) {
+            match Layout::from_bytes(&bytes) {
+                Ok(layout) => self.layout = layout,
+                Err(e) => warn!("kbd: layout: {:?}", e),
+            }
+        }
+    }
+
+    /// Getting this far counts as passing the self-test, so confirm an update that's on trial,
+    /// and raise the rollback counter to its version so it can't be replaced by anything older.
+    pub fn boot_confirm(&mut self) {
+        let mut boot: BootStore<BtSpiNorPhy> = BootStore::new(&mut self.flash);
+        let mut block: BootBlock = match boot.load() {
+            Some(block) if block.on_trial() && block.running_slot() == RUNNING_SLOT => block,
+            _ => return,
+        };
+        if block.confirm().is_err() {
+            return;
+        }
+        if let Err(e) = boot.store(&block) {
+            warn!("boot: {:?}", e);
+            return;
+        }
+        info!("boot: confirmed slot {:?}", RUNNING_SLOT);
+        if let Some(version) = boot.slot_header(RUNNING_SLOT).map(|h| h.version) {
+            match boot.advance_counter(version) {
+                Ok(()) => info!("boot: rollback floor now {}", version),
+                Err(e) => warn!("boot: rollback counter: {:?}", e),
+            }
+        }
+    }
+
+    /// write the staged image into the slot that isn't running, and put it on trial
+    pub fn commit_image(&mut self) {
+        let header: ImageHeader = match ImageHeader::from_bytes(&self.staged) {
+            Ok(header) => header,
+            Err(_) => {
+                warn!("no image staged");
+                return;
+            },
+        };
+        let slot: Slot = RUNNING_SLOT.other();
+        // check before erasing anything
+        if header.slot_b() != (slot == Slot::B) {
+            warn!("image is linked for the running slot {:?}", RUNNING_SLOT);
+            return;
+        }
+        let mut boot: BootStore<BtSpiNorPhy> = BootStore::new(&mut self.flash);
+        let mut block: BootBlock = boot.load().unwrap_or_default();
+        match boot.counter() {
+            Ok(counter) => block.min_version = block.min_version.max(counter),
+            Err(e) => {
+                warn!("boot: rollback counter: {:?}", e);
+                return;
+            },
+        }
+        if header.version < block.min_version {
+            warn!("image version {} is below the floor {}", header.version, block.min_version);
+            return;
+        }
+        // whatever is running is good enough to fall back to
+        block.active = RUNNING_SLOT;
+
+        if let Err(e) = boot.write_slot(slot, &self.staged) {
+            warn!("slot {:?} write failed: {:?}", slot, e);
+            return;
+        }
+        match block.stage_update(slot, header.version) {
+            Ok(()) => match boot.store(&block) {
+                Ok(()) => info!("version {} on trial in slot {:?}", header.version, slot),
+                Err(e) => warn!("boot: {:?}", e),
+            },
+            Err(e) => warn!("boot: {:?}", e),
+        }
+    }
+
     pub fn spi_perftest(&mut self) {
         const SPI_MEM: *const [u32; 0x100_0000] = 0x20000000 as *const [u32; 0x100_0000];
         let time: u32 = readpac32!(self, TICKTIMER, time0);
@@ -298,7 +524,7 @@ impl Repl {
 
         let endtime: u32 = readpac32!(self, TICKTIMER, time0);
 
-        self.text.add_text(&mut format!("time: {} sum: 0x{:08x}", endtime - time, sum));
+        self.text.add_line(&format!("time: {} sum: 0x{:08x}", endtime - time, sum));
     }
 
     pub fn ram_standby_init(&mut self) -> u32 {
@@ -349,20 +575,6 @@ impl Repl {
         }
     }
 
-    pub fn uart_tx_u8(&mut self, c: u8) {
-        while self.p.UART.txfull.read().bits() != 0 {}
-        unsafe { self.p.UART.rxtx.write(|w| w.bits(c as u32)); }
-        unsafe { self.p.UART.ev_pending.write(|w| w.bits(1)); }
-    }
-
-    pub fn get_cmd(&self) -> String {
-        self.cmd.clone()
-    }
-
-    pub fn get_input(&self) -> String {
-        self.input.clone()
-    }
-
     pub fn get_powerstate(self) -> bool {
         self.power
     }
@@ -384,33 +596,40 @@ impl Repl {
             return;
         } else {
             if self.cmd.trim() == "shutdown" || self.cmd.trim() == "shut" {
-                self.text.add_text(&mut String::from("Shutting down system"));
+                self.text.add_line("Shutting down system");
                 self.power = false; // the main UI loop needs to pick this up and render the display accordingly
             } else if self.cmd.trim() == "reboot" || self.cmd.trim() == "reb" {
-                self.text.add_text(&mut String::from("Rebooting in 5 seconds")); // can't see the message actually :P
+                self.text.add_line("Rebooting in 5 seconds"); // can't see the message actually :P
                 // set the wakeup alarm
                 self.rtc.wakeup_alarm(5);
                 // power down
                 self.power = false;
 /*            } else if self.cmd.trim() == "buzz" {
-                self.text.add_text(&mut String::from("Making a buzz"));
+                self.text.add_line("Making a buzz");
                 unsafe{ self.p.GPIO.drive.write(|w| w.bits(4)); }
                 unsafe{ self.p.GPIO.output.write(|w| w.bits(4)); }
                 let time: u32 = get_time_ms(&self.p);
                 while get_time_ms(&self.p) - time < 250 { }
                 unsafe{ self.p.GPIO.output.write(|w| w.bits(0)); }*/
             } else if self.cmd.trim() == "blon" {
-                self.text.add_text(&mut String::from("Turning backlight on"));
-                com_txrx(&self.p, 0x681F); // turn on the backlight to full brightness (31)
+                self.text.add_line("Turning backlight on");
+                self.ec.backlight(31).ok(); // full brightness
             } else if self.cmd.trim() == "bloff" {
-                self.text.add_text(&mut String::from("Turning backlight off"));
-                com_txrx(&self.p, 0x6800);
+                self.text.add_line("Turning backlight off");
+                self.ec.backlight(0).ok();
             } else if self.cmd.trim() == "boo" {
-                self.text.add_text(&mut String::from("Going boost"));
-                com_txrx(&self.p, 0x5afe);
+                match self.boost.request(true) {
+                    Some(boost) => {
+                        self.text.add_line("Going boost");
+                        self.ec.set_boost(boost).ok();
+                    },
+                    None => self.text.add_line("USB source attached, boost once it's unplugged"),
+                }
             } else if self.cmd.trim() == "chg" {
-                self.text.add_text(&mut String::from("Going charge"));
-                com_txrx(&self.p, 0x5a00);
+                self.text.add_line("Going charge");
+                if let Some(boost) = self.boost.request(false) {
+                    self.ec.set_boost(boost).ok();
+                }
             } else if self.cmd.trim() == "step" {
                 self.jtag.step(&mut self.jtagphy);
             } else if self.cmd.trim() == "id" {
@@ -421,7 +640,7 @@ impl Repl {
                 self.jtag.next(&mut self.jtagphy);
                 // NOW: - check the return data on .get() before using it
                 if self.jtag.get().is_none() { // discard ID code but check that there's something
-                   self.text.add_text(&mut format!("ID instruction not in get queue!"));
+                   self.text.add_line("ID instruction not in get queue!");
                    return;
                 }
 
@@ -432,30 +651,30 @@ impl Repl {
                 self.jtag.next(&mut self.jtagphy);
                 let d: u32 = self.jtag.dbg_get();
                 if let Some(mut iddata) = self.jtag.get() { // this contains the actual idcode data
-                    self.text.add_text(&mut format!("tag: {}, code: 0x{:08x}, d:{}", iddata.tag(), iddata.pop_u32(32, JtagEndian::Little).unwrap(), d));
+                    self.text.add_line(&format!("tag: {}, code: 0x{:08x}, d:{}", iddata.tag(), iddata.pop_u32(32, JtagEndian::Little).unwrap(), d));
                 } else {
-                    self.text.add_text(&mut format!("ID data not in get queue!"));
+                    self.text.add_line("ID data not in get queue!");
                 }
             } else if self.cmd.trim() == "fk" { // crypto fuse
                 self.efuse.fetch(&mut self.jtag, &mut self.jtagphy);
                 let key: [u8; 32] = self.efuse.phy_key();
-                self.text.add_text(&mut String::from("Key, in hex:"));
+                self.text.add_line("Key, in hex:");
                 let mut line = String::from("");
                 for i in (16..32).rev() {
                     line = line + &format!("{:02x}", key[i]);
                 }
-                self.text.add_text(&mut line);
+                self.text.add_line(&line);
                 line = String::from("");
                 for i in (0..16).rev() {
                     line = line + &format!("{:02x}", key[i]);
                 }
-                self.text.add_text(&mut line);
+                self.text.add_line(&line);
             } else if self.cmd.trim() == "fu" {
                 self.efuse.fetch(&mut self.jtag, &mut self.jtagphy);
-                self.text.add_text(&mut format!("user: 0x{:08x}", self.efuse.phy_user()));
+                self.text.add_line(&format!("user: 0x{:08x}", self.efuse.phy_user()));
             } else if self.cmd.trim() == "fc" {
                 self.efuse.fetch(&mut self.jtag, &mut self.jtagphy);
-                self.text.add_text(&mut format!("cntl: 0x{:02x}", self.efuse.phy_cntl()));
+                self.text.add_line(&format!("cntl: 0x{:02x}", self.efuse.phy_cntl()));
             }  else if self.cmd.trim() == "test1" {
                 self.efuse.fetch(&mut self.jtag, &mut self.jtagphy);
                 let mut key: [u8; 32] = self.efuse.phy_key();
@@ -464,9 +683,9 @@ impl Repl {
                 key[24] = 0x81;
                 self.efuse.set_key(key);
                 if self.efuse.is_valid() {
-                    self.text.add_text(&mut format!("Patch is valid."));
+                    self.text.add_line("Patch is valid.");
                 } else {
-                    self.text.add_text(&mut format!("Patch is not valid."));
+                    self.text.add_line("Patch is not valid.");
                 }
                 self.efuse.burn(&mut self.jtag, &mut self.jtagphy);
             }  else if self.cmd.trim() == "dna" { // dna
@@ -476,7 +695,7 @@ impl Repl {
                 self.jtag.add(ir_leg);
                 self.jtag.next(&mut self.jtagphy);
                 if self.jtag.get().is_none() { // discard ID code but check that there's something
-                   self.text.add_text(&mut format!("cmd instruction not in get queue!"));
+                   self.text.add_line("cmd instruction not in get queue!");
                    return;
                 }
 
@@ -486,47 +705,85 @@ impl Repl {
                 self.jtag.next(&mut self.jtagphy);
                 if let Some(mut data) = self.jtag.get() {
                     let dna: u128 = data.pop_u128(64, JtagEndian::Little).unwrap();
-                    self.text.add_text(&mut format!("{}/0x{:16x}", data.tag(), dna));
+                    self.text.add_line(&format!("{}/0x{:16x}", data.tag(), dna));
                 } else {
-                    self.text.add_text(&mut format!("dna data not in queue!"));
+                    self.text.add_line("dna data not in queue!");
                 }
             } else if self.cmd.trim() == "loop" {
-                unsafe { self.p.UART.ev_pending.write(|w| w.bits(self.p.UART.ev_pending.read().bits())); }
-                unsafe { self.p.UART.ev_enable.write(|w| w.bits(3)); }
-
                 // send 0-9 as a test
                 for _ in 0..10 {
                     for i in 0..10 {
-                        while self.p.UART.txfull.read().bits() != 0 {}
-                        unsafe { self.p.UART.rxtx.write(|w| w.bits(0x30 + i as u32)); }
-                        unsafe { self.p.UART.ev_pending.write(|w| w.bits(1)); }
+                        write!(self.uart, "{}", i).ok();
                     }
-                    // crlf
-                    unsafe { self.p.UART.rxtx.write(|w| w.bits(0xa as u32)); }
-                    unsafe { self.p.UART.rxtx.write(|w| w.bits(0xd as u32)); }
+                    write!(self.uart, "\n\r").ok();
+                }
+                if self.uart.overruns() != 0 {
+                    self.text.add_line(&format!("uart overruns: {}", self.uart.overruns()));
+                }
+            } else if self.cmd.trim() == "logdump" {
+                // the log_encode()d records, back to back, as one transfer on the log channel
+                let mut dump: Vec<u8> = Vec::new();
+                let mut count: u32 = 0;
+                log_read(|r| { log_encode(r, &mut dump); count += 1; });
+                if let Err(e) = self.link.send_stream(Channel::Log, &dump) {
+                    self.text.add_line(&format!("log dump failed: {:?}", e));
+                }
+                let (lost, dropped) = log_losses();
+                self.text.add_line(&format!("dumped {} records, {} lost, {} dropped", count, lost, dropped));
+            } else if self.cmd.trim() == "shot" {
+                self.screenshot = true; // the main loop sends the next frame it draws
+            } else if self.cmd.trim() == "upload" {
+                self.text.add_line("Waiting for upload...");
+                let mut file: Vec<u8> = Vec::new();
+                match self.link.recv_stream(Channel::FileUpload, 10_000, |chunk| file.extend_from_slice(chunk)) {
+                    Ok(len) => self.text.add_line(&format!("got {} bytes, crc32 0x{:08x}", len, crc32(&file))),
+                    Err(e) => self.text.add_line(&format!("upload failed: {:?}", e)),
                 }
+            } else if self.cmd.trim() == "loguart" {
+                // note: shares the UART with the JTAG-over-UART PHY on evt boards
+                if log_add_sink(log_uart_sink) {
+                    self.text.add_line("Logging to UART");
+                } else {
+                    self.text.add_line("No free log sinks");
+                }
+            } else if self.cmd.trim() == "fwup" {
+                self.text.add_line("Waiting for image...");
+                self.receive_image(false);
+            } else if self.cmd.trim() == "fwx" {
+                self.text.add_line("Waiting for XMODEM image...");
+                self.receive_image(true);
+            } else if self.cmd.trim() == "fwcommit" {
+                self.commit_image();
+            } else if self.cmd.trim() == "boot" {
+                let mut boot: BootStore<BtSpiNorPhy> = BootStore::new(&mut self.flash);
+                let counter: Result<u32, FlashError> = boot.counter();
+                match boot.load() {
+                    Some(b) => self.text.add_line(&format!("run {:?} act {:?} pend {:?}/{} min {}",
+                        RUNNING_SLOT, b.active, b.pending, b.tries, b.min_version)),
+                    None => self.text.add_line(&format!("run {:?}, no boot block", RUNNING_SLOT)),
+                }
+                self.text.add_line(&format!("rollback counter: {:?}", counter));
             } else if self.cmd.trim() == "xadc" {
-                let vccint: u32 = self.p.INFO.xadc_vccint.read().bits() as u32;
-                let vccaux: u32 = self.p.INFO.xadc_vccaux.read().bits() as u32;
-                let vccbram: u32 = self.p.INFO.xadc_vccbram.read().bits() as u32;
-                let temp: u32 = self.p.INFO.xadc_temperature.read().bits() as u32;
-
-                self.text.add_text(&mut format!("vccint: {:.3}V", (vccint as f64) / 1365.0));
-                self.text.add_text(&mut format!("vccaux: {:.3}V", (vccaux as f64) / 1365.0));
-                self.text.add_text(&mut format!("vccbram: {:.3}V", (vccbram as f64) / 1365.0));
-                self.text.add_text(&mut format!("temp: {:.2}C", ((temp as f64) * 0.12304) - 273.15));
+                for (name, rail) in [("vccint", XadcSupply::VccInt), ("vccaux", XadcSupply::VccAux), ("vccbram", XadcSupply::VccBram)].iter() {
+                    let (min, max) = self.xadc.supply_range(*rail);
+                    self.text.add_line(&format!("{}: {} ({} - {})", name, self.xadc.supply(*rail), min, max));
+                }
+                let (min, max) = self.xadc.temperature_range();
+                self.text.add_line(&format!("temp: {} ({} - {})", self.xadc.temperature(), min, max));
             } else if self.cmd.trim() == "sense" {
-                self.xadc.wait_update();
-                self.text.add_text(&mut format!("int:  {:.3}V  aux: {:.3}V", (self.xadc.vccint() as f64) / 1365.0, (self.xadc.vccaux() as f64) / 1365.0));
-                self.text.add_text(&mut format!("bram: {:.3}V temp: {:.2}C",
-                                                (self.xadc.vccbram() as f64) / 1365.0,
-                                                ((self.xadc.temp() as f64) * 0.12304) - 273.15 ));
-                self.text.add_text(&mut format!("vbus: {:4}mV cc1: {:4}mV cc2: {:4}mV",
+                if let Err(e) = self.xadc.wait_update() {
+                    self.text.add_line(&format!("xadc: {:?}, readings are stale", e));
+                }
+                self.text.add_line(&format!("int:  {}  aux: {}", self.xadc.vccint(), self.xadc.vccaux()));
+                self.text.add_line(&format!("bram: {} temp: {}", self.xadc.vccbram(), self.xadc.temperature()));
+                self.text.add_line(&format!("vbus: {:4}mV cc1: {:4}mV cc2: {:4}mV",
                                                 self.xadc.vbus_mv(),
                                                 self.xadc.cc1_mv(),
                                                 self.xadc.cc2_mv()  ));
-                self.text.add_text(&mut format!("noise0: {:4} noise1: {:4}", self.xadc.noise0(), self.xadc.noise1()));
-                self.text.add_text(&mut format!("audio: 0x{:04x}", self.xadc.audio_sample() ));
+                let cc: u16 = self.xadc.cc1_mv().max(self.xadc.cc2_mv());
+                self.text.add_line(&format!("cc: {:?}", SourceCurrent::from_cc_mv(cc)));
+                self.text.add_line(&format!("noise0: {:4} noise1: {:4}", self.xadc.noise0(), self.xadc.noise1()));
+                self.text.add_line(&format!("audio: 0x{:04x}", self.xadc.audio_sample() ));
             } else if self.cmd.trim() == "non" {
                 unsafe{ self.p.POWER.power.write(|w| w.noisebias().bit(true).noise().bits(3).self_().bit(true).state().bits(3) ); }
                 self.update_noise = true;
@@ -534,28 +791,28 @@ impl Repl {
                 unsafe{ self.p.POWER.power.write(|w| w.noisebias().bit(false).noise().bits(0).self_().bit(true).state().bits(3) ); }
                 self.update_noise = false;
             } else if self.cmd.trim() == "flag" {
-                self.text.add_text(&mut format!("xadc flags: 0x{:04x}", self.xadc.flags()));
+                self.text.add_line(&format!("xadc flags: 0x{:04x}", self.xadc.flags()));
             } else if self.cmd.trim() == "rom" || self.cmd.trim() == "r" {
                 let mut line: [u32; 3] = [0; 3];
                 for adr in 0..3 {
                     line[adr] = self.rom_read(adr as u8);
                 }
-                self.text.add_text(&mut format!("0x00: 0x{:08x} 0x{:08x} 0x{:08x}", line[0], line[1], line[2] ));
+                self.text.add_line(&format!("0x00: 0x{:08x} 0x{:08x} 0x{:08x}", line[0], line[1], line[2] ));
                 for adr in 0..3 {
                     line[adr] = self.rom_read((adr + 0x40) as u8);
                 }
-                self.text.add_text(&mut format!("0x40: 0x{:08x} 0x{:08x} 0x{:08x}", line[0], line[1], line[2] ));
+                self.text.add_line(&format!("0x40: 0x{:08x} 0x{:08x} 0x{:08x}", line[0], line[1], line[2] ));
                 for adr in 0..3 {
                     line[adr] = self.rom_read((adr + 0x80) as u8);
                 }
-                self.text.add_text(&mut format!("0x80: 0x{:08x} 0x{:08x} 0x{:08x}", line[0], line[1], line[2] ));
+                self.text.add_line(&format!("0x80: 0x{:08x} 0x{:08x} 0x{:08x}", line[0], line[1], line[2] ));
                 for adr in 0..3 {
                     line[adr] = self.rom_read((adr + 0xFC) as u8);
                 }
-                self.text.add_text(&mut format!("0xFC: 0x{:08x} 0x{:08x} 0x{:08x}", line[0], line[1], line[2] ));
+                self.text.add_line(&format!("0xFC: 0x{:08x} 0x{:08x} 0x{:08x}", line[0], line[1], line[2] ));
             } else if self.cmd.trim() == "inject" {
                 let (val, inv) = patch_frame(0x35e, 0, rom);
-                self.text.add_text(&mut format!("inject: 0x35e, 0, ROM: 0x{:08x}/0x{:08x}", val.unwrap(), inv.unwrap() ));
+                self.text.add_line(&format!("inject: 0x35e, 0, ROM: 0x{:08x}/0x{:08x}", val.unwrap(), inv.unwrap() ));
             } else if self.cmd.trim() == "dn" { // dump noise
                 unsafe{ self.p.POWER.power.write(|w| w.noisebias().bit(true).noise().bits(3).self_().bit(true).state().bits(3) ); }
                 delay_ms(&self.p, 200); // let the noise source stabilize
@@ -564,6 +821,39 @@ impl Repl {
             } else if self.cmd.trim() == "spi" {
                 // spi performance test
                 self.spi_perftest();
+            } else if self.cmd.trim() == "fid" {
+                let id: JedecId = self.flash.jedec_id();
+                self.text.add_line(&format!("flash id {:02x} {:02x} {:02x} sr {:02x}", id.manufacturer, id.memory_type, id.capacity, self.flash.status()));
+            } else if self.cmd.trim() == "cfg" {
+                let mut region = self.flash.region(flash_partition("config").unwrap());
+                match self.config.as_mut().map(|c| c.entries(&mut region)) {
+                    Some(Ok(entries)) => {
+                        for (k, v) in entries.iter() {
+                            self.text.add_line(&format!("{} = {:02x?}", String::from_utf8_lossy(k), v));
+                        }
+                    },
+                    Some(Err(e)) => self.text.add_line(&format!("config: {:?}", e)),
+                    None => self.text.add_line("config not mounted"),
+                }
+            } else if self.cmd.trim() == "kbd" {
+                let names: String = LAYOUTS.join(" ");
+                self.text.add_line(&format!("layout {}; have {}", self.layout.name(), names));
+            } else if self.cmd.trim().starts_with("kbd ") {
+                let name: String = String::from(self.cmd.trim()["kbd ".len()..].trim());
+                match Layout::builtin(&name) {
+                    Some(layout) => {
+                        let text: String = format!("base {}", name);
+                        if !self.config_set(LAYOUT_KEY, text.as_bytes()) {
+                            self.text.add_line("layout not saved");
+                        }
+                        self.layout = layout;
+                    },
+                    None => self.text.add_line(&format!("no layout {}", name)),
+                }
+            } else if self.cmd.trim() == "flash" {
+                for part in FLASH_PARTITIONS.iter() {
+                    self.text.add_line(&format!("{} 0x{:07x}+0x{:x}", part.name, part.offset, part.len));
+                }
             } else if self.cmd.trim() == "au" {
                 // start sampling
                 unsafe{ self.p.POWER.power.write(|w| w.audio().bit(true).self_().bit(true).state().bits(3)); }
@@ -602,7 +892,7 @@ impl Repl {
                     }
                 }
 
-                self.text.add_text(&mut format!("{} samples", samples));
+                self.text.add_line(&format!("{} samples", samples));
 
                 self.audio.audio_i2s_stop();
                 self.audio_run = false;
@@ -620,36 +910,50 @@ impl Repl {
                 self.audio.audio_i2s_stop();
             } else if self.cmd.trim() == "ramc" {
                 self.ram_clear();
-                self.text.add_text(&mut format!("RAM cleared."));
+                self.text.add_line("RAM cleared.");
             } else if self.cmd.trim() == "ramx" {
                 let errors = self.ram_check();
-                self.text.add_text(&mut format!("0x{:x} RAM errors.", errors));
+                self.text.add_line(&format!("0x{:x} RAM errors.", errors));
             } else if self.cmd.trim() == "rami" {
                 let len = self.ram_standby_init();
-                self.text.add_text(&mut format!("0x{:x} RAM states.", len));
+                self.text.add_line(&format!("0x{:x} RAM states.", len));
             } else if self.cmd.trim() == "rtc" {
                 self.rtc.rtc_set(0, 59, 22, 3, 3, 20, Weekdays::TUESDAY);
+            } else if self.cmd.trim() == "i2cscan" {
+                let found: Vec<u8> = i2c_scan(&self.p);
+                self.text.add_line(&format!("{} I2C devices", found.len()));
+                let mut line = String::from("");
+                for addr in found {
+                    line = line + &format!("0x{:02x} ", addr);
+                }
+                self.text.add_line(&line);
+            } else if self.cmd.trim() == "i2crec" {
+                if i2c_recover(&self.p) {
+                    self.text.add_line("I2C bus recovered");
+                } else {
+                    self.text.add_line("I2C bus still busy");
+                }
             } else if self.cmd.trim() == "ro" {
                 self.p.TRNG_OSC.ctl.write(|w| w.ena().bit(true));
             } else if self.cmd.trim() == "ae" {
                 let (pass, data) = test_aes_enc(&mut self.aes);
                 if pass {
-                    self.text.add_text(&mut format!("AES Encrypt passed"));
+                    self.text.add_line("AES Encrypt passed");
                 } else {
-                    self.text.add_text(&mut format!("AES Encrypt failed"));
+                    self.text.add_line("AES Encrypt failed");
                 }
                 for i in 0..4 {
-                    self.text.add_text(&mut format!("0x{:x} 0x{:x} 0x{:x} 0x{:x}", data[0 + i*4], data[1 + i*4], data[2 + i*4], data[3 + i*4]));
+                    self.text.add_line(&format!("0x{:x} 0x{:x} 0x{:x} 0x{:x}", data[0 + i*4], data[1 + i*4], data[2 + i*4], data[3 + i*4]));
                 }
             } else if self.cmd.trim() == "ad" {
                 let (pass, data) = test_aes_dec(&mut self.aes);
                 if pass {
-                    self.text.add_text(&mut format!("AES Decrypt passed"));
+                    self.text.add_line("AES Decrypt passed");
                 } else {
-                    self.text.add_text(&mut format!("AES Decrypt failed"));
+                    self.text.add_line("AES Decrypt failed");
                 }
                 for i in 0..4 {
-                    self.text.add_text(&mut format!("0x{:x} 0x{:x} 0x{:x} 0x{:x}", data[0 + i*4], data[1 + i*4], data[2 + i*4], data[3 + i*4]));
+                    self.text.add_line(&format!("0x{:x} 0x{:x} 0x{:x} 0x{:x}", data[0 + i*4], data[1 + i*4], data[2 + i*4], data[3 + i*4]));
                 }
             } else if self.cmd.trim() == "sh" {
                 self.sha2.config = Sha2Config::ENDIAN_SWAP | Sha2Config::DIGEST_SWAP | Sha2Config::SHA256_EN; // Sha2Config::HMAC_EN; // Sha2Config::SHA256_EN;
@@ -665,63 +969,21 @@ impl Repl {
                     }
                 }
                 if pass {
-                    self.text.add_text(&mut format!("SHA test passed"));
+                    self.text.add_line("SHA test passed");
                 } else {
-                    self.text.add_text(&mut format!("SHA test failed"));
+                    self.text.add_line("SHA test failed");
                 }
                 for i in 0..4 {
-                    self.text.add_text(&mut format!("0x{:x} 0x{:x}", digest[0 + i*2], digest[1 + i*2]));
+                    self.text.add_line(&format!("0x{:x} 0x{:x}", digest[0 + i*2], digest[1 + i*2]));
                 }
             } else {
-                self.text.add_text(&mut format!("{}: not recognized.", self.cmd.trim()));
-            }
-        }
-    }
-
-    pub fn get_line(&self, line: usize)-> String {
-        self.text.get_line(line)
-    }
-}
-
-pub struct TextArea {
-    height_lines: usize,
-    text: Vec<String>,
-}
-
-impl TextArea {
-    pub fn new(lines: usize) -> Self {
-        TextArea {
-            height_lines: lines,
-            text: Vec::new(),
-        }
-    }
-
-    pub fn get_height(&self) -> usize { self.height_lines }
-    pub fn get_width(&self) -> usize { 38 as usize }
-
-    pub fn get_line(&self, line: usize) -> String {
-        if line > self.height_lines {
-            String::from("")
-        } else {
-            if let Some(line) = self.text.get(line) {
-                line.clone()
-            } else {
-                String::from("")
+                self.text.add_line(&format!("{}: not recognized.", self.cmd.trim()));
             }
         }
     }
 
-    pub fn add_text(&mut self, text: &mut String) {
-        // add the new text
-        let strbytes = text.as_bytes();
-        for chunk in strbytes.chunks(self.get_width()) {
-            self.text.insert(0, String::from_utf8(chunk.to_vec()).unwrap());
-        }
-
-        // trim the old text
-        while self.text.len() > self.height_lines {
-            self.text.pop();
-        }
+    pub fn console(&self) -> &Console {
+        &self.text
     }
 }
 
@@ -732,14 +994,16 @@ fn main() -> ! {
     xous_nommu::init();
 
     let p = betrusted_pac::Peripherals::take().unwrap();
-    com_txrx(&p, 0xFFFF as u16);  // reset the link
+    let mut ec: BtEc<BtCom> = BtEc::new(BtCom::new());
+    ec.link_reset().ok();
     delay_ms(&p, 2); // give it 2 milliseconds to reset
-    com_txrx(&p, 0x9003 as u16);  // 0x90cc specifies power set command. bit 0 set means EC stays on; bit 1 means power SoC on
+    ec.power_set(PowerFlags::EC_STAY_ON | PowerFlags::SOC_ON).ok();
     unsafe{ p.POWER.power.write(|w| w.self_().bit(true).state().bits(3)); }
 
     p.SRAM_EXT.read_config.write( |w| w.trigger().bit(true) );  // check SRAM config
-    i2c_init(&p, CONFIG_CLOCK_FREQUENCY / 1_000_000);
-    time_init(&p);
+    let mut i2c: BtI2c = BtI2c::new();
+    i2c.init(CONFIG_CLOCK_FREQUENCY / 1_000_000).unwrap(); // also claims the I2C interrupt
+    time_init(&p, CONFIG_CLOCK_FREQUENCY / 1_000_000);
 
     let cr = p.SRAM_EXT.config_status.read().bits(); // pull out config params for debug
     unsafe {
@@ -751,25 +1015,43 @@ fn main() -> ! {
         DBGSTR[2] = cr;
     }
 
-    let display: LockedBtDisplay = LockedBtDisplay::new();
-    display.lock().init(CONFIG_CLOCK_FREQUENCY);
+    // word exchanges time out on the ticktimer, so this waits for time_init()
+    let ec_protocol: Result<EcProtocol, EcError> = ec.negotiate(); // an older EC only takes legacy words
+
+    // the timer wheel allocates, so it can't be touched before the heap is up
+    let mut timers: BtTimers = BtTimers::new();
+    timers.init().unwrap();
+    timers.every(Duration::from_ms(50), ec_poll_timer);
+    // catches I2C transactions that would otherwise only time out when polled
+    timers.every(Duration::from_ms(I2C_TXN_TIMEOUT_MS), i2c_timeout_timer);
+
+    // the OS draws on the display directly; the status bar gets the trusted rows from here
+    let mut compositor: Compositor<BtDisplay> = Compositor::new(BtDisplay::new());
+    compositor.init(CONFIG_CLOCK_FREQUENCY);
 
     let mut keyboard: KeyManager = KeyManager::new();
+    let mut key_events: KeyEvents = KeyEvents::new();
 
     // initialize vibe motor patch
 /*    unsafe{ p.GPIO.drive.write(|w| w.bits(4)); }
     unsafe{ p.GPIO.output.write(|w| w.bits(0)); }*/
 
     let radius: u32 = 14;
-    let size: Size = display.lock().size();
-    let mut cur_time: u32 = get_time_ms(&p);
+    let size: Size = compositor.display().size();
     let mut _stat_array: [u16; 10] = [0; 10];
-    let mut gg_array: [u16; 4] = [0; 4];
+    let mut battery: BtBattery = BtBattery::new();
+    let mut status_bar: StatusBar = StatusBar::new();
     let mut line_height: i32 = 18;
     let left_margin: i32 = 10;
     let mut bouncy_ball: Bounce = Bounce::new(radius, Rectangle::new(Point::new(0, line_height * 21), Point::new(size.width as i32, size.height as i32 - 1)));
-    let mut tx_index: usize = 0;
     let mut repl: Repl = Repl::new();
+    repl.ec = BtEc::with_protocol(BtCom::new(), ec.protocol());
+    info!("ec protocol: {:?}", ec_protocol);
+    let mut supervisor: Supervisor = Supervisor::new(SupervisorLimits::default());
+    if let Err(e) = supervisor.init(&mut repl.xadc) {
+        warn!("supervisor: {:?}", e);
+    }
+    let mut usbc: TypeCSink = TypeCSink::new();
 
     let mut nd: u8 = 0;
     let mut d1: char = ' ';
@@ -779,25 +1061,44 @@ fn main() -> ! {
     let mut u2: char = ' ';
 
     let mut samples: u32 = 0;
+    let mut log_cursor: u32 = 0;
 loop {
-        display.lock().clear();
+        timers.service();
+        for e in supervisor.service(&mut repl.xadc) {
+            warn!("supervisor: {:?}", e);
+        }
+        if take_shutdown_request() {
+            repl.power = false;
+        }
+        if let Some(event) = usbc.update(Instant::now(&p), repl.xadc.vbus_mv(), repl.xadc.cc1_mv(), repl.xadc.cc2_mv()) {
+            match event {
+                TypeCEvent::Attached { orientation, current } => info!("usb attached: {:?}, {}mA", orientation, current.ma()),
+                e => info!("usb: {:?}", e),
+            }
+            if let Some(boost) = repl.boost.event(event) {
+                ec.set_boost(boost).ok();
+            }
+        }
+
         if repl.power == false {
+            compositor.clear();
+            status_bar.invalidate(); // the standby screen takes the whole display
             Font12x16::render_str("Betrusted in Standby")
             .stroke_color(Some(BinaryColor::On))
             .translate(Point::new(50, 250))
-            .draw(&mut *display.lock());
+            .draw(&mut compositor.system());
 
             Font12x16::render_str("Press '0' to power on")
             .stroke_color(Some(BinaryColor::On))
             .translate(Point::new(40, 270))
-            .draw(&mut *display.lock());
+            .draw(&mut compositor.system());
 
-            display.lock().blocking_flush();
+            compositor.blocking_flush();
 
             unsafe{p.POWER.power.write(|w| w.self_().bit(false).state().bits(1));} // FIXME: figure out how to float the state bit while system is running...
-            com_txrx(&p, 0x9005 as u16);  // 0x90cc specifies power set command. bit 0 set means EC stays on; bit 2 set means fast discharge of FPGA domain
+            ec.power_set(PowerFlags::EC_STAY_ON | PowerFlags::DISCHARGE_FPGA).ok();
             delay_ms(&p, 3); // don't DoS the EC
-            com_txrx(&p, 0xFFFF as u16);  // reset the link
+            ec.link_reset().ok();
             delay_ms(&p, 3); // don't DoS the EC
 
             continue; // this creates the illusion of being powered off even if we're plugged in
@@ -806,18 +1107,20 @@ loop {
         if repl.audio_run {
             if repl.audio.audio_loopback_quick() {
                 samples = samples + 1;
-                repl.text.add_text(&mut format!("{} samples", samples));
+                repl.print(&format!("{} samples", samples));
             }
         }
 
-        let mut cur_line: i32 = 5;
+        // the status bar keeps the trusted rows, and redraws them itself when they change
+        compositor.clear_system();
+        let mut cur_line: i32 = TRUSTED_ROWS + 5;
 
         let uptime = format!{"Uptime {}s", (get_time_ms(&p) / 1000) as u32};
         line_height = 18;
         Font12x16::render_str(&uptime)
         .stroke_color(Some(BinaryColor::On))
         .translate(Point::new(left_margin,cur_line))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
         cur_line += line_height;
 
         // power state testing ONLY - force a power off in 5 seconds
@@ -830,21 +1133,27 @@ loop {
         bouncy_ball.update();
         let circle = egcircle!(bouncy_ball.loc, bouncy_ball.radius,
                                stroke_color = Some(BinaryColor::Off), fill_color = Some(BinaryColor::On));
-        circle.draw(&mut *display.lock());
+        circle.draw(&mut compositor.system());
 
         // ping the EC and update various records over time
-        if get_time_ms(&p) - cur_time > 50 {
-            cur_time = get_time_ms(&p);
-            if tx_index == 0 {
-                com_txrx(&p, 0x7000 as u16); // send the pointer reset command
-            } else if tx_index < gg_array.len() + 1 {
-                gg_array[tx_index - 1] = com_txrx(&p, 0xF0F0) as u16; // the transmit is a dummy byte
-            } else {
-                com_txrx(&p, 0xFFFF); // send link reset command
+        if EC_POLL_DUE.swap(false, Ordering::Relaxed) {
+            match ec.read_gas_gauge() {
+                Ok(gg) => {
+                    match battery.update(get_time_ms(&p), gg) {
+                        Some(BatteryEvent::Low) => warn!("battery low: {}%", gg.soc_percent().unwrap_or(0)),
+                        Some(BatteryEvent::Recovered) => info!("battery ok: {}%", gg.soc_percent().unwrap_or(0)),
+                        None => (),
+                    }
+                },
+                Err(e) => {
+                    warn!("gas gauge read failed: {:?}", e);
+                    ec.link_reset().ok(); // resync and try again next time around
+                },
             }
-            tx_index += 1;
-            tx_index = tx_index % (gg_array.len() + 2);
         }
+
+        // echo new log records to the console
+        log_follow(&mut log_cursor, |r| repl.print(&format!("{} {}", r.level.as_str(), r.message)));
         /*
         for i in 0..4 {
             // but update the result every loop iteration
@@ -852,48 +1161,69 @@ loop {
             Font12x16::render_str(&dbg)
             .stroke_color(Some(BinaryColor::On))
             .translate(Point::new(left_margin, cur_line))
-            .draw(&mut *display.lock());
+            .draw(&mut compositor.system());
             cur_line += line_height;
         }*/
-        let dbg = format!{"voltage: {}mV", gg_array[2]};
+        let gas_gauge: GasGauge = battery.latest().unwrap_or_default();
+        let dbg = format!{"voltage: {}mV", gas_gauge.voltage_mv};
+        Font12x16::render_str(&dbg)
+        .stroke_color(Some(BinaryColor::On))
+        .translate(Point::new(left_margin, cur_line))
+        .draw(&mut compositor.system());
+
+        cur_line += line_height;
+        let dbg = format!{"avg current: {}mA", gas_gauge.avg_current_ma};
         Font12x16::render_str(&dbg)
         .stroke_color(Some(BinaryColor::On))
         .translate(Point::new(left_margin, cur_line))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
 
         cur_line += line_height;
-        let dbg = format!{"avg current: {}mA", (gg_array[0] as i16)};
+        let dbg = format!{"sby current: {}mA", gas_gauge.sby_current_ma};
         Font12x16::render_str(&dbg)
         .stroke_color(Some(BinaryColor::On))
         .translate(Point::new(left_margin, cur_line))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
 
         cur_line += line_height;
-        let dbg = format!{"sby current: {}mA", (gg_array[1] as i16)};
+        let dbg = match (gas_gauge.charge, battery.time_to_empty_min(), battery.time_to_full_min()) {
+            // a legacy EC only reports the voltage: no capacity, so no time left either
+            (None, _, _) => match gas_gauge.soc_percent() {
+                Some(soc) => format!{"batt: ~{}% {}mV, time left unknown", soc, gas_gauge.voltage_mv},
+                None => String::from("batt: unknown"),
+            },
+            (Some(c), Some(tte), _) => format!{"batt: {}% {}mAh {}m left", c.soc_percent, c.remaining_mah, tte},
+            (Some(c), _, Some(ttf)) => format!{"batt: {}% {}mAh {}m to full", c.soc_percent, c.remaining_mah, ttf},
+            (Some(c), _, _) => format!{"batt: {}% {}mAh", c.soc_percent, c.remaining_mah},
+        };
         Font12x16::render_str(&dbg)
         .stroke_color(Some(BinaryColor::On))
         .translate(Point::new(left_margin, cur_line))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
 
         let (keydown, keyup) = keyboard.update();
+        for e in key_events.update(Instant::now(&p), &keyboard.pressed(), &repl.layout) {
+            if e.kind != KeyKind::Release {
+                repl.input_char(e.char);
+            }
+        }
         if keydown.is_some() {
             let mut keyvect = keydown.unwrap();
             nd = keyvect.len() as u8;
 
             if nd >= 1 {
                 let (r, c) = keyvect.pop().unwrap();
-                let scancode = map_dvorak((r,c));
+                let scancode = repl.layout.map((r,c));
                 let c: char;
                 match scancode.key {
                     None => c = ' ',
                     _ => c = scancode.key.unwrap(),
                 }
                 d1 = c;
-                repl.input_char(c);
             }
             if nd >= 2 {
                 let (r, c) = keyvect.pop().unwrap();
-                let scancode = map_dvorak((r,c));
+                let scancode = repl.layout.map((r,c));
                 let c: char;
                 match scancode.key {
                     None => c = ' ',
@@ -909,7 +1239,7 @@ loop {
 
             if nu >= 1 {
                 let (r, c) = keyvect.pop().unwrap();
-                let scancode = map_dvorak((r,c));
+                let scancode = repl.layout.map((r,c));
                 let c: char;
                 match scancode.key {
                     None => c = ' ',
@@ -919,7 +1249,7 @@ loop {
             }
             if nu >= 2 {
                 let (r, c) = keyvect.pop().unwrap();
-                let scancode = map_dvorak((r,c));
+                let scancode = repl.layout.map((r,c));
                 let c: char;
                 match scancode.key {
                     None => c = ' ',
@@ -934,7 +1264,7 @@ loop {
         Font8x16::render_str(&dbg)
         .stroke_color(Some(BinaryColor::On))
         .translate(Point::new(left_margin, cur_line))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
 
         if !repl.audio_run {
             cur_line += line_height;
@@ -943,14 +1273,14 @@ loop {
             Font12x16::render_str(&dbg)
             .stroke_color(Some(BinaryColor::On))
             .translate(Point::new(left_margin, cur_line))
-            .draw(&mut *display.lock());
+            .draw(&mut compositor.system());
         } else {
             cur_line += line_height;
             let dbg = format!{"RTC paused for audio"};
             Font12x16::render_str(&dbg)
             .stroke_color(Some(BinaryColor::On))
             .translate(Point::new(left_margin, cur_line))
-            .draw(&mut *display.lock());
+            .draw(&mut compositor.system());
         }
 
         // draw a demarcation line
@@ -958,59 +1288,34 @@ loop {
         Line::<BinaryColor>::new(Point::new(left_margin, cur_line),
         Point::new(size.width as i32 - left_margin, cur_line))
         .stroke_color(Some(BinaryColor::On))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
 
-        // split string into 4 lines and render
         cur_line += 4;
-        line_height = 15; // shorter line, smaller font
-
-        for line in (0..NUM_LINES).rev() {
-            let out = repl.get_line(line);
-            Font8x16::render_str(&out)
-            .stroke_color(Some(BinaryColor::On))
-            .translate(Point::new(left_margin, cur_line))
-            .draw(&mut *display.lock());
-            cur_line += line_height;
-        }
-
-        let cmd = repl.get_cmd();
-        Font8x16::render_str(&cmd)
-        .stroke_color(Some(BinaryColor::On))
-        .translate(Point::new(left_margin, cur_line))
-        .draw(&mut *display.lock());
-
-        cur_line += line_height;
-        let mut input = repl.get_input();
-        if (get_time_ms(&p) / 500) % 2 == 0 {
-            input.push('_'); // add an insertion carat
-        }
-        Font8x16::render_str(&input)
-        .stroke_color(Some(BinaryColor::On))
-        .translate(Point::new(left_margin, cur_line))
-        .draw(&mut *display.lock());
+        repl.blink((get_time_ms(&p) / 500) % 2 == 0);
+        repl.console().draw(&mut compositor.system(), Point::new(left_margin, cur_line));
+        cur_line += repl.console().size().height as i32;
 
-        cur_line += line_height;
         const GRAPH_MARGIN: i32 = 18;
         Line::<BinaryColor>::new(Point::new(GRAPH_MARGIN, cur_line + 128),
         Point::new(size.width as i32 - GRAPH_MARGIN, cur_line + 128))
         .stroke_color(Some(BinaryColor::On))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
         Line::<BinaryColor>::new(Point::new(GRAPH_MARGIN, cur_line + 64),
         Point::new(size.width as i32 - GRAPH_MARGIN, cur_line + 64))
         .stroke_color(Some(BinaryColor::On))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
         Line::<BinaryColor>::new(Point::new(GRAPH_MARGIN, cur_line + 0),
         Point::new(size.width as i32 - GRAPH_MARGIN, cur_line + 0))
         .stroke_color(Some(BinaryColor::On))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
         Line::<BinaryColor>::new(Point::new(size.width as i32 - GRAPH_MARGIN, cur_line),
         Point::new(size.width as i32 - GRAPH_MARGIN, cur_line + 128))
         .stroke_color(Some(BinaryColor::On))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
         Line::<BinaryColor>::new(Point::new(GRAPH_MARGIN, cur_line),
         Point::new(GRAPH_MARGIN, cur_line + 128))
         .stroke_color(Some(BinaryColor::On))
-        .draw(&mut *display.lock());
+        .draw(&mut compositor.system());
         if repl.get_update_noise() {
             repl.sample_noise();
             let noise0: [u16; 300] = repl.get_noise0();
@@ -1020,7 +1325,7 @@ loop {
                 Line::<BinaryColor>::new(Point::new(x, cur_line + 64 - noise0[index] as i32 / 64),
                 Point::new(x+1, cur_line + 64 - noise0[index+1] as i32 / 64))
                 .stroke_color(Some(BinaryColor::On))
-                .draw(&mut *display.lock());
+                .draw(&mut compositor.system());
                 x = x + 1;
             }
             x = GRAPH_MARGIN;
@@ -1028,11 +1333,25 @@ loop {
                 Line::<BinaryColor>::new(Point::new(x, cur_line + 128 - noise1[index] as i32 / 64),
                 Point::new(x+1, cur_line + 128 - noise1[index+1] as i32 / 64))
                 .stroke_color(Some(BinaryColor::On))
-                .draw(&mut *display.lock());
+                .draw(&mut compositor.system());
                 x = x + 1;
             }
         }
 
-        display.lock().flush().unwrap();
+        let status: SystemStatus = SystemStatus {
+            hours: repl.rtc.hours,
+            minutes: repl.rtc.minutes,
+            soc_percent: battery.latest().and_then(|g| g.soc_percent()),
+            charging: battery.avg_current_ma().map_or(false, |ma| ma > 0),
+            usb: UsbAttach::from_state(usbc.state()),
+            secure: compositor.system_only(),
+        };
+        status_bar.render(&status, &mut compositor.trusted());
+
+        if repl.screenshot {
+            repl.screenshot = false;
+            repl.send_screenshot(compositor.display());
+        }
+        compositor.flush().unwrap();
     }
 }